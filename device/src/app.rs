@@ -8,7 +8,14 @@ use esp_mbedtls::TlsReference;
 
 use framework::prelude::*;
 
-use crate::{app_config::AppConfig, bambu, spool_tag};
+use crate::{
+    app_config::AppConfig,
+    bambu::{self, BambuPrinter},
+    clock,
+    filament_staging::FilamentStaging,
+    printer_discovery::{self, PrinterDiscovery},
+    spool_inventory, spool_tag, spoolman, AppSDCard,
+};
 
 slint::include_modules!();
 
@@ -25,17 +32,32 @@ pub async fn app_task(
     tls: TlsReference<'static>,
     // Application
     app_config: Rc<RefCell<AppConfig>>,
+    bambu_printer_model: Rc<RefCell<BambuPrinter>>,
+    printer_discovery_model: Rc<RefCell<PrinterDiscovery>>,
+    filament_staging: Rc<RefCell<FilamentStaging>>,
+    sdcard: Rc<RefCell<AppSDCard>>,
     spi_device: ExclusiveDevice<esp_hal::spi::master::SpiDmaBus<'static, esp_hal::Async>, esp_hal::gpio::Output<'static>, embassy_time::Delay>,
     irq: esp_hal::gpio::Input<'static>,
 ) {
+    // == Setup Clock =================================================================
+
+    clock::init(stack, app_config.clone()).await;
+
     // == Setup Bambu Printer Model ===================================================
 
-    let bambu_printer_model = bambu::init(stack, app_config.clone(), tls).await;
+    printer_discovery::init(stack, printer_discovery_model.clone()).await;
+
+    bambu::init(stack, bambu_printer_model.clone(), printer_discovery_model.clone(), sdcard.clone(), tls).await;
 
     // == Setup spool_tag =============================================================
 
     let spool_tag_model = spool_tag::init(spi_device, irq, app_config.clone()).await;
 
+    // == Setup Spool Inventory =======================================================
+
+    let spoolman_model = spoolman::init(stack, app_config.clone()).await;
+    let spool_inventory_model = spool_inventory::create_model(sdcard.clone(), spoolman_model);
+
     // == Setup ViewModel =============================================================
     let ui_strong = ui.upgrade().unwrap();
     let view_model = crate::view_model::ViewModel::new(
@@ -46,7 +68,11 @@ pub async fn app_task(
         // Application
         app_config.clone(),
         bambu_printer_model,
+        printer_discovery_model,
         spool_tag_model,
+        spool_inventory_model,
+        filament_staging,
+        sdcard,
     );
 
     (*view_model).borrow_mut().init();
@@ -13,6 +13,7 @@ use framework::prelude::*;
 
 const PRINTER_CONFIG_KEY: &str = "_printer_";
 const TAG_CONFIG_KEY: &str = "_tag_";
+const SPOOLMAN_CONFIG_KEY: &str = "_spoolman_";
 
 fn serialize_option_ipv4<S>(ip: &Option<Ipv4Address>, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -55,6 +56,52 @@ struct PrinterConfig {
 struct TagConfig {
     pub scan_timeout: u64,
 }
+#[derive(serde::Deserialize, serde::Serialize)]
+struct SpoolmanConfig {
+    pub url: Option<String>,
+    pub report_consumption: bool,
+}
+
+// Status of the connection to the printer, as reported by the mqtt task
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrinterConnectionStatus {
+    NotConfigured, // printer serial and/or access code missing
+    Discovering,
+    Connecting,
+    Connected,
+    Unreachable,       // no response to the tcp connection
+    Refused,           // printer refused the tcp connection
+    TlsFailed(String), // tls handshake failed (e.g. certificate verification)
+    BadAccessCode,     // printer rejected the mqtt connection with bad user name or password
+    NotAuthorized,     // printer rejected the mqtt connection as not authorized
+    Rejected(String),  // printer rejected the mqtt connection for other reasons
+    Disconnected,
+}
+
+impl PrinterConnectionStatus {
+    // Retrying won't help, the configuration needs to be fixed
+    pub fn configuration_needed(&self) -> bool {
+        matches!(self, Self::NotConfigured | Self::BadAccessCode | Self::NotAuthorized)
+    }
+}
+
+impl core::fmt::Display for PrinterConnectionStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NotConfigured => write!(f, "Printer Serial or Access Code Missing"),
+            Self::Discovering => write!(f, "Searching for Printer"),
+            Self::Connecting => write!(f, "Connecting to Printer"),
+            Self::Connected => write!(f, "Connected to Printer"),
+            Self::Unreachable => write!(f, "Printer Unreachable"),
+            Self::Refused => write!(f, "Printer Refused Connection"),
+            Self::TlsFailed(reason) => write!(f, "Secure Connection Failed ({reason})"),
+            Self::BadAccessCode => write!(f, "Printer Rejected Access Code"),
+            Self::NotAuthorized => write!(f, "Printer Not Authorizing Connection"),
+            Self::Rejected(reason) => write!(f, "Printer Rejected Connection ({reason})"),
+            Self::Disconnected => write!(f, "Printer Disconnected"),
+        }
+    }
+}
 
 pub struct AppConfig {
     observers: Vec<alloc::rc::Weak<RefCell<dyn AppControlObserver>>>,
@@ -68,10 +115,17 @@ pub struct AppConfig {
     pub printer_serial: Option<String>,
     pub printer_access_code: Option<String>,
     pub tag_scan_timeout: u64,
+    pub spoolman_url: Option<String>, // e.g. http://192.168.1.20:7912, None when not syncing with Spoolman
+    pub spoolman_report_consumption: bool,
+    pub low_filament_threshold: u32,     // grams, a spool in a tray with less filament left is alerted, 0 for no alerts
+    pub ntp_server: Option<Ipv4Address>, // set only in the config file, the clock isn't synced without it
+    // debugging, set only in the config file
+    pub mqtt_capture: bool,          // capture the MQTT traffic with the printer to the SDCard
+    pub mqtt_replay: Option<String>, // replay the capture files in this SDCard folder instead of connecting to the printer
 
     config_processed_ok: Option<bool>,
     pn532_ok: Option<bool>,
-    printer_connectivity_ok: Option<bool>,
+    printer_connection_status: PrinterConnectionStatus,
 }
 
 impl AppConfig {
@@ -104,10 +158,16 @@ impl AppConfig {
             printer_serial: None,
             printer_access_code: None,
             tag_scan_timeout: 10,
+            spoolman_url: None,
+            spoolman_report_consumption: false,
+            low_filament_threshold: 100,
+            ntp_server: None,
+            mqtt_capture: false,
+            mqtt_replay: None,
 
             config_processed_ok: None,
             pn532_ok: None,
-            printer_connectivity_ok: None,
+            printer_connection_status: PrinterConnectionStatus::Connecting,
         }
     }
     // A function to parse the TOML-like string and populate the structure
@@ -129,6 +189,13 @@ impl AppConfig {
             }
         }
 
+        if let Ok(Some(spoolman_store)) = self.framework.borrow_mut().fetch(String::from(SPOOLMAN_CONFIG_KEY)) {
+            if let Ok(spoolman_config) = serde_json::from_str::<SpoolmanConfig>(&spoolman_store) {
+                self.spoolman_url = spoolman_config.url;
+                self.spoolman_report_consumption = spoolman_config.report_consumption;
+            }
+        }
+
         let mut section = String::from("");
 
         let mut parse_errors = false;
@@ -178,6 +245,44 @@ impl AppConfig {
                             term_error!("config file format error at tag timeout");
                         }
                     }
+                    "spoolman_url" => {
+                        self.spoolman_url = if value.is_empty() { None } else { Some(String::from(value)) };
+                    }
+                    "spoolman_report_consumption" => {
+                        if let Ok(report_consumption) = value.parse::<bool>() {
+                            self.spoolman_report_consumption = report_consumption;
+                        } else {
+                            parse_errors = true;
+                            term_error!("config file format error at spoolman report consumption");
+                        }
+                    }
+                    "inventory_low_filament_threshold" => {
+                        if let Ok(low_filament_threshold) = value.parse::<u32>() {
+                            self.low_filament_threshold = low_filament_threshold;
+                        } else {
+                            parse_errors = true;
+                            term_error!("config file format error at inventory low filament threshold");
+                        }
+                    }
+                    "clock_ntp_server" => {
+                        if let Ok(addr) = Ipv4Address::from_str(value) {
+                            self.ntp_server = Some(addr);
+                        } else {
+                            parse_errors = true;
+                            term_error!("config file format error at clock ntp server");
+                        }
+                    }
+                    "debug_mqtt_capture" => {
+                        if let Ok(mqtt_capture) = value.parse::<bool>() {
+                            self.mqtt_capture = mqtt_capture;
+                        } else {
+                            parse_errors = true;
+                            term_error!("config file format error at mqtt capture");
+                        }
+                    }
+                    "debug_mqtt_replay" => {
+                        self.mqtt_replay = if value.is_empty() { None } else { Some(String::from(value)) };
+                    }
                     _ => {
                         // allow unknown configs, ignore them
                     }
@@ -200,9 +305,15 @@ impl AppConfig {
     pub fn report_pn532(&mut self, status: bool) {
         self.pn532_ok = Some(status);
     }
-    pub fn report_printer_connectivity(&mut self, status: bool) {
-        self.printer_connectivity_ok = Some(status);
-        self.notify_printer_connect_status(status);
+    pub fn report_printer_connection_status(&mut self, status: PrinterConnectionStatus) {
+        if self.printer_connection_status != status {
+            self.printer_connection_status = status;
+            self.notify_printer_connect_status(&self.printer_connection_status);
+        }
+    }
+
+    pub fn printer_connection_status(&self) -> &PrinterConnectionStatus {
+        &self.printer_connection_status
     }
 
     pub fn initialization_ok(&self) -> bool {
@@ -215,7 +326,7 @@ impl AppConfig {
 
     #[allow(dead_code)]
     pub fn boot_completed(&self) -> bool {
-        self.framework.borrow().boot_completed() && self.initialization_ok() && matches!(self.printer_connectivity_ok, Some(true))
+        self.framework.borrow().boot_completed() && self.initialization_ok() && self.printer_connection_status == PrinterConnectionStatus::Connected
     }
 
     pub fn set_printer_config(
@@ -243,6 +354,24 @@ impl AppConfig {
         self.framework.borrow().store(String::from(PRINTER_CONFIG_KEY), printer_store)
     }
 
+    // The printer was found at a new ip (e.g. after a new DHCP lease), if the ip was configured the new one is
+    // stored instead so the next boot connects to it directly
+    pub fn update_printer_ip(&mut self, printer_ip: Ipv4Address) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
+        self.printer_ip = Some(printer_ip);
+        if self.configured_printer_ip.is_none() {
+            return Ok(());
+        }
+        self.configured_printer_ip = Some(printer_ip);
+        let printer_config = PrinterConfig {
+            ip: self.configured_printer_ip,
+            name: self.configured_printer_name.clone(),
+            serial: self.printer_serial.clone(),
+            access_code: self.printer_access_code.clone(),
+        };
+        let printer_store = serde_json::to_string(&printer_config).unwrap();
+        self.framework.borrow().store(String::from(PRINTER_CONFIG_KEY), printer_store)
+    }
+
     pub fn set_tag_config(&mut self, tag_scan_timeout: u64) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
         self.tag_scan_timeout = tag_scan_timeout;
         let tag_config = TagConfig {
@@ -252,13 +381,28 @@ impl AppConfig {
         self.framework.borrow().store(String::from(TAG_CONFIG_KEY), tag_store)
     }
 
+    pub fn set_spoolman_config(
+        &mut self,
+        spoolman_url: String,
+        report_consumption: bool,
+    ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
+        self.spoolman_url = if spoolman_url.is_empty() { None } else { Some(spoolman_url) };
+        self.spoolman_report_consumption = report_consumption;
+        let spoolman_config = SpoolmanConfig {
+            url: self.spoolman_url.clone(),
+            report_consumption: self.spoolman_report_consumption,
+        };
+        let spoolman_store = serde_json::to_string(&spoolman_config).unwrap();
+        self.framework.borrow().store(String::from(SPOOLMAN_CONFIG_KEY), spoolman_store)
+    }
+
     // Events
 
     pub fn subscribe(&mut self, observer: alloc::rc::Weak<RefCell<dyn AppControlObserver>>) {
         self.observers.push(observer);
     }
 
-    pub fn notify_printer_connect_status(&self, status: bool) {
+    pub fn notify_printer_connect_status(&self, status: &PrinterConnectionStatus) {
         for weak_observer in self.observers.iter() {
             let observer = weak_observer.upgrade().unwrap();
             observer.borrow_mut().on_printer_connect_status(status);
@@ -267,5 +411,5 @@ impl AppConfig {
 }
 
 pub trait AppControlObserver {
-    fn on_printer_connect_status(&self, status: bool);
+    fn on_printer_connect_status(&self, status: &PrinterConnectionStatus);
 }
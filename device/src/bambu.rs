@@ -1,12 +1,17 @@
 use crate::spool_tag::TAG_PLACEHOLDER;
 use alloc::{
+    collections::{BTreeMap, VecDeque},
     format,
     rc::Rc,
     string::{String, ToString},
+    vec,
     vec::Vec,
 };
-use core::{cell::RefCell, str::FromStr};
-use embassy_futures::select::{select, Either};
+use core::{
+    cell::RefCell,
+    str::FromStr,
+    sync::atomic::{AtomicU32, Ordering},
+};
 use embassy_net::{Ipv4Address, Stack};
 use embassy_sync::{
     blocking_mutex::{
@@ -15,8 +20,9 @@ use embassy_sync::{
     },
     channel::Channel,
     pubsub::PubSubChannel,
+    signal::Signal,
 };
-use embassy_time::{with_deadline, with_timeout, Duration, Instant, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use esp_mbedtls::TlsReference;
 use hashbrown::HashMap;
 use mqttrust::QoS;
@@ -26,29 +32,134 @@ use regex::Regex;
 use framework::prelude::*;
 
 use crate::{
-    app_config::AppConfig,
-    bambu_api::{self, PrintAms, PrintTray},
+    app_config::{AppConfig, PrinterConnectionStatus},
+    bambu_api::{self, Command, PrintAms, PrintTray},
+    drying::DryingPreset,
+    mqtt_capture::{self, MqttCapture},
     my_mqtt::BufferedMqttPacket,
+    printer_discovery::{self, PrinterDiscovery},
+    AppSDCard,
 };
 
 const FILAMENT_URL_PREFIX: &str = "https://info.filament3d.org/";
+// The tag NDEF message length is a single byte (see ndef::NDEFStructure), with room for the tag id replacing the placeholder
+// and the remaining weight added when usage is written back (see spool_inventory::descriptor_with_remaining_weight)
+const MAX_DESCRIPTOR_LEN: usize = 240;
+pub const BAMBU_BRAND: &str = "Bambu Lab";
+pub const AMS_HT_FIRST_ID: usize = 128; // AMS HT units are reported with ams_id 128 and up
+const MAX_AMS_WITH_BITS: usize = 4; // AMS units with exist / reading bits, see tray_bit_index
+const PENDING_CALI_SELECTION_TIMEOUT: Duration = Duration::from_secs(30);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+const COMMANDS_OUTBOX_CAPACITY: usize = 16;
+pub const MAIN_EXTRUDER: usize = 0; // the only extruder on single extruder printers, the right one on H2D
+const MAX_EXTRUDERS: usize = 2;
+// tray_now / tray_tar of single extruder printers when no tray is loaded. Multi extruder printers have an external tray 255,
+// so their loaded trays are taken from the extruders info instead (see process_print_message__push_status__extruders)
+const NO_TRAY: usize = 255;
+const EXTRUDER_NO_TRAY: u32 = 0xFFFF; // snow / star of the extruders info when no tray is loaded
+const DEFAULT_CHANGE_FILAMENT_TEMP: u32 = 220; // nozzle temperature for loading / unloading filament with unknown temperature
+const AMS_SETTINGS_HOLD_TIME: Duration = Duration::from_secs(3); // reports right after a settings change may still have the old values
+const RUNOUT_ERROR_MASK: u32 = 0xFF00_FFFF; // print_error of a runout is 0x07uu8011, uu the AMS id, or 0xFF for the external spool
+const RUNOUT_ERROR: u32 = 0x0700_8011;
+const HOME_FLAG_REMAIN_ESTIMATE_BIT: u32 = 7; // home_flag bits of the AMS settings
+const HOME_FLAG_AUTO_REFILL_BIT: u32 = 10;
+const AMS_TYPE_AMS_2_PRO: u32 = 3; // AMS types, bits 0-3 of the ams info
+const AMS_TYPE_AMS_HT: u32 = 4;
+pub const AMS_2_PRO_MAX_DRYING_TEMP: u32 = 65;
+pub const AMS_HT_MAX_DRYING_TEMP: u32 = 85;
 
 pub struct BambuPrinter {
-    pub nozzle_diameter: Option<String>,
-    pub ams_trays: [Tray; 16],
-    pub virt_tray: Tray,
+    pub extruders: Vec<Extruder>, // indexed by extruder id, a single one on single extruder printers
+    pub ams_trays: BTreeMap<(usize, usize), Tray>, // keyed by (ams_id, slot), built from ams_exist_bits and the ams array
+    pub virt_trays: BTreeMap<usize, Tray>, // external trays by tray id, 254 and on multi extruder printers also 255
     pub calibrations: HashMap<String, HashMap<i32, Calibration>>,
+    calibrations_version: u32, // changes whenever calibrations change, so observers can tell
+    pub ams_settings: AmsSettings,
+    ams_settings_hold: Option<Instant>, // reported settings are ignored until then, after changing them
     write_packets: &'static embassy_sync::channel::Channel<embassy_sync::blocking_mutex::raw::NoopRawMutex, crate::my_mqtt::BufferedMqttPacket, 3>,
     observers: Vec<alloc::rc::Weak<RefCell<dyn BambuPrinterObserver>>>,
     app_config: Rc<RefCell<AppConfig>>,
     tray_exist_bits: Option<u32>,
     tray_read_done_bits: Option<u32>,
     tray_reading_bits: Option<u32>,
-    pub ams_exist_bits: Option<u32>,
+    tray_is_bbl_bits: Option<u32>,
+    ams_exist_bits: Option<u32>,
+    tray_now: Option<usize>,         // tray loaded into the extruder, None for none (or not reported yet)
+    tray_tar: Option<Option<usize>>, // tray the printer is switching to, Some(None) when unloading, None if not reported
+    runout_tray: Option<usize>,      // tray whose filament ran out, while the printer reports the runout
+    ams_extruders: HashMap<usize, usize>, // ams_id -> the extruder it feeds, only reported by multi extruder printers
+    ams_types: HashMap<usize, u32>,     // ams_id -> AMS type from the ams info
+    ams_dry_times: HashMap<usize, u32>, // ams_id -> minutes left of drying, for AMS units that can dry filament
+    pending_cali_selections: Vec<PendingCaliSelection>,
+    nozzle_remaps: Vec<usize>, // extruders with a swapped nozzle, their trays calibrations are remapped once the new nozzle calibrations arrive
+    nozzle_changes: Vec<usize>, // extruders with a swapped nozzle, waiting to be reported to the observers
+    calibrations_restore_queue: Vec<(String, CalibrationBackup)>, // (nozzle_diameter, calibration) to add to the printer
+    calibrations_restore_signal: &'static Signal<NoopRawMutex, ()>, // raised when calibrations were queued for the restore task
+    // Commands pipeline, interior mutability since commands are published also through shared references
+    commands_outbox: RefCell<VecDeque<String>>, // payloads waiting for commands_task to send them
+    commands_signal: &'static Signal<NoopRawMutex, ()>,
+    pending_commands: RefCell<Vec<PendingCommand>>,
+    command_results: RefCell<Vec<(CommandContext, CommandResult)>>, // waiting to be reported to the observers
+    // push_status 'print' section merged from the last full report and the deltas after it (P1/A1 report only what changed)
+    printer_state: serde_json::Value,
+}
+
+static NEXT_SEQUENCE_ID: AtomicU32 = AtomicU32::new(1);
+
+fn next_sequence_id() -> u32 {
+    NEXT_SEQUENCE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// What a command was published for, reported back to the observers with the command result
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandContext {
+    Internal,
+    SetTrayFilament { tray_id: i32 },
+    LoadTray { tray_id: i32 },
+    UnloadTray,
+    ResetTrayFilament { tray_id: i32 },
+    AmsDrying { ams_id: usize, start: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandResult {
+    Success,
+    Failed(String), // reason reported by the printer
+    Timeout,
+    NotSent, // too many commands waiting to be sent
+}
+
+// AMS options of the printer, None until reported
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AmsSettings {
+    pub insertion_read: Option<bool>, // read RFID of spools on insertion, without it SpoolEase sees the spool once inserted
+    pub startup_read: Option<bool>,   // read RFID of spools on startup
+    pub remain_estimate: Option<bool>, // estimate remaining filament of Bambu spools
+    pub auto_refill: Option<bool>,    // switch to a spool of the same filament when a spool runs out
+}
+
+// A command sent to the printer, waiting for the response with its sequence_id
+struct PendingCommand {
+    sequence_id: u32,
+    command: String,
+    context: CommandContext,
+    expires: Instant,
+}
+
+// A calibration that was sent to the printer (extrusion_cali_set) and needs to be selected for a tray
+// once it shows up in the printer calibrations with its new cali_idx
+struct PendingCaliSelection {
+    nozzle_diameter: String,
+    extruder_id: usize,
+    tray_id: i32,
+    calibration: Calibration,
+    expires: Instant,
 }
 
 pub trait BambuPrinterObserver {
     fn on_trays_update(&self, bambu_printer: &BambuPrinter, prev_tray_reading_bits: Option<u32>, new_tray_reading_bits: Option<u32>);
+    fn on_command_result(&self, _bambu_printer: &BambuPrinter, _context: &CommandContext, _result: &CommandResult) {}
+    fn on_nozzle_change(&self, _bambu_printer: &BambuPrinter, _extruder_id: usize) {}
 }
 
 impl BambuPrinter {
@@ -58,6 +169,8 @@ impl BambuPrinter {
             crate::my_mqtt::BufferedMqttPacket,
             3,
         >,
+        commands_signal: &'static Signal<NoopRawMutex, ()>,
+        calibrations_restore_signal: &'static Signal<NoopRawMutex, ()>,
         app_config: Rc<RefCell<AppConfig>>,
     ) -> Self {
         let unknown = Tray {
@@ -65,47 +178,112 @@ impl BambuPrinter {
             filament: Filament::Unknown,
             k: None,
             cali_idx: None,
+            is_bbl: false,
         };
         Self {
-            nozzle_diameter: None,
-            ams_trays: [
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-                unknown.clone(),
-            ], //, unknown, unknown, unknown],
-            virt_tray: unknown,
+            extruders: vec![Extruder::default()],
+            ams_trays: BTreeMap::new(),
+            virt_trays: BTreeMap::from([(254, unknown)]),
             calibrations: HashMap::new(),
+            calibrations_version: 0,
+            ams_settings: AmsSettings::default(),
+            ams_settings_hold: None,
             write_packets,
             observers: Vec::new(),
             app_config,
             tray_exist_bits: None,
             tray_read_done_bits: None,
             tray_reading_bits: None,
+            tray_is_bbl_bits: None,
             ams_exist_bits: None,
+            tray_now: None,
+            tray_tar: None,
+            runout_tray: None,
+            ams_extruders: HashMap::new(),
+            ams_types: HashMap::new(),
+            ams_dry_times: HashMap::new(),
+            pending_cali_selections: Vec::new(),
+            nozzle_remaps: Vec::new(),
+            nozzle_changes: Vec::new(),
+            calibrations_restore_queue: Vec::new(),
+            calibrations_restore_signal,
+            commands_outbox: RefCell::new(VecDeque::new()),
+            commands_signal,
+            pending_commands: RefCell::new(Vec::new()),
+            command_results: RefCell::new(Vec::new()),
+            printer_state: serde_json::Value::Null,
         }
     }
     pub fn subscribe(&mut self, observer: alloc::rc::Weak<RefCell<dyn BambuPrinterObserver>>) {
         self.observers.push(observer);
     }
 
-    pub fn get_filament_calibration_for_current_nozzle<'a>(&self, filament_info: &'a FilamentInfo) -> Option<&'a Calibration> {
-        if let Some(filament_calibration) = filament_info.calibrations.get(self.nozzle_diameter.as_ref().unwrap()) {
-            return Some(filament_calibration);
+    pub fn nozzle_diameter(&self, extruder_id: usize) -> Option<&String> {
+        self.extruders.get(extruder_id).and_then(|extruder| extruder.nozzle_diameter.as_ref())
+    }
+
+    pub fn calibrations_version(&self) -> u32 {
+        self.calibrations_version
+    }
+
+    // Key of the calibrations of the extruder's nozzle (see nozzle_key)
+    pub fn nozzle_key(&self, extruder_id: usize) -> Option<String> {
+        let extruder = self.extruders.get(extruder_id)?;
+        Some(nozzle_key(extruder.nozzle_diameter.as_ref()?, extruder.nozzle_code().as_deref()))
+    }
+
+    // Printer calibrations of a nozzle, a key with only the diameter (older tags and backups, or fetched before the nozzle type
+    // was known) resolves to the calibrations of that diameter
+    pub fn printer_calibrations(&self, nozzle_key: &str) -> Option<&HashMap<i32, Calibration>> {
+        let nozzle_diameter = nozzle_key_diameter(nozzle_key);
+        self.calibrations.get(nozzle_key).or_else(|| self.calibrations.get(nozzle_diameter)).or_else(|| {
+            if nozzle_key_code(nozzle_key).is_some() {
+                return None;
+            }
+            self.calibrations
+                .iter()
+                .find(|(key, _)| nozzle_key_diameter(key) == nozzle_diameter)
+                .map(|(_, nozzle_calibrations)| nozzle_calibrations)
+        })
+    }
+
+    pub fn is_multi_extruder(&self) -> bool {
+        self.extruders.len() > 1
+    }
+
+    // Calibration commands carry the extruder_id only on multi extruder printers
+    fn command_extruder_id(&self, extruder_id: usize) -> Option<u32> {
+        if self.is_multi_extruder() {
+            Some(extruder_id as u32)
+        } else {
+            None
+        }
+    }
+
+    // The extruder a tray feeds. AMS units report it (on multi extruder printers), external tray 255 feeds the main
+    // extruder and 254 the deputy one on multi extruder printers (on single extruder printers 254 is the only external tray)
+    pub fn tray_extruder(&self, tray_id: usize) -> usize {
+        match tray_id {
+            254 if self.is_multi_extruder() => 1,
+            254 | 255 => MAIN_EXTRUDER,
+            _ => {
+                let (ams_id, _slot) = Self::get_ams_and_tray_id(tray_id);
+                self.ams_extruders.get(&ams_id).copied().unwrap_or(MAIN_EXTRUDER)
+            }
         }
-        None
+    }
+
+    // Calibrations of single extruder printers aren't tied to an extruder
+    fn calibration_on_extruder(calibration: &Calibration, extruder_id: usize) -> bool {
+        calibration.extruder_id.is_none_or(|v| v as usize == extruder_id)
+    }
+
+    pub fn get_filament_calibration_for_extruder<'a>(&self, filament_info: &'a FilamentInfo, extruder_id: usize) -> Option<&'a Calibration> {
+        filament_info.calibration_for_nozzle(&self.nozzle_key(extruder_id)?)
+    }
+
+    pub fn get_filament_calibration_for_current_nozzle<'a>(&self, filament_info: &'a FilamentInfo) -> Option<&'a Calibration> {
+        self.get_filament_calibration_for_extruder(filament_info, MAIN_EXTRUDER)
     }
 
     pub fn get_filament_k_for_current_nozzle(&self, filament_info: &FilamentInfo) -> String {
@@ -115,8 +293,8 @@ impl BambuPrinter {
         "".to_string()
     }
 
-    fn get_cali_k_value(&self, nozzle_diameter: &str, cali_idx: i32) -> Option<String> {
-        let nozzle_calibrations = match self.calibrations.get(nozzle_diameter) {
+    fn get_cali_k_value(&self, nozzle_key: &str, cali_idx: i32) -> Option<String> {
+        let nozzle_calibrations = match self.printer_calibrations(nozzle_key) {
             Some(calibrations) => calibrations,
             None => return None,
         };
@@ -128,23 +306,23 @@ impl BambuPrinter {
         Some(calibration.k_value.clone())
     }
 
-    fn get_tray_cali_k_value(&self, tray: &Tray) -> Option<String> {
+    fn get_tray_cali_k_value(&self, tray: &Tray, extruder_id: usize) -> Option<String> {
         let cali_idx = match tray.cali_idx {
             Some(cali_idx) => cali_idx,
             None => {
                 return tray.k.clone();
             }
         };
-        let nozzle_diameter = match &self.nozzle_diameter {
-            Some(nozzle_diameter) => nozzle_diameter,
+        let nozzle_key = match self.nozzle_key(extruder_id) {
+            Some(nozzle_key) => nozzle_key,
             None => {
                 return tray.k.clone();
             }
         };
-        self.get_cali_k_value(nozzle_diameter, cali_idx).or_else(|| tray.k.clone())
+        self.get_cali_k_value(&nozzle_key, cali_idx).or_else(|| tray.k.clone())
     }
 
-    fn tray_from_update(&self, tray_update: &PrintTray) -> Result<Option<Tray>, String> {
+    fn tray_from_update(&self, tray_update: &PrintTray, extruder_id: usize) -> Result<Option<Tray>, String> {
         if let (Some(tray_type_update), Some(tray_info_idx_update), Some(tray_color_update)) =
             (&tray_update.tray_type, &tray_update.tray_info_idx, &tray_update.tray_color)
         {
@@ -182,16 +360,17 @@ impl BambuPrinter {
 
             // TODO: This snippet is in two places, fix that
             new_tray.cali_idx = tray_update.cali_idx;
+            new_tray.remain = tray_update.remain.and_then(|remain| u32::try_from(remain).ok());
             // start by assigning the tray 'k', then override with calibration if exist
             new_tray.k = tray_update.k.map(|k| format!("({k:.3})"));
-            new_tray.k = self.get_tray_cali_k_value(&new_tray);
+            new_tray.k = self.get_tray_cali_k_value(&new_tray, extruder_id);
 
             // add the cali_idx and its name into the filament for the specific nozzle
-            if let (Some(nozzle_diameter), Some(cali_idx)) = (self.nozzle_diameter.as_ref(), tray_update.cali_idx.as_ref()) {
-                if let Some(calibrations) = self.calibrations.get(nozzle_diameter) {
+            if let (Some(nozzle_key), Some(cali_idx)) = (self.nozzle_key(extruder_id), tray_update.cali_idx.as_ref()) {
+                if let Some(calibrations) = self.printer_calibrations(&nozzle_key) {
                     if let Some(calibration) = calibrations.get(cali_idx) {
                         if let Filament::Known(ref mut filament_info) = new_tray.filament {
-                            filament_info.calibrations.insert(nozzle_diameter.clone(), calibration.clone());
+                            filament_info.calibrations.insert(nozzle_key, calibration.clone());
                         }
                     }
                 }
@@ -205,21 +384,34 @@ impl BambuPrinter {
     // Arguments:
     //   old_tray is the tray as known prior to this update
     //   tray_update is the tray information received from the printer
-    //   tray_id is the tray_id in case of AMS or None in case of External spool
+    //   tray_key is the (ams_id, slot) in case of AMS or None in case of External spool
     // Return value:
     //   if tray not changed from old_tray, or something wrong with tray, returns None
-    pub fn get_updated_tray(&self, old_tray: &Tray, tray_update: Option<&PrintTray>, tray_id: Option<usize>) -> Option<Tray> {
-        if let Some(tray_id) = tray_id {
+    pub fn get_updated_tray(&self, old_tray: &Tray, tray_update: Option<&PrintTray>, tray_key: Option<(usize, usize)>) -> Option<Tray> {
+        let extruder_id = match tray_key {
+            Some((ams_id, slot)) => self.tray_extruder(Self::get_global_tray_id(ams_id, slot)),
+            None => tray_update.map_or(MAIN_EXTRUDER, |tray_update| self.tray_extruder(tray_update.id as usize)),
+        };
+        if let Some((ams_id, slot)) = tray_key {
             // AMS tray
-            if let Some(tray_exist_bits) = self.tray_exist_bits {
-                let tray_exist = ((tray_exist_bits >> tray_id) & 0x01) != 0;
-
+            let tray_bit = Self::tray_bit_index(ams_id, slot);
+            let tray_exist = match tray_bit {
+                Some(tray_bit) => self.tray_exist_bits.map(|x| ((x >> tray_bit) & 0x01) != 0),
+                // No bits for this ams, existence can only be deduced from the tray data, if there is no tray data don't change anything
+                None => Some(tray_update?.tray_type.is_some()),
+            };
+            if let Some(tray_exist) = tray_exist {
                 if tray_exist {
-                    let tray_reading = self.tray_reading_bits.map_or(false, |x| ((x >> tray_id) & 0x01) != 0);
-                    let tray_read_done = self.tray_read_done_bits.map_or(false, |x| ((x >> tray_id) & 0x01) != 0);
+                    let tray_bit_set = |bits: Option<u32>, default: bool| match tray_bit {
+                        Some(tray_bit) => bits.map_or(false, |x| ((x >> tray_bit) & 0x01) != 0),
+                        None => default,
+                    };
+                    let tray_reading = tray_bit_set(self.tray_reading_bits, false);
+                    let tray_read_done = tray_bit_set(self.tray_read_done_bits, true);
+                    let tray_is_bbl = tray_bit_set(self.tray_is_bbl_bits, false);
 
                     let mut new_tray = if let Some(tray_update) = tray_update {
-                        if let Ok(tray_update) = self.tray_from_update(tray_update) {
+                        if let Ok(tray_update) = self.tray_from_update(tray_update, extruder_id) {
                             // TODO: in case I a tray w/o any information (but with exist bit) then I just copy old, is it ok?
                             let tray_based_on_update = tray_update.unwrap_or_else(|| {
                                 let mut new_tray = old_tray.clone();
@@ -245,6 +437,8 @@ impl BambuPrinter {
                     if tray_read_done {
                         new_tray.state = TrayState::Ready;
                     }
+                    // The AMS identified the spool by its Bambu RFID tag, so it owns the tray filament settings
+                    new_tray.is_bbl = tray_is_bbl;
                     return Some(new_tray);
                 } else {
                     // In case the tray is empty (so no ready bits), we still want to keep the filamen-info of the tray, but set it as empty
@@ -252,6 +446,8 @@ impl BambuPrinter {
                     // we remember historical color, K, etc (which the printer also remembers, just doesn't report)
                     let mut new_tray = old_tray.clone();
                     new_tray.state = TrayState::Empty;
+                    new_tray.is_bbl = false;
+                    new_tray.remain = None;
                     Some(new_tray)
                 }
             } else {
@@ -261,7 +457,7 @@ impl BambuPrinter {
         } else {
             // External Tray
             if let Some(tray_update) = tray_update {
-                if let Ok(tray_update) = self.tray_from_update(tray_update) {
+                if let Ok(tray_update) = self.tray_from_update(tray_update, extruder_id) {
                     if let Some(mut new_tray) = tray_update {
                         // External tray with data is always considered Ready
                         if matches!(new_tray.filament, Filament::Unknown) {
@@ -365,14 +561,14 @@ impl BambuPrinter {
                             new_tray.cali_idx = tray_update.cali_idx;
                             // start by assigning the tray 'k', then override with calibration if exist
                             new_tray.k = tray_update.k.map(|k| format!("({k:.3})"));
-                            new_tray.k = self.get_tray_cali_k_value(&new_tray);
+                            new_tray.k = self.get_tray_cali_k_value(&new_tray, MAIN_EXTRUDER);
 
                             // add the cali_idx and its name into the filament for the specific nozzle
-                            if let (Some(nozzle_diameter), Some(cali_idx)) = (self.nozzle_diameter.as_ref(), tray_update.cali_idx.as_ref()) {
-                                if let Some(calibrations) = self.calibrations.get(nozzle_diameter) {
+                            if let (Some(nozzle_key), Some(cali_idx)) = (self.nozzle_key(MAIN_EXTRUDER), tray_update.cali_idx.as_ref()) {
+                                if let Some(calibrations) = self.printer_calibrations(&nozzle_key) {
                                     if let Some(calibration) = calibrations.get(cali_idx) {
                                         if let Filament::Known(ref mut filament_info) = new_tray.filament {
-                                            filament_info.calibrations.insert(nozzle_diameter.clone(), calibration.clone());
+                                            filament_info.calibrations.insert(nozzle_key, calibration.clone());
                                         }
                                     }
                                 }
@@ -390,6 +586,7 @@ impl BambuPrinter {
                             filament: Filament::Unknown,
                             cali_idx: None,
                             k: None,
+                            is_bbl: false,
                         })
                     } else {
                         // No data in ams tray and tray exist, don't change a thing for this tray
@@ -447,13 +644,76 @@ impl BambuPrinter {
         }
     }
 
+    // Global tray id (as used by the printer in extrusion_cali_sel, tray_now, etc.) to (ams_id, slot)
+    //   AMS / AMS 2 Pro (ams_id 0..) - ams_id * 4 + slot
+    //   AMS HT (ams_id 128..) - single slot, global tray id is the ams_id
+    //   External - 254 (returned as (254, 254))
     pub fn get_ams_and_tray_id(tray_id: usize) -> (usize, usize) {
-        if tray_id < 254 {
+        if tray_id >= 254 {
+            (254, tray_id)
+        } else if tray_id >= AMS_HT_FIRST_ID {
+            (tray_id, 0)
+        } else {
             let ams_id = tray_id / 4;
             let ams_tray_id = tray_id - ams_id * 4;
             (ams_id, ams_tray_id)
+        }
+    }
+
+    pub fn get_global_tray_id(ams_id: usize, slot: usize) -> usize {
+        if ams_id >= AMS_HT_FIRST_ID {
+            ams_id
         } else {
-            (254, tray_id)
+            ams_id * 4 + slot
+        }
+    }
+
+    pub fn ams_slots(ams_id: usize) -> usize {
+        if ams_id >= AMS_HT_FIRST_ID {
+            1
+        } else {
+            4
+        }
+    }
+
+    // Bit of the tray in the tray_*_bits, the first four AMS units use bits 0-15 (four per unit, as the ams_exist_bits "f" of
+    // four units). Where other units (AMS HT) have their bits isn't known, so their presence is taken from the ams array and
+    // their tray state is deduced from the tray data
+    fn tray_bit_index(ams_id: usize, slot: usize) -> Option<usize> {
+        if ams_id < MAX_AMS_WITH_BITS {
+            Some(ams_id * 4 + slot)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_tray(&self, tray_id: usize) -> Option<&Tray> {
+        if tray_id >= 254 {
+            self.virt_trays.get(&tray_id)
+        } else {
+            self.ams_trays.get(&Self::get_ams_and_tray_id(tray_id))
+        }
+    }
+
+    fn get_tray_mut(&mut self, tray_id: usize) -> Option<&mut Tray> {
+        if tray_id >= 254 {
+            self.virt_trays.get_mut(&tray_id)
+        } else {
+            self.ams_trays.get_mut(&Self::get_ams_and_tray_id(tray_id))
+        }
+    }
+
+    // Connected AMS units, in display order (regular units first, then AMS HT units)
+    pub fn get_ams_ids(&self) -> Vec<usize> {
+        let mut ams_ids: Vec<usize> = self.ams_trays.keys().map(|(ams_id, _slot)| *ams_id).collect();
+        ams_ids.dedup();
+        ams_ids
+    }
+
+    pub fn is_tray_reading(tray_reading_bits: Option<u32>, ams_id: usize, slot: usize) -> bool {
+        match (tray_reading_bits, Self::tray_bit_index(ams_id, slot)) {
+            (Some(tray_reading_bits), Some(tray_bit)) => ((tray_reading_bits >> tray_bit) & 0x01) != 0,
+            _ => false,
         }
     }
 
@@ -461,9 +721,9 @@ impl BambuPrinter {
     pub fn process_print_message__push_status__ams(&mut self, ams: &PrintAms) -> bool {
         let mut change_made = false;
 
-        // first check which ams's exist
+        // first check which ams's exist (hex, like the other bits, "f" for four AMS units)
         if let Some(ams_exist_bits) = &ams.ams_exist_bits {
-            let ams_exist_bits = ams_exist_bits.parse::<u32>();
+            let ams_exist_bits = u32::from_str_radix(ams_exist_bits, 16);
             if let Ok(ams_exist_bits) = ams_exist_bits {
                 if self.ams_exist_bits.is_none() || self.ams_exist_bits.unwrap() != ams_exist_bits {
                     self.ams_exist_bits = Some(ams_exist_bits);
@@ -505,41 +765,303 @@ impl BambuPrinter {
                 }
             }
         }
+        // tray_is_bbl - which trays (from those that exist) hold a Bambu spool identified by its RFID tag
+        if let Some(tray_is_bbl_bits) = &ams.tray_is_bbl_bits {
+            if let Ok(tray_is_bbl_bits) = u32::from_str_radix(tray_is_bbl_bits, 16) {
+                if self.tray_is_bbl_bits != Some(tray_is_bbl_bits) {
+                    self.tray_is_bbl_bits = Some(tray_is_bbl_bits);
+                    change_made = true;
+                }
+            }
+        }
+
+        if !self.ams_settings_held() {
+            let mut ams_settings = self.ams_settings;
+            ams_settings.insertion_read = ams.insert_flag.or(ams_settings.insertion_read);
+            ams_settings.startup_read = ams.power_on_flag.or(ams_settings.startup_read);
+            change_made |= self.update_ams_settings(ams_settings);
+        }
+
+        // tray_now / tray_tar - the tray loaded into the extruder and the one the printer is switching to
+        // (multi extruder printers report them per extruder, see process_print_message__push_status__extruders)
+        if !self.is_multi_extruder() {
+            let reported_tray = |v: &String| v.parse::<usize>().ok().map(|tray_id| Some(tray_id).filter(|v| *v != NO_TRAY));
+            if let Some(tray_now) = ams.tray_now.as_ref().and_then(reported_tray) {
+                change_made |= self.update_loaded_tray(tray_now, self.tray_tar);
+            }
+            if let Some(tray_tar) = ams.tray_tar.as_ref().and_then(reported_tray) {
+                change_made |= self.update_loaded_tray(self.tray_now, Some(tray_tar));
+            }
+        }
+
+        if self.update_ams_topology(ams) {
+            change_made = true;
+        }
 
-        for tray_id in 0..self.ams_trays.len() {
-            let (ams_id, ams_tray_id) = BambuPrinter::get_ams_and_tray_id(tray_id);
+        // the AMS type, bits 0-3 of the ams info, and the extruder each AMS feeds, bits 8-11 (reported only by multi extruder printers)
+        if let Some(amss) = &ams.ams {
+            for ams_data in amss {
+                let ams_id = ams_data.id.parse::<usize>();
+                let info = ams_data.info.as_ref().map(|info| u32::from_str_radix(info, 16));
+                if let (Ok(ams_id), Some(Ok(info))) = (&ams_id, info) {
+                    let ams_type = info & 0x0F;
+                    if self.ams_types.insert(*ams_id, ams_type) != Some(ams_type) {
+                        change_made = true;
+                    }
+                    let extruder_id = ((info >> 8) & 0x0F) as usize;
+                    if self.ams_extruders.insert(*ams_id, extruder_id) != Some(extruder_id) {
+                        change_made = true;
+                    }
+                }
+                if let (Ok(ams_id), Some(dry_time)) = (ams_id, ams_data.dry_time) {
+                    if self.ams_dry_times.insert(ams_id, dry_time) != Some(dry_time) {
+                        change_made = true;
+                    }
+                }
+            }
+        }
+
+        let tray_keys: Vec<(usize, usize)> = self.ams_trays.keys().cloned().collect();
+        for (ams_id, slot) in tray_keys {
             let ams_id_str = format!("{ams_id}");
             let source_tray = if let Some(amss) = &ams.ams {
                 let ams = amss.iter().find(|v| v.id == ams_id_str);
                 if let Some(ams_data) = ams {
-                    ams_data.tray.iter().find(|v| v.id as usize == ams_tray_id)
+                    ams_data.tray.iter().find(|v| v.id as usize == slot)
                 } else {
                     None
                 }
             } else {
                 None
             };
-            let old_tray = &self.ams_trays[tray_id];
-            let new_tray = self.get_updated_tray(old_tray, source_tray, Some(tray_id));
+            let old_tray = &self.ams_trays[&(ams_id, slot)];
+            let new_tray = self.get_updated_tray(old_tray, source_tray, Some((ams_id, slot)));
             if let Some(new_tray) = new_tray {
                 change_made = true;
-                self.ams_trays[tray_id] = new_tray;
+                self.ams_trays.insert((ams_id, slot), new_tray);
+            }
+        }
+        change_made
+    }
+
+    // Adds trays of newly connected AMS units and removes trays of disconnected ones
+    // Units come from ams_exist_bits, and from the ams array for units that don't have exist bits (AMS HT)
+    fn update_ams_topology(&mut self, ams: &PrintAms) -> bool {
+        let mut ams_ids: Vec<usize> = Vec::new();
+        if let Some(ams_exist_bits) = self.ams_exist_bits {
+            for ams_id in 0..MAX_AMS_WITH_BITS {
+                if (ams_exist_bits >> ams_id) & 0x01 != 0 {
+                    ams_ids.push(ams_id);
+                }
+            }
+        }
+        if let Some(amss) = &ams.ams {
+            for ams_data in amss {
+                if let Ok(ams_id) = ams_data.id.parse::<usize>() {
+                    // units with exist bits are already known from the bits
+                    if Self::tray_bit_index(ams_id, 0).is_none() && !ams_ids.contains(&ams_id) {
+                        ams_ids.push(ams_id);
+                    }
+                }
+            }
+        }
+        // units without exist bits are kept until the printer reports the ams array without them
+        for ams_id in self.get_ams_ids() {
+            if Self::tray_bit_index(ams_id, 0).is_none() && !ams_ids.contains(&ams_id) && ams.ams.is_none() {
+                ams_ids.push(ams_id);
+            }
+        }
+
+        let mut change_made = false;
+        let trays_count = self.ams_trays.len();
+        self.ams_trays.retain(|(ams_id, _slot), _tray| ams_ids.contains(ams_id));
+        if self.ams_trays.len() != trays_count {
+            change_made = true;
+        }
+        for ams_id in ams_ids {
+            for slot in 0..Self::ams_slots(ams_id) {
+                if !self.ams_trays.contains_key(&(ams_id, slot)) {
+                    self.ams_trays.insert((ams_id, slot), Tray::unknown());
+                    change_made = true;
+                }
             }
         }
         change_made
     }
 
+    fn update_loaded_tray(&mut self, tray_now: Option<usize>, tray_tar: Option<Option<usize>>) -> bool {
+        let change_made = self.tray_now != tray_now || self.tray_tar != tray_tar;
+        self.tray_now = tray_now;
+        self.tray_tar = tray_tar;
+        change_made
+    }
+
+    // Tray of the snow / star of the extruders info, ams_id << 8 | slot, the external trays have their tray id as ams_id
+    fn extruder_tray(value: u32) -> Option<usize> {
+        if value == EXTRUDER_NO_TRAY {
+            return None;
+        }
+        let ams_id = (value >> 8) as usize;
+        let slot = (value & 0xFF) as usize;
+        if ams_id >= 254 {
+            Some(ams_id)
+        } else {
+            Some(Self::get_global_tray_id(ams_id, slot))
+        }
+    }
+
+    // Multi extruder printers report the loaded tray per extruder, the loaded tray is the one of the extruder in use and
+    // the target is of the extruder the printer is switching to (the same one unless switching extruders)
+    #[allow(non_snake_case)]
+    pub fn process_print_message__push_status__extruders(&mut self, extruder: &bambu_api::PrintExtruder) -> bool {
+        let Some(state) = extruder.state else {
+            return false;
+        };
+        let extruder_info = |extruder_id: u32| extruder.info.iter().find(|info| info.id == extruder_id);
+        let Some(current) = extruder_info((state >> 4) & 0x0F) else {
+            return false;
+        };
+        let target = extruder_info((state >> 8) & 0x0F).unwrap_or(current);
+        let tray_now = current.snow.map_or(self.tray_now, Self::extruder_tray);
+        let tray_tar = target.star.map(Self::extruder_tray).or(self.tray_tar);
+        self.update_loaded_tray(tray_now, tray_tar)
+    }
+
+    // Loading state of a tray with a ready spool, from tray_now and tray_tar
+    fn tray_load_state(&self, tray_id: usize) -> TrayState {
+        let tray_now = self.tray_now;
+        if tray_now == Some(tray_id) {
+            // tray_tar isn't reported by all printers, without it the loaded tray can't be seen unloading
+            if self.tray_tar.is_none() || self.tray_tar == Some(tray_now) {
+                TrayState::Loaded
+            } else {
+                TrayState::Unloading
+            }
+        } else if self.tray_tar == Some(Some(tray_id)) {
+            TrayState::Loading
+        } else {
+            TrayState::Ready
+        }
+    }
+
+    // Applies the loading state on the trays with a ready spool, the rest of the states come from the tray reports
+    fn update_trays_load_state(&mut self) -> bool {
+        let mut tray_ids: Vec<usize> = self.ams_trays.keys().map(|(ams_id, slot)| Self::get_global_tray_id(*ams_id, *slot)).collect();
+        tray_ids.extend(self.virt_trays.keys());
+        let mut change_made = false;
+        for tray_id in tray_ids {
+            let load_state = self.tray_load_state(tray_id);
+            let Some(tray) = self.get_tray_mut(tray_id) else {
+                continue;
+            };
+            let loadable = matches!(tray.state, TrayState::Ready | TrayState::Loading | TrayState::Unloading | TrayState::Loaded);
+            if loadable && tray.state != load_state {
+                tray.state = load_state;
+                change_made = true;
+            }
+        }
+        change_made
+    }
+
+    // The tray loaded into the extruder, if its spool is known
+    pub fn loaded_tray(&self) -> Option<usize> {
+        self.tray_now.filter(|v| self.get_tray(*v).is_some())
+    }
+
     #[allow(non_snake_case)]
     pub fn process_print_message__push_status__vt_tray(&mut self, v_tray: &PrintTray) -> bool {
-        let old_tray = self.virt_tray.clone();
+        let tray_id = v_tray.id as usize;
+        let old_tray = self.virt_trays.get(&tray_id).cloned().unwrap_or_else(Tray::unknown);
         let new_tray = self.get_updated_tray(&old_tray, Some(v_tray), None);
         if let Some(new_tray) = new_tray {
-            self.virt_tray = new_tray;
+            self.virt_trays.insert(tray_id, new_tray);
             return true;
         }
         false
     }
 
+    // AMS settings reported in the home_flag status bits
+    #[allow(non_snake_case)]
+    pub fn process_print_message__push_status__home_flag(&mut self, home_flag: i64) -> bool {
+        if self.ams_settings_held() {
+            return false;
+        }
+        let flag_bit = |bit: u32| Some((home_flag >> bit) & 0x01 != 0);
+        let mut ams_settings = self.ams_settings;
+        ams_settings.remain_estimate = flag_bit(HOME_FLAG_REMAIN_ESTIMATE_BIT);
+        ams_settings.auto_refill = flag_bit(HOME_FLAG_AUTO_REFILL_BIT);
+        self.update_ams_settings(ams_settings)
+    }
+
+    // Filament runout pauses the print with an error naming the AMS (or the external spool), the tray is the one loaded
+    #[allow(non_snake_case)]
+    pub fn process_print_message__push_status__print_error(&mut self, print_error: i64) -> bool {
+        let print_error = print_error as u32;
+        let runout_tray = if print_error & RUNOUT_ERROR_MASK == RUNOUT_ERROR {
+            let ams_id = ((print_error >> 16) & 0xFF) as usize;
+            let ams_id = if ams_id == 0xFF { 254 } else { ams_id };
+            // the printer may unload the tray while the error is still reported
+            self.runout_tray
+                .or_else(|| self.loaded_tray().filter(|tray_id| Self::get_ams_and_tray_id(*tray_id).0 == ams_id))
+        } else {
+            None
+        };
+        if self.runout_tray == runout_tray {
+            return false;
+        }
+        if let Some(tray_id) = runout_tray {
+            term_info!("Printer reports tray {} filament ran out", tray_id);
+        }
+        self.runout_tray = runout_tray;
+        true
+    }
+
+    pub fn runout_tray(&self) -> Option<usize> {
+        self.runout_tray
+    }
+
+    fn ams_settings_held(&self) -> bool {
+        self.ams_settings_hold.is_some_and(|hold| Instant::now() < hold)
+    }
+
+    fn update_ams_settings(&mut self, ams_settings: AmsSettings) -> bool {
+        if self.ams_settings == ams_settings {
+            return false;
+        }
+        self.ams_settings = ams_settings;
+        true
+    }
+
+    // Nozzles of all extruders, reported by multi extruder printers
+    #[allow(non_snake_case)]
+    pub fn process_print_message__push_status__nozzles(&mut self, nozzle: &bambu_api::PrintNozzle) -> bool {
+        let mut change_made = false;
+        for nozzle_info in &nozzle.info {
+            let extruder_id = nozzle_info.id as usize;
+            if extruder_id >= MAX_EXTRUDERS {
+                continue;
+            }
+            if self.extruders.len() <= extruder_id {
+                self.extruders.resize(extruder_id + 1, Extruder::default());
+                change_made = true;
+            }
+            let extruder = Extruder {
+                nozzle_diameter: Some(format!("{:.1}", nozzle_info.diameter)),
+                nozzle_type: nozzle_info.nozzle_type.clone(),
+            };
+            if self.extruders[extruder_id] != extruder {
+                self.extruders[extruder_id] = extruder;
+                change_made = true;
+            }
+        }
+        // each extruder has its own external tray
+        if self.is_multi_extruder() && !self.virt_trays.contains_key(&255) {
+            self.virt_trays.insert(255, Tray::unknown());
+            change_made = true;
+        }
+        change_made
+    }
+
     #[allow(non_snake_case)]
     pub fn process_print_message__ams_filament_setting(&mut self, print: &bambu_api::PrintData) -> bool {
         let mut change_made = false;
@@ -558,25 +1080,33 @@ impl BambuPrinter {
                     nozzle_temp_max: print.nozzle_temp_max.unwrap_or(250),
                     nozzle_temp_min: print.nozzle_temp_min.unwrap_or(190),
                     calibrations: HashMap::new(),
+                    tray_uuid: None,
+                    tray_weight: None,
+                    spool_info: SpoolInfo::default(),
                 })
             };
             if tray_id == 254 {
-                // Handle external tray
-                if new_filament == Filament::Unknown {
-                    self.virt_tray.state = TrayState::Empty;
-                } else {
-                    self.virt_tray.state = TrayState::Ready;
+                // Handle external tray, on multi extruder printers the ams_id tells which one (see set_tray_filament)
+                let virt_tray_id = if self.is_multi_extruder() && print.ams_id == Some(255) { 255 } else { 254 };
+                if let Some(virt_tray) = self.virt_trays.get_mut(&virt_tray_id) {
+                    if new_filament == Filament::Unknown {
+                        virt_tray.state = TrayState::Empty;
+                    } else {
+                        virt_tray.state = TrayState::Ready;
+                    }
+                    virt_tray.filament = new_filament;
+                    virt_tray.k = None; // Is this correct to do?
                 }
-                self.virt_tray.filament = new_filament;
-                self.virt_tray.k = None; // Is this correct to do?
             } else {
                 // Handle AMS tray
                 if let Some(ams_id) = print.ams_id {
                     // no change to tray state in case of AMS
                     let ams_id = usize::try_from(ams_id).unwrap();
-                    self.ams_trays[ams_id * 4 + usize::try_from(tray_id).unwrap()].filament = new_filament;
-                    self.ams_trays[ams_id * 4 + usize::try_from(tray_id).unwrap()].k = None;
-                    // Is this correct to do?
+                    let slot = usize::try_from(tray_id).unwrap_or(0);
+                    if let Some(tray) = self.ams_trays.get_mut(&(ams_id, slot)) {
+                        tray.filament = new_filament;
+                        tray.k = None; // Is this correct to do?
+                    }
                 }
             }
             change_made = true;
@@ -590,22 +1120,25 @@ impl BambuPrinter {
         if let (Some(nozzle_diameter), Some(tray_id), Some(cali_idx)) = (&print.nozzle_diameter, &print.tray_id, &print.cali_idx) {
             if *tray_id >= 0 {
                 let tray_id: usize = (*tray_id).try_into().unwrap();
-                let k = self.get_cali_k_value(nozzle_diameter, *cali_idx);
-                let tray = if tray_id == 254 {
-                    &mut self.virt_tray
-                } else {
-                    &mut self.ams_trays[tray_id]
+                // the echo carries the nozzle diameter of the tray's extruder
+                let nozzle_key = self
+                    .nozzle_key(self.tray_extruder(tray_id))
+                    .filter(|nozzle_key| nozzle_key_diameter(nozzle_key) == nozzle_diameter)
+                    .unwrap_or_else(|| nozzle_diameter.clone());
+                let k = self.get_cali_k_value(&nozzle_key, *cali_idx);
+                let current_nozzle_calibration = self
+                    .printer_calibrations(&nozzle_key)
+                    .and_then(|calibrations| calibrations.get(cali_idx))
+                    .map(|calibration| (nozzle_key.clone(), calibration.clone()));
+                let Some(tray) = self.get_tray_mut(tray_id) else {
+                    return false;
                 };
                 // TODO: This snippet is in two places, fix that
                 tray.cali_idx = if *cali_idx == -1 { None } else { Some(*cali_idx) };
                 tray.k = k.or(Some(format!("({:.3})", 0.02))); // TODO: where to bring default from, all materials 0.020?
-                if let (Some(nozzle_diameter), Some(cali_idx)) = (self.nozzle_diameter.as_ref(), tray.cali_idx.as_ref()) {
-                    if let Some(calibrations) = self.calibrations.get(nozzle_diameter) {
-                        if let Some(calibration) = calibrations.get(cali_idx) {
-                            if let Filament::Known(ref mut filament_info) = tray.filament {
-                                filament_info.calibrations.insert(nozzle_diameter.clone(), calibration.clone());
-                            }
-                        }
+                if let (Some((nozzle_key, calibration)), Some(_)) = (current_nozzle_calibration, tray.cali_idx) {
+                    if let Filament::Known(ref mut filament_info) = tray.filament {
+                        filament_info.calibrations.insert(nozzle_key, calibration);
                     }
                 }
                 change_made = true;
@@ -629,31 +1162,198 @@ impl BambuPrinter {
 
         if let Some(ref filaments) = print.filaments {
             change_made = true;
-            let nozzle_calibrations = self.calibrations.entry_ref(nozzle_diameter).or_default(); //insert(HashMap::new()) let calibration = Calibration::from(filament);
-            if filament_id.is_empty() {
-                nozzle_calibrations.clear();
-            } else {
-                nozzle_calibrations.retain(|_k, v| &v.filament_id != filament_id);
+            self.calibrations_version = self.calibrations_version.wrapping_add(1);
+            // on multi extruder printers the response is for a specific extruder, calibrations of the other extruder are kept
+            let extruder_id = print.extruder_id;
+            let other_extruder = |v: &Calibration| extruder_id.is_some() && v.extruder_id != extruder_id;
+            // calibrations are kept per nozzle key, the nozzle type is the one the printer reports with the calibration,
+            // otherwise the type of the installed nozzle of that diameter (the printer keeps the calibrations of the installed nozzle)
+            let installed_nozzle_code = (0..self.extruders.len())
+                .find(|v| self.nozzle_diameter(*v) == Some(nozzle_diameter) && extruder_id.is_none_or(|extruder_id| extruder_id as usize == *v))
+                .and_then(|v| self.extruders[v].nozzle_code());
+            for (key, nozzle_calibrations) in self.calibrations.iter_mut() {
+                if nozzle_key_diameter(key) != nozzle_diameter {
+                    continue;
+                }
+                if filament_id.is_empty() {
+                    nozzle_calibrations.retain(|_k, v| other_extruder(v));
+                } else {
+                    nozzle_calibrations.retain(|_k, v| &v.filament_id != filament_id || other_extruder(v));
+                }
             }
             for filament in filaments {
-                let calibration = Calibration::from(filament);
-                nozzle_calibrations.insert(filament.cali_idx, calibration);
+                let mut calibration = Calibration::from(filament);
+                calibration.extruder_id = calibration.extruder_id.or(extruder_id);
+                calibration.nozzle_code = calibration.nozzle_code.or(installed_nozzle_code.clone());
+                let key = nozzle_key(nozzle_diameter, calibration.nozzle_code.as_deref());
+                self.calibrations.entry(key).or_default().insert(filament.cali_idx, calibration);
+            }
+            // an empty list is still kept for the nozzle, so it shows as a nozzle without calibrations
+            let nozzle_key = nozzle_key(nozzle_diameter, installed_nozzle_code.as_deref());
+            self.calibrations.entry(nozzle_key.clone()).or_default();
+            self.calibrations
+                .retain(|key, nozzle_calibrations| *key == nozzle_key || nozzle_key_diameter(key) != nozzle_diameter || !nozzle_calibrations.is_empty());
+            let tray_keys: Vec<(usize, usize)> = self.ams_trays.keys().cloned().collect();
+            for (ams_id, slot) in tray_keys {
+                let extruder_id = self.tray_extruder(Self::get_global_tray_id(ams_id, slot));
+                let k = self.get_tray_cali_k_value(&self.ams_trays[&(ams_id, slot)], extruder_id);
+                self.ams_trays.get_mut(&(ams_id, slot)).unwrap().k = k;
             }
-            for i in 0..self.ams_trays.len() {
-                self.ams_trays[i].k = self.get_tray_cali_k_value(&self.ams_trays[i]);
+            let virt_tray_ids: Vec<usize> = self.virt_trays.keys().cloned().collect();
+            for tray_id in virt_tray_ids {
+                let k = self.get_tray_cali_k_value(&self.virt_trays[&tray_id], self.tray_extruder(tray_id));
+                self.virt_trays.get_mut(&tray_id).unwrap().k = k;
+            }
+            self.select_pending_calibrations(nozzle_diameter);
+
+            let nozzle_remaps = core::mem::take(&mut self.nozzle_remaps);
+            for nozzle_extruder_id in nozzle_remaps {
+                if self.nozzle_diameter(nozzle_extruder_id) == Some(nozzle_diameter)
+                    && extruder_id.is_none_or(|v| v as usize == nozzle_extruder_id)
+                {
+                    self.remap_trays_calibrations(nozzle_extruder_id);
+                } else {
+                    self.nozzle_remaps.push(nozzle_extruder_id);
+                }
             }
-            self.virt_tray.k = self.get_tray_cali_k_value(&self.virt_tray);
         }
 
         change_made
     }
 
+    // Calibrations are per nozzle, so after a nozzle swap (diameter or type, e.g. to hardened steel or high flow) the trays
+    // calibrations are of the previous nozzle. Fetch the calibrations of the new nozzle, and once they arrive remap the trays to them
+    fn process_nozzle_change(&mut self, extruder_id: usize) {
+        let Some(nozzle_diameter) = self.nozzle_diameter(extruder_id).cloned() else {
+            return;
+        };
+        let nozzle_type = self.extruders[extruder_id].nozzle_type.clone().unwrap_or_default();
+        term_info!("Nozzle changed to {} {}, updating PA calibrations", nozzle_diameter, nozzle_type);
+        if !self.nozzle_remaps.contains(&extruder_id) {
+            self.nozzle_remaps.push(extruder_id);
+        }
+        if !self.nozzle_changes.contains(&extruder_id) {
+            self.nozzle_changes.push(extruder_id);
+        }
+        self.fetch_filament_calibrations(&nozzle_diameter, self.command_extruder_id(extruder_id));
+    }
+
+    // Selects for every tray on the extruder its filament calibration for the current nozzle, or the default (-1) if there is none
+    fn remap_trays_calibrations(&mut self, extruder_id: usize) {
+        let (Some(nozzle_diameter), Some(nozzle_key)) = (self.nozzle_diameter(extruder_id).cloned(), self.nozzle_key(extruder_id)) else {
+            return;
+        };
+        let mut tray_ids: Vec<usize> = self.ams_trays.keys().map(|(ams_id, slot)| Self::get_global_tray_id(*ams_id, *slot)).collect();
+        tray_ids.extend(self.virt_trays.keys());
+        for tray_id in tray_ids {
+            if self.tray_extruder(tray_id) != extruder_id {
+                continue;
+            }
+            let Some(tray) = self.get_tray(tray_id) else {
+                continue;
+            };
+            let Filament::Known(filament_info) = &tray.filament else {
+                continue;
+            };
+            let cali_idx = filament_info
+                .calibration_for_nozzle(&nozzle_key)
+                .and_then(|calibration| self.find_printer_calibration(&nozzle_key, extruder_id, calibration))
+                .unwrap_or(-1);
+            if tray.cali_idx.unwrap_or(-1) == cali_idx {
+                continue;
+            }
+            let cmd = crate::bambu_api::ExtrusionCaliSelCommand::new(
+                &nozzle_diameter,
+                tray_id as i32,
+                &filament_info.tray_info_idx,
+                Some(cali_idx),
+                self.command_extruder_id(extruder_id),
+            );
+            self.publish_command(cmd, CommandContext::Internal);
+        }
+    }
+
+    pub fn take_nozzle_changes(&mut self) -> Vec<usize> {
+        core::mem::take(&mut self.nozzle_changes)
+    }
+
+    pub fn notify_nozzle_changes(&self, extruder_ids: &[usize]) {
+        for extruder_id in extruder_ids {
+            for weak_observer in self.observers.iter() {
+                let observer = weak_observer.upgrade().unwrap();
+                observer.borrow_mut().on_nozzle_change(self, *extruder_id);
+            }
+        }
+    }
+
+    // Select calibrations that were added to the printer for trays, now that they (hopefully) got their cali_idx
+    fn select_pending_calibrations(&mut self, nozzle_diameter: &str) {
+        let now = Instant::now();
+        let pending_cali_selections = core::mem::take(&mut self.pending_cali_selections);
+        for pending in pending_cali_selections {
+            if pending.nozzle_diameter != nozzle_diameter {
+                self.pending_cali_selections.push(pending);
+                continue;
+            }
+            let nozzle_key = self.nozzle_key(pending.extruder_id).unwrap_or_else(|| String::from(nozzle_diameter));
+            let cali_idx = self.printer_calibrations(&nozzle_key).and_then(|nozzle_calibrations| {
+                nozzle_calibrations
+                    .values()
+                    .find(|v| {
+                        v.name.trim() == pending.calibration.name.trim()
+                            && v.filament_id == pending.calibration.filament_id
+                            && v.setting_id == pending.calibration.setting_id
+                            && Self::calibration_on_extruder(v, pending.extruder_id)
+                    })
+                    .map(|v| v.cali_idx)
+            });
+            if let Some(cali_idx) = cali_idx {
+                term_info!("Selecting added PA calibration '{}' for tray {}", pending.calibration.name, pending.tray_id);
+                let cmd = crate::bambu_api::ExtrusionCaliSelCommand::new(
+                    nozzle_diameter,
+                    pending.tray_id,
+                    &pending.calibration.filament_id,
+                    Some(cali_idx),
+                    self.command_extruder_id(pending.extruder_id),
+                );
+                self.publish_command(cmd, CommandContext::Internal);
+            } else if now < pending.expires {
+                self.pending_cali_selections.push(pending);
+            } else {
+                term_error!("PA calibration '{}' wasn't added by printer, not selected", pending.calibration.name);
+            }
+        }
+    }
+
+    // A push_status 'print' section is merged into the printer state and parsed from it, so trays are processed based on the
+    // full state even when the printer reports only what changed. Full reports (msg 0, e.g. response to pushall) replace the state,
+    // and are requested when the connected AMS units change, so units that were disconnected are dropped from the state.
+    // The state holds only the fields kept while reports are read (see keep_report_field), and is parsed in place, not copied
+    pub fn parse_print_message(&mut self, print: serde_json::Value) -> Result<bambu_api::Print, serde_json::Error> {
+        if print.get("command").and_then(|v| v.as_str()) != Some("push_status") {
+            let print = <bambu_api::PrintData as serde::Deserialize>::deserialize(&print)?;
+            return Ok(bambu_api::Print { print });
+        }
+        let full_report = print.get("msg").and_then(|v| v.as_u64()) == Some(0);
+        if full_report || self.printer_state.is_null() {
+            self.printer_state = print;
+        } else {
+            if bambu_api::ams_units_changed(&self.printer_state, &print) {
+                self.request_full_report();
+            }
+            bambu_api::merge_printer_state(&mut self.printer_state, print);
+        }
+        let print = <bambu_api::PrintData as serde::Deserialize>::deserialize(&self.printer_state)?;
+        Ok(bambu_api::Print { print })
+    }
+
     pub fn process_print_message(&mut self, print: &bambu_api::PrintData) -> bool {
         if let Some(sequence_id) = &print.sequence_id {
             dbgt!("-> Message ", sequence_id);
         } else {
             warn!("-> Message with No sequence_id ?");
         }
+        self.complete_pending_command(print);
         // important: Can't issue event from here because this method is called with a mut reference (even if behind RefCell)
         // Therefore, to issue an event need to call update_ams_trays_done afterwards through a non mut reference (so not borrow_mut if refcell)
         //   in order to issue the event on observers
@@ -664,32 +1364,77 @@ impl BambuPrinter {
                 let mut nozzle_diameter_change_made = false;
                 let mut ams_change_made = false;
                 let mut vt_tray_change_made = false;
-                if let Some(nozzle_diameter) = &print.nozzle_diameter {
-                    let old_nozzle_diameter = self.nozzle_diameter.clone();
-                    self.nozzle_diameter = Some(nozzle_diameter.clone());
-                    nozzle_diameter_change_made = old_nozzle_diameter != self.nozzle_diameter;
+                let prev_extruders = self.extruders.clone();
+                if let Some(nozzle) = print.device.as_ref().and_then(|device| device.nozzle.as_ref()) {
+                    nozzle_diameter_change_made = self.process_print_message__push_status__nozzles(nozzle);
+                }
+                if let Some(extruder) = print.device.as_ref().and_then(|device| device.extruder.as_ref()) {
+                    if self.is_multi_extruder() {
+                        ams_change_made = self.process_print_message__push_status__extruders(extruder);
+                    }
+                }
+                // multi extruder printers report all nozzles in the device section above
+                if !self.is_multi_extruder() {
+                    let main_extruder = &mut self.extruders[MAIN_EXTRUDER];
+                    if let Some(nozzle_diameter) = &print.nozzle_diameter {
+                        let old_nozzle_diameter = main_extruder.nozzle_diameter.clone();
+                        main_extruder.nozzle_diameter = Some(nozzle_diameter.clone());
+                        nozzle_diameter_change_made |= old_nozzle_diameter != main_extruder.nozzle_diameter;
+                    }
+                    if let Some(nozzle_type) = &print.nozzle_type {
+                        main_extruder.nozzle_type = Some(nozzle_type.clone());
+                    }
+                }
+                // first report of a nozzle isn't a swap
+                for (extruder_id, prev_extruder) in prev_extruders.iter().enumerate() {
+                    let Some(extruder) = self.extruders.get(extruder_id) else {
+                        continue;
+                    };
+                    if (prev_extruder.nozzle_diameter.is_some() && prev_extruder.nozzle_diameter != extruder.nozzle_diameter)
+                        || (prev_extruder.nozzle_type.is_some() && prev_extruder.nozzle_type != extruder.nozzle_type)
+                    {
+                        self.process_nozzle_change(extruder_id);
+                        nozzle_diameter_change_made = true;
+                    }
                 }
                 if let Some(ams) = &print.ams {
-                    ams_change_made = self.process_print_message__push_status__ams(ams);
+                    ams_change_made |= self.process_print_message__push_status__ams(ams);
+                }
+                if let Some(home_flag) = print.home_flag {
+                    ams_change_made |= self.process_print_message__push_status__home_flag(home_flag);
+                }
+                if let Some(print_error) = print.print_error {
+                    ams_change_made |= self.process_print_message__push_status__print_error(print_error);
                 }
                 if let Some(v_tray) = &print.vt_tray {
                     vt_tray_change_made = self.process_print_message__push_status__vt_tray(v_tray);
                 }
-                change_made = nozzle_diameter_change_made || ams_change_made || vt_tray_change_made;
+                if let Some(vir_slot) = &print.vir_slot {
+                    for v_tray in vir_slot {
+                        vt_tray_change_made |= self.process_print_message__push_status__vt_tray(v_tray);
+                    }
+                }
+                let load_state_change_made = self.update_trays_load_state();
+                change_made = nozzle_diameter_change_made || ams_change_made || vt_tray_change_made || load_state_change_made;
             } else if command == "ams_filament_setting" {
                 change_made = self.process_print_message__ams_filament_setting(print)
             } else if command == "extrusion_cali_set" {
                 // trigger request command for cali_get (request, not response)
                 debug!("             {command} message");
                 if let Some(nozzle_diameter) = &print.nozzle_diameter {
-                    self.fetch_filament_calibrations(nozzle_diameter);
+                    if print.result.as_deref() == Some("fail") {
+                        // calibrations we tried to add won't show up, no point waiting for them
+                        self.pending_cali_selections.retain(|v| &v.nozzle_diameter != nozzle_diameter);
+                        term_error!("Printer failed setting PA calibration ({})", print.reason.as_deref().unwrap_or(""));
+                    }
+                    self.fetch_filament_calibrations(nozzle_diameter, print.extruder_id);
                 }
                 change_made = true;
             } else if command == "extrusion_cali_del" {
                 // trigger request command for cali_get (request, not response)
                 debug!("             {command} message");
                 if let Some(nozzle_diameter) = &print.nozzle_diameter {
-                    self.fetch_filament_calibrations(nozzle_diameter);
+                    self.fetch_filament_calibrations(nozzle_diameter, print.extruder_id);
                 }
                 change_made = true;
             } else if command == "extrusion_cali_sel" {
@@ -715,27 +1460,108 @@ impl BambuPrinter {
         }
     }
 
-    // TODO: Unify sending messages, no need for two functions
+    // For tray changes made locally, not from a printer report
+    pub fn notify_trays_update(&self) {
+        self.update_ams_trays_done(self.tray_reading_bits, self.tray_reading_bits);
+    }
 
-    pub fn publish_payload(&self, payload: String) {
-        debug!("MQTT Publish: {}", payload);
+    pub fn notify_command_results(&self) {
+        let command_results = core::mem::take(&mut *self.command_results.borrow_mut());
+        for (context, result) in command_results.iter() {
+            for weak_observer in self.observers.iter() {
+                let observer = weak_observer.upgrade().unwrap();
+                observer.borrow_mut().on_command_result(self, context, result);
+            }
+        }
+    }
 
-        let topic_name = format!("device/{}/request", self.app_config.borrow().printer_serial.as_ref().unwrap());
-        let topic_name = topic_name.as_str();
+    // == Commands Pipeline ===========================================================
 
-        let packet = mqttrust::Packet::Publish(mqttrust::Publish {
-            dup: false,
-            qos: QoS::AtMostOnce,
-            pid: Some(mqttrust::encoding::v4::Pid::new()),
-            retain: false,
-            topic_name,
-            payload: payload.as_bytes(),
+    // Assigns the command a unique sequence_id and queues it for commands_task to send, the printer response is matched
+    // by the sequence_id (see complete_pending_command) and the result is reported to the observers with the context
+    pub fn publish_command<C: Command>(&self, mut cmd: C, context: CommandContext) -> u32 {
+        let sequence_id = next_sequence_id();
+        cmd.set_sequence_id(sequence_id);
+        let payload = serde_json::to_string_pretty(&cmd).unwrap();
+
+        let mut commands_outbox = self.commands_outbox.borrow_mut();
+        if commands_outbox.len() >= COMMANDS_OUTBOX_CAPACITY {
+            term_error!("Too many printer commands waiting, {} not sent", cmd.command());
+            self.command_results.borrow_mut().push((context, CommandResult::NotSent));
+        } else {
+            commands_outbox.push_back(payload);
+            self.pending_commands.borrow_mut().push(PendingCommand {
+                sequence_id,
+                command: String::from(cmd.command()),
+                context,
+                expires: Instant::now() + COMMAND_TIMEOUT,
+            });
+        }
+        self.commands_signal.signal(());
+        sequence_id
+    }
+
+    // The printer responds to pushall with a full push_status report, it isn't tracked as a pending command
+    fn request_full_report(&self) {
+        let mut cmd = bambu_api::PushAllCommand::new();
+        cmd.set_sequence_id(next_sequence_id());
+        let mut commands_outbox = self.commands_outbox.borrow_mut();
+        if commands_outbox.len() < COMMANDS_OUTBOX_CAPACITY {
+            commands_outbox.push_back(serde_json::to_string_pretty(&cmd).unwrap());
+            self.commands_signal.signal(());
+        }
+    }
+
+    // Matches a printer response to its pending command, also by the command name since the printer numbers its own messages
+    fn complete_pending_command(&self, print: &bambu_api::PrintData) {
+        let sequence_id = print.sequence_id.as_ref().and_then(|v| v.parse::<u32>().ok());
+        let (Some(sequence_id), Some(command)) = (sequence_id, &print.command) else {
+            return;
+        };
+        let mut pending_commands = self.pending_commands.borrow_mut();
+        let Some(index) = pending_commands
+            .iter()
+            .position(|v| v.sequence_id == sequence_id && &v.command == command)
+        else {
+            return;
+        };
+        let pending = pending_commands.remove(index);
+        let result = match print.result.as_deref() {
+            Some(result) if result.eq_ignore_ascii_case("fail") || result.eq_ignore_ascii_case("failed") => {
+                let reason = print.reason.clone().unwrap_or_default();
+                term_error!("Printer command {} failed ({})", pending.command, reason);
+                CommandResult::Failed(reason)
+            }
+            _ => CommandResult::Success,
+        };
+        self.command_results.borrow_mut().push((pending.context, result));
+    }
+
+    fn expire_pending_commands(&self) {
+        let now = Instant::now();
+        let mut command_results = self.command_results.borrow_mut();
+        self.pending_commands.borrow_mut().retain(|pending| {
+            if now < pending.expires {
+                return true;
+            }
+            warn!("No printer response to command {} ({})", pending.command, pending.sequence_id);
+            command_results.push((pending.context.clone(), CommandResult::Timeout));
+            false
         });
-        let message = BufferedMqttPacket::try_from(packet).unwrap();
-        let _ = self.write_packets.try_send(message);
     }
 
-    // TODO: Unify sending messages, no need for two functions
+    // Calibrations that didn't show up in the printer calibrations in time, also when no calibrations response arrives at all
+    // (select_pending_calibrations runs only on responses)
+    fn expire_pending_cali_selections(&mut self) {
+        let now = Instant::now();
+        self.pending_cali_selections.retain(|pending| {
+            if now < pending.expires {
+                return true;
+            }
+            term_error!("PA calibration '{}' wasn't added by printer, not selected", pending.calibration.name);
+            false
+        });
+    }
 
     pub async fn publish_payload_async(
         printer_serial: &String,
@@ -762,23 +1588,36 @@ impl BambuPrinter {
         write_packets.send(message).await;
     }
 
-    pub async fn request_full_update(
+    // For tasks that can't borrow the printer while sending, the command gets a sequence_id but its response isn't tracked
+    pub async fn publish_command_async<C: Command>(
         printer_serial: &String,
         write_packets: &'static embassy_sync::channel::Channel<
             embassy_sync::blocking_mutex::raw::NoopRawMutex,
             crate::my_mqtt::BufferedMqttPacket,
             3,
         >,
+        mut cmd: C,
     ) {
-        let cmd = crate::bambu_api::PushAllCommand::new();
+        cmd.set_sequence_id(next_sequence_id());
         let payload = serde_json::to_string_pretty(&cmd).unwrap();
         BambuPrinter::publish_payload_async(printer_serial, write_packets, payload).await;
     }
 
-    pub fn fetch_filament_calibrations(&self, nozzle_diameter: &str) {
-        let cmd = crate::bambu_api::ExtrusionCaliGetCommand::new(nozzle_diameter);
-        let payload = serde_json::to_string_pretty(&cmd).unwrap();
-        self.publish_payload(payload);
+    pub async fn request_full_update(
+        printer_serial: &String,
+        write_packets: &'static embassy_sync::channel::Channel<
+            embassy_sync::blocking_mutex::raw::NoopRawMutex,
+            crate::my_mqtt::BufferedMqttPacket,
+            3,
+        >,
+    ) {
+        let cmd = crate::bambu_api::PushAllCommand::new();
+        BambuPrinter::publish_command_async(printer_serial, write_packets, cmd).await;
+    }
+
+    pub fn fetch_filament_calibrations(&self, nozzle_diameter: &str, extruder_id: Option<u32>) {
+        let cmd = crate::bambu_api::ExtrusionCaliGetCommand::new(nozzle_diameter, extruder_id);
+        self.publish_command(cmd, CommandContext::Internal);
     }
 
     pub async fn fetch_filament_calibrations_async(
@@ -789,25 +1628,45 @@ impl BambuPrinter {
             3,
         >,
         nozzle_diameter: &str,
+        extruder_id: Option<u32>,
     ) {
-        let cmd = crate::bambu_api::ExtrusionCaliGetCommand::new(nozzle_diameter);
-        let payload = serde_json::to_string_pretty(&cmd).unwrap();
-        BambuPrinter::publish_payload_async(printer_serial, write_packets, payload).await;
+        let cmd = crate::bambu_api::ExtrusionCaliGetCommand::new(nozzle_diameter, extruder_id);
+        BambuPrinter::publish_command_async(printer_serial, write_packets, cmd).await;
     }
 
-    pub fn set_tray_filament(&self, tray_id: i32, filament: &FilamentInfo) {
+    // Addressing of a tray in ams_filament_setting: (ams_id, tray_id within the AMS, slot_id)
+    fn filament_setting_target(&self, tray_id: i32) -> (u32, i32, Option<i32>) {
         let ams_id: u32;
         let ams_tray_id;
-
-        if tray_id == 254 {
-            ams_id = 255;
+        let mut slot_id = None;
+        if tray_id >= 254 {
+            if self.is_multi_extruder() {
+                // the external tray is selected by the ams_id, see AmsFilamentSettingCommand
+                ams_id = tray_id as u32;
+                slot_id = Some(0);
+            } else {
+                ams_id = 255;
+            }
             ams_tray_id = 254
         } else {
-            ams_id = u32::try_from(tray_id).unwrap() / 4;
-            ams_tray_id = tray_id % 4;
+            let (ams, slot) = Self::get_ams_and_tray_id(usize::try_from(tray_id).unwrap());
+            ams_id = ams as u32;
+            ams_tray_id = slot as i32;
+            if self.is_multi_extruder() {
+                slot_id = Some(ams_tray_id);
+            }
         }
+        (ams_id, ams_tray_id, slot_id)
+    }
+
+    pub fn set_tray_filament(&mut self, tray_id: i32, filament: &FilamentInfo) {
+        // calibrations are per the nozzle of the extruder the tray feeds
+        let extruder_id = self.tray_extruder(usize::try_from(tray_id).unwrap());
+        let nozzle_diameter = self.nozzle_diameter(extruder_id).cloned().unwrap_or_default();
+        let nozzle_key = self.nozzle_key(extruder_id).unwrap_or_default();
+        let (ams_id, ams_tray_id, slot_id) = self.filament_setting_target(tray_id);
 
-        let setting_id = if let Some(calibration) = self.get_filament_calibration_for_current_nozzle(filament) {
+        let setting_id = if let Some(calibration) = self.get_filament_calibration_for_extruder(filament, extruder_id) {
             Some(calibration.setting_id.as_str())
         } else {
             None
@@ -816,6 +1675,7 @@ impl BambuPrinter {
         let cmd = crate::bambu_api::AmsFilamentSettingCommand::new(
             ams_id,
             ams_tray_id, // here we need the tray_id within the specific ams
+            slot_id,
             &filament.tray_info_idx,
             setting_id,
             &filament.tray_type,
@@ -823,39 +1683,449 @@ impl BambuPrinter {
             filament.nozzle_temp_min,
             filament.nozzle_temp_max,
         );
-        let payload = serde_json::to_string_pretty(&cmd).unwrap();
-        self.publish_payload(payload);
+        self.publish_command(cmd, CommandContext::SetTrayFilament { tray_id });
 
         let mut cali_idx = -1;
+        let mut missing_calibration = None;
+
+        // If the filament info contains calibration for the tray's nozzle and the printer calibrations contain that calibration for that nozzle diameter (and extruder) then send that, otherwise send -1 (so no calibration)
+        // and if the calibration is missing in the printer, add it to the printer and select it once the printer has it
+        if let Some(filament_calibration) = filament.calibration_for_nozzle(&nozzle_key) {
+            match self.find_printer_calibration(&nozzle_key, extruder_id, filament_calibration) {
+                Some(printer_cali_idx) => cali_idx = printer_cali_idx,
+                None => missing_calibration = Some(filament_calibration.clone()),
+            }
+        }
+
+        let cmd = crate::bambu_api::ExtrusionCaliSelCommand::new(
+            &nozzle_diameter,
+            tray_id,                 // here we need the original tray_id
+            &filament.tray_info_idx, // tray_info_idx is filament_id in this command
+            Some(cali_idx),
+            self.command_extruder_id(extruder_id),
+        );
+        self.publish_command(cmd, CommandContext::Internal);
+
+        if let Some(calibration) = missing_calibration {
+            self.add_calibration_for_tray(tray_id, extruder_id, calibration);
+        }
+    }
+
+    // Clears the tray filament setting and calibration on the printer. For an empty AMS tray it also drops the filament
+    // remembered for it (see get_updated_tray), since the printer doesn't report empty trays
+    // Returns whether the remembered filament was dropped, so the observers need to be notified (see notify_trays_update)
+    pub fn reset_tray_filament(&mut self, tray_id: i32) -> bool {
+        let extruder_id = self.tray_extruder(usize::try_from(tray_id).unwrap());
+        let nozzle_diameter = self.nozzle_diameter(extruder_id).cloned().unwrap_or_default();
+        let (ams_id, ams_tray_id, slot_id) = self.filament_setting_target(tray_id);
+
+        let cmd = crate::bambu_api::AmsFilamentSettingCommand::new(ams_id, ams_tray_id, slot_id, "", None, "", "", 0, 0);
+        self.publish_command(cmd, CommandContext::ResetTrayFilament { tray_id });
+        let cmd = crate::bambu_api::ExtrusionCaliSelCommand::new(&nozzle_diameter, tray_id, "", Some(-1), self.command_extruder_id(extruder_id));
+        self.publish_command(cmd, CommandContext::Internal);
+
+        // a calibration waiting to be selected for the tray is no longer relevant
+        self.pending_cali_selections.retain(|v| v.tray_id != tray_id);
+
+        match self.get_tray_mut(usize::try_from(tray_id).unwrap()) {
+            Some(tray) if tray.state == TrayState::Empty && tray.filament != Filament::Unknown => {
+                tray.filament = Filament::Unknown;
+                tray.k = None;
+                tray.cali_idx = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Sends the settings that differ from the current ones, settings that are None are left as they are
+    // The new values are shown right away, the printer reports them only after a while
+    pub fn set_ams_settings(&mut self, ams_settings: &AmsSettings) -> Result<(), Error> {
+        let curr = self.ams_settings;
+        let user_setting_changed = (ams_settings.insertion_read.is_some() && ams_settings.insertion_read != curr.insertion_read)
+            || (ams_settings.startup_read.is_some() && ams_settings.startup_read != curr.startup_read)
+            || (ams_settings.remain_estimate.is_some() && ams_settings.remain_estimate != curr.remain_estimate);
+        if user_setting_changed {
+            // the command sets all three, so all need to be known
+            let (Some(startup_read), Some(insertion_read), Some(remain_estimate)) = (
+                ams_settings.startup_read.or(curr.startup_read),
+                ams_settings.insertion_read.or(curr.insertion_read),
+                ams_settings.remain_estimate.or(curr.remain_estimate),
+            ) else {
+                return Err(Error::NotFound);
+            };
+            let cmd = crate::bambu_api::AmsUserSettingCommand::new(startup_read, insertion_read, remain_estimate);
+            self.publish_command(cmd, CommandContext::Internal);
+            self.ams_settings.startup_read = Some(startup_read);
+            self.ams_settings.insertion_read = Some(insertion_read);
+            self.ams_settings.remain_estimate = Some(remain_estimate);
+        }
+        if let Some(auto_refill) = ams_settings.auto_refill.filter(|v| Some(*v) != curr.auto_refill) {
+            let cmd = crate::bambu_api::PrintOptionCommand::new(auto_refill);
+            self.publish_command(cmd, CommandContext::Internal);
+            self.ams_settings.auto_refill = Some(auto_refill);
+        }
+        if self.ams_settings != curr {
+            self.ams_settings_hold = Some(Instant::now() + AMS_SETTINGS_HOLD_TIME);
+        }
+        Ok(())
+    }
+
+    // Nozzle temperature for loading / unloading the tray filament
+    fn tray_change_filament_temp(&self, tray_id: Option<usize>) -> u32 {
+        match tray_id.and_then(|tray_id| self.get_tray(tray_id)).map(|tray| &tray.filament) {
+            Some(Filament::Known(filament)) if filament.nozzle_temp_max != 0 => filament.nozzle_temp_max,
+            _ => DEFAULT_CHANGE_FILAMENT_TEMP,
+        }
+    }
+
+    // Loads the tray filament into the extruder, the printer first unloads the filament currently loaded
+    // Progress is reported through the tray states (Unloading, Loading, Loaded)
+    pub fn load_tray(&self, tray_id: i32) {
+        let tray = usize::try_from(tray_id).unwrap();
+        let (ams, slot) = Self::get_ams_and_tray_id(tray);
+        let (target, ams_id, slot_id) = if tray >= 254 {
+            (254, Some(tray as u32), Some(0))
+        } else {
+            (tray as u32, Some(ams as u32), Some(slot as u32))
+        };
+        let (ams_id, slot_id) = if self.is_multi_extruder() { (ams_id, slot_id) } else { (None, None) };
+        let curr_temp = self.tray_change_filament_temp(self.loaded_tray());
+        let tar_temp = self.tray_change_filament_temp(Some(tray));
+        let cmd = crate::bambu_api::AmsChangeFilamentCommand::new(target, curr_temp, tar_temp, ams_id, slot_id);
+        self.publish_command(cmd, CommandContext::LoadTray { tray_id });
+    }
+
+    // Unloads the filament currently loaded into the extruder back to its tray
+    pub fn unload_tray(&self) {
+        let temp = self.tray_change_filament_temp(self.loaded_tray());
+        let ams_id = if self.is_multi_extruder() {
+            Some(bambu_api::UNLOAD_TARGET)
+        } else {
+            None
+        };
+        let cmd = crate::bambu_api::AmsChangeFilamentCommand::new(bambu_api::UNLOAD_TARGET, temp, temp, ams_id, ams_id);
+        self.publish_command(cmd, CommandContext::UnloadTray);
+    }
+
+    // Highest drying temperature of the AMS, None for AMS units that can't dry filament
+    // AMS HT units are known also by their ams_id, before their type is reported
+    fn ams_max_drying_temp(&self, ams_id: usize) -> Option<u32> {
+        match self.ams_types.get(&ams_id) {
+            Some(&AMS_TYPE_AMS_2_PRO) => Some(AMS_2_PRO_MAX_DRYING_TEMP),
+            Some(&AMS_TYPE_AMS_HT) => Some(AMS_HT_MAX_DRYING_TEMP),
+            None if ams_id >= AMS_HT_FIRST_ID => Some(AMS_HT_MAX_DRYING_TEMP),
+            _ => None,
+        }
+    }
+
+    pub fn ams_drying_supported(&self, ams_id: usize) -> bool {
+        self.ams_max_drying_temp(ams_id).is_some()
+    }
+
+    // Minutes left of drying, 0 when not drying
+    pub fn ams_drying_remaining(&self, ams_id: usize) -> u32 {
+        self.ams_dry_times.get(&ams_id).copied().unwrap_or(0)
+    }
+
+    // Drying suitable for the filaments of the spools in the AMS, None if it can't dry or there's no known filament
+    pub fn ams_drying_preset(&self, ams_id: usize) -> Option<DryingPreset> {
+        let max_temp = self.ams_max_drying_temp(ams_id)?;
+        let tray_types = self
+            .ams_trays
+            .range((ams_id, 0)..(ams_id + 1, 0))
+            .filter(|(_, tray)| !matches!(tray.state, TrayState::Empty | TrayState::Unknown))
+            .filter_map(|(_, tray)| match &tray.filament {
+                Filament::Known(filament) => Some(filament.tray_type.as_str()),
+                Filament::Unknown => None,
+            });
+        crate::drying::combined_preset(tray_types, max_temp)
+    }
+
+    pub fn start_ams_drying(&self, ams_id: usize) -> Result<DryingPreset, Error> {
+        let preset = self.ams_drying_preset(ams_id).ok_or(Error::NotFound)?;
+        let cmd = crate::bambu_api::AmsFilamentDryingCommand::new(ams_id as u32, true, preset.temp, preset.duration, preset.material);
+        self.publish_command(cmd, CommandContext::AmsDrying { ams_id, start: true });
+        Ok(preset)
+    }
+
+    pub fn stop_ams_drying(&self, ams_id: usize) {
+        let cmd = crate::bambu_api::AmsFilamentDryingCommand::new(ams_id as u32, false, 0, 0, "");
+        self.publish_command(cmd, CommandContext::AmsDrying { ams_id, start: false });
+    }
+
+    // The printer calibration for a tag calibration: the same cali_idx on the extruder, otherwise (e.g. calibration of the other
+    // extruder of a multi extruder printer) the same calibration by name, filament and setting on the extruder
+    fn find_printer_calibration(&self, nozzle_key: &str, extruder_id: usize, calibration: &Calibration) -> Option<i32> {
+        let nozzle_calibrations = self.printer_calibrations(nozzle_key)?;
+        if nozzle_calibrations
+            .get(&calibration.cali_idx)
+            .is_some_and(|v| Self::calibration_on_extruder(v, extruder_id))
+        {
+            return Some(calibration.cali_idx);
+        }
+        nozzle_calibrations
+            .values()
+            .find(|v| {
+                Self::calibration_on_extruder(v, extruder_id)
+                    && v.name.trim() == calibration.name.trim()
+                    && v.filament_id == calibration.filament_id
+                    && v.setting_id == calibration.setting_id
+            })
+            .map(|v| v.cali_idx)
+    }
+
+    fn add_calibration_for_tray(&mut self, tray_id: i32, extruder_id: usize, calibration: Calibration) {
+        let nozzle_diameter = self.nozzle_diameter(extruder_id).cloned().unwrap_or_default();
+        term_info!("Adding PA calibration '{}' to printer for nozzle {}", calibration.name, nozzle_diameter);
+        let k_value = format!("{:.3}", f32::from_str(&calibration.k_value).unwrap_or_default());
+        let cmd = crate::bambu_api::ExtrusionCaliSetCommand::new(
+            &nozzle_diameter,
+            &calibration.filament_id,
+            &calibration.setting_id,
+            &calibration.name,
+            &k_value,
+            None,
+            self.command_extruder_id(extruder_id),
+        );
+        self.publish_command(cmd, CommandContext::Internal);
+
+        // a later assignment to the same tray replaces the earlier one
+        self.pending_cali_selections.retain(|v| v.tray_id != tray_id);
+        self.pending_cali_selections.push(PendingCaliSelection {
+            nozzle_diameter,
+            extruder_id,
+            tray_id,
+            calibration,
+            expires: Instant::now() + PENDING_CALI_SELECTION_TIMEOUT,
+        });
+    }
+
+    // == Calibrations Management =====================================================
+
+    // Printer calibrations for a nozzle (see nozzle_key), optionally only those of a specific filament, sorted by filament and name
+    pub fn get_nozzle_calibrations(&self, nozzle_key: &str, filament_id: Option<&str>) -> Vec<Calibration> {
+        let mut calibrations: Vec<Calibration> = self
+            .printer_calibrations(nozzle_key)
+            .map(|nozzle_calibrations| {
+                nozzle_calibrations
+                    .values()
+                    .filter(|v| filament_id.is_none_or(|filament_id| v.filament_id == filament_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        calibrations.sort_by(|a, b| a.filament_id.cmp(&b.filament_id).then_with(|| a.name.cmp(&b.name)));
+        calibrations
+    }
+
+    // Filament ids that have calibrations for a nozzle, sorted
+    pub fn get_calibrated_filament_ids(&self, nozzle_key: &str) -> Vec<String> {
+        let mut filament_ids: Vec<String> = Vec::new();
+        if let Some(nozzle_calibrations) = self.printer_calibrations(nozzle_key) {
+            for calibration in nozzle_calibrations.values() {
+                if !filament_ids.contains(&calibration.filament_id) {
+                    filament_ids.push(calibration.filament_id.clone());
+                }
+            }
+        }
+        filament_ids.sort();
+        filament_ids
+    }
+
+    // Rename and/or change K of an existing printer calibration, printer response triggers a refresh of the calibrations
+    pub fn update_calibration(&self, nozzle_key: &str, cali_idx: i32, name: Option<&str>, k_value: Option<&str>) -> Result<(), Error> {
+        let calibration = self
+            .printer_calibrations(nozzle_key)
+            .and_then(|nozzle_calibrations| nozzle_calibrations.get(&cali_idx))
+            .ok_or(Error::NotFound)?;
+
+        let name = match name {
+            Some(name) => {
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(Error::InvalidValue);
+                }
+                name
+            }
+            None => calibration.name.as_str(),
+        };
+        let k_value = match k_value {
+            Some(k_value) => Self::validated_k_value(k_value)?,
+            None => calibration.k_value.clone(),
+        };
+
+        term_info!("Updating PA calibration '{}' (nozzle {}) to '{}' K {}", calibration.name, nozzle_key, name, k_value);
+        let cmd = crate::bambu_api::ExtrusionCaliSetCommand::new(
+            nozzle_key_diameter(nozzle_key),
+            &calibration.filament_id,
+            &calibration.setting_id,
+            name,
+            &k_value,
+            Some(cali_idx),
+            calibration.extruder_id,
+        );
+        self.publish_command(cmd, CommandContext::Internal);
+        Ok(())
+    }
+
+    // Delete a printer calibration, printer response triggers a refresh of the calibrations
+    pub fn delete_calibration(&self, nozzle_key: &str, cali_idx: i32) -> Result<(), Error> {
+        let calibration = self
+            .printer_calibrations(nozzle_key)
+            .and_then(|nozzle_calibrations| nozzle_calibrations.get(&cali_idx))
+            .ok_or(Error::NotFound)?;
+
+        term_info!("Deleting PA calibration '{}' (nozzle {})", calibration.name, nozzle_key);
+        let cmd = crate::bambu_api::ExtrusionCaliDelCommand::new(
+            nozzle_key_diameter(nozzle_key),
+            &calibration.filament_id,
+            cali_idx,
+            calibration.extruder_id,
+        );
+        self.publish_command(cmd, CommandContext::Internal);
+        Ok(())
+    }
+
+    // == Calibrations Backup & Restore ===============================================
+
+    pub fn calibrations_backup(&self) -> String {
+        let mut nozzle_keys: Vec<&String> = self.calibrations.keys().collect();
+        nozzle_keys.sort();
+        let backup = CalibrationsBackup {
+            version: CALIBRATIONS_BACKUP_VERSION,
+            printer_serial: self.app_config.borrow().printer_serial.clone().unwrap_or_default(),
+            nozzles: nozzle_keys
+                .into_iter()
+                .map(|nozzle_key| NozzleCalibrationsBackup {
+                    nozzle_diameter: nozzle_key.clone(),
+                    calibrations: self
+                        .get_nozzle_calibrations(nozzle_key, None)
+                        .iter()
+                        .map(CalibrationBackup::from)
+                        .collect(),
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&backup).unwrap()
+    }
 
-        // If the filament info contains calibration for the current nozzle and the printer calibrations contain that calibration-idx for that nozzle diameter then send that, otherwise send -1 (so no calibration)
-        if let Some(filament_calibration) = filament.calibrations.get(self.nozzle_diameter.as_ref().unwrap()) {
-            if let Some(printer_calibrations) = self.calibrations.get(self.nozzle_diameter.as_ref().unwrap()) {
-                if printer_calibrations.contains_key(&filament_calibration.cali_idx) {
-                    cali_idx = filament_calibration.cali_idx;
+    // Queues the backup calibrations missing on the printer to be added by the restore task, returns how many were queued
+    // and how many were skipped for an invalid K value (e.g. a backup edited by hand)
+    pub fn restore_calibrations(&mut self, backup: &str) -> Result<(usize, usize), Error> {
+        let backup = serde_json::from_str::<CalibrationsBackup>(backup).map_err(|_| Error::ParseError)?;
+        if backup.version > CALIBRATIONS_BACKUP_VERSION {
+            return Err(Error::InvalidValue);
+        }
+        if !self.calibrations_restore_queue.is_empty() {
+            return Err(Error::Busy);
+        }
+        let mut invalid = 0;
+        for nozzle in backup.nozzles {
+            for mut calibration in nozzle.calibrations {
+                // the printer keeps the calibrations per diameter, so checking them for all nozzle types of the diameter
+                let nozzle_diameter = nozzle_key_diameter(&nozzle.nozzle_diameter);
+                let exists = self.calibrations.iter().any(|(key, nozzle_calibrations)| {
+                    nozzle_key_diameter(key) == nozzle_diameter
+                        && nozzle_calibrations.values().any(|v| {
+                            v.name.trim() == calibration.name.trim()
+                                && v.filament_id == calibration.filament_id
+                                && v.setting_id == calibration.setting_id
+                                && v.extruder_id == calibration.extruder_id
+                        })
+                });
+                if exists {
+                    continue;
+                }
+                match Self::validated_k_value(&calibration.k_value) {
+                    Ok(k_value) => {
+                        calibration.k_value = k_value;
+                        self.calibrations_restore_queue.push((String::from(nozzle_diameter), calibration));
+                    }
+                    Err(_) => {
+                        term_error!(
+                            "Not restoring PA calibration '{}' (nozzle {}), invalid K value '{}'",
+                            calibration.name,
+                            nozzle_diameter,
+                            calibration.k_value
+                        );
+                        invalid += 1;
+                    }
                 }
             }
         }
+        term_info!("Restoring {} PA calibrations missing on printer", self.calibrations_restore_queue.len());
+        if !self.calibrations_restore_queue.is_empty() {
+            self.calibrations_restore_signal.signal(());
+        }
+        Ok((self.calibrations_restore_queue.len(), invalid))
+    }
 
-        let cmd = crate::bambu_api::ExtrusionCaliSelCommand::new(
-            &self.nozzle_diameter.clone().unwrap_or_default(),
-            tray_id,                 // here we need the original tray_id
-            &filament.tray_info_idx, // tray_info_idx is filament_id in this command
-            Some(cali_idx),
-        );
-        let payload = serde_json::to_string_pretty(&cmd).unwrap();
-        self.publish_payload(payload);
+    // K values are sent to the printer with fixed precision, printer accepts 0 to 2 (same as Bambu Studio)
+    pub fn validated_k_value(k_value: &str) -> Result<String, Error> {
+        let k = f32::from_str(k_value.trim()).map_err(|_| Error::ParseError)?;
+        if !(0.0..=2.0).contains(&k) {
+            return Err(Error::InvalidValue);
+        }
+        Ok(format!("{k:.3}"))
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Extruder {
+    pub nozzle_diameter: Option<String>, // e.g. "0.4"
+    pub nozzle_type: Option<String>,     // as reported by the printer, e.g. "hardened_steel" or "HS01"
+}
+
+impl Extruder {
+    pub fn nozzle_code(&self) -> Option<String> {
+        nozzle_code_from_type(self.nozzle_type.as_ref()?)
+    }
+}
+
+// Nozzle material and flow as two letters, the material: S - stainless steel, H - hardened steel, T - tungsten carbide,
+// and the flow: S - standard, H - high flow. Printers reporting nozzle ids use that form (e.g. "HS01", "HH00-0.4"), others use names
+fn nozzle_code_from_type(nozzle_type: &str) -> Option<String> {
+    match nozzle_type {
+        "stainless_steel" => Some(String::from("SS")),
+        "hardened_steel" => Some(String::from("HS")),
+        "tungsten_carbide" => Some(String::from("TS")),
+        _ => nozzle_type.get(..2).filter(|code| is_nozzle_code(code)).map(String::from),
+    }
+}
+
+fn is_nozzle_code(code: &str) -> bool {
+    let mut chars = code.chars();
+    matches!((chars.next(), chars.next(), chars.next()), (Some('S' | 'H' | 'T'), Some('S' | 'H'), None))
+}
+
+// Calibrations are kept per nozzle key: the nozzle diameter, followed by the nozzle code when known, e.g. "0.4" or "0.4-HH"
+// (hardened steel, high flow), since PA differs between nozzle materials and flows of the same diameter
+pub fn nozzle_key(nozzle_diameter: &str, nozzle_code: Option<&str>) -> String {
+    match nozzle_code {
+        Some(nozzle_code) => format!("{nozzle_diameter}-{nozzle_code}"),
+        None => String::from(nozzle_diameter),
+    }
+}
+
+pub fn nozzle_key_diameter(nozzle_key: &str) -> &str {
+    nozzle_key.split_once('-').map_or(nozzle_key, |(nozzle_diameter, _)| nozzle_diameter)
+}
+
+pub fn nozzle_key_code(nozzle_key: &str) -> Option<&str> {
+    nozzle_key.split_once('-').map(|(_, nozzle_code)| nozzle_code)
+}
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Tray {
     pub state: TrayState,
     pub filament: Filament,
     pub k: Option<String>,
     pub cali_idx: Option<i32>,
+    pub is_bbl: bool,        // Bambu spool identified by the AMS through its RFID tag
+    pub remain: Option<u32>, // percent of filament left, as estimated by the AMS (when the AMS remain estimate setting is on)
 }
 
 impl Tray {
@@ -874,7 +2144,6 @@ impl Tray {
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum TrayState {
     #[default]
@@ -889,9 +2158,13 @@ pub enum TrayState {
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug)]
 pub enum Error {
     ParseError,
     MissingFields,
+    NotFound,
+    InvalidValue,
+    Busy,
 }
 
 #[derive(Debug, Clone, PartialEq, Default)]
@@ -901,7 +2174,7 @@ pub enum Filament {
     Known(FilamentInfo),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct FilamentInfo {
     pub tray_info_idx: String,                      // e.g. "GFL99"
     pub tray_type: String,                          // e.g. "PLA"
@@ -909,10 +2182,35 @@ pub struct FilamentInfo {
     pub nozzle_temp_max: u32,                       // e.g. 250
     pub nozzle_temp_min: u32,                       // w.g. 190
     pub calibrations: HashMap<String, Calibration>, // calibration for nozzles
+    pub tray_uuid: Option<String>,                  // Bambu spool RFID uuid, if identified by the AMS
+    pub tray_weight: Option<u32>,                   // e.g. 1000 (grams), as reported for Bambu spools, net weight of the spool
+    #[serde(default)]
+    pub spool_info: SpoolInfo,                      // spool metadata, in V2 descriptors
 }
 
 impl FilamentInfo {
     pub fn to_descriptor(&self, printer_name: &Option<String>) -> String {
+        self.descriptor_with_spool_info(printer_name, &self.spool_info)
+    }
+
+    // Descriptor to write to a tag, when the spool metadata doesn't fit in the tag the least needed fields are left off,
+    // returns the names of the fields left off
+    pub fn to_tag_descriptor(&self, printer_name: &Option<String>) -> (String, Vec<&'static str>) {
+        let mut spool_info = self.spool_info.clone();
+        let mut left_off = Vec::new();
+        loop {
+            let descriptor = self.descriptor_with_spool_info(printer_name, &spool_info);
+            if descriptor.len() <= MAX_DESCRIPTOR_LEN {
+                return (descriptor, left_off);
+            }
+            match spool_info.leave_off_least_needed() {
+                Some(field_name) => left_off.push(field_name),
+                None => return (descriptor, left_off),
+            }
+        }
+    }
+
+    fn descriptor_with_spool_info(&self, printer_name: &Option<String>, spool_info: &SpoolInfo) -> String {
         let mut inner_calibrations_part = String::new();
         let printer_name = printer_name.as_ref();
 
@@ -926,11 +2224,13 @@ impl FilamentInfo {
         let k_postfix = if !k_prefix.is_empty() { ")" } else { "" };
 
         for calibration_kv in self.calibrations.iter() {
-            if let Some(cal_nozzle_diameter_char) = calibration_kv.0.chars().nth(2) {
+            // K followed by the nozzle diameter digit and the nozzle code if known, e.g. K4 or K4HH (see descriptor_nozzle_key)
+            if let Some(cal_nozzle_diameter_char) = nozzle_key_diameter(calibration_kv.0).chars().nth(2) {
                 let calibration = calibration_kv.1;
                 inner_calibrations_part += &format!(
-                    "K{}={}~{}~{}",
+                    "K{}{}={}~{}~{}",
                     cal_nozzle_diameter_char,
+                    nozzle_key_code(calibration_kv.0).unwrap_or(""),
                     calibration.k_value.trim_end_matches('0'),
                     &calibration.setting_id,
                     &my_encode_to_url_part(&calibration.name)
@@ -942,10 +2242,50 @@ impl FilamentInfo {
         } else {
             format!("{k_prefix}{inner_calibrations_part}{k_postfix}")
         };
-        format!(
-            "{FILAMENT_URL_PREFIX}V1?ID={TAG_PLACEHOLDER}&M={}&C={}&NN={}&NX={}{}&FI={}",
-            self.tray_type, self.tray_color, self.nozzle_temp_min, self.nozzle_temp_max, calibrations_part, self.tray_info_idx
-        )
+        let mut bambu_part = String::new();
+        if let Some(tray_uuid) = &self.tray_uuid {
+            bambu_part += &format!("&BU={}", tray_uuid);
+        }
+        if let Some(tray_weight) = self.tray_weight {
+            bambu_part += &format!("&W={}", tray_weight);
+        }
+        let descriptor = |version: &str, spool_part: &str| {
+            format!(
+                "{FILAMENT_URL_PREFIX}{version}?ID={TAG_PLACEHOLDER}&M={}&C={}&NN={}&NX={}{}&FI={}{}{}",
+                self.tray_type,
+                self.tray_color,
+                self.nozzle_temp_min,
+                self.nozzle_temp_max,
+                calibrations_part,
+                self.tray_info_idx,
+                bambu_part,
+                spool_part
+            )
+        };
+        // V1 when there's no spool metadata, so these tags can still be read by older versions
+        if spool_info.is_empty() {
+            descriptor("V1", "")
+        } else {
+            descriptor("V2", &spool_info.to_descriptor_part())
+        }
+    }
+
+    // Calibrations of older tags have only the nozzle diameter, they apply to the standard flow nozzles of that diameter,
+    // and when the nozzle type isn't known a calibration of a standard flow nozzle of the diameter applies
+    pub fn calibration_for_nozzle(&self, nozzle_key: &str) -> Option<&Calibration> {
+        if let Some(calibration) = self.calibrations.get(nozzle_key) {
+            return Some(calibration);
+        }
+        let nozzle_diameter = nozzle_key_diameter(nozzle_key);
+        match nozzle_key_code(nozzle_key) {
+            Some(nozzle_code) if nozzle_code.ends_with('S') => self.calibrations.get(nozzle_diameter),
+            Some(_) => None,
+            None => self
+                .calibrations
+                .iter()
+                .find(|(key, _)| nozzle_key_diameter(key) == nozzle_diameter && nozzle_key_code(key).is_none_or(|v| v.ends_with('S')))
+                .map(|(_, calibration)| calibration),
+        }
     }
 
     pub fn new() -> Self {
@@ -956,10 +2296,21 @@ impl FilamentInfo {
             nozzle_temp_max: 0,
             nozzle_temp_min: 0,
             calibrations: HashMap::new(),
+            tray_uuid: None,
+            tray_weight: None,
+            spool_info: SpoolInfo::default(),
         }
     }
 
     pub fn from_descriptor(descriptor: &str, bambu_printer: &BambuPrinter) -> Result<Self, Error> {
+        Self::from_descriptor_with_calibrations(descriptor, |nozzle_key| bambu_printer.printer_calibrations(nozzle_key))
+    }
+
+    // printer_calibrations gets the printer calibrations of a nozzle key, which the tag calibrations are matched to
+    fn from_descriptor_with_calibrations<'a>(
+        descriptor: &str,
+        printer_calibrations: impl Fn(&str) -> Option<&'a HashMap<i32, Calibration>>,
+    ) -> Result<Self, Error> {
         let mut filament_info_result = FilamentInfo::new();
         if !(descriptor.starts_with(FILAMENT_URL_PREFIX)) {
             return Err(Error::ParseError);
@@ -974,7 +2325,7 @@ impl FilamentInfo {
         let mut nn = false;
         let mut nx = false;
         for param in descriptor.split(['&', '/', '?']) {
-            if param == "V1" {
+            if param == "V1" || param == "V2" {
                 v = true;
                 continue;
             }
@@ -1019,6 +2370,28 @@ impl FilamentInfo {
                         filament_info_result.tray_info_idx = String::from(param_value);
                         fi = true;
                     }
+                    // Bambu spool Uuid (optional, only for tags encoded from an RFID identified Bambu spool)
+                    "BU" => {
+                        filament_info_result.tray_uuid = Some(String::from(param_value));
+                    }
+                    // Weight of spool in grams (optional, an invalid value is ignored rather than failing the tag)
+                    "W" => {
+                        if let Ok(ret_val) = param_value.parse::<u32>() {
+                            filament_info_result.tray_weight = Some(ret_val);
+                        } else {
+                            warn!("Ignoring invalid spool weight '{}' in tag", param_value);
+                        }
+                    }
+                    // Spool metadata (V2, all optional, an invalid value is ignored rather than failing the tag), see SpoolInfo
+                    "B" => filament_info_result.spool_info.brand = descriptor_text(param_value),
+                    "PN" => filament_info_result.spool_info.name = descriptor_text(param_value),
+                    "D" => filament_info_result.spool_info.diameter = descriptor_decimal(param_name, param_value),
+                    "SW" => filament_info_result.spool_info.spool_weight = descriptor_number(param_name, param_value),
+                    "L" => filament_info_result.spool_info.lot = descriptor_text(param_value),
+                    "PD" => filament_info_result.spool_info.purchase_date = descriptor_text(param_value),
+                    "OD" => filament_info_result.spool_info.open_date = descriptor_text(param_value),
+                    "P" => filament_info_result.spool_info.price = descriptor_decimal(param_name, param_value),
+                    "BT" => filament_info_result.spool_info.bed_temp = descriptor_number(param_name, param_value),
                     _ => (), //return Err(Error::ParseError), TODO: verify match to pattern, or even run what's coming next inside here
                 }
             }
@@ -1037,13 +2410,11 @@ impl FilamentInfo {
                 // currently not used, could compare to current printer name and ignore
             }
             if let Some((param_name, param_value)) = param.split_once("=") {
-                match param_name {
-                    // K - Pressure Advance Factor for Nozzle Diameter 0.4, 0.2, 0.6, 0.8
-                    "K4" | "K2" | "K6" | "K8" => {
-                        //TODO: Currently we set the filament calibration only if it is found in the printer tables
-                        // In the future consider adding the calibarion to the printer if it's not available
-                        let nozzle_diameter_digit = param_name.chars().nth(1).unwrap();
-                        let nozzle_diameter = format!("0.{}", nozzle_diameter_digit);
+                match descriptor_nozzle_key(param_name) {
+                    // K - Pressure Advance Factor for Nozzle Diameter 0.4, 0.2, 0.6, 0.8 (and nozzle type if specified)
+                    Some(nozzle_key) => {
+                        // If the calibration isn't found in the printer tables, it is kept without cali_idx (-1)
+                        // and added to the printer when the filament is set to a tray (see set_tray_filament)
 
                         let mut k_parts = param_value.splitn(3, '~');
 
@@ -1062,7 +2433,8 @@ impl FilamentInfo {
                         // I could also ignore K, or force only K and find something that match the K
                         // I can also check what to do exactly based on printer name - if its the original printer or not - see belo comment
 
-                        if let Some(nozzle_calibrations) = bambu_printer.calibrations.get(&nozzle_diameter) {
+                        let mut found_in_printer = false;
+                        if let Some(nozzle_calibrations) = printer_calibrations(&nozzle_key) {
                             if let Some(calibration) = nozzle_calibrations.values().find(|v| {
                                 v.k_value.trim_end_matches('0') == k_value.trim_end_matches('0')
                                     && v.filament_id == filament_info_result.tray_info_idx
@@ -1075,7 +2447,8 @@ impl FilamentInfo {
                                     &calibration.name,
                                     calibration.cali_idx,
                                 );
-                                filament_info_result.calibrations.insert(nozzle_diameter, calibration);
+                                filament_info_result.calibrations.insert(nozzle_key.clone(), calibration);
+                                found_in_printer = true;
                             } else if let Some(calibration) = nozzle_calibrations.values().find(|v| {
                                 // TODO: Key note for multiprinter support
                                 // if I'll remove the setting_id check it will allow tag from one printer to match another if PA profile named the same
@@ -1090,11 +2463,17 @@ impl FilamentInfo {
                                     &calibration.name,
                                     calibration.cali_idx,
                                 );
-                                filament_info_result.calibrations.insert(nozzle_diameter, calibration);
+                                filament_info_result.calibrations.insert(nozzle_key.clone(), calibration);
+                                found_in_printer = true;
                             }
                         }
+                        if !found_in_printer {
+                            let calibration =
+                                Calibration::new_minimal(k_value, &filament_info_result.tray_info_idx, setting_id, &name, -1);
+                            filament_info_result.calibrations.insert(nozzle_key, calibration);
+                        }
                     }
-                    _ => (), // previous run already identified unrecognized parameters, here we skip also those that were ok so can't error
+                    None => (), // previous run already identified unrecognized parameters, here we skip also those that were ok so can't error
                 }
             }
         }
@@ -1106,6 +2485,126 @@ impl FilamentInfo {
     }
 }
 
+// Spool metadata beyond the filament settings, kept as written to the tag (e.g. dates as YYYY-MM-DD, price in the user's currency).
+// The net weight of the spool is FilamentInfo tray_weight (W parameter, also in V1 descriptors)
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+pub struct SpoolInfo {
+    pub brand: Option<String>,         // e.g. "Bambu Lab"
+    pub name: Option<String>,          // product name, e.g. "PLA Basic"
+    pub diameter: Option<String>,      // e.g. "1.75" (mm)
+    pub spool_weight: Option<u32>,     // e.g. 250 (grams), of the empty spool
+    pub lot: Option<String>,           // lot / batch number
+    pub purchase_date: Option<String>, // e.g. "2025-03-21"
+    pub open_date: Option<String>,     // e.g. "2025-04-02"
+    pub price: Option<String>,         // e.g. "19.99"
+    pub bed_temp: Option<u32>,         // e.g. 60
+}
+
+impl SpoolInfo {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // Only for Bambu spools identified by the AMS, for other spools the AMS reports the slicer filament preset values
+    fn from_print_tray(v: &PrintTray) -> Self {
+        if v.bambu_tray_uuid().is_none() {
+            return Self::default();
+        }
+        Self {
+            brand: Some(String::from(BAMBU_BRAND)),
+            name: v.tray_sub_brands.clone().filter(|v| !v.is_empty()),
+            diameter: v.tray_diameter.clone().filter(|v| v.parse::<f32>().is_ok_and(|v| v > 0.0)),
+            bed_temp: v.bed_temp.as_ref().and_then(|v| v.parse::<u32>().ok()).filter(|v| *v != 0),
+            ..Self::default()
+        }
+    }
+
+    // Clears the least needed of the fields set, returns its name (for the user), None when there's none set
+    fn leave_off_least_needed(&mut self) -> Option<&'static str> {
+        if self.price.take().is_some() {
+            Some("Price")
+        } else if self.open_date.take().is_some() {
+            Some("Open Date")
+        } else if self.purchase_date.take().is_some() {
+            Some("Purchase Date")
+        } else if self.lot.take().is_some() {
+            Some("Lot")
+        } else if self.bed_temp.take().is_some() {
+            Some("Bed Temp")
+        } else if self.name.take().is_some() {
+            Some("Product Name")
+        } else if self.spool_weight.take().is_some() {
+            Some("Spool Weight")
+        } else if self.diameter.take().is_some() {
+            Some("Diameter")
+        } else if self.brand.take().is_some() {
+            Some("Brand")
+        } else {
+            None
+        }
+    }
+
+    // Parameters of the metadata in a V2 descriptor: B brand, PN product name, D diameter, SW spool weight, L lot,
+    // PD purchase date, OD open date, P price and BT bed temperature
+    fn to_descriptor_part(&self) -> String {
+        let mut descriptor_part = String::new();
+        let text_params = [
+            ("B", &self.brand),
+            ("PN", &self.name),
+            ("D", &self.diameter),
+            ("L", &self.lot),
+            ("PD", &self.purchase_date),
+            ("OD", &self.open_date),
+            ("P", &self.price),
+        ];
+        for (param_name, param_value) in text_params {
+            if let Some(param_value) = param_value {
+                descriptor_part += &format!("&{param_name}={}", my_encode_to_url_part(param_value));
+            }
+        }
+        let number_params = [("SW", self.spool_weight), ("BT", self.bed_temp)];
+        for (param_name, param_value) in number_params {
+            if let Some(param_value) = param_value {
+                descriptor_part += &format!("&{param_name}={param_value}");
+            }
+        }
+        descriptor_part
+    }
+}
+
+fn descriptor_text(param_value: &str) -> Option<String> {
+    Some(my_decode_from_url_part(param_value)).filter(|v| !v.is_empty())
+}
+
+// Numbers with decimals are kept as text, so they are written back to tags as they were
+fn descriptor_decimal(param_name: &str, param_value: &str) -> Option<String> {
+    descriptor_number::<f32>(param_name, param_value).map(|_| String::from(param_value))
+}
+
+// None if not a valid number
+fn descriptor_number<T: FromStr>(param_name: &str, param_value: &str) -> Option<T> {
+    let number = param_value.parse::<T>().ok();
+    if number.is_none() {
+        warn!("Ignoring invalid value '{}' of tag parameter {}", param_value, param_name);
+    }
+    number
+}
+
+// Nozzle key of a descriptor calibration parameter: K, the nozzle diameter digit and optionally the nozzle code (see nozzle_key),
+// e.g. K4 (older tags, any 0.4 nozzle) or K4HH (0.4 hardened steel high flow)
+fn descriptor_nozzle_key(param_name: &str) -> Option<String> {
+    let param_name = param_name.strip_prefix('K')?;
+    let nozzle_diameter_digit = param_name.chars().next().filter(|v| matches!(v, '2' | '4' | '6' | '8'))?;
+    let nozzle_code = &param_name[1..];
+    if nozzle_code.is_empty() {
+        Some(nozzle_key(&format!("0.{nozzle_diameter_digit}"), None))
+    } else if is_nozzle_code(nozzle_code) {
+        Some(nozzle_key(&format!("0.{nozzle_diameter_digit}"), Some(nozzle_code)))
+    } else {
+        None
+    }
+}
+
 const ENCODING_TABLE: [(char, &str); 8] = [
     ('%', "%25"),
     ('/', "%2F"),
@@ -1228,6 +2727,9 @@ pub fn efficient_decode(input: &str, char_table: &[(char, &str)]) -> String {
 
 impl From<bambu_api::PrintTray> for FilamentInfo {
     fn from(v: bambu_api::PrintTray) -> Self {
+        let tray_uuid = v.bambu_tray_uuid().map(String::from);
+        let tray_weight = v.tray_weight_grams();
+        let spool_info = SpoolInfo::from_print_tray(&v);
         Self {
             tray_info_idx: v.tray_info_idx.unwrap_or_default(),
             tray_type: v.tray_type.unwrap_or_default(),
@@ -1235,6 +2737,9 @@ impl From<bambu_api::PrintTray> for FilamentInfo {
             nozzle_temp_max: v.nozzle_temp_max.unwrap_or(250),
             nozzle_temp_min: v.nozzle_temp_min.unwrap_or(190),
             calibrations: HashMap::new(),
+            tray_uuid,
+            tray_weight,
+            spool_info,
         }
     }
 }
@@ -1259,6 +2764,9 @@ impl From<&bambu_api::PrintTray> for FilamentInfo {
             nozzle_temp_max: v.nozzle_temp_max.unwrap_or(250),
             nozzle_temp_min: v.nozzle_temp_min.unwrap_or(190),
             calibrations: HashMap::new(),
+            tray_uuid: v.bambu_tray_uuid().map(String::from),
+            tray_weight: v.tray_weight_grams(),
+            spool_info: SpoolInfo::from_print_tray(v),
         }
     }
 }
@@ -1277,7 +2785,7 @@ impl From<&bambu_api::PrintTray> for FilamentInfo {
 /////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, PartialEq, Default)]
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
 pub struct Calibration {
     filament_id: String,
     k_value: String,
@@ -1285,6 +2793,8 @@ pub struct Calibration {
     setting_id: String,
     name: String,
     cali_idx: i32,
+    extruder_id: Option<u32>, // only on multi extruder printers
+    nozzle_code: Option<String>, // nozzle material and flow (see Extruder::nozzle_code), if known
 }
 
 impl From<&bambu_api::Filament> for Calibration {
@@ -1296,11 +2806,35 @@ impl From<&bambu_api::Filament> for Calibration {
             n_coef: f32::from_str(&v.n_coef).unwrap_or(-1.0),
             setting_id: v.setting_id.clone(),
             cali_idx: v.cali_idx,
+            extruder_id: v.extruder_id,
+            nozzle_code: v.nozzle_id.as_deref().and_then(nozzle_code_from_type),
         }
     }
 }
 
 impl Calibration {
+    pub fn filament_id(&self) -> &str {
+        &self.filament_id
+    }
+    pub fn k_value(&self) -> &str {
+        &self.k_value
+    }
+    pub fn setting_id(&self) -> &str {
+        &self.setting_id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn cali_idx(&self) -> i32 {
+        self.cali_idx
+    }
+    pub fn extruder_id(&self) -> Option<u32> {
+        self.extruder_id
+    }
+    pub fn nozzle_code(&self) -> Option<&str> {
+        self.nozzle_code.as_deref()
+    }
+
     pub fn new_minimal(k_value: &str, filament_id: &str, setting_id: &str, name: &str, cali_idx: i32) -> Self {
         Self {
             k_value: String::from(k_value),
@@ -1313,39 +2847,178 @@ impl Calibration {
     }
 }
 
+// Backup file format of the printer calibrations (all nozzles), used for export/import
+const CALIBRATIONS_BACKUP_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CalibrationsBackup {
+    version: u32,
+    printer_serial: String,
+    nozzles: Vec<NozzleCalibrationsBackup>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct NozzleCalibrationsBackup {
+    nozzle_diameter: String, // nozzle key (see nozzle_key), older backups have only the diameter
+    calibrations: Vec<CalibrationBackup>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CalibrationBackup {
+    filament_id: String,
+    setting_id: String,
+    name: String,
+    k_value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extruder_id: Option<u32>, // only on multi extruder printers
+}
+
+impl From<&Calibration> for CalibrationBackup {
+    fn from(v: &Calibration) -> Self {
+        Self {
+            filament_id: v.filament_id.clone(),
+            setting_id: v.setting_id.clone(),
+            name: v.name.clone(),
+            k_value: v.k_value.clone(),
+            extruder_id: v.extruder_id,
+        }
+    }
+}
+
 /////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+// Creates the printer model early (before the web app is built, which needs access to it), tasks are started later by init
+pub fn create_model(app_config: Rc<RefCell<AppConfig>>) -> Rc<RefCell<BambuPrinter>> {
+    let write_packets = mk_static!(
+        embassy_sync::channel::Channel< embassy_sync::blocking_mutex::raw::NoopRawMutex, crate::my_mqtt::BufferedMqttPacket, 3,>,
+        embassy_sync::channel::Channel::< embassy_sync::blocking_mutex::raw::NoopRawMutex, crate::my_mqtt::BufferedMqttPacket, 3,>::new()
+    );
+    let commands_signal = mk_static!(Signal<NoopRawMutex, ()>, Signal::new());
+    let calibrations_restore_signal = mk_static!(Signal<NoopRawMutex, ()>, Signal::new());
+    Rc::new(RefCell::new(BambuPrinter::new(
+        write_packets,
+        commands_signal,
+        calibrations_restore_signal,
+        app_config,
+    )))
+}
+
 // needs to be async to get a spawner even though shouldn't be async
 pub async fn init(
     // Initializes stuff for Main Thread
     stack: Stack<'static>,
-    app_config: Rc<RefCell<AppConfig>>,
+    bambu_printer_model: Rc<RefCell<BambuPrinter>>,
+    printer_discovery: Rc<RefCell<PrinterDiscovery>>,
+    sdcard: Rc<RefCell<AppSDCard>>,
     tls: TlsReference<'static>,
-) -> Rc<RefCell<BambuPrinter>> {
+) {
     let spawner = embassy_executor::Spawner::for_current_executor().await;
 
+    let write_packets = bambu_printer_model.borrow().write_packets;
+    let app_config = bambu_printer_model.borrow().app_config.clone();
+
     // == Setup MQTT ==================================================================
-    let write_packets = mk_static!(
-        embassy_sync::channel::Channel< embassy_sync::blocking_mutex::raw::NoopRawMutex, crate::my_mqtt::BufferedMqttPacket, 3,>,
-        embassy_sync::channel::Channel::< embassy_sync::blocking_mutex::raw::NoopRawMutex, crate::my_mqtt::BufferedMqttPacket, 3,>::new()
-    );
     let read_packets = mk_static!(
         embassy_sync::pubsub::PubSubChannel<embassy_sync::blocking_mutex::raw::NoopRawMutex, crate::my_mqtt::BufferedMqttPacket, 5, 2, 1,>,
         embassy_sync::pubsub::PubSubChannel::<embassy_sync::blocking_mutex::raw::NoopRawMutex, crate::my_mqtt::BufferedMqttPacket, 5, 2, 1,>::new()
     );
 
-    spawner
-        .spawn(bambu_mqtt_task(stack, read_packets, write_packets, app_config.clone(), tls))
-        .ok();
-
-    let bambu_printer_model = Rc::new(RefCell::new(BambuPrinter::new(write_packets, app_config)));
+    let (mqtt_capture, mqtt_replay) = {
+        let app_config_borrow = app_config.borrow();
+        (app_config_borrow.mqtt_capture, app_config_borrow.mqtt_replay.clone())
+    };
+    if let Some(replay_dir) = mqtt_replay {
+        // debugging without a printer, a capture is processed instead
+        spawner
+            .spawn(mqtt_capture::mqtt_replay_task(replay_dir, sdcard, read_packets, write_packets, app_config))
+            .ok();
+    } else {
+        let capture = if mqtt_capture {
+            let capture = Rc::new(RefCell::new(MqttCapture::new(sdcard)));
+            spawner.spawn(mqtt_capture::mqtt_capture_task(capture.clone())).ok();
+            Some(capture)
+        } else {
+            None
+        };
+        spawner
+            .spawn(bambu_mqtt_task(stack, read_packets, write_packets, app_config, printer_discovery, capture, tls))
+            .ok();
+    }
 
-    let spawner = embassy_executor::Spawner::for_current_executor().await;
     spawner.spawn(incoming_messages_task(read_packets, bambu_printer_model.clone())).ok();
 
-    spawner.spawn(fetch_initial_info(bambu_printer_model.clone())).ok();
+    spawner.spawn(commands_task(bambu_printer_model.clone())).ok();
+
+    spawner.spawn(restore_calibrations_task(bambu_printer_model.clone())).ok();
+
+    spawner.spawn(fetch_initial_info(bambu_printer_model)).ok();
+}
+
+// Sends the published commands in order (waiting for room in the write channel instead of dropping them),
+// and times out commands the printer didn't respond to and pending calibration selections
+// Like fetch_initial_info it can't await while borrowing bambu_printer
+#[embassy_executor::task]
+pub async fn commands_task(bambu_printer: Rc<RefCell<BambuPrinter>>) {
+    const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+    let write_packets = bambu_printer.borrow().write_packets;
+    let commands_signal = bambu_printer.borrow().commands_signal;
+    loop {
+        let _ = with_timeout(EXPIRY_CHECK_INTERVAL, commands_signal.wait()).await;
+        loop {
+            let payload = bambu_printer.borrow().commands_outbox.borrow_mut().pop_front();
+            let Some(payload) = payload else {
+                break;
+            };
+            let printer_serial = bambu_printer.borrow().app_config.borrow().printer_serial.clone().unwrap_or_default();
+            BambuPrinter::publish_payload_async(&printer_serial, write_packets, payload).await;
+        }
+        bambu_printer.borrow().expire_pending_commands();
+        bambu_printer.borrow_mut().expire_pending_cali_selections();
+        bambu_printer.borrow().notify_command_results();
+    }
+}
 
-    bambu_printer_model
+// Adds queued calibrations (from a restored backup) to the printer one at a time, so the write channel isn't flooded
+// Like fetch_initial_info it can't await while borrowing bambu_printer
+#[embassy_executor::task]
+pub async fn restore_calibrations_task(bambu_printer: Rc<RefCell<BambuPrinter>>) {
+    const RESTORE_INTERVAL_MS: u64 = 300;
+    let write_packets = bambu_printer.borrow().write_packets;
+    let calibrations_restore_signal = bambu_printer.borrow().calibrations_restore_signal;
+    let mut restored_nozzles: Vec<(String, Option<u32>)> = Vec::new();
+    loop {
+        calibrations_restore_signal.wait().await;
+        let printer_serial = bambu_printer.borrow().app_config.borrow().printer_serial.clone().unwrap_or_default();
+        loop {
+            let next = bambu_printer.borrow_mut().calibrations_restore_queue.pop();
+            let Some((nozzle_diameter, calibration)) = next else {
+                break;
+            };
+            term_info!("Restoring PA calibration '{}' (nozzle {})", calibration.name, nozzle_diameter);
+            let cmd = crate::bambu_api::ExtrusionCaliSetCommand::new(
+                &nozzle_diameter,
+                &calibration.filament_id,
+                &calibration.setting_id,
+                &calibration.name,
+                &calibration.k_value, // validated when queued
+                None,
+                calibration.extruder_id,
+            );
+            BambuPrinter::publish_command_async(&printer_serial, write_packets, cmd).await;
+            let restored_nozzle = (nozzle_diameter, calibration.extruder_id);
+            if !restored_nozzles.contains(&restored_nozzle) {
+                restored_nozzles.push(restored_nozzle);
+            }
+            Timer::after_millis(RESTORE_INTERVAL_MS).await;
+        }
+        // done restoring, refresh the restored nozzles calibrations (echo refreshes may have been dropped)
+        if !restored_nozzles.is_empty() {
+            for (nozzle_diameter, extruder_id) in restored_nozzles.drain(..) {
+                BambuPrinter::fetch_filament_calibrations_async(&printer_serial, write_packets, &nozzle_diameter, extruder_id).await;
+            }
+            term_info!("Restoring PA calibrations completed");
+        }
+    }
 }
 
 // Important: This is the initial load task. Because it issues more commands than can fit the Channel, it can't await while borrowing bambu_printer
@@ -1368,20 +3041,31 @@ pub async fn fetch_initial_info(bambu_printer: Rc<RefCell<BambuPrinter>>) {
     // fetch first setting for all nozzles, need that in advance before getting filaments
     let nozzle_diameters = ["0.8", "0.6", "0.2", "0.4"];
     for nozzle_diameter in nozzle_diameters {
-        BambuPrinter::fetch_filament_calibrations_async(&printer_serial, write_packets, nozzle_diameter).await;
+        BambuPrinter::fetch_filament_calibrations_async(&printer_serial, write_packets, nozzle_diameter, None).await;
     }
 
     // Now request full update, and wait until data is processed and have the nozzle diameter at hand for next request
     BambuPrinter::request_full_update(&printer_serial, write_packets).await;
-    while bambu_printer.borrow().nozzle_diameter.is_none() {
+    while bambu_printer.borrow().nozzle_diameter(MAIN_EXTRUDER).is_none() {
         Timer::after_millis(100).await;
     }
 
-    // Get again the filaments for current nozzle size,
+    // Get again the filaments for current nozzle size (of each extruder),
     // that's because in slicer they don't check if data received from printer it's current nozzle or not
     // it's a bug there, can even be reproduced in the slicer by switching in the manage results to another nozzle diameter
-    let curr_nozzle_diameter = bambu_printer.borrow().nozzle_diameter.as_ref().unwrap().clone();
-    BambuPrinter::fetch_filament_calibrations_async(&printer_serial, write_packets, &curr_nozzle_diameter).await;
+    let curr_nozzles: Vec<(String, Option<u32>)> = {
+        let bambu_printer = bambu_printer.borrow();
+        (0..bambu_printer.extruders.len())
+            .filter_map(|extruder_id| {
+                bambu_printer
+                    .nozzle_diameter(extruder_id)
+                    .map(|nozzle_diameter| (nozzle_diameter.clone(), bambu_printer.command_extruder_id(extruder_id)))
+            })
+            .collect()
+    };
+    for (curr_nozzle_diameter, extruder_id) in curr_nozzles {
+        BambuPrinter::fetch_filament_calibrations_async(&printer_serial, write_packets, &curr_nozzle_diameter, extruder_id).await;
+    }
 }
 
 #[embassy_executor::task]
@@ -1409,7 +3093,10 @@ pub async fn incoming_messages_task(
                             topic_name: _,
                             payload,
                         }) => {
-                            let parse_res = serde_json::from_slice::<bambu_api::Print>(payload);
+                            let parse_res = serde_json::from_slice::<serde_json::Value>(payload).and_then(|mut message| {
+                                let print = message.get_mut("print").map(serde_json::Value::take).unwrap_or_default();
+                                bambu_printer.borrow_mut().parse_print_message(print)
+                            });
                             if let Ok(print) = parse_res {
                                 debug!("MQTT Receive: {:?}", print);
                                 let previous_reading_bits = bambu_printer.borrow().tray_reading_bits;
@@ -1418,6 +3105,11 @@ pub async fn incoming_messages_task(
                                 if change_made {
                                     (*bambu_printer.borrow()).update_ams_trays_done(previous_reading_bits, updated_reading_bits);
                                 }
+                                let nozzle_changes = (*bambu_printer.borrow_mut()).take_nozzle_changes();
+                                if !nozzle_changes.is_empty() {
+                                    (*bambu_printer.borrow()).notify_nozzle_changes(&nozzle_changes);
+                                }
+                                (*bambu_printer.borrow()).notify_command_results();
                             } else {
                                 warn!("Unprocessed message {:?} : {:?}", parse_res, core::str::from_utf8(payload));
                             }
@@ -1449,6 +3141,49 @@ pub async fn incoming_messages_task(
     }
 }
 
+// Fields of the printer reports that BambuPrinter consumes (see bambu_api::PrintData), the rest is dropped while the
+// report arrives so large reports (e.g. of printers with several AMS's) don't need to be held in memory whole.
+// When processing a new field of the reports it needs to be added here
+const REPORT_PRINT_FIELDS: [&str; 24] = [
+    "ams",
+    "vt_tray",
+    "vir_slot",
+    "device",
+    "command",
+    "sequence_id",
+    "msg",
+    "nozzle_temp_max",
+    "nozzle_temp_min",
+    "tray_color",
+    "tray_id",
+    "ams_id",
+    "cali_idx",
+    "tray_info_idx",
+    "tray_type",
+    "reason",
+    "result",
+    "nozzle_diameter",
+    "nozzle_type",
+    "extruder_id",
+    "filament_id",
+    "filaments",
+    "home_flag",
+    "print_error",
+];
+// Fields inside the kept sections that are large and not used
+// (tray_sub_brands and bed_temp of the trays are kept, they are the spool metadata of Bambu spools, see SpoolInfo)
+const REPORT_DROPPED_FIELDS: [&str; 6] = ["xcam_info", "bed_temp_type", "tray_time", "tray_temp", "cols", "ctype"];
+
+pub fn keep_report_field(path: &[String]) -> bool {
+    match path {
+        [section] => section == "print",
+        [_, field] => REPORT_PRINT_FIELDS.contains(&field.as_str()),
+        [_, section, field] if section == "device" => field == "nozzle" || field == "extruder",
+        [.., field] => !REPORT_DROPPED_FIELDS.contains(&field.as_str()),
+        [] => true,
+    }
+}
+
 // Usage example, this should be in the client code using the generic_mqtt_task, specific per scenario
 // This indirection is because embassy can't have generic functions as tasks
 // https://github.com/embassy-rs/embassy/issues/2454#issuecomment-2336644031
@@ -1459,6 +3194,8 @@ pub async fn bambu_mqtt_task(
     read_packets: &'static PubSubChannel<NoopRawMutex, BufferedMqttPacket, 5, 2, 1>,
     write_packets: &'static Channel<NoopRawMutex, BufferedMqttPacket, 3>,
     app_config: Rc<RefCell<AppConfig>>,
+    printer_discovery: Rc<RefCell<PrinterDiscovery>>,
+    capture: Option<Rc<RefCell<MqttCapture>>>,
     tls: TlsReference<'static>,
 ) {
     let app_config_borrow = app_config.borrow();
@@ -1476,6 +3213,7 @@ pub async fn bambu_mqtt_task(
 
     if !printer_login_exist {
         term_info!("Missing Printer Serial and/or Access Code configurations");
+        app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::NotConfigured);
         return;
     }
 
@@ -1509,82 +3247,12 @@ pub async fn bambu_mqtt_task(
 
     if app_config.borrow().printer_ip.is_none() {
         term_info!("No Printer IP configured, discovering Printer");
-        let (mut rx_buffer1, mut rx_buffer2) = ([0; 512], [0; 512]);
-        let (mut tx_buffer1, mut tx_buffer2) = ([0; 0], [0; 0]);
-        let (mut rx_meta1, mut rx_meta2) = (
-            [embassy_net::udp::PacketMetadata::EMPTY; 16],
-            [embassy_net::udp::PacketMetadata::EMPTY; 16],
-        );
-        let (mut tx_meta1, mut tx_meta2) = (
-            [embassy_net::udp::PacketMetadata::EMPTY; 16],
-            [embassy_net::udp::PacketMetadata::EMPTY; 16],
-        );
-        let (mut buf1, mut buf2) = ([0; 512], [0; 512]);
-
-        let _ = stack.join_multicast_group(embassy_net::Ipv4Address::new(239, 255, 255, 250)).unwrap();
-        let recv_source_endpoint1 = embassy_net::IpEndpoint {
-            addr: embassy_net::Ipv4Address::UNSPECIFIED.into(),
-            port: 1990,
-        };
-        let mut recv_socket1 = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta1, &mut rx_buffer1, &mut tx_meta1, &mut tx_buffer1);
-        recv_socket1.bind(recv_source_endpoint1).unwrap();
-
-        let recv_source_endpoint2 = embassy_net::IpEndpoint {
-            addr: embassy_net::Ipv4Address::UNSPECIFIED.into(),
-            port: 2021,
-        };
-        let mut recv_socket2 = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta2, &mut rx_buffer2, &mut tx_meta2, &mut tx_buffer2);
-        recv_socket2.bind(recv_source_endpoint2).unwrap();
-
-        'outer_loop: loop {
-            debug!("Waiting for SSDP UDP");
-
-            let data = match select(recv_socket1.recv_from(&mut buf1), recv_socket2.recv_from(&mut buf2)).await {
-                Either::First(Ok(inner_res)) => {
-                    let data = &buf1[0..inner_res.0];
-                    Ok(data)
-                }
-                Either::Second(Ok(inner_res)) => {
-                    let data = &buf2[0..inner_res.0];
-                    Ok(data)
-                }
-                _ => {
-                    error!("There was some error");
-                    Err("Error waiting for data")
-                }
-            };
-
-            if let Ok(data) = data {
-                if let Ok(s) = core::str::from_utf8(data) {
-                    if s.contains("NT: urn:bambulab-com:device:3dprinter") && s.contains(&printer_serial) {
-                        let mut found_printer_ip = None;
-                        let mut found_printer_name = None;
-                        for line in s.lines() {
-                            if let Some((first, second)) = line.split_once(" ") {
-                                match first {
-                                    "Location:" => {
-                                        if let Ok(ip) = embassy_net::Ipv4Address::from_str(second) {
-                                            found_printer_ip = Some(ip);
-                                        }
-                                    }
-                                    "DevName.bambu.com:" => {
-                                        found_printer_name = Some(String::from(second));
-                                    }
-                                    _ => (),
-                                }
-                            }
-                        }
-                        if found_printer_ip.is_some() {
-                            printer_ip = found_printer_ip.unwrap();
-                            printer_name = found_printer_name.as_ref().unwrap_or(&String::from("Unknown")).to_string();
-                            term_info!("Discovered Printer at {}", printer_ip);
-                            term_info!("Printer named '{}'", &printer_name);
-                            break 'outer_loop;
-                        }
-                    }
-                }
-            }
-        }
+        app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::Discovering);
+        let printer = printer_discovery::wait_for_printer(&printer_discovery, &printer_serial).await;
+        printer_ip = printer.ip;
+        printer_name = printer.name;
+        term_info!("Discovered Printer at {}", printer_ip);
+        term_info!("Printer named '{}'", &printer_name);
     } else {
         printer_ip = app_config.borrow().printer_ip.unwrap();
         printer_name = app_config.borrow().printer_name.as_ref().unwrap_or(&String::from("Unknown")).to_string();
@@ -1621,89 +3289,215 @@ pub async fn bambu_mqtt_task(
         socket_rx_buffer,
         socket_tx_buffer,
         Duration::from_secs(20),
+        Some(keep_report_field),
+        capture,
         app_config,
+        printer_discovery,
         tls,
     )
     .await
 }
 
-pub async fn _wait_for_printer_ssdp(stack: Stack<'static>, duration: Duration) -> Option<String> {
-    let (mut rx_buffer1, mut rx_buffer2) = ([0; 512], [0; 512]);
-    let (mut tx_buffer1, mut tx_buffer2) = ([0; 0], [0; 0]);
-    let (mut rx_meta1, mut rx_meta2) = (
-        [embassy_net::udp::PacketMetadata::EMPTY; 16],
-        [embassy_net::udp::PacketMetadata::EMPTY; 16],
-    );
-    let (mut tx_meta1, mut tx_meta2) = (
-        [embassy_net::udp::PacketMetadata::EMPTY; 16],
-        [embassy_net::udp::PacketMetadata::EMPTY; 16],
-    );
-    let (mut buf1, mut buf2) = ([0; 512], [0; 512]);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let _ = stack.join_multicast_group(embassy_net::Ipv4Address::new(239, 255, 255, 250)).unwrap();
-    let recv_source_endpoint1 = embassy_net::IpEndpoint {
-        addr: embassy_net::Ipv4Address::UNSPECIFIED.into(),
-        port: 1990,
-    };
-    let mut recv_socket1 = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta1, &mut rx_buffer1, &mut tx_meta1, &mut tx_buffer1);
-    recv_socket1.bind(recv_source_endpoint1).unwrap();
+    fn path(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
 
-    let recv_source_endpoint2 = embassy_net::IpEndpoint {
-        addr: embassy_net::Ipv4Address::UNSPECIFIED.into(),
-        port: 2021,
-    };
-    let mut recv_socket2 = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta2, &mut rx_buffer2, &mut tx_meta2, &mut tx_buffer2);
-    recv_socket2.bind(recv_source_endpoint2).unwrap();
+    #[test]
+    fn keeps_only_print_reports() {
+        assert!(keep_report_field(&path(&[])));
+        assert!(keep_report_field(&path(&["print"])));
+        assert!(!keep_report_field(&path(&["info"])));
+        assert!(!keep_report_field(&path(&["upgrade"])));
+    }
 
-    let deadline = Instant::now().checked_add(duration).unwrap();
+    #[test]
+    fn keeps_consumed_print_fields() {
+        assert!(keep_report_field(&path(&["print", "ams"])));
+        assert!(keep_report_field(&path(&["print", "vt_tray"])));
+        assert!(keep_report_field(&path(&["print", "command"])));
+        assert!(keep_report_field(&path(&["print", "sequence_id"])));
+        assert!(!keep_report_field(&path(&["print", "xcam_info"])));
+        assert!(!keep_report_field(&path(&["print", "lights_report"])));
+        assert!(!keep_report_field(&path(&["print", "upgrade_state"])));
+    }
 
-    '_outer_loop: loop {
-        debug!("Waiting for SSDP UDP");
+    #[test]
+    fn keeps_only_nozzle_of_device() {
+        assert!(keep_report_field(&path(&["print", "device", "nozzle"])));
+        assert!(keep_report_field(&path(&["print", "device", "nozzle", "info", "diameter"])));
+        assert!(!keep_report_field(&path(&["print", "device", "airduct"])));
+    }
 
-        let data = with_deadline(deadline, select(recv_socket1.recv_from(&mut buf1), recv_socket2.recv_from(&mut buf2))).await;
+    #[test]
+    fn drops_unused_nested_fields() {
+        assert!(keep_report_field(&path(&["print", "ams", "ams", "tray", "tray_type"])));
+        assert!(keep_report_field(&path(&["print", "ams", "ams", "tray", "tray_color"])));
+        assert!(!keep_report_field(&path(&["print", "ams", "ams", "tray", "tray_time"])));
+        assert!(!keep_report_field(&path(&["print", "ams", "ams", "tray", "cols"])));
+        assert!(!keep_report_field(&path(&["print", "vt_tray", "tray_temp"])));
+    }
 
-        if let Ok(data) = data {
-            let data = match data {
-                Either::First(Ok(inner_res)) => {
-                    let data = &buf1[0..inner_res.0];
-                    Ok(data)
-                }
-                Either::Second(Ok(inner_res)) => {
-                    let data = &buf2[0..inner_res.0];
-                    Ok(data)
-                }
-                _ => {
-                    error!("There was some error");
-                    Err("Error waiting for data")
-                }
-            };
+    #[test]
+    fn decodes_extruder_trays() {
+        assert_eq!(BambuPrinter::extruder_tray(0xFFFF), None);
+        // external trays, 255 of the main extruder and 254 of the deputy one
+        assert_eq!(BambuPrinter::extruder_tray(0xFF00), Some(255));
+        assert_eq!(BambuPrinter::extruder_tray(0xFE00), Some(254));
+        assert_eq!(BambuPrinter::extruder_tray(0x0000), Some(0));
+        assert_eq!(BambuPrinter::extruder_tray(0x0102), Some(6));
+        assert_eq!(BambuPrinter::extruder_tray(0x8000), Some(128));
+    }
 
-            if let Ok(data) = data {
-                if let Ok(s) = core::str::from_utf8(data) {
-                    if s.contains("NT: urn:bambulab-com:device:3dprinter") {
-                        let mut _printer_ip;
-                        let printer_name;
-                        for line in s.lines() {
-                            if let Some((first, second)) = line.split_once(" ") {
-                                match first {
-                                    "Location:" => {
-                                        if let Ok(ip) = embassy_net::Ipv4Address::from_str(second) {
-                                            _printer_ip = Some(ip);
-                                        }
-                                    }
-                                    "DevName.bambu.com:" => {
-                                        printer_name = Some(String::from(second));
-                                        return printer_name;
-                                    }
-                                    _ => (),
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        } else {
-            return None;
+    fn filament() -> FilamentInfo {
+        FilamentInfo {
+            tray_info_idx: String::from("GFA00"),
+            tray_type: String::from("PLA"),
+            tray_color: String::from("FF0000FF"),
+            nozzle_temp_max: 230,
+            nozzle_temp_min: 190,
+            tray_weight: Some(1000),
+            ..FilamentInfo::new()
         }
     }
+
+    fn spool_info() -> SpoolInfo {
+        SpoolInfo {
+            brand: Some(String::from("Generic")),
+            name: Some(String::from("PLA-Matte-Charcoal-Black-Extra-Long-Name")),
+            diameter: Some(String::from("1.75")),
+            spool_weight: Some(250),
+            lot: Some(String::from("LOT-2025-0001-ABCDEFGHIJKLMNOP")),
+            purchase_date: Some(String::from("2025-03-21")),
+            open_date: Some(String::from("2025-04-02")),
+            price: Some(String::from("19.99")),
+            bed_temp: Some(60),
+        }
+    }
+
+    fn parse(descriptor: &str) -> Result<FilamentInfo, Error> {
+        FilamentInfo::from_descriptor_with_calibrations(descriptor, |_| None)
+    }
+
+    #[test]
+    fn parses_v1_descriptor() {
+        let descriptor = "https://info.filament3d.org/V1?ID=AbCdEfGhIj&M=PLA&C=FF0000FF&NN=190&NX=230&K4=0.020~GFSA00~My%20PLA&FI=GFA00&W=1000";
+        let filament = parse(descriptor).unwrap();
+        assert_eq!(filament.tray_type, "PLA");
+        assert_eq!(filament.tray_color, "FF0000FF");
+        assert_eq!(filament.nozzle_temp_min, 190);
+        assert_eq!(filament.nozzle_temp_max, 230);
+        assert_eq!(filament.tray_info_idx, "GFA00");
+        assert_eq!(filament.tray_weight, Some(1000));
+        assert_eq!(filament.tray_uuid, None);
+        assert!(filament.spool_info.is_empty());
+        let calibration = filament.calibrations.get("0.4").unwrap();
+        assert_eq!(calibration.k_value, "0.02");
+        assert_eq!(calibration.setting_id, "GFSA00");
+        assert_eq!(calibration.name, "My PLA");
+        assert_eq!(calibration.cali_idx, -1);
+    }
+
+    #[test]
+    fn rejects_descriptors_missing_fields() {
+        assert!(matches!(parse("https://example.com/V1?ID=A&M=PLA"), Err(Error::ParseError)));
+        let descriptor = "https://info.filament3d.org/V1?ID=AbCdEfGhIj&M=PLA&C=FF0000FF&NN=190&FI=GFA00";
+        assert!(matches!(parse(descriptor), Err(Error::MissingFields)));
+        let descriptor = "https://info.filament3d.org/V1?ID=AbCdEfGhIj&M=PLA&C=FF0000FF&NN=hot&NX=230&FI=GFA00";
+        assert!(matches!(parse(descriptor), Err(Error::ParseError)));
+    }
+
+    #[test]
+    fn writes_v1_without_spool_info() {
+        let descriptor = filament().to_descriptor(&None);
+        assert_eq!(
+            descriptor,
+            "https://info.filament3d.org/V1?ID=$tag-id$&M=PLA&C=FF0000FF&NN=190&NX=230&FI=GFA00&W=1000"
+        );
+        assert_eq!(parse(&descriptor).unwrap(), filament());
+    }
+
+    #[test]
+    fn round_trips_v2_descriptor() {
+        let filament = FilamentInfo {
+            spool_info: SpoolInfo {
+                brand: Some(String::from("Brand & Co (EU)")),
+                name: Some(String::from("PLA Matte / 50% ~off? 100%")),
+                ..spool_info()
+            },
+            ..filament()
+        };
+        let descriptor = filament.to_descriptor(&None);
+        assert!(descriptor.starts_with("https://info.filament3d.org/V2?ID=$tag-id$&"));
+        assert_eq!(parse(&descriptor).unwrap(), filament);
+    }
+
+    #[test]
+    fn skips_invalid_optional_fields() {
+        let descriptor = "https://info.filament3d.org/V2?ID=AbCdEfGhIj&M=PLA&C=FF0000FF&NN=190&NX=230&FI=GFA00\
+            &W=heavy&B=Generic&PN=&D=1.7.5&SW=-1&P=free&BT=hot&L=42";
+        let filament = parse(descriptor).unwrap();
+        assert_eq!(filament.tray_weight, None);
+        assert_eq!(
+            filament.spool_info,
+            SpoolInfo {
+                brand: Some(String::from("Generic")),
+                lot: Some(String::from("42")),
+                ..SpoolInfo::default()
+            }
+        );
+    }
+
+    #[test]
+    fn leaves_off_least_needed_fields_to_fit_tag() {
+        let bambu_filament = FilamentInfo {
+            tray_uuid: Some(String::from("0123456789ABCDEF0123456789ABCDEF")),
+            spool_info: spool_info(),
+            ..filament()
+        };
+        assert!(bambu_filament.to_descriptor(&None).len() > MAX_DESCRIPTOR_LEN);
+        let (descriptor, left_off) = bambu_filament.to_tag_descriptor(&None);
+        assert!(descriptor.len() <= MAX_DESCRIPTOR_LEN);
+        assert_eq!(left_off, ["Price", "Open Date", "Purchase Date"]);
+        let tag_filament = parse(&descriptor).unwrap();
+        assert_eq!(
+            tag_filament.spool_info,
+            SpoolInfo {
+                price: None,
+                open_date: None,
+                purchase_date: None,
+                ..spool_info()
+            }
+        );
+
+        // fits with nothing left off
+        let (descriptor, left_off) = filament().to_tag_descriptor(&None);
+        assert_eq!(descriptor, filament().to_descriptor(&None));
+        assert!(left_off.is_empty());
+    }
+
+    #[test]
+    fn tag_descriptor_fits_ndef_message() {
+        // The NDEF message length is a single byte, the URI record is its header (1), type length (1), payload length (4),
+        // type (1) and the URI prefix code (1) standing for "https://", so the record is as long as the descriptor
+        let tag_id = "AbCdEfGhIj"; // base64 of a 7 bytes tag UID
+        let max_tag_len = MAX_DESCRIPTOR_LEN + tag_id.len() - TAG_PLACEHOLDER.len() + "&RW=99999".len();
+        assert!(max_tag_len <= u8::MAX as usize);
+
+        let bambu_filament = FilamentInfo {
+            tray_uuid: Some(String::from("0123456789ABCDEF0123456789ABCDEF")),
+            spool_info: spool_info(),
+            ..filament()
+        };
+        let (descriptor, _) = bambu_filament.to_tag_descriptor(&None);
+        let tag_descriptor = descriptor.replace(TAG_PLACEHOLDER, tag_id);
+        let tag_descriptor = crate::spool_inventory::descriptor_with_remaining_weight(&tag_descriptor, 99999);
+        assert!(tag_descriptor.len() <= max_tag_len);
+        assert_eq!(crate::spool_inventory::descriptor_tag_id(&tag_descriptor).as_deref(), Some(tag_id));
+        assert_eq!(crate::spool_inventory::descriptor_remaining_weight(&tag_descriptor), Some(99999));
+        assert_eq!(parse(&tag_descriptor).unwrap(), parse(&descriptor).unwrap());
+    }
 }
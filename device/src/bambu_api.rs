@@ -1,5 +1,6 @@
-use alloc::{format, string::String, vec::Vec};
+use alloc::{format, string::String, vec, vec::Vec};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 
 // ==========================================================================
 
@@ -31,6 +32,8 @@ pub struct Filament {
     pub k_value: String,
     pub n_coef: String,
     pub setting_id: String,
+    pub extruder_id: Option<u32>, // only on multi extruder printers (H2D)
+    pub nozzle_id: Option<String>, // nozzle type and diameter, e.g. "HS00-0.4", only on printers that keep calibrations per nozzle type
     // pub tray_id: Option<i32>, // ??? why is it here? In extrusion_cali_set it can exist (case when adding new calibration)
     pub cali_idx: i32, // Need to switch to optional since in extrusion_cali_set it is missing at least sometimes (case when adding new calibration)
 }
@@ -56,7 +59,7 @@ pub struct PrintData {
     // pub hw_switch_state: Option<i64>,
     // pub spd_mag: Option<i64>,
     // pub spd_lvl: Option<i64>,
-    // pub print_error: Option<i64>,
+    pub print_error: Option<i64>, // error the printer is paused on, 0 when none, e.g. 0x07008011 AMS A filament ran out
     // pub lifecycle: Option<String>,
     // pub wifi_signal: Option<String>,
     // pub gcode_state: Option<String>,
@@ -74,7 +77,7 @@ pub struct PrintData {
     // pub stg: Option<Vec<Value>>,
     // pub stg_cur: Option<i64>,
     // pub print_type: Option<String>,
-    // pub home_flag: Option<i64>,
+    pub home_flag: Option<i64>, // status bits, AMS settings: bit 7 remaining filament estimate, bit 10 auto refill
     // pub mc_print_line_number: Option<String>,
     // pub mc_print_sub_stage: Option<i64>,
     // pub sdcard: Option<bool>,
@@ -89,6 +92,8 @@ pub struct PrintData {
     pub ams: Option<PrintAms>,
     // pub ipcam: Option<PrintIpcam>,
     pub vt_tray: Option<PrintTray>, // was PrintVtTray
+    pub vir_slot: Option<Vec<PrintTray>>, // external trays on multi extruder printers (H2D), ids 254 and 255
+    pub device: Option<PrintDevice>,
     // pub lights_report: Option<Vec<PrintLightsReport>>,
     // pub upgrade_state: Option<PrintUpgradeState>,
     pub command: Option<String>,
@@ -110,10 +115,64 @@ pub struct PrintData {
     pub result: Option<String>,
 
     pub nozzle_diameter: Option<String>, // sometimes received, required so to be sent in extruder_cali commane as below after filament setting (like slicer)
+    pub nozzle_type: Option<String>,     // e.g. "hardened_steel"
+    pub extruder_id: Option<u32>,        // in extrusion_cali_* responses on multi extruder printers (H2D)
     pub filament_id: Option<String>,
     pub filaments: Option<Vec<Filament>>,
 }
 
+// Extruders and nozzles, reported by multi extruder printers (H2D)
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintDevice {
+    pub nozzle: Option<PrintNozzle>,
+    pub extruder: Option<PrintExtruder>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintNozzle {
+    pub info: Vec<PrintNozzleInfo>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintNozzleInfo {
+    pub id: u32,       // extruder id, 0 - main (right), 1 - deputy (left)
+    pub diameter: f32, // e.g. 0.4
+    #[serde(rename = "type")]
+    pub nozzle_type: Option<String>, // e.g. "HS01"
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintExtruder {
+    pub state: Option<u32>, // bits 4-7 the extruder in use, bits 8-11 the extruder the printer is switching to
+    pub info: Vec<PrintExtruderInfo>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintExtruderInfo {
+    pub id: u32,           // extruder id, as in PrintNozzleInfo
+    pub snow: Option<u32>, // tray loaded into the extruder, ams_id << 8 | slot, 0xFFFF for none
+    pub star: Option<u32>, // tray the extruder is switching to, same encoding
+}
+
+// "device": {
+//   "nozzle": {
+//     "info": [
+//       { "id": 0, "diameter": 0.4, "type": "HS01", ... },
+//       { "id": 1, "diameter": 0.4, "type": "HS01", ... }
+//     ],
+//     ...
+//   },
+//   "extruder": {
+//     "state": 529,
+//     "info": [
+//       { "id": 0, "snow": 65280, "star": 65280, ... },   <- external tray 255 (ams_id 255, slot 0)
+//       { "id": 1, "snow": 1, "star": 1, ... }            <- AMS 0 slot 1
+//     ]
+//   },
+//   ...
+// }
+// (as Bambu Studio decodes them, multi extruder printers only)
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PrintAms {
     // Several AMS's - AMS as a System
@@ -122,14 +181,14 @@ pub struct PrintAms {
     pub ams_exist_bits: Option<String>,
     pub tray_exist_bits: Option<String>,
     pub tray_is_bbl_bits: Option<String>,
-    // pub tray_tar: Option<String>,
-    // pub tray_now: Option<String>,
+    pub tray_tar: Option<String>, // tray the printer is switching to, "255" for none (unloading), see PrintExtruderInfo for H2D
+    pub tray_now: Option<String>, // tray loaded into the extruder, "255" for none, see PrintExtruderInfo for H2D
     // pub tray_pre: Option<String>,
     pub tray_read_done_bits: Option<String>,
     pub tray_reading_bits: Option<String>,
     // pub version: Option<i64>,
-    // pub insert_flag: Option<bool>,
-    // pub power_on_flag: Option<bool>,
+    pub insert_flag: Option<bool>,   // AMS setting, read RFID of spools on insertion
+    pub power_on_flag: Option<bool>, // AMS setting, read RFID of spools on startup
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -137,6 +196,8 @@ pub struct PrintAmsData {
     // A Specific AMS
     pub id: String,
     pub humidity: String,
+    pub info: Option<String>, // hex flags, bits 0-3 the AMS type, on multi extruder printers bits 8-11 are the extruder the AMS feeds
+    pub dry_time: Option<u32>, // minutes left of drying, 0 when not drying (AMS units that can dry filament)
     // pub temp: String,
     pub tray: Vec<PrintTray>, // Vector of Trays
 }
@@ -162,19 +223,39 @@ pub struct PrintTray {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default, serialize_with = "option_u32_as_str_se", deserialize_with = "option_u32_as_str_de")]
     pub nozzle_temp_min: Option<u32>, // w.g. 190
-                                      // pub remain: Option<i64>,
-                                      // pub n: Option<f64>,
-                                      // pub tag_uid: Option<String>,
-                                      // pub tray_id_name: Option<String>,
-                                      // pub tray_sub_brands: Option<String>,
-                                      // pub tray_weight: Option<String>,
-                                      // pub tray_diameter: Option<String>,
-                                      // pub tray_temp: Option<String>,
-                                      // pub tray_time: Option<String>,
-                                      // pub bed_temp_type: Option<String>,
-                                      // pub bed_temp: Option<String>,
-                                      // pub xcam_info: Option<String>,
-                                      // pub tray_uuid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remain: Option<i32>, // e.g. 85 (percent), -1 when unknown
+    // pub n: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag_uid: Option<String>, // e.g. "0000000000000000" when no RFID tag
+    // pub tray_id_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tray_sub_brands: Option<String>, // e.g. "PLA Basic"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tray_weight: Option<String>, // e.g. "1000" (grams), "0" when unknown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tray_diameter: Option<String>, // e.g. "1.75"
+    // pub tray_temp: Option<String>,
+    // pub tray_time: Option<String>,
+    // pub bed_temp_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bed_temp: Option<String>, // e.g. "35", "0" when unknown
+    // pub xcam_info: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tray_uuid: Option<String>, // e.g. "00000000000000000000000000000000" when not a Bambu spool
+}
+
+impl PrintTray {
+    // The AMS reports zeros in tag_uid/tray_uuid when no Bambu RFID tag was read
+    pub fn bambu_tray_uuid(&self) -> Option<&str> {
+        self.tray_uuid
+            .as_deref()
+            .filter(|v| !v.is_empty() && v.chars().any(|c| c != '0'))
+    }
+
+    pub fn tray_weight_grams(&self) -> Option<u32> {
+        self.tray_weight.as_ref().and_then(|v| v.parse::<u32>().ok()).filter(|v| *v != 0)
+    }
 }
 // TODO: check if can consolidate the two types of trays to a single one(only difference is optional items for serde?)
 // External Tray - One per printer
@@ -213,8 +294,71 @@ pub struct PrintTray {
 //     // pub n: i64,
 // }
 
+// Printer State
+
+// Applies a push_status delta onto the merged printer state, field by field
+// Arrays of objects with "id" (ams units, trays, nozzles) are merged per id, elements missing from the delta are kept,
+// an element with only its "id" (an emptied tray) replaces the kept one. Other arrays and values are replaced
+// Removed elements (e.g. a disconnected AMS unit) stay in the state until the next full report, see ams_units_changed
+pub fn merge_printer_state(state: &mut Value, delta: Value) {
+    match (state, delta) {
+        (Value::Object(state), Value::Object(delta)) => {
+            for (key, value) in delta {
+                match state.get_mut(&key) {
+                    Some(state_value) => merge_printer_state(state_value, value),
+                    None => {
+                        state.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(state), Value::Array(delta)) if is_id_array(state) && is_id_array(&delta) => {
+            for value in delta {
+                let id_only = value.as_object().is_some_and(|value| value.len() == 1);
+                match state.iter_mut().find(|v| v.get("id") == value.get("id")) {
+                    Some(state_value) if id_only => *state_value = value,
+                    Some(state_value) => merge_printer_state(state_value, value),
+                    None => state.push(value),
+                }
+            }
+        }
+        (state, delta) => *state = delta,
+    }
+}
+
+fn is_id_array(array: &[Value]) -> bool {
+    array.iter().all(|v| v.get("id").is_some())
+}
+
+// Whether a push_status delta reports different connected AMS units than the merged state, the state then needs a full
+// report since merging doesn't remove the units that were disconnected
+pub fn ams_units_changed(state: &Value, delta: &Value) -> bool {
+    let ams_exist_bits = |print: &Value| print.get("ams").and_then(|ams| ams.get("ams_exist_bits")).cloned();
+    ams_exist_bits(delta).is_some_and(|bits| Some(bits) != ams_exist_bits(state))
+}
+
 // Commands
 
+// Commands get a unique sequence_id when published, the printer echoes it in its response (with result / reason)
+// which is how responses are matched to the commands (see BambuPrinter::publish_command)
+pub trait Command: Serialize {
+    fn command(&self) -> &str;
+    fn set_sequence_id(&mut self, sequence_id: u32);
+}
+
+macro_rules! impl_command {
+    ($command_type:ty, $section:ident) => {
+        impl Command for $command_type {
+            fn command(&self) -> &str {
+                &self.$section.command
+            }
+            fn set_sequence_id(&mut self, sequence_id: u32) {
+                self.$section.sequence_id = format!("{sequence_id}");
+            }
+        }
+    };
+}
+
 ////////
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -224,7 +368,8 @@ pub struct PushAllCommand {
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PushAll {
-    pub command: String, // ams_filament_setting
+    pub command: String, // pushall
+    pub sequence_id: String,
 }
 
 impl PushAllCommand {
@@ -232,11 +377,14 @@ impl PushAllCommand {
         Self {
             pushing: PushAll {
                 command: String::from("pushall"),
+                sequence_id: String::new(), // assigned when published
             },
         }
     }
 }
 
+impl_command!(PushAllCommand, pushing);
+
 ///////
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -250,6 +398,8 @@ pub struct AmsFilamentSetting {
     pub ams_id: u32,
     // #[serde(serialize_with = "u32_as_str_se", deserialize_with = "u32_as_str_de")]
     pub tray_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot_id: Option<i32>, // only on multi extruder printers (H2D)
     pub tray_info_idx: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub setting_id: Option<String>,
@@ -266,6 +416,7 @@ impl AmsFilamentSettingCommand {
     pub fn new(
         ams_id: u32,
         tray_id: i32,
+        slot_id: Option<i32>,
         tray_info_idx: &str,
         setting_id: Option<&str>,
         tray_type: &str,
@@ -278,18 +429,21 @@ impl AmsFilamentSettingCommand {
                 command: String::from("ams_filament_setting"),
                 ams_id,
                 tray_id,
+                slot_id,
                 tray_info_idx: String::from(tray_info_idx),
                 setting_id: setting_id.map(|v| String::from(v)),
                 tray_color: String::from(tray_color),
                 nozzle_temp_min,
                 nozzle_temp_max,
                 tray_type: String::from(tray_type),
-                sequence_id: String::from("1"),
+                sequence_id: String::new(), // assigned when published
             },
         }
     }
 }
 
+impl_command!(AmsFilamentSettingCommand, print);
+
 fn u32_as_str_se<S>(x: &u32, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -334,6 +488,9 @@ where
 //     "tray_type": "PLA"
 // }
 //  }"#;
+//
+// On multi extruder printers (H2D) the external trays are set with ams_id 255 (main extruder) or 254 (deputy extruder),
+// tray_id 254 and slot_id 0
 
 ////////////////////////////////////////////////////////////
 
@@ -347,22 +504,27 @@ pub struct ExtrusionCaliGet {
     pub command: String,     // extrusion_cali_get
     pub filament_id: String, // always empty
     pub nozzle_diameter: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extruder_id: Option<u32>, // only on multi extruder printers (H2D)
     pub sequence_id: String,
 }
 
 impl ExtrusionCaliGetCommand {
-    pub fn new(nozzle_diameter: &str) -> Self {
+    pub fn new(nozzle_diameter: &str, extruder_id: Option<u32>) -> Self {
         Self {
             print: ExtrusionCaliGet {
                 command: String::from("extrusion_cali_get"),
                 filament_id: String::from(""),
                 nozzle_diameter: String::from(nozzle_diameter),
-                sequence_id: String::from("1"),
+                extruder_id,
+                sequence_id: String::new(), // assigned when published
             },
         }
     }
 }
 
+impl_command!(ExtrusionCaliGetCommand, print);
+
 // {
 //   "print": {
 //     "command": "extrusion_cali_get",
@@ -384,11 +546,13 @@ pub struct ExtrusionCaliSel {
     pub filament_id: String, // always empty
     pub nozzle_diameter: String,
     pub tray_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extruder_id: Option<u32>, // only on multi extruder printers (H2D)
     pub sequence_id: String,
 }
 
 impl ExtrusionCaliSelCommand {
-    pub fn new(nozzle_diameter: &str, tray_id: i32, filament_id: &str, cali_idx: Option<i32>) -> Self {
+    pub fn new(nozzle_diameter: &str, tray_id: i32, filament_id: &str, cali_idx: Option<i32>, extruder_id: Option<u32>) -> Self {
         Self {
             print: ExtrusionCaliSel {
                 command: String::from("extrusion_cali_sel"),
@@ -396,12 +560,15 @@ impl ExtrusionCaliSelCommand {
                 filament_id: String::from(filament_id),
                 nozzle_diameter: String::from(nozzle_diameter),
                 tray_id,
-                sequence_id: String::from("1"),
+                extruder_id,
+                sequence_id: String::new(), // assigned when published
             },
         }
     }
 }
 
+impl_command!(ExtrusionCaliSelCommand, print);
+
 // {
 //   "print": {
 //     "cali_idx": -1,
@@ -414,3 +581,442 @@ impl ExtrusionCaliSelCommand {
 //     "result": "success"
 //   }
 // }
+
+///////////////////////////////////////////////////////////////////
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtrusionCaliSetCommand {
+    print: ExtrusionCaliSet,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtrusionCaliSet {
+    pub command: String, // extrusion_cali_set
+    pub filaments: Vec<ExtrusionCaliSetFilament>,
+    pub nozzle_diameter: String,
+    pub sequence_id: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtrusionCaliSetFilament {
+    pub filament_id: String,
+    pub k_value: String,
+    pub n_coef: String,
+    pub name: String,
+    pub setting_id: String,
+    pub tray_id: i32, // -1 when calibration isn't set for a specific tray
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cali_idx: Option<i32>, // only when modifying an existing calibration, missing when adding a new one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extruder_id: Option<u32>, // only on multi extruder printers (H2D)
+}
+
+impl ExtrusionCaliSetCommand {
+    pub fn new(
+        nozzle_diameter: &str,
+        filament_id: &str,
+        setting_id: &str,
+        name: &str,
+        k_value: &str,
+        cali_idx: Option<i32>,
+        extruder_id: Option<u32>,
+    ) -> Self {
+        Self {
+            print: ExtrusionCaliSet {
+                command: String::from("extrusion_cali_set"),
+                filaments: vec![ExtrusionCaliSetFilament {
+                    filament_id: String::from(filament_id),
+                    k_value: String::from(k_value),
+                    n_coef: String::from("0.000000"),
+                    name: String::from(name),
+                    setting_id: String::from(setting_id),
+                    tray_id: -1,
+                    cali_idx,
+                    extruder_id,
+                }],
+                nozzle_diameter: String::from(nozzle_diameter),
+                sequence_id: String::new(), // assigned when published
+            },
+        }
+    }
+}
+
+impl_command!(ExtrusionCaliSetCommand, print);
+
+// {
+//   "print": {
+//     "command": "extrusion_cali_set",
+//     "filaments": [
+//       {
+//         "filament_id": "GFL99",
+//         "k_value": "0.020000",
+//         "n_coef": "0.000000",
+//         "name": "Generic PLA Red",
+//         "setting_id": "GFSL99",
+//         "tray_id": -1
+//       }
+//     ],
+//     "nozzle_diameter": "0.4",
+//     "sequence_id": "1"
+//   }
+// }
+///////////////////////////////////////////////////////////////////
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtrusionCaliDelCommand {
+    print: ExtrusionCaliDel,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtrusionCaliDel {
+    pub command: String, // extrusion_cali_del
+    pub cali_idx: i32,
+    pub filament_id: String,
+    pub nozzle_diameter: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extruder_id: Option<u32>, // only on multi extruder printers (H2D)
+    pub sequence_id: String,
+}
+
+impl ExtrusionCaliDelCommand {
+    pub fn new(nozzle_diameter: &str, filament_id: &str, cali_idx: i32, extruder_id: Option<u32>) -> Self {
+        Self {
+            print: ExtrusionCaliDel {
+                command: String::from("extrusion_cali_del"),
+                cali_idx,
+                filament_id: String::from(filament_id),
+                nozzle_diameter: String::from(nozzle_diameter),
+                extruder_id,
+                sequence_id: String::new(), // assigned when published
+            },
+        }
+    }
+}
+
+impl_command!(ExtrusionCaliDelCommand, print);
+
+// {
+//   "print": {
+//     "command": "extrusion_cali_del",
+//     "cali_idx": 5,
+//     "filament_id": "GFL99",
+//     "nozzle_diameter": "0.4",
+//     "sequence_id": "1"
+//   }
+// }
+
+///////////////////////////////////////////////////////////////////
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AmsChangeFilamentCommand {
+    print: AmsChangeFilament,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AmsChangeFilament {
+    pub command: String, // ams_change_filament
+    pub target: u32,     // tray id to load (ams_id * 4 + slot, 254 for external), UNLOAD_TARGET to unload
+    pub curr_temp: u32,  // nozzle temperature for the filament currently loaded
+    pub tar_temp: u32,   // nozzle temperature for the filament to load
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ams_id: Option<u32>, // only on multi extruder printers (H2D)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot_id: Option<u32>, // only on multi extruder printers (H2D)
+    pub sequence_id: String,
+}
+
+impl AmsChangeFilamentCommand {
+    pub fn new(target: u32, curr_temp: u32, tar_temp: u32, ams_id: Option<u32>, slot_id: Option<u32>) -> Self {
+        Self {
+            print: AmsChangeFilament {
+                command: String::from("ams_change_filament"),
+                target,
+                curr_temp,
+                tar_temp,
+                ams_id,
+                slot_id,
+                sequence_id: String::new(), // assigned when published
+            },
+        }
+    }
+}
+
+impl_command!(AmsChangeFilamentCommand, print);
+
+// {
+//   "print": {
+//     "command": "ams_change_filament",
+//     "target": 1,
+//     "curr_temp": 220,
+//     "tar_temp": 220,
+//     "sequence_id": "1"
+//   }
+// }
+//
+// Unloading is the same command with target UNLOAD_TARGET. While changing, the ams section reports the target in tray_tar
+// and tray_now switches to it once loaded (on multi extruder printers the extruders info reports them, see PrintExtruderInfo)
+
+// Target of ams_change_filament for unloading, on multi extruder printers also its ams_id and slot_id. It isn't a tray id,
+// though 255 is also the id of the main extruder external tray on multi extruder printers. This is the unload that
+// Bambu Studio sends (MachineObject::command_ams_change_filament in DeviceManager.cpp)
+pub const UNLOAD_TARGET: u32 = 255;
+
+///////////////////////////////////////////////////////////////////
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AmsFilamentDryingCommand {
+    print: AmsFilamentDrying,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AmsFilamentDrying {
+    pub command: String, // ams_filament_drying
+    pub ams_id: u32,
+    pub mode: u32,         // 1 to start drying, 0 to stop
+    pub temp: u32,         // °C
+    pub cooling_temp: u32, // °C, the AMS cools down to it at the end of drying
+    pub duration: u32,     // hours
+    pub humidity: u32,     // target humidity, 0 for drying the full duration
+    pub rotate_tray: bool,
+    pub filament: String, // tray_type the drying is for, e.g. PLA
+    pub close_power_conflict: bool,
+    pub sequence_id: String,
+}
+
+impl AmsFilamentDryingCommand {
+    pub fn new(ams_id: u32, start: bool, temp: u32, duration: u32, filament: &str) -> Self {
+        Self {
+            print: AmsFilamentDrying {
+                command: String::from("ams_filament_drying"),
+                ams_id,
+                mode: if start { 1 } else { 0 },
+                temp,
+                cooling_temp: 45,
+                duration,
+                humidity: 0,
+                rotate_tray: false,
+                filament: String::from(filament),
+                close_power_conflict: false,
+                sequence_id: String::new(), // assigned when published
+            },
+        }
+    }
+}
+
+impl_command!(AmsFilamentDryingCommand, print);
+
+// {
+//   "print": {
+//     "command": "ams_filament_drying",
+//     "ams_id": 0,
+//     "mode": 1,
+//     "temp": 55,
+//     "cooling_temp": 45,
+//     "duration": 8,
+//     "humidity": 0,
+//     "rotate_tray": false,
+//     "filament": "PLA",
+//     "close_power_conflict": false,
+//     "sequence_id": "1"
+//   }
+// }
+//
+// Stopping is the same command with mode 0. While drying, the AMS reports the minutes left in dry_time
+
+///////////////////////////////////////////////////////////////////
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AmsUserSettingCommand {
+    print: AmsUserSetting,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AmsUserSetting {
+    pub command: String, // ams_user_setting
+    pub ams_id: u32,     // the settings apply to all AMS units
+    pub startup_read_option: bool,
+    pub tray_read_option: bool,
+    pub calibrate_remain_flag: bool,
+    pub sequence_id: String,
+}
+
+impl AmsUserSettingCommand {
+    pub fn new(startup_read_option: bool, tray_read_option: bool, calibrate_remain_flag: bool) -> Self {
+        Self {
+            print: AmsUserSetting {
+                command: String::from("ams_user_setting"),
+                ams_id: 0,
+                startup_read_option,
+                tray_read_option,
+                calibrate_remain_flag,
+                sequence_id: String::new(), // assigned when published
+            },
+        }
+    }
+}
+
+impl_command!(AmsUserSettingCommand, print);
+
+// {
+//   "print": {
+//     "command": "ams_user_setting",
+//     "ams_id": 0,
+//     "startup_read_option": true,
+//     "tray_read_option": true,
+//     "calibrate_remain_flag": true,
+//     "sequence_id": "1"
+//   }
+// }
+//
+// The current values are reported in push_status, startup_read_option as ams.power_on_flag, tray_read_option as
+// ams.insert_flag and calibrate_remain_flag as bit 7 of home_flag
+
+///////////////////////////////////////////////////////////////////
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintOptionCommand {
+    print: PrintOption,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintOption {
+    pub command: String, // print_option
+    pub auto_switch_filament: bool,
+    pub sequence_id: String,
+}
+
+impl PrintOptionCommand {
+    pub fn new(auto_switch_filament: bool) -> Self {
+        Self {
+            print: PrintOption {
+                command: String::from("print_option"),
+                auto_switch_filament,
+                sequence_id: String::new(), // assigned when published
+            },
+        }
+    }
+}
+
+impl_command!(PrintOptionCommand, print);
+
+// {
+//   "print": {
+//     "command": "print_option",
+//     "auto_switch_filament": true,
+//     "sequence_id": "1"
+//   }
+// }
+//
+// AMS auto refill (switching to a backup spool of the same filament when a spool runs out), reported as bit 10 of home_flag
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn merged(state: Value, delta: Value) -> Value {
+        let mut state = state;
+        merge_printer_state(&mut state, delta);
+        state
+    }
+
+    fn state() -> Value {
+        json!({
+            "command": "push_status",
+            "ams": {
+                "ams_exist_bits": "1",
+                "tray_exist_bits": "3",
+                "ams": [{
+                    "id": "0",
+                    "humidity": "4",
+                    "tray": [
+                        { "id": "0", "tray_type": "PLA", "tray_color": "FF0000FF" },
+                        { "id": "1", "tray_type": "PETG", "tray_color": "00FF00FF" },
+                        { "id": "2" },
+                        { "id": "3" }
+                    ]
+                }]
+            },
+            "vt_tray": { "id": "254", "tray_type": "PLA" }
+        })
+    }
+
+    #[test]
+    fn merges_updated_fields() {
+        let delta = json!({
+            "ams": { "ams": [{ "id": "0", "tray": [{ "id": "1", "tray_color": "0000FFFF" }] }] },
+            "vt_tray": { "tray_type": "ABS" }
+        });
+        let state = merged(state(), delta);
+        assert_eq!(state["ams"]["ams"][0]["humidity"], "4");
+        assert_eq!(
+            state["ams"]["ams"][0]["tray"][0],
+            json!({ "id": "0", "tray_type": "PLA", "tray_color": "FF0000FF" })
+        );
+        assert_eq!(
+            state["ams"]["ams"][0]["tray"][1],
+            json!({ "id": "1", "tray_type": "PETG", "tray_color": "0000FFFF" })
+        );
+        assert_eq!(state["vt_tray"], json!({ "id": "254", "tray_type": "ABS" }));
+        assert_eq!(state["ams"]["tray_exist_bits"], "3");
+    }
+
+    #[test]
+    fn adds_new_elements_and_fields() {
+        let delta = json!({
+            "ams": {
+                "ams_exist_bits": "3",
+                "ams": [
+                    { "id": "0", "tray": [{ "id": "2", "tray_type": "ASA", "tray_color": "FFFFFFFF" }] },
+                    { "id": "1", "humidity": "5", "tray": [{ "id": "0", "tray_type": "PLA" }] }
+                ]
+            },
+            "nozzle_diameter": "0.4"
+        });
+        let state = merged(state(), delta);
+        assert_eq!(
+            state["ams"]["ams"][0]["tray"][2],
+            json!({ "id": "2", "tray_type": "ASA", "tray_color": "FFFFFFFF" })
+        );
+        assert_eq!(
+            state["ams"]["ams"][1],
+            json!({ "id": "1", "humidity": "5", "tray": [{ "id": "0", "tray_type": "PLA" }] })
+        );
+        assert_eq!(state["ams"]["ams_exist_bits"], "3");
+        assert_eq!(state["nozzle_diameter"], "0.4");
+    }
+
+    #[test]
+    fn replaces_emptied_trays() {
+        let delta = json!({
+            "ams": { "tray_exist_bits": "2", "ams": [{ "id": "0", "tray": [{ "id": "0" }] }] }
+        });
+        let state = merged(state(), delta);
+        assert_eq!(state["ams"]["ams"][0]["tray"][0], json!({ "id": "0" }));
+        assert_eq!(state["ams"]["ams"][0]["tray"][1]["tray_type"], "PETG");
+        let print = PrintData::deserialize(&state).unwrap();
+        let trays = &print.ams.unwrap().ams.unwrap()[0].tray;
+        assert_eq!(trays[0].tray_type, None);
+        assert_eq!(trays[1].tray_type.as_deref(), Some("PETG"));
+    }
+
+    #[test]
+    fn keeps_removed_units_until_full_report() {
+        let delta = json!({ "ams": { "ams_exist_bits": "0", "ams": [] } });
+        assert!(ams_units_changed(&state(), &delta));
+        let state = merged(state(), delta);
+        assert_eq!(state["ams"]["ams"].as_array().unwrap().len(), 1);
+
+        assert!(!ams_units_changed(&state, &json!({ "ams": { "ams_exist_bits": "0" } })));
+        assert!(!ams_units_changed(&state, &json!({ "ams": { "tray_exist_bits": "0" } })));
+        assert!(!ams_units_changed(&state, &json!({ "vt_tray": { "tray_type": "PLA" } })));
+    }
+
+    #[test]
+    fn replaces_other_arrays_and_values() {
+        let state = merged(json!({ "filaments": ["a", "b"], "msg": 0 }), json!({ "filaments": ["c"], "msg": 1 }));
+        assert_eq!(state, json!({ "filaments": ["c"], "msg": 1 }));
+        let state = merged(json!({ "list": [{ "id": 1 }] }), json!({ "list": [{ "name": "x" }] }));
+        assert_eq!(state, json!({ "list": [{ "name": "x" }] }));
+    }
+}
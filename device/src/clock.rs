@@ -0,0 +1,143 @@
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::rc::Rc;
+use embassy_net::{udp::PacketMetadata, IpEndpoint, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use framework::prelude::*;
+
+use crate::app_config::AppConfig;
+
+// Wall clock time, the device has no RTC so the time is taken from an SNTP server once connected to the network.
+// The network stack has no DNS, so the server is addressed by ip, set in the config file ([clock] ntp_server).
+// Without it the time isn't synced, so the device doesn't contact a server outside the network unless told to
+
+const NTP_PORT: u16 = 123;
+const NTP_MODE_SERVER: u8 = 4;
+const NTP_TO_UNIX_SECS: u32 = 2_208_988_800; // NTP time counts from 1900, unix time from 1970
+const NTP_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const SYNC_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+const SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Unix time at boot, 0 until synced
+static BOOT_UNIX_TIME: AtomicU32 = AtomicU32::new(0);
+
+// Unix time in seconds, None until the time was received from the server
+pub fn unix_time() -> Option<u32> {
+    let boot_unix_time = BOOT_UNIX_TIME.load(Ordering::Relaxed);
+    if boot_unix_time == 0 {
+        return None;
+    }
+    Some(boot_unix_time + Instant::now().as_secs() as u32)
+}
+
+pub async fn init(stack: Stack<'static>, app_config: Rc<RefCell<AppConfig>>) {
+    let spawner = embassy_executor::Spawner::for_current_executor().await;
+    spawner.spawn(clock_task(stack, app_config)).ok();
+}
+
+#[embassy_executor::task]
+async fn clock_task(stack: Stack<'static>, app_config: Rc<RefCell<AppConfig>>) {
+    let Some(ntp_server) = app_config.borrow().ntp_server else {
+        info!("No NTP server configured, clock not synced");
+        return;
+    };
+    loop {
+        if stack.config_v4().is_some() {
+            break;
+        }
+        Timer::after(Duration::from_millis(250)).await;
+    }
+
+    let mut rx_buffer = [0; 128];
+    let mut tx_buffer = [0; 128];
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut socket = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    socket.bind(0).unwrap();
+
+    // SNTP request: leap indicator 0, version 3, mode 3 (client), rest zeros
+    let mut request = [0u8; 48];
+    request[0] = 0x1b;
+    let mut buf = [0u8; 128];
+
+    loop {
+        let remote_endpoint = IpEndpoint::new(ntp_server.into(), NTP_PORT);
+        if let Err(e) = socket.send_to(&request, remote_endpoint).await {
+            warn!("Failed to send SNTP request: {:?}", e);
+            Timer::after(SYNC_RETRY_INTERVAL).await;
+            continue;
+        }
+        let unix_time = match with_timeout(NTP_RESPONSE_TIMEOUT, socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, meta))) if meta.endpoint == remote_endpoint => parse_sntp_response(&buf[..len]),
+            Ok(Ok((_, meta))) => {
+                debug!("Ignoring SNTP response from {}, not the queried server", meta.endpoint);
+                None
+            }
+            _ => None,
+        };
+        match unix_time {
+            Some(unix_time) => {
+                let first_sync = BOOT_UNIX_TIME.load(Ordering::Relaxed) == 0;
+                BOOT_UNIX_TIME.store(unix_time - Instant::now().as_secs() as u32, Ordering::Relaxed);
+                if first_sync {
+                    info!("Clock synced, unix time {}", unix_time);
+                }
+                Timer::after(SYNC_INTERVAL).await;
+            }
+            None => {
+                debug!("No valid SNTP response");
+                Timer::after(SYNC_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+// Unix time from the transmit timestamp of an SNTP response, None if it isn't a valid server response
+fn parse_sntp_response(data: &[u8]) -> Option<u32> {
+    if data.len() < 48 || data[0] & 0x07 != NTP_MODE_SERVER {
+        return None;
+    }
+    // transmit timestamp at offset 40, seconds then fraction, zero when the server has no time to give
+    if data[40..48].iter().all(|b| *b == 0) {
+        return None;
+    }
+    u32::from_be_bytes([data[40], data[41], data[42], data[43]]).checked_sub(NTP_TO_UNIX_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sntp_response(mode: u8, transmit_secs: u32) -> [u8; 48] {
+        let mut response = [0u8; 48];
+        response[0] = 0x18 | mode;
+        response[40..44].copy_from_slice(&transmit_secs.to_be_bytes());
+        response[44..48].copy_from_slice(&[0x80, 0, 0, 0]);
+        response
+    }
+
+    #[test]
+    fn parses_sntp_response() {
+        // 2025-01-01 00:00:00 UTC
+        let response = sntp_response(NTP_MODE_SERVER, 1_735_689_600 + NTP_TO_UNIX_SECS);
+        assert_eq!(parse_sntp_response(&response), Some(1_735_689_600));
+    }
+
+    #[test]
+    fn rejects_invalid_sntp_responses() {
+        let response = sntp_response(NTP_MODE_SERVER, 1_735_689_600 + NTP_TO_UNIX_SECS);
+        assert_eq!(parse_sntp_response(&response[..47]), None);
+        // client mode, e.g. the request itself
+        assert_eq!(parse_sntp_response(&sntp_response(3, 1_735_689_600 + NTP_TO_UNIX_SECS)), None);
+        // kiss-o'-death responses have no transmit time
+        let mut response = sntp_response(NTP_MODE_SERVER, 0);
+        response[44] = 0;
+        assert_eq!(parse_sntp_response(&response), None);
+        // before 1970
+        assert_eq!(parse_sntp_response(&sntp_response(NTP_MODE_SERVER, 1000)), None);
+    }
+}
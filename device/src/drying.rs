@@ -0,0 +1,108 @@
+// Drying presets for AMS units that can dry filament (AMS 2 Pro, AMS HT), by the filament type (tray_type) of the spools
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DryingPreset {
+    pub material: &'static str,
+    pub temp: u32,     // °C
+    pub duration: u32, // hours
+}
+
+const fn preset(material: &'static str, temp: u32, duration: u32) -> DryingPreset {
+    DryingPreset { material, temp, duration }
+}
+
+// Matched by the longest material that starts the tray_type, so e.g. PLA-CF uses PLA and PA6-GF uses PA
+// Temperatures above what the AMS supports are lowered to its maximum (see combined_preset)
+const DRYING_PRESETS: [DryingPreset; 15] = [
+    preset("PLA", 55, 8),
+    preset("PETG", 65, 8),
+    preset("PET", 65, 8),
+    preset("TPU", 65, 8),
+    preset("PVA", 65, 8),
+    preset("BVOH", 65, 8),
+    preset("HIPS", 70, 8),
+    preset("ABS", 80, 8),
+    preset("ASA", 80, 8),
+    preset("PC", 80, 8),
+    preset("PA", 85, 12),
+    preset("PAHT", 85, 12),
+    preset("PPA", 85, 12),
+    preset("PPS", 85, 12),
+    preset("PET-CF", 85, 12),
+];
+
+pub fn preset_for(tray_type: &str) -> Option<&'static DryingPreset> {
+    let tray_type = tray_type.trim().to_ascii_uppercase();
+    DRYING_PRESETS
+        .iter()
+        .filter(|preset| tray_type.starts_with(preset.material))
+        .max_by_key(|preset| preset.material.len())
+}
+
+// Spools in an AMS are dried together, so the lowest temperature (not to soften any of them) for the longest duration
+// Returns None if none of the tray types has a preset
+pub fn combined_preset<'a>(tray_types: impl Iterator<Item = &'a str>, max_temp: u32) -> Option<DryingPreset> {
+    let mut combined: Option<DryingPreset> = None;
+    for preset in tray_types.filter_map(preset_for) {
+        combined = Some(match combined {
+            None => *preset,
+            Some(curr) => DryingPreset {
+                material: if preset.temp < curr.temp { preset.material } else { curr.material },
+                temp: curr.temp.min(preset.temp),
+                duration: curr.duration.max(preset.duration),
+            },
+        });
+    }
+    combined.map(|preset| DryingPreset {
+        temp: preset.temp.min(max_temp),
+        ..preset
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bambu::{AMS_2_PRO_MAX_DRYING_TEMP, AMS_HT_MAX_DRYING_TEMP};
+
+    fn combined(tray_types: &[&str], max_temp: u32) -> Option<DryingPreset> {
+        combined_preset(tray_types.iter().copied(), max_temp)
+    }
+
+    #[test]
+    fn ams_2_pro_presets() {
+        assert_eq!(combined(&["PLA"], AMS_2_PRO_MAX_DRYING_TEMP), Some(preset("PLA", 55, 8)));
+        assert_eq!(combined(&["PETG"], AMS_2_PRO_MAX_DRYING_TEMP), Some(preset("PETG", 65, 8)));
+        // lowered to what the AMS 2 Pro supports, keeping the duration
+        assert_eq!(combined(&["ABS"], AMS_2_PRO_MAX_DRYING_TEMP), Some(preset("ABS", 65, 8)));
+        assert_eq!(combined(&["PA6-CF"], AMS_2_PRO_MAX_DRYING_TEMP), Some(preset("PA", 65, 12)));
+    }
+
+    #[test]
+    fn ams_ht_presets() {
+        assert_eq!(combined(&["PLA"], AMS_HT_MAX_DRYING_TEMP), Some(preset("PLA", 55, 8)));
+        assert_eq!(combined(&["ABS"], AMS_HT_MAX_DRYING_TEMP), Some(preset("ABS", 80, 8)));
+        assert_eq!(combined(&["PAHT-CF"], AMS_HT_MAX_DRYING_TEMP), Some(preset("PAHT", 85, 12)));
+        assert_eq!(combined(&["PET-CF"], AMS_HT_MAX_DRYING_TEMP), Some(preset("PET-CF", 85, 12)));
+    }
+
+    #[test]
+    fn mixed_materials_dry_at_lowest_temp_for_longest_duration() {
+        assert_eq!(
+            combined(&["PETG", "PLA", "PLA-CF", "PETG"], AMS_HT_MAX_DRYING_TEMP),
+            Some(preset("PLA", 55, 8))
+        );
+        assert_eq!(
+            combined(&["PA", "ABS", "ASA", "PPS"], AMS_HT_MAX_DRYING_TEMP),
+            Some(preset("ABS", 80, 12))
+        );
+        assert_eq!(combined(&["PC", "PETG"], AMS_2_PRO_MAX_DRYING_TEMP), Some(preset("PETG", 65, 8)));
+        assert_eq!(combined(&["PC", "PPA"], AMS_2_PRO_MAX_DRYING_TEMP), Some(preset("PC", 65, 12)));
+    }
+
+    #[test]
+    fn unknown_materials_are_ignored() {
+        assert_eq!(combined(&["", "Wood", "abs "], AMS_HT_MAX_DRYING_TEMP), Some(preset("ABS", 80, 8)));
+        assert_eq!(combined(&["Wood", ""], AMS_HT_MAX_DRYING_TEMP), None);
+        assert_eq!(combined(&[], AMS_2_PRO_MAX_DRYING_TEMP), None);
+    }
+}
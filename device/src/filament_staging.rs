@@ -1,17 +1,22 @@
+use alloc::string::String;
+
 use crate::bambu::Filament;
 
 pub struct FilamentStaging {
     pub filament_info: Filament,
+    pub tag_id: Option<String>, // tag of the staged spool, for the spool inventory
 }
 
 impl FilamentStaging {
     pub fn new() -> Self {
         Self {
             filament_info: Filament::Unknown,
+            tag_id: None,
         }
     }
 
     pub fn clear(&mut self) {
         self.filament_info = Filament::Unknown;
+        self.tag_id = None;
     }
 }
@@ -0,0 +1,329 @@
+use alloc::{string::String, vec::Vec};
+
+// Strings (keys and values) longer than this are dropped (with their key) instead of kept in memory
+const MAX_TOKEN_LEN: usize = 1024;
+
+// Decides if a field is kept, gets the keys path of the field, e.g. ["print", "ams", "ams", "tray", "tray_color"]
+// (arrays are transparent, so fields of array elements have the path of the array)
+pub type KeepField = fn(path: &[String]) -> bool;
+
+enum Lexer {
+    Between,
+    String { escape: bool },
+    Scalar, // number, true, false, null
+}
+
+struct Frame {
+    object: bool,
+    expect_key: bool,
+    pending_key: Option<Vec<u8>>, // key of the value about to arrive, if it is kept
+    count: usize,                 // values written
+}
+
+// Incremental JSON filter, gets the json in chunks as they arrive and builds a compact json with only the kept fields.
+// The whole json is never held in memory, only the filtered output (limited to max_len, values that don't fit are dropped
+// and the output is marked truncated, but it is still valid json)
+pub struct JsonFilter {
+    keep: KeepField,
+    max_len: usize,
+    output: Vec<u8>,
+    truncated: bool,
+
+    lexer: Lexer,
+    token: Vec<u8>,
+    token_overflow: bool,
+
+    frames: Vec<Frame>,
+    path: Vec<String>,   // current key of each object frame
+    skip_nesting: usize, // depth inside a container being dropped
+}
+
+impl JsonFilter {
+    pub fn new(keep: KeepField, max_len: usize) -> Self {
+        Self {
+            keep,
+            max_len,
+            output: Vec::new(),
+            truncated: false,
+            lexer: Lexer::Between,
+            token: Vec::new(),
+            token_overflow: false,
+            frames: Vec::new(),
+            path: Vec::new(),
+            skip_nesting: 0,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        for &b in data {
+            self.feed_byte(b);
+        }
+    }
+
+    // Returns the filtered json and whether fields were dropped because it reached max_len
+    pub fn finish(mut self) -> (Vec<u8>, bool) {
+        if matches!(self.lexer, Lexer::Scalar) {
+            // scalar at the root, nothing follows it
+            self.complete_token(false);
+        }
+        (self.output, self.truncated)
+    }
+
+    fn feed_byte(&mut self, b: u8) {
+        match self.lexer {
+            Lexer::String { escape } => {
+                if escape {
+                    self.push_token_byte(b);
+                    self.lexer = Lexer::String { escape: false };
+                } else if b == b'\\' {
+                    self.push_token_byte(b);
+                    self.lexer = Lexer::String { escape: true };
+                } else if b == b'"' {
+                    self.lexer = Lexer::Between;
+                    self.complete_token(true);
+                } else {
+                    self.push_token_byte(b);
+                }
+                return;
+            }
+            Lexer::Scalar => {
+                if matches!(b, b',' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n') {
+                    self.lexer = Lexer::Between;
+                    self.complete_token(false);
+                    // the delimiter is processed below
+                } else {
+                    self.push_token_byte(b);
+                    return;
+                }
+            }
+            Lexer::Between => (),
+        }
+
+        match b {
+            b'{' => self.begin_container(true),
+            b'[' => self.begin_container(false),
+            b'}' | b']' => self.end_container(),
+            b',' => self.comma(),
+            b'"' => self.lexer = Lexer::String { escape: false },
+            b':' | b' ' | b'\t' | b'\r' | b'\n' => (),
+            _ => {
+                self.lexer = Lexer::Scalar;
+                self.push_token_byte(b);
+            }
+        }
+    }
+
+    fn push_token_byte(&mut self, b: u8) {
+        if self.token.len() < MAX_TOKEN_LEN {
+            self.token.push(b);
+        } else {
+            self.token_overflow = true;
+        }
+    }
+
+    fn complete_token(&mut self, string: bool) {
+        let token = core::mem::take(&mut self.token);
+        let overflow = core::mem::replace(&mut self.token_overflow, false);
+
+        if self.skip_nesting > 0 {
+            // part of a dropped container
+        } else if string && self.frames.last().is_some_and(|frame| frame.object && frame.expect_key) {
+            self.key(&token, overflow);
+        } else if !overflow && self.begin_value(token.len() + if string { 2 } else { 0 }) {
+            if string {
+                self.output.push(b'"');
+                self.output.extend_from_slice(&token);
+                self.output.push(b'"');
+            } else {
+                self.output.extend_from_slice(&token);
+            }
+        }
+
+        self.token = token;
+        self.token.clear();
+    }
+
+    fn key(&mut self, key: &[u8], overflow: bool) {
+        let object_depth = self.frames.iter().filter(|frame| frame.object).count();
+        self.path.truncate(object_depth - 1);
+        self.path.push(String::from_utf8_lossy(key).into_owned());
+        let keep = !overflow && (self.keep)(&self.path);
+        let frame = self.frames.last_mut().unwrap();
+        frame.expect_key = false;
+        frame.pending_key = if keep { Some(Vec::from(key)) } else { None };
+    }
+
+    // Writes what comes before a kept value (separator and key), returns false if the value is dropped.
+    // value_len is the length of the value as written (for containers their opening and closing bytes), it is dropped
+    // if it doesn't fit in max_len along with the closing bytes of the containers it is in
+    fn begin_value(&mut self, value_len: usize) -> bool {
+        let closing_len = self.frames.len();
+        let Some(frame) = self.frames.last_mut() else {
+            // root value
+            if !self.output.is_empty() {
+                return false;
+            }
+            if value_len > self.max_len {
+                self.truncated = true;
+                return false;
+            }
+            return true;
+        };
+        let key = if frame.object {
+            let Some(key) = frame.pending_key.take() else {
+                return false; // not a kept field
+            };
+            Some(key)
+        } else {
+            None
+        };
+        let key_len = key.as_ref().map(|key| key.len() + 3).unwrap_or(0);
+        let separator_len = if frame.count > 0 { 1 } else { 0 };
+        if self.output.len() + separator_len + key_len + value_len + closing_len > self.max_len {
+            self.truncated = true;
+            return false;
+        }
+        if frame.count > 0 {
+            self.output.push(b',');
+        }
+        frame.count += 1;
+        if let Some(key) = key {
+            self.output.push(b'"');
+            self.output.extend_from_slice(&key);
+            self.output.extend_from_slice(b"\":");
+        }
+        true
+    }
+
+    fn begin_container(&mut self, object: bool) {
+        if self.skip_nesting > 0 {
+            self.skip_nesting += 1;
+            return;
+        }
+        if !self.begin_value(2) {
+            self.skip_nesting = 1;
+            return;
+        }
+        self.output.push(if object { b'{' } else { b'[' });
+        self.frames.push(Frame {
+            object,
+            expect_key: object,
+            pending_key: None,
+            count: 0,
+        });
+    }
+
+    fn end_container(&mut self) {
+        if self.skip_nesting > 0 {
+            self.skip_nesting -= 1;
+            return;
+        }
+        if let Some(frame) = self.frames.pop() {
+            self.output.push(if frame.object { b'}' } else { b']' });
+            if frame.object {
+                let object_depth = self.frames.iter().filter(|frame| frame.object).count();
+                self.path.truncate(object_depth);
+            }
+        }
+    }
+
+    fn comma(&mut self) {
+        if self.skip_nesting > 0 {
+            return;
+        }
+        if let Some(frame) = self.frames.last_mut() {
+            if frame.object {
+                frame.expect_key = true;
+                frame.pending_key = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    fn keep_all(_path: &[String]) -> bool {
+        true
+    }
+
+    fn keep_tray_type(path: &[String]) -> bool {
+        match path {
+            [key] => key == "ams",
+            [_, key] => key == "tray",
+            [_, _, key] => key == "tray_type" || key == "id",
+            _ => false,
+        }
+    }
+
+    fn filter(json: &str, keep: KeepField, max_len: usize, chunk_len: usize) -> (String, bool) {
+        let mut filter = JsonFilter::new(keep, max_len);
+        for chunk in json.as_bytes().chunks(chunk_len) {
+            filter.feed(chunk);
+        }
+        let (output, truncated) = filter.finish();
+        (String::from_utf8(output).unwrap(), truncated)
+    }
+
+    #[test]
+    fn keeps_all_compacted() {
+        let json = r#"{ "a": 1, "b": [true, null, -2.5e3], "c": { "d": "x" }, "e": [] }"#;
+        for chunk_len in [1, 3, json.len()] {
+            assert_eq!(
+                filter(json, keep_all, 1024, chunk_len),
+                (String::from(r#"{"a":1,"b":[true,null,-2.5e3],"c":{"d":"x"},"e":[]}"#), false)
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_nested_fields_by_path() {
+        let json = r#"{"ams":{"tray":[{"id":"0","tray_type":"PLA","tray_color":"FF0000FF","cols":["FF0000FF"]},{"id":"1"}],"humidity":"5"},"other":{"tray_type":"x"}}"#;
+        assert_eq!(
+            filter(json, keep_tray_type, 1024, 7),
+            (String::from(r#"{"ams":{"tray":[{"id":"0","tray_type":"PLA"},{"id":"1"}]}}"#), false)
+        );
+    }
+
+    #[test]
+    fn keeps_escaped_strings() {
+        let json = r#"{"a":"quote \" backslash \\ brace } bracket ] comma ,","b":"é"}"#;
+        assert_eq!(filter(json, keep_all, 1024, 1), (String::from(json), false));
+    }
+
+    #[test]
+    fn drops_over_long_strings_with_their_key() {
+        let long = "x".repeat(MAX_TOKEN_LEN + 1);
+        let json = format!(r#"{{"a":"{long}","b":1,"{long}":2}}"#);
+        assert_eq!(filter(&json, keep_all, 4096, 64), (String::from(r#"{"b":1}"#), false));
+    }
+
+    #[test]
+    fn truncates_at_max_len() {
+        let json = r#"{"a":"0123456789","b":{"c":"0123456789"},"d":[1,2,3],"e":"0123456789abcdef"}"#;
+        for max_len in 2..json.len() {
+            let (output, truncated) = filter(json, keep_all, max_len, 5);
+            assert!(output.len() <= max_len, "max_len {max_len}: {output}");
+            assert!(truncated, "max_len {max_len}: {output}");
+            serde_json::from_str::<serde_json::Value>(&output).unwrap();
+        }
+        assert_eq!(filter(json, keep_all, json.len(), 5), (String::from(json), false));
+    }
+
+    #[test]
+    fn drops_fields_that_dont_fit() {
+        let json = r#"{"a":"0123456789","b":1}"#;
+        assert_eq!(filter(json, keep_all, 12, 1), (String::from(r#"{"b":1}"#), true));
+        assert_eq!(filter(json, keep_all, 1, 1), (String::new(), true));
+    }
+
+    #[test]
+    fn root_values() {
+        assert_eq!(filter("[1, 2]", keep_all, 16, 1), (String::from("[1,2]"), false));
+        assert_eq!(filter("42", keep_all, 16, 1), (String::from("42"), false));
+        assert_eq!(filter(r#""text""#, keep_all, 16, 1), (String::from(r#""text""#), false));
+        assert_eq!(filter("", keep_all, 16, 1), (String::new(), false));
+    }
+}
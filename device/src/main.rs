@@ -14,13 +14,20 @@ mod app;
 mod app_config;
 mod bambu;
 mod bambu_api;
+mod clock;
+mod drying;
 mod filament_staging;
+mod json_filter;
+mod mqtt_capture;
 mod my_mqtt;
 mod ndef;
 mod nfc;
 mod pn532_ext;
+mod printer_discovery;
 mod settings;
+mod spool_inventory;
 mod spool_tag;
+mod spoolman;
 mod view_model;
 mod web_app;
 
@@ -71,7 +78,12 @@ use settings::{
 };
 use web_app::NestedAppBuilder;
 
-const STA_STACK_RESOURCES: usize = WEB_SERVER_NUM_LISTENERS + 4; // web-config listeners + potentially https captive + mqtt + USDP(?) + ota + captive dns
+pub type AppSDCard = framework::sdcard::SDCard<
+    embedded_hal_bus::spi::ExclusiveDevice<esp_hal::spi::master::Spi<'static, Blocking>, esp_hal::gpio::Output<'static>, embedded_hal_bus::spi::NoDelay>,
+    esp_hal::delay::Delay,
+>;
+
+const STA_STACK_RESOURCES: usize = WEB_SERVER_NUM_LISTENERS + 6; // web-config listeners + potentially https captive + mqtt + USDP(?) + ota + captive dns + sntp + spoolman
 const AP_STACK_RESOURCES: usize = WEB_SERVER_NUM_LISTENERS + 4;
 
 #[macro_export]
@@ -286,6 +298,12 @@ async fn main(spawner: Spawner) {
 
     let app_config = Rc::new(RefCell::new(AppConfig::new(framework.clone())));
 
+    // Printer and discovery models are needed by the web app, their tasks are started by the app task
+    let bambu_printer_model = bambu::create_model(app_config.clone());
+    let printer_discovery_model = printer_discovery::create_model();
+    // The staged spool info can be edited on the web app
+    let filament_staging = Rc::new(RefCell::new(filament_staging::FilamentStaging::new()));
+
     // == Setup Web Application and Run Web Server ====================================
 
     let web_app_builder = framework::framework_web_app::WebAppProps::<NestedAppBuilder> {
@@ -295,6 +313,9 @@ async fn main(spawner: Spawner) {
         app_builder: NestedAppBuilder {
             framework: framework.clone(),
             app_config: app_config.clone(),
+            bambu_printer: bambu_printer_model.clone(),
+            printer_discovery: printer_discovery_model.clone(),
+            filament_staging: filament_staging.clone(),
         },
     };
 
@@ -357,24 +378,14 @@ async fn main(spawner: Spawner) {
 
     let sdcard_spi_device = ExclusiveDevice::new_no_delay(spi_bus, sd_cs).unwrap();
 
-    let sdcard = mk_static!(
-        framework::sdcard::SDCard<
-            embedded_hal_bus::spi::ExclusiveDevice<
-                esp_hal::spi::master::Spi<'_, Blocking>,
-                esp_hal::gpio::Output<'_>,
-                embedded_hal_bus::spi::NoDelay,
-            >,
-            esp_hal::delay::Delay,
-        >,
-        framework::sdcard::SDCard::new(sdcard_spi_device, delay)
-    );
+    let sdcard: Rc<RefCell<AppSDCard>> = Rc::new(RefCell::new(framework::sdcard::SDCard::new(sdcard_spi_device, delay)));
 
     // == Load Configuration from SDCard, required here for WiFi ssid & password ======
 
     let config_filename = format!("/{}.cfg", env!("CARGO_PKG_NAME").to_lowercase());
     term_info!("Loading config file '{}' from SDCard", config_filename);
 
-    let read_file_str = sdcard.read_file_str(&config_filename);
+    let read_file_str = sdcard.borrow_mut().read_file_str(&config_filename);
     let config_toml = match read_file_str {
         Ok(config_toml) => {
             term_info!("Read config file '{}' from SDCard", config_filename);
@@ -454,6 +465,10 @@ async fn main(spawner: Spawner) {
             framework.clone(),
             tls.reference(),
             app_config.clone(),
+            bambu_printer_model,
+            printer_discovery_model,
+            filament_staging,
+            sdcard,
             pn532_spi_device,
             pn532_irq,
         ))
@@ -0,0 +1,302 @@
+use core::cell::RefCell;
+
+use alloc::{
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    vec::Vec,
+};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel, pubsub::PubSubChannel};
+use embassy_time::{Duration, Instant, Timer};
+use mqttrust::{Packet, Publish, QoS};
+use serde::{Deserialize, Serialize};
+
+use framework::prelude::*;
+
+use crate::{
+    app_config::{AppConfig, PrinterConnectionStatus},
+    my_mqtt::BufferedMqttPacket,
+    AppSDCard,
+};
+
+// Capture of the MQTT traffic with the printer to the SDCard, and replay of a capture instead of connecting to the printer,
+// so issues reported by users (e.g. odd tray states) can be reproduced exactly as they happened.
+// The capture rotates over a few files, each starts with a header line holding the file's sequence number
+// within the capture, followed by one json record per line. Replay walks the files in sequence order.
+// Records are only buffered on the MQTT path, a task of their own appends them to the files every few seconds.
+
+const CAPTURE_FILES: usize = 4;
+const CAPTURE_FILE_SIZE: usize = 32768;
+const CAPTURE_FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+const CAPTURE_MAX_PENDING: usize = 2 * CAPTURE_FILE_SIZE; // records beyond it are dropped while the SDCard isn't written to
+
+// dir is the SDCard folder of the capture files, "/" for where they are captured to
+pub fn capture_filename(dir: &str, index: usize) -> String {
+    format!("{}/mqttcap{index}.log", dir.trim_end_matches('/'))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureDirection {
+    Rx, // received from the printer
+    Tx, // sent to the printer
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CaptureFileHeader {
+    pub seq: u32, // order of the file within the capture, the files are reused in rotation
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub time: u64, // ms since boot
+    pub dir: CaptureDirection,
+    pub topic: String,
+    pub payload: String,
+}
+
+// Records waiting to be written to a capture file
+struct PendingWrite {
+    file_index: usize,
+    new_file: bool, // the file is rewritten, starting with its header, instead of appended to
+    data: String,
+}
+
+pub struct MqttCapture {
+    sdcard: Rc<RefCell<AppSDCard>>,
+    file_index: usize,
+    file_seq: u32,
+    file_records: usize,
+    file_size: usize, // of the current file, including what's pending
+    pending: Vec<PendingWrite>,
+    pending_size: usize,
+    dropped_records: usize,
+    write_failed: bool,
+}
+
+impl MqttCapture {
+    // A capture is of a single session, so files of previous sessions are cleared (copy them before restarting with capture on)
+    pub fn new(sdcard: Rc<RefCell<AppSDCard>>) -> Self {
+        for index in 0..CAPTURE_FILES {
+            let _ = sdcard.borrow_mut().write_file_str(&capture_filename("/", index), "");
+        }
+        term_info!("Capturing MQTT traffic to SDCard files '{}'..", capture_filename("/", 0));
+        let mut capture = Self {
+            sdcard,
+            file_index: 0,
+            file_seq: 0,
+            file_records: 0,
+            file_size: 0,
+            pending: Vec::new(),
+            pending_size: 0,
+            dropped_records: 0,
+            write_failed: false,
+        };
+        capture.start_file();
+        capture
+    }
+
+    fn start_file(&mut self) {
+        let mut data = String::new();
+        if let Ok(header) = serde_json::to_string(&CaptureFileHeader { seq: self.file_seq }) {
+            data.push_str(&header);
+            data.push('\n');
+        }
+        self.file_records = 0;
+        self.file_size = data.len();
+        self.pending_size += data.len();
+        self.pending.push(PendingWrite {
+            file_index: self.file_index,
+            new_file: true,
+            data,
+        });
+    }
+
+    pub fn capture(&mut self, dir: CaptureDirection, packet: &Packet) {
+        if let Packet::Publish(publish) = packet {
+            self.capture_payload(dir, publish.topic_name, publish.payload);
+        }
+    }
+
+    pub fn capture_payload(&mut self, dir: CaptureDirection, topic: &str, payload: &[u8]) {
+        if self.pending_size >= CAPTURE_MAX_PENDING {
+            self.dropped_records += 1;
+            return;
+        }
+        let record = CaptureRecord {
+            time: Instant::now().as_millis(),
+            dir,
+            topic: topic.to_string(),
+            payload: String::from_utf8_lossy(payload).into_owned(),
+        };
+        let Ok(line) = serde_json::to_string(&record) else {
+            return;
+        };
+        if self.file_records != 0 && self.file_size + line.len() + 1 > CAPTURE_FILE_SIZE {
+            self.file_index = (self.file_index + 1) % CAPTURE_FILES;
+            self.file_seq = self.file_seq.wrapping_add(1);
+            self.start_file();
+        }
+        if self.pending.last().is_none_or(|v| v.file_index != self.file_index) {
+            self.pending.push(PendingWrite {
+                file_index: self.file_index,
+                new_file: false,
+                data: String::new(),
+            });
+        }
+        if let Some(pending) = self.pending.last_mut() {
+            pending.data.push_str(&line);
+            pending.data.push('\n');
+        }
+        self.file_records += 1;
+        self.file_size += line.len() + 1;
+        self.pending_size += line.len() + 1;
+    }
+
+    // Writes the pending records, what fails to be written is dropped so the buffering doesn't grow
+    fn flush(&mut self) {
+        if self.dropped_records != 0 {
+            warn!("MQTT capture dropped {} records, SDCard writes were behind", self.dropped_records);
+            self.dropped_records = 0;
+        }
+        for pending in self.pending.drain(..) {
+            let filename = capture_filename("/", pending.file_index);
+            let write_res = if pending.new_file {
+                self.sdcard.borrow_mut().write_file_str(&filename, &pending.data)
+            } else {
+                self.sdcard.borrow_mut().append_file_str(&filename, &pending.data)
+            };
+            match write_res {
+                Ok(_) => self.write_failed = false,
+                Err(e) => {
+                    // reported once, not on every flush
+                    if !self.write_failed {
+                        term_error!("Failed to write MQTT capture '{}' to SDCard : {}", filename, e);
+                    }
+                    self.write_failed = true;
+                }
+            }
+        }
+        self.pending_size = 0;
+    }
+}
+
+// Writes the captured records to the SDCard, off the MQTT path so the SDCard writes don't hold the printer communication
+#[embassy_executor::task]
+pub async fn mqtt_capture_task(capture: Rc<RefCell<MqttCapture>>) {
+    loop {
+        Timer::after(CAPTURE_FLUSH_INTERVAL).await;
+        capture.borrow_mut().flush();
+    }
+}
+
+// Feeds the received messages of a capture to the printer messages processing, keeping their original timing,
+// instead of connecting to the printer. Messages sent to the printer meanwhile are dropped.
+// capture_dir is the SDCard folder holding the capture files, they are replayed in their capture order
+#[embassy_executor::task]
+pub async fn mqtt_replay_task(
+    capture_dir: String,
+    sdcard: Rc<RefCell<AppSDCard>>,
+    read_packets: &'static PubSubChannel<NoopRawMutex, BufferedMqttPacket, 5, 2, 1>,
+    write_packets: &'static Channel<NoopRawMutex, BufferedMqttPacket, 3>,
+    app_config: Rc<RefCell<AppConfig>>,
+) {
+    let filenames = ordered_capture_files(&capture_dir, &sdcard);
+    if filenames.is_empty() {
+        term_error!("No MQTT capture files found in SDCard folder '{}'", capture_dir);
+        return;
+    }
+
+    term_info!("Replaying MQTT capture from SDCard folder '{}'", capture_dir);
+    app_config
+        .borrow_mut()
+        .report_printer_connection_status(PrinterConnectionStatus::Connected);
+
+    let publisher = read_packets.immediate_publisher();
+    let start = Instant::now();
+    let mut first_record_time = None;
+    for filename in filenames {
+        let read_res = sdcard.borrow_mut().read_file_str(&filename);
+        let capture = match read_res {
+            Ok(capture) => capture,
+            Err(e) => {
+                term_error!("Failed to read MQTT capture '{}' from SDCard : {}", filename, e);
+                continue;
+            }
+        };
+        term_info!("Replaying MQTT capture file '{}'", filename);
+
+        // first line is the file header
+        for (line_num, line) in capture.lines().enumerate().skip(1) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = match serde_json::from_str::<CaptureRecord>(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Skipping MQTT capture '{}' line {} : {:?}", filename, line_num + 1, e);
+                    continue;
+                }
+            };
+            if record.dir != CaptureDirection::Rx {
+                continue;
+            }
+
+            let first_record_time = *first_record_time.get_or_insert(record.time);
+            let deadline = start + Duration::from_millis(record.time.saturating_sub(first_record_time));
+            while let Either::Second(packet) = select(Timer::at(deadline), write_packets.receive()).await {
+                log_dropped_packet(&packet);
+            }
+
+            debug!("Replaying MQTT capture '{}' line {}", filename, line_num + 1);
+            let packet = Packet::Publish(Publish {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                pid: None,
+                retain: false,
+                topic_name: &record.topic,
+                payload: record.payload.as_bytes(),
+            });
+            match BufferedMqttPacket::try_from(packet) {
+                Ok(p) => {
+                    publisher.publish_immediate(p);
+                }
+                Err(e) => {
+                    term_error!("Error converting replayed MQTT message at '{}' line {} : {:?}", filename, line_num + 1, e);
+                }
+            }
+        }
+    }
+    term_info!("MQTT capture replay completed");
+
+    loop {
+        let packet = write_packets.receive().await;
+        log_dropped_packet(&packet);
+    }
+}
+
+// The capture files in the folder ordered by their header sequence, files that are missing or without a header
+// (never written to during the capture) are left out
+fn ordered_capture_files(capture_dir: &str, sdcard: &Rc<RefCell<AppSDCard>>) -> Vec<String> {
+    let mut files = Vec::new();
+    for index in 0..CAPTURE_FILES {
+        let filename = capture_filename(capture_dir, index);
+        let read_res = sdcard.borrow_mut().read_file_str(&filename);
+        let Ok(capture) = read_res else {
+            continue;
+        };
+        match capture.lines().next().map(serde_json::from_str::<CaptureFileHeader>) {
+            Some(Ok(header)) => files.push((header.seq, filename)),
+            _ => debug!("Skipping MQTT capture '{}', no capture header", filename),
+        }
+    }
+    files.sort_by_key(|(seq, _)| *seq);
+    files.into_iter().map(|(_, filename)| filename).collect()
+}
+
+fn log_dropped_packet(packet: &BufferedMqttPacket) {
+    if let Ok(Packet::Publish(publish)) = Packet::try_from(packet) {
+        debug!("Replay, not sent to printer : {:?}", core::str::from_utf8(publish.payload));
+    }
+}
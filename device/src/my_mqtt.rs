@@ -9,6 +9,7 @@ use core::cell::RefCell;
 use core::cmp::min;
 use embassy_futures::select::Either;
 use embassy_futures::select::Either3;
+use embassy_net::tcp::ConnectError;
 use embassy_net::tcp::State;
 use embassy_net::tcp::TcpSocket;
 use embassy_net::IpEndpoint;
@@ -22,7 +23,8 @@ use embedded_io_async::Write;
 use esp_mbedtls::TlsError;
 use esp_mbedtls::TlsReference;
 use esp_mbedtls::X509;
-use mqttrust::encoding::v4::decode_slice;
+use mqttrust::encoding::v4::{decode_slice, ConnectReturnCode};
+use mqttrust::QoS;
 use mqttrust::{
     encoding::v4::{encode_slice, Connect, Pid, Protocol},
     MqttError, Packet, Subscribe, SubscribeTopic,
@@ -30,7 +32,10 @@ use mqttrust::{
 
 use framework::prelude::*;
 
-use crate::app_config::AppConfig;
+use crate::app_config::{AppConfig, PrinterConnectionStatus};
+use crate::json_filter::{JsonFilter, KeepField};
+use crate::mqtt_capture::{CaptureDirection, MqttCapture};
+use crate::printer_discovery::{self, PrinterDiscovery};
 
 #[derive(Debug)]
 #[allow(clippy::enum_variant_names)]
@@ -41,6 +46,7 @@ pub enum MyMqttError {
     EncodingError(mqttrust::encoding::v4::Error),
     WriteTimeoutError,
     RecvMessageTooLarge(usize),
+    ConnectionRefused(ConnectReturnCode),
 }
 
 impl From<TlsError> for MyMqttError {
@@ -64,6 +70,131 @@ impl From<mqttrust::encoding::v4::utils::Error> for MyMqttError {
 const INITIAL_MQTT_BUFFER_SIZE: usize = 32768;
 const MAX_MQTT_BUFFER_SIZE: usize = 49152;
 const MQTT_BUFFER_SIZE_GROW_STEPS: usize = 8192;
+// When published payloads are filtered, the buffer only needs to hold headers and chunks of the payload as they arrive
+const FILTERED_MQTT_BUFFER_SIZE: usize = 4096;
+const MAX_FILTERED_PAYLOAD_SIZE: usize = 16384;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_FAILURES_BEFORE_REDISCOVERY: u32 = 5; // then the printer is searched for by serial, it may have a new ip
+const REDISCOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const MBEDTLS_ERR_X509_CERT_VERIFY_FAILED: i32 = -0x2700;
+
+#[cfg(not(feature = "simulator"))]
+const PRINTER_CA_CHAIN: &str = concat!(include_str!("./certs/bambulab.pem"), "\0");
+// the simulator's CA is generated locally by simulator/gen-certs.sh
+#[cfg(feature = "simulator")]
+const PRINTER_CA_CHAIN: &str = concat!(include_str!("./certs/bambulab.pem"), include_str!("../../simulator/certs/ca.pem"), "\0");
+
+// Delay between connection attempts, doubled on every failed attempt
+struct ReconnectBackoff {
+    delay: Duration,
+}
+
+impl ReconnectBackoff {
+    fn new() -> Self {
+        Self { delay: MIN_RECONNECT_DELAY }
+    }
+
+    fn reset(&mut self) {
+        self.delay = MIN_RECONNECT_DELAY;
+    }
+
+    async fn wait(&mut self) {
+        Timer::after(self.delay).await;
+        self.delay = min(self.delay * 2, MAX_RECONNECT_DELAY);
+    }
+}
+
+fn tls_error_status(e: &TlsError) -> PrinterConnectionStatus {
+    match e {
+        TlsError::MbedTlsError(MBEDTLS_ERR_X509_CERT_VERIFY_FAILED) => PrinterConnectionStatus::TlsFailed(String::from("Certificate Verification")),
+        e => PrinterConnectionStatus::TlsFailed(alloc::format!("{e:?}")),
+    }
+}
+
+fn connect_error_status(e: &MyMqttError) -> PrinterConnectionStatus {
+    match e {
+        MyMqttError::ConnectionRefused(ConnectReturnCode::BadUsernamePassword) => PrinterConnectionStatus::BadAccessCode,
+        MyMqttError::ConnectionRefused(ConnectReturnCode::NotAuthorized) => PrinterConnectionStatus::NotAuthorized,
+        MyMqttError::ConnectionRefused(code) => PrinterConnectionStatus::Rejected(alloc::format!("{code:?}")),
+        MyMqttError::TlsError(e) => tls_error_status(e),
+        e => PrinterConnectionStatus::Rejected(alloc::format!("{e:?}")),
+    }
+}
+
+// The printer rejected the access code, no point retrying until it is changed (e.g. on web config)
+async fn wait_for_access_code_change(app_config: &Rc<RefCell<AppConfig>>, password: &Option<Vec<u8>>) -> Option<Vec<u8>> {
+    loop {
+        let access_code = app_config.borrow().printer_access_code.clone().map(String::into_bytes);
+        if access_code.is_some() && access_code != *password {
+            return access_code;
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+struct PublishHeader {
+    dup: bool,
+    qos: QoS,
+    retain: bool,
+    pid: Option<Pid>,
+    topic_start: usize,
+    topic_end: usize,
+    payload_start: usize,
+    packet_end: usize,
+}
+
+// The fixed and variable headers of a PUBLISH packet at the start of buf, None if not a PUBLISH or not fully read yet
+fn parse_publish_header(buf: &[u8]) -> Option<PublishHeader> {
+    let first = *buf.first()?;
+    if first >> 4 != 3 {
+        return None;
+    }
+    let mut remaining_len = 0;
+    let mut offset = 1;
+    loop {
+        let byte = *buf.get(offset)?;
+        remaining_len |= ((byte & 0x7F) as usize) << (7 * (offset - 1));
+        offset += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if offset > 4 {
+            return None; // malformed, left to the regular decoding to report
+        }
+    }
+    let qos = match (first >> 1) & 0x03 {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => return None,
+    };
+    let topic_len = u16::from_be_bytes([*buf.get(offset)?, *buf.get(offset + 1)?]) as usize;
+    let topic_start = offset + 2;
+    let topic_end = topic_start + topic_len;
+    let (pid, payload_start) = if matches!(qos, QoS::AtMostOnce) {
+        (None, topic_end)
+    } else {
+        let pid = u16::from_be_bytes([*buf.get(topic_end)?, *buf.get(topic_end + 1)?]);
+        (Some(Pid::try_from(pid).ok()?), topic_end + 2)
+    };
+    let packet_end = offset + remaining_len;
+    // a topic (and pid) longer than the packet is malformed
+    if buf.len() < payload_start || payload_start > packet_end {
+        return None;
+    }
+    Some(PublishHeader {
+        dup: first & 0x08 != 0,
+        qos,
+        retain: first & 0x01 != 0,
+        pid,
+        topic_start,
+        topic_end,
+        payload_start,
+        packet_end,
+    })
+}
 
 pub struct MyMqtt<'a, T>
 where
@@ -74,19 +205,39 @@ where
     message_bytes_in_buf: usize,
     data_bytes_in_buf: usize,
     write_timeout: Duration,
+    // When set, published payloads (json) are filtered while they arrive instead of buffered whole
+    payload_filter: Option<KeepField>,
+    filtered_topic: String,
+    filtered_payload: Vec<u8>,
+    // Received messages are captured as they arrive, before filtering
+    capture: Option<Rc<RefCell<MqttCapture>>>,
 }
 
 impl<'a, T> MyMqtt<'a, T>
 where
     T: embedded_io_async::Read + embedded_io_async::Write,
 {
-    pub fn new(tls: esp_mbedtls::asynch::Session<'a, T>, write_timeout: Duration) -> MyMqtt<'a, T> {
+    pub fn new(
+        tls: esp_mbedtls::asynch::Session<'a, T>,
+        write_timeout: Duration,
+        payload_filter: Option<KeepField>,
+        capture: Option<Rc<RefCell<MqttCapture>>>,
+    ) -> MyMqtt<'a, T> {
+        let buf_size = if payload_filter.is_some() {
+            FILTERED_MQTT_BUFFER_SIZE
+        } else {
+            INITIAL_MQTT_BUFFER_SIZE
+        };
         MyMqtt {
             tls,
-            buf: vec![0u8; INITIAL_MQTT_BUFFER_SIZE],
+            buf: vec![0u8; buf_size],
             message_bytes_in_buf: 0,
             data_bytes_in_buf: 0,
             write_timeout,
+            payload_filter,
+            filtered_topic: String::new(),
+            filtered_payload: Vec::new(),
+            capture,
         }
     }
 
@@ -109,9 +260,14 @@ where
 
         self.write(connect).await?;
         let resp = self.read().await?;
-        // TODO: handle various connack response options
         match resp {
-            Some(mqttrust::Packet::Connack(mqttrust::encoding::v4::Connack { session_present: _, code: _ })) => {}
+            Some(mqttrust::Packet::Connack(mqttrust::encoding::v4::Connack {
+                session_present: _,
+                code: ConnectReturnCode::Accepted,
+            })) => {}
+            Some(mqttrust::Packet::Connack(mqttrust::encoding::v4::Connack { session_present: _, code })) => {
+                return Err(MyMqttError::ConnectionRefused(code));
+            }
             _ => {
                 warn!("Unexpected connect response {:?}", resp);
             }
@@ -178,6 +334,12 @@ where
         loop {
             // Start by checking if there's data from previous round (unlikely, but theoretically could)
 
+            if let Some(keep) = self.payload_filter {
+                if let Some(header) = parse_publish_header(&self.buf[..self.data_bytes_in_buf]) {
+                    return self.read_filtered_publish(header, keep).await;
+                }
+            }
+
             let mut offset = 0;
             if self.data_bytes_in_buf >= 4 {
                 // minimal size is 4 bytes, so no point waisting time on less
@@ -190,6 +352,9 @@ where
                         match decode_val_res {
                             Ok(decode_val) => {
                                 self.message_bytes_in_buf = offset + remaining_len;
+                                if let (Some(capture), Some(packet)) = (&self.capture, &decode_val) {
+                                    capture.borrow_mut().capture(CaptureDirection::Rx, packet);
+                                }
                                 return Ok(decode_val);
                             }
                             Err(decode_err) =>  {
@@ -235,6 +400,69 @@ where
             self.data_bytes_in_buf += read_len;
         }
     }
+
+    // Streams the payload of a PUBLISH through the filter, so only the filtered payload is held in memory
+    // (when capturing, the full payload is also kept, so the capture holds the payload as received)
+    async fn read_filtered_publish(&mut self, header: PublishHeader, keep: KeepField) -> Result<Option<Packet>, MyMqttError> {
+        self.filtered_topic = String::from_utf8_lossy(&self.buf[header.topic_start..header.topic_end]).into_owned();
+        let mut filter = JsonFilter::new(keep, MAX_FILTERED_PAYLOAD_SIZE);
+        let mut raw_payload = self.capture.as_ref().map(|_| Vec::new());
+
+        let in_buf_end = min(self.data_bytes_in_buf, header.packet_end);
+        filter.feed(&self.buf[header.payload_start..in_buf_end]);
+        if let Some(raw_payload) = &mut raw_payload {
+            raw_payload.extend_from_slice(&self.buf[header.payload_start..in_buf_end]);
+        }
+        let mut remaining = header.packet_end - in_buf_end;
+        if remaining == 0 {
+            self.message_bytes_in_buf = header.packet_end;
+        }
+        // All the data in the buffer is of this packet, the buffer is reused for the rest of the payload
+        while remaining > 0 {
+            let read_len = match self.tls.read(&mut self.buf[..]).await {
+                Ok(0) => Err(TlsError::Eof),
+                res => res,
+            };
+            let read_len = match read_len {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("TLS Error {:?}", e);
+                    self.data_bytes_in_buf = 0;
+                    self.message_bytes_in_buf = 0;
+                    return Err(MyMqttError::TlsError(e));
+                }
+            };
+            let payload_len = min(read_len, remaining);
+            filter.feed(&self.buf[..payload_len]);
+            if let Some(raw_payload) = &mut raw_payload {
+                raw_payload.extend_from_slice(&self.buf[..payload_len]);
+            }
+            remaining -= payload_len;
+            // what's beyond the payload is the start of the next packet
+            self.data_bytes_in_buf = read_len;
+            self.message_bytes_in_buf = payload_len;
+        }
+
+        let (payload, truncated) = filter.finish();
+        if truncated {
+            warn!("MQTT message over {} bytes after filtering, some fields dropped", MAX_FILTERED_PAYLOAD_SIZE);
+        }
+        self.filtered_payload = payload;
+        if let (Some(capture), Some(raw_payload)) = (&self.capture, &raw_payload) {
+            capture
+                .borrow_mut()
+                .capture_payload(CaptureDirection::Rx, &self.filtered_topic, raw_payload);
+        }
+
+        Ok(Some(Packet::Publish(mqttrust::Publish {
+            dup: header.dup,
+            qos: header.qos,
+            pid: header.pid,
+            retain: header.retain,
+            topic_name: &self.filtered_topic,
+            payload: &self.filtered_payload,
+        })))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -342,7 +570,7 @@ pub async fn generic_mqtt_task<
     remote_endpoint: E,
     printer_serial: &String,
     username: Option<&str>,
-    password: Option<Vec<u8>>,
+    mut password: Option<Vec<u8>>,
     keep_alive_secs: u16,
     subscribe_topics: &[SubscribeTopic<'_>],
     stack: Stack<'static>,
@@ -351,8 +579,11 @@ pub async fn generic_mqtt_task<
     socket_rx_buffer: &'static mut [u8; SOCKET_RX_SIZE],
     socket_tx_buffer: &'static mut [u8; SOCKET_TX_SIZE],
     write_timeout: Duration,
+    payload_filter: Option<KeepField>,
+    capture: Option<Rc<RefCell<MqttCapture>>>,
     // mut rsa: esp_hal::peripherals::RSA,
     app_config: Rc<RefCell<AppConfig>>,
+    printer_discovery: Rc<RefCell<PrinterDiscovery>>,
     // mut sha: impl esp_hal::peripheral::Peripheral<P = esp_hal::peripherals::SHA>,
     tls: TlsReference<'static>,
 ) -> ! {
@@ -360,6 +591,10 @@ pub async fn generic_mqtt_task<
     //     .unwrap()
     //     .with_hardware_rsa(&mut rsa);
 
+    let mut remote_endpoint: IpEndpoint = remote_endpoint.into();
+    let mut connect_failures = 0;
+    let mut backoff = ReconnectBackoff::new();
+
     'establish_communication: loop {
         let mut socket = TcpSocket::new(stack, socket_rx_buffer, socket_tx_buffer);
 
@@ -374,26 +609,54 @@ pub async fn generic_mqtt_task<
             socket.abort();
         }
 
-        let endpoint: IpEndpoint = remote_endpoint.into();
-        let port = endpoint.port;
-        let embassy_net::IpAddress::Ipv4(addr) = endpoint.addr else { todo!() }; // Ipv6 should not happen
+        let port = remote_endpoint.port;
+        let embassy_net::IpAddress::Ipv4(addr) = remote_endpoint.addr else { todo!() }; // Ipv6 should not happen
         let octets = addr.octets();
 
         term_info!("Connecting to Printer {}.{}.{}.{}:{}", octets[0], octets[1], octets[2], octets[3], port);
-        match socket.connect(remote_endpoint).await {
-            Ok(()) => (),
-            Err(e) => {
-                // match e {
-                //     ConnectError::InvalidState | ConnectError::ConnectionReset => {
-                //     }
-                //     ConnectError::TimedOut => (),
-                //     ConnectError::NoRoute => (),
-                // }
+        let connected = match with_timeout(CONNECT_TIMEOUT, socket.connect(remote_endpoint)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
                 term_error!("Unexpected error connecting socket {:?}", e);
-                Timer::after(Duration::from_millis(500)).await;
-                continue;
+                let status = match e {
+                    ConnectError::ConnectionReset => PrinterConnectionStatus::Refused,
+                    _ => PrinterConnectionStatus::Unreachable,
+                };
+                app_config.borrow_mut().report_printer_connection_status(status);
+                false
+            }
+            Err(_) => {
+                term_error!("Timeout connecting to Printer");
+                app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::Unreachable);
+                false
+            }
+        };
+
+        if !connected {
+            connect_failures += 1;
+            if connect_failures >= CONNECT_FAILURES_BEFORE_REDISCOVERY {
+                // The printer may have gotten a new ip (e.g. new DHCP lease), look for it by its serial
+                connect_failures = 0;
+                term_info!("Printer not answering at {}, searching for it on the network", addr);
+                app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::Discovering);
+                match with_timeout(REDISCOVERY_TIMEOUT, printer_discovery::wait_for_printer_new_ip(&printer_discovery, printer_serial, addr)).await {
+                    Ok(printer) => {
+                        term_info!("Discovered Printer at {}", printer.ip);
+                        remote_endpoint = IpEndpoint::new(printer.ip.into(), port);
+                        if let Err(e) = app_config.borrow_mut().update_printer_ip(printer.ip) {
+                            term_error!("Failed to store the new Printer IP {:?}", e);
+                        }
+                    }
+                    Err(_) => {
+                        term_info!("Printer not discovered at another IP, retrying {}", addr);
+                    }
+                }
+            } else {
+                backoff.wait().await;
             }
+            continue;
         }
+        connect_failures = 0;
 
         term_info!("Connected to Printer");
 
@@ -406,7 +669,7 @@ pub async fn generic_mqtt_task<
             },
             esp_mbedtls::TlsVersion::Tls1_2,
             esp_mbedtls::Certificates {
-                ca_chain: X509::pem(concat!(include_str!("./certs/bambulab.pem"), "\0").as_bytes()).ok(),
+                ca_chain: X509::pem(PRINTER_CA_CHAIN.as_bytes()).ok(),
                 ..Default::default()
             },
             tls,
@@ -414,7 +677,8 @@ pub async fn generic_mqtt_task<
             Ok(tls_starter) => tls_starter,
             Err(e) => {
                 term_error!("Error establishing TLS Connection {:?}", e);
-                Timer::after(Duration::from_millis(500)).await;
+                app_config.borrow_mut().report_printer_connection_status(tls_error_status(&e));
+                backoff.wait().await;
                 continue;
             }
         };
@@ -424,19 +688,30 @@ pub async fn generic_mqtt_task<
         if let Err(e) = session.connect().await {
             // any point in retrying several times when tls fail?
             term_error!("Unexpected error during tls handshake {:?}", e);
-            Timer::after(Duration::from_millis(500)).await;
+            app_config.borrow_mut().report_printer_connection_status(tls_error_status(&e));
+            backoff.wait().await;
             continue;
         }
 
         term_info!("TLS connection with Printer established");
 
         term_info!("Establishing MQTT connection with Printer");
-        let mut my_mqtt = MyMqtt::new(session, write_timeout);
+        let mut my_mqtt = MyMqtt::new(session, write_timeout, payload_filter, capture.clone());
 
         if let Err(e) = my_mqtt.connect(keep_alive_secs, username, password.as_deref()).await {
-            // any point in retrying mqtt connect ?
-            term_error!("Unexpected error during mqtt connect {:?}", e);
-            Timer::after(Duration::from_millis(500)).await;
+            let status = connect_error_status(&e);
+            if status.configuration_needed() {
+                term_error!("{}, set the correct Access Code in Web Config", status);
+                app_config.borrow_mut().report_printer_connection_status(status);
+                drop(my_mqtt);
+                password = wait_for_access_code_change(&app_config, &password).await;
+                term_info!("Access Code changed, reconnecting");
+                backoff.reset();
+            } else {
+                term_error!("Unexpected error during mqtt connect {:?}", e);
+                app_config.borrow_mut().report_printer_connection_status(status);
+                backoff.wait().await;
+            }
             continue;
         }
         term_info!("MQTT connection with Printer established");
@@ -445,12 +720,14 @@ pub async fn generic_mqtt_task<
         if let Err(e) = my_mqtt.subscribe(None, subscribe_topics).await {
             // any point in retrying mqtt subscribe ?
             term_error!("Unexpected error during mqtt subscribe {:?}", e);
-            Timer::after(Duration::from_millis(500)).await;
+            app_config.borrow_mut().report_printer_connection_status(connect_error_status(&e));
+            backoff.wait().await;
             continue;
         }
 
         term_info!("Subscription to Printer reports confirmed");
-        app_config.borrow_mut().report_printer_connectivity(true);
+        backoff.reset();
+        app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::Connected);
 
         let publisher = read_packets.immediate_publisher();
 
@@ -469,20 +746,24 @@ pub async fn generic_mqtt_task<
             match res {
                 // First : Receive
                 Either3::First(res) => match res {
-                    Ok(Some(packet)) => match BufferedMqttPacket::try_from(packet) {
-                        Ok(p) => {
-                            // publish internally the received packet
-                            publisher.publish_immediate(p);
-                        }
-                        Err(e) => {
-                            term_error!("Error converting internal packets data on read {:?}", e);
+                    Ok(Some(packet)) => {
+                        // captured by my_mqtt as received
+                        match BufferedMqttPacket::try_from(packet) {
+                            Ok(p) => {
+                                // publish internally the received packet
+                                publisher.publish_immediate(p);
+                            }
+                            Err(e) => {
+                                term_error!("Error converting internal packets data on read {:?}", e);
+                            }
                         }
-                    },
+                    }
                     Ok(None) => {
                         term_error!("MQTT Recv:  None Packet");
                     }
                     Err(MyMqttError::TlsError(e)) => {
                         term_error!("TLS Error on receive {:?}", e);
+                        app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::Disconnected);
                         continue 'establish_communication;
                     }
                     Err(e) => {
@@ -492,9 +773,13 @@ pub async fn generic_mqtt_task<
                 // Second: Write Request
                 Either3::Second(packet) => match mqttrust::Packet::try_from(&packet) {
                     Ok(p) => {
+                        if let Some(capture) = &capture {
+                            capture.borrow_mut().capture(CaptureDirection::Tx, &p);
+                        }
                         if let Err(e) = my_mqtt.write(p).await {
                             term_error!("MQTT write error: {:?}\nReconnecting...", e);
                             // any point retrying?
+                            app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::Disconnected);
                             continue 'establish_communication;
                         }
                     }
@@ -506,6 +791,7 @@ pub async fn generic_mqtt_task<
                     if let Err(e) = my_mqtt.write_pingreq().await {
                         term_error!("MQTT Send: ping message error: {:?}", e);
                         // any point retrying?
+                        app_config.borrow_mut().report_printer_connection_status(PrinterConnectionStatus::Disconnected);
                         continue 'establish_communication;
                     }
                 }
@@ -513,3 +799,97 @@ pub async fn generic_mqtt_task<
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PUBLISH packet bytes, the remaining length is encoded in as many bytes as it needs
+    fn publish_packet(flags: u8, topic: &[u8], pid: Option<u16>, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        body.extend_from_slice(topic);
+        if let Some(pid) = pid {
+            body.extend_from_slice(&pid.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        let mut packet = vec![0x30 | flags];
+        let mut remaining_len = body.len();
+        loop {
+            let byte = (remaining_len & 0x7F) as u8;
+            remaining_len >>= 7;
+            if remaining_len == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    #[test]
+    fn parses_qos0_publish() {
+        let packet = publish_packet(0x01, b"device/1/report", None, b"{}");
+        let header = parse_publish_header(&packet).unwrap();
+        assert!(!header.dup && header.retain);
+        assert!(matches!(header.qos, QoS::AtMostOnce));
+        assert!(header.pid.is_none());
+        assert_eq!(&packet[header.topic_start..header.topic_end], b"device/1/report");
+        assert_eq!(&packet[header.payload_start..header.packet_end], b"{}");
+        assert_eq!(header.packet_end, packet.len());
+    }
+
+    #[test]
+    fn parses_qos1_publish_with_pid() {
+        let packet = publish_packet(0x08 | 0x02, b"t", Some(7), b"payload");
+        let header = parse_publish_header(&packet).unwrap();
+        assert!(header.dup && !header.retain);
+        assert!(matches!(header.qos, QoS::AtLeastOnce));
+        assert!(header.pid == Pid::try_from(7).ok());
+        assert_eq!(&packet[header.payload_start..header.packet_end], b"payload");
+    }
+
+    #[test]
+    fn parses_multi_byte_remaining_length() {
+        let payload = vec![b'x'; 20000];
+        let packet = publish_packet(0, b"device/1/report", None, &payload);
+        // only the headers are needed, the payload is filtered as it arrives
+        let header = parse_publish_header(&packet[..30]).unwrap();
+        assert_eq!(header.payload_start, 1 + 3 + 2 + 15);
+        assert_eq!(header.packet_end, packet.len());
+    }
+
+    #[test]
+    fn waits_for_full_headers() {
+        let packet = publish_packet(0x02, b"device/1/report", Some(1), b"{}");
+        let header_len = 1 + 1 + 2 + 15 + 2;
+        for len in 0..header_len {
+            assert!(parse_publish_header(&packet[..len]).is_none(), "len {len}");
+        }
+        assert!(parse_publish_header(&packet[..header_len]).is_some());
+    }
+
+    #[test]
+    fn rejects_topic_longer_than_packet() {
+        let mut packet = publish_packet(0, b"device/1/report", None, b"{}");
+        // topic length of 100 within a packet of 19 bytes
+        packet[2..4].copy_from_slice(&100u16.to_be_bytes());
+        packet.resize(200, b'x');
+        assert!(parse_publish_header(&packet).is_none());
+    }
+
+    #[test]
+    fn rejects_other_packets() {
+        // PINGRESP
+        assert!(parse_publish_header(&[0xD0, 0x00]).is_none());
+        // CONNACK
+        assert!(parse_publish_header(&[0x20, 0x02, 0x00, 0x00]).is_none());
+        // QoS 3
+        assert!(parse_publish_header(&[0x36, 0x05, 0x00, 0x01, b't', 0x00, 0x01]).is_none());
+        // remaining length over 4 bytes
+        assert!(parse_publish_header(&[0x30, 0x80, 0x80, 0x80, 0x80, 0x01]).is_none());
+        // pid 0
+        assert!(parse_publish_header(&[0x32, 0x05, 0x00, 0x01, b't', 0x00, 0x00]).is_none());
+    }
+}
@@ -0,0 +1,325 @@
+use core::{cell::RefCell, str::FromStr};
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use embassy_futures::select::{select3, Either3};
+use embassy_net::{Ipv4Address, Stack};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+
+use framework::prelude::*;
+
+// Bambu printers announce themselves with SSDP NOTIFY messages (to ports 1990 and 2021) and respond to M-SEARCH
+const SSDP_MULTICAST_ADDRESS: Ipv4Address = Ipv4Address::new(239, 255, 255, 250);
+const SSDP_PORTS: [u16; 2] = [1990, 2021];
+const BAMBU_PRINTER_URN: &str = "urn:bambulab-com:device:3dprinter";
+const M_SEARCH_INTERVAL: Duration = Duration::from_secs(30);
+const PRINTER_EXPIRY: Duration = Duration::from_secs(180); // printers that stopped announcing themselves are removed
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredPrinter {
+    pub ip: Ipv4Address,
+    pub name: String,
+    pub serial: String,
+    pub model: String, // model code, e.g. "C11" (P1P), "N2S" (A1)
+    last_seen: Instant,
+}
+
+impl DiscoveredPrinter {
+    // Display name of the model, falls back to the model code for unknown models
+    pub fn model_name(&self) -> &str {
+        match self.model.as_str() {
+            "BL-P001" | "3DPrinter-X1-Carbon" => "X1C",
+            "BL-P002" | "3DPrinter-X1" => "X1",
+            "C13" => "X1E",
+            "C11" => "P1P",
+            "C12" => "P1S",
+            "N1" => "A1 mini",
+            "N2S" => "A1",
+            "O1D" => "H2D",
+            model => model,
+        }
+    }
+}
+
+pub struct PrinterDiscovery {
+    printers: Vec<DiscoveredPrinter>,
+    probe_signal: &'static Signal<NoopRawMutex, ()>,
+    observers: Vec<alloc::rc::Weak<RefCell<dyn PrinterDiscoveryObserver>>>,
+}
+
+impl PrinterDiscovery {
+    pub fn new(probe_signal: &'static Signal<NoopRawMutex, ()>) -> Self {
+        Self {
+            printers: Vec::new(),
+            probe_signal,
+            observers: Vec::new(),
+        }
+    }
+
+    // Discovered printers, sorted by name
+    pub fn printers(&self) -> &[DiscoveredPrinter] {
+        &self.printers
+    }
+
+    pub fn find_by_serial(&self, serial: &str) -> Option<&DiscoveredPrinter> {
+        self.printers.iter().find(|v| v.serial == serial)
+    }
+
+    // Send an M-SEARCH now instead of waiting for the next interval
+    pub fn probe(&self) {
+        self.probe_signal.signal(());
+    }
+
+    // Returns true if the list changed (new printer, or a printer with a new ip or name)
+    fn update(&mut self, printer: DiscoveredPrinter) -> bool {
+        if let Some(existing) = self.printers.iter_mut().find(|v| v.serial == printer.serial) {
+            let changed = existing.ip != printer.ip || existing.name != printer.name || existing.model != printer.model;
+            *existing = printer;
+            return changed;
+        }
+        term_info!("Discovered printer '{}' at {}", printer.name, printer.ip);
+        self.printers.push(printer);
+        self.printers.sort_by(|a, b| a.name.cmp(&b.name));
+        true
+    }
+
+    fn remove_expired(&mut self) -> bool {
+        let count = self.printers.len();
+        let now = Instant::now();
+        self.printers.retain(|v| now < v.last_seen + PRINTER_EXPIRY);
+        self.printers.len() != count
+    }
+
+    // Events
+
+    pub fn subscribe(&mut self, observer: alloc::rc::Weak<RefCell<dyn PrinterDiscoveryObserver>>) {
+        self.observers.push(observer);
+    }
+
+    fn notify_printers_update(&self) {
+        for weak_observer in self.observers.iter() {
+            let observer = weak_observer.upgrade().unwrap();
+            observer.borrow_mut().on_printers_update(self);
+        }
+    }
+}
+
+pub trait PrinterDiscoveryObserver {
+    fn on_printers_update(&self, printer_discovery: &PrinterDiscovery);
+}
+
+// Parses SSDP NOTIFY messages and M-SEARCH responses of Bambu printers
+fn parse_ssdp_message(message: &str) -> Option<DiscoveredPrinter> {
+    let mut is_printer = false;
+    let mut ip = None;
+    let mut name = None;
+    let mut serial = None;
+    let mut model = None;
+    for line in message.lines() {
+        let Some((header, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match header.trim().to_ascii_lowercase().as_str() {
+            "nt" | "st" => is_printer |= value.starts_with(BAMBU_PRINTER_URN),
+            "location" => ip = Ipv4Address::from_str(value).ok(),
+            "usn" => serial = Some(String::from(value)),
+            "devname.bambu.com" => name = Some(String::from(value)),
+            "devmodel.bambu.com" => model = Some(String::from(value)),
+            _ => (),
+        }
+    }
+    if !is_printer {
+        return None;
+    }
+    Some(DiscoveredPrinter {
+        ip: ip?,
+        serial: serial?,
+        name: name.unwrap_or(String::from("Unknown")),
+        model: model.unwrap_or_default(),
+        last_seen: Instant::now(),
+    })
+}
+
+pub fn create_model() -> Rc<RefCell<PrinterDiscovery>> {
+    let probe_signal = mk_static!(Signal<NoopRawMutex, ()>, Signal::new());
+    Rc::new(RefCell::new(PrinterDiscovery::new(probe_signal)))
+}
+
+pub async fn init(stack: Stack<'static>, printer_discovery: Rc<RefCell<PrinterDiscovery>>) {
+    let spawner = embassy_executor::Spawner::for_current_executor().await;
+    spawner.spawn(printer_discovery_task(stack, printer_discovery)).ok();
+}
+
+// Waits until the printer with the serial is discovered
+pub async fn wait_for_printer(printer_discovery: &Rc<RefCell<PrinterDiscovery>>, serial: &str) -> DiscoveredPrinter {
+    printer_discovery.borrow().probe();
+    loop {
+        if let Some(printer) = printer_discovery.borrow().find_by_serial(serial) {
+            return printer.clone();
+        }
+        Timer::after(Duration::from_millis(250)).await;
+    }
+}
+
+// Waits until the printer with the serial is discovered at an ip other than the one it is known by
+pub async fn wait_for_printer_new_ip(printer_discovery: &Rc<RefCell<PrinterDiscovery>>, serial: &str, ip: Ipv4Address) -> DiscoveredPrinter {
+    printer_discovery.borrow().probe();
+    loop {
+        if let Some(printer) = printer_discovery.borrow().find_by_serial(serial).filter(|v| v.ip != ip) {
+            return printer.clone();
+        }
+        Timer::after(Duration::from_millis(250)).await;
+    }
+}
+
+// Listens to the printers announcements, and actively searches for printers periodically (or when probed)
+#[embassy_executor::task]
+async fn printer_discovery_task(stack: Stack<'static>, printer_discovery: Rc<RefCell<PrinterDiscovery>>) {
+    loop {
+        if stack.config_v4().is_some() {
+            break;
+        }
+        Timer::after(Duration::from_millis(250)).await;
+    }
+
+    let (mut rx_buffer1, mut rx_buffer2, mut rx_buffer3) = ([0; 512], [0; 512], [0; 512]);
+    let (mut tx_buffer1, mut tx_buffer2, mut tx_buffer3) = ([0; 0], [0; 0], [0; 256]);
+    let (mut rx_meta1, mut rx_meta2, mut rx_meta3) = (
+        [embassy_net::udp::PacketMetadata::EMPTY; 16],
+        [embassy_net::udp::PacketMetadata::EMPTY; 16],
+        [embassy_net::udp::PacketMetadata::EMPTY; 16],
+    );
+    let (mut tx_meta1, mut tx_meta2, mut tx_meta3) = (
+        [embassy_net::udp::PacketMetadata::EMPTY; 16],
+        [embassy_net::udp::PacketMetadata::EMPTY; 16],
+        [embassy_net::udp::PacketMetadata::EMPTY; 4],
+    );
+    let (mut buf1, mut buf2, mut buf3) = ([0; 512], [0; 512], [0; 512]);
+
+    let _ = stack.join_multicast_group(SSDP_MULTICAST_ADDRESS);
+    let mut notify_socket1 = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta1, &mut rx_buffer1, &mut tx_meta1, &mut tx_buffer1);
+    notify_socket1.bind(SSDP_PORTS[0]).unwrap();
+    let mut notify_socket2 = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta2, &mut rx_buffer2, &mut tx_meta2, &mut tx_buffer2);
+    notify_socket2.bind(SSDP_PORTS[1]).unwrap();
+    // M-SEARCH is sent from an ephemeral port, responses are sent back to it
+    let mut search_socket = embassy_net::udp::UdpSocket::new(stack, &mut rx_meta3, &mut rx_buffer3, &mut tx_meta3, &mut tx_buffer3);
+    search_socket.bind(0).unwrap();
+
+    let m_search = alloc::format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_MULTICAST_ADDRESS}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: 3\r\nST: {BAMBU_PRINTER_URN}:1\r\n\r\n",
+        SSDP_PORTS[0]
+    );
+    let probe_signal = printer_discovery.borrow().probe_signal;
+    let mut next_search = Instant::now();
+
+    loop {
+        if Instant::now() >= next_search {
+            debug!("Sending SSDP M-SEARCH");
+            for port in SSDP_PORTS {
+                let remote_endpoint = embassy_net::IpEndpoint::new(SSDP_MULTICAST_ADDRESS.into(), port);
+                if let Err(e) = search_socket.send_to(m_search.as_bytes(), remote_endpoint).await {
+                    warn!("Failed to send SSDP M-SEARCH: {:?}", e);
+                }
+            }
+            next_search = Instant::now() + M_SEARCH_INTERVAL;
+        }
+
+        let wait_res = with_deadline(
+            next_search,
+            select3(
+                select3(notify_socket1.recv_from(&mut buf1), notify_socket2.recv_from(&mut buf2), search_socket.recv_from(&mut buf3)),
+                probe_signal.wait(),
+                Timer::after(Duration::from_secs(10)), // to remove expired printers also when nothing arrives
+            ),
+        )
+        .await;
+
+        let data = match wait_res {
+            Ok(Either3::First(Either3::First(Ok((len, _))))) => Some(&buf1[..len]),
+            Ok(Either3::First(Either3::Second(Ok((len, _))))) => Some(&buf2[..len]),
+            Ok(Either3::First(Either3::Third(Ok((len, _))))) => Some(&buf3[..len]),
+            Ok(Either3::Second(_)) => {
+                next_search = Instant::now();
+                None
+            }
+            _ => None,
+        };
+
+        let mut changed = false;
+        if let Some(printer) = data.and_then(|data| core::str::from_utf8(data).ok()).and_then(parse_ssdp_message) {
+            changed |= printer_discovery.borrow_mut().update(printer);
+        }
+        changed |= printer_discovery.borrow_mut().remove_expired();
+        if changed {
+            printer_discovery.borrow().notify_printers_update();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTIFY: &str = "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1990\r\nServer: UPnP/1.0\r\nLocation: 192.168.1.50\r\nNT: urn:bambulab-com:device:3dprinter:1\r\nNTS: ssdp:alive\r\nUSN: 01P00A123456789\r\nCache-Control: max-age=1800\r\nDevModel.bambu.com: C12\r\nDevName.bambu.com: Workshop P1S\r\nDevConnect.bambu.com: lan\r\nDevBind.bambu.com: free\r\nDevseclink.bambu.com: secure\r\nDevVersion.bambu.com: 01.08.00.00\r\n\r\n";
+
+    const SEARCH_RESPONSE: &str = "HTTP/1.1 200 OK\r\nServer: Buildroot/2018.02-rc3 UPnP/1.0 ssdpd/1.8\r\nDate: Fri, 21 Mar 2025 10:12:00 GMT\r\nLOCATION: 192.168.1.51\r\nST: urn:bambulab-com:device:3dprinter:1\r\nEXT:\r\nUSN: 0309CA471800123\r\nCache-Control: max-age=1800\r\nDevModel.bambu.com: N2S\r\nDevName.bambu.com: A1\r\n\r\n";
+
+    #[test]
+    fn parses_notify() {
+        let printer = parse_ssdp_message(NOTIFY).unwrap();
+        assert_eq!(printer.ip, Ipv4Address::new(192, 168, 1, 50));
+        assert_eq!(printer.serial, "01P00A123456789");
+        assert_eq!(printer.name, "Workshop P1S");
+        assert_eq!(printer.model, "C12");
+        assert_eq!(printer.model_name(), "P1S");
+    }
+
+    #[test]
+    fn parses_search_response() {
+        let printer = parse_ssdp_message(SEARCH_RESPONSE).unwrap();
+        assert_eq!(printer.ip, Ipv4Address::new(192, 168, 1, 51));
+        assert_eq!(printer.serial, "0309CA471800123");
+        assert_eq!(printer.name, "A1");
+        assert_eq!(printer.model_name(), "A1");
+    }
+
+    #[test]
+    fn defaults_missing_name_and_model() {
+        let message = "NOTIFY * HTTP/1.1\r\nLocation: 192.168.1.50\r\nNT: urn:bambulab-com:device:3dprinter:1\r\nUSN: 01P00A123456789\r\n\r\n";
+        let printer = parse_ssdp_message(message).unwrap();
+        assert_eq!(printer.name, "Unknown");
+        assert_eq!(printer.model, "");
+    }
+
+    #[test]
+    fn ignores_other_devices_and_incomplete_messages() {
+        let router = NOTIFY.replace(
+            "urn:bambulab-com:device:3dprinter:1",
+            "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+        );
+        assert!(parse_ssdp_message(&router).is_none());
+        // location is an url on other devices, printers give only the ip
+        let url_location = NOTIFY.replace("Location: 192.168.1.50", "Location: http://192.168.1.50:80/desc.xml");
+        assert!(parse_ssdp_message(&url_location).is_none());
+        let no_serial = NOTIFY.replace("USN: 01P00A123456789\r\n", "");
+        assert!(parse_ssdp_message(&no_serial).is_none());
+    }
+
+    #[test]
+    fn names_models() {
+        let printer = |model: &str| DiscoveredPrinter {
+            ip: Ipv4Address::new(192, 168, 1, 50),
+            name: String::from("Printer"),
+            serial: String::from("01P00A123456789"),
+            model: String::from(model),
+            last_seen: Instant::now(),
+        };
+        assert_eq!(printer("BL-P001").model_name(), "X1C");
+        assert_eq!(printer("3DPrinter-X1-Carbon").model_name(), "X1C");
+        assert_eq!(printer("N1").model_name(), "A1 mini");
+        assert_eq!(printer("O1D").model_name(), "H2D");
+        // unknown models are shown by their code
+        assert_eq!(printer("X9Z").model_name(), "X9Z");
+    }
+}
@@ -0,0 +1,377 @@
+use core::cell::RefCell;
+
+use alloc::{format, rc::Rc, string::String, vec::Vec};
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+use framework::prelude::*;
+
+use crate::{
+    bambu::{BambuPrinter, Filament, FilamentInfo, TrayState, BAMBU_BRAND},
+    clock,
+    spoolman::Spoolman,
+    AppSDCard,
+};
+
+// Inventory of the spools SpoolEase encountered, keyed by the tag id embedded in the descriptor of their tag (see nfc_task).
+// Kept in a json file on the SDCard, rewritten on every change. Without an SDCard it's kept only in memory.
+// Changes are also synced to Spoolman, when configured.
+// Filament used from spools in AMS trays is estimated from the drop of the AMS remain estimate of the tray (percent of the spool
+// weight), which the AMS reports only for Bambu spools and only with its remain estimate setting on. Usage of other spools isn't
+// tracked: the printer reports neither per tray usage nor the filament weight of the print, so print progress (mc_percent)
+// can't be turned into grams without the sliced file.
+
+const INVENTORY_FILENAME: &str = "/spools.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpoolLocation {
+    Shelf,
+    Tray(usize), // global tray id
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpoolRecord {
+    pub filament: FilamentInfo,
+    pub brand: Option<String>,
+    pub initial_weight: Option<u32>,   // grams
+    pub remaining_weight: Option<u32>, // grams
+    pub location: SpoolLocation,
+    pub first_seen: Option<u32>,  // unix time, None if first seen before the clock was synced
+    pub last_seen: Option<u32>,   // unix time
+    pub tray_remain: Option<u32>, // AMS remain estimate (percent) of the spool tray at the last update, usage is estimated from its drop
+}
+
+pub struct SpoolInventory {
+    sdcard: Rc<RefCell<AppSDCard>>,
+    spoolman: Rc<RefCell<Spoolman>>,
+    spools: HashMap<String, SpoolRecord>,
+    write_failed: bool,
+}
+
+impl SpoolInventory {
+    fn new(sdcard: Rc<RefCell<AppSDCard>>, spoolman: Rc<RefCell<Spoolman>>) -> Self {
+        let read_res = sdcard.borrow_mut().read_file_str(INVENTORY_FILENAME);
+        let spools = match read_res {
+            Ok(data) => match serde_json::from_str::<HashMap<String, SpoolRecord>>(&data) {
+                Ok(spools) => {
+                    term_info!("Loaded {} spools from SDCard inventory", spools.len());
+                    spools
+                }
+                Err(e) => {
+                    term_error!("Failed to parse spool inventory '{}' : {}", INVENTORY_FILENAME, e);
+                    HashMap::new()
+                }
+            },
+            // no inventory yet, or no SDCard
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            sdcard,
+            spoolman,
+            spools,
+            write_failed: false,
+        }
+    }
+
+    // A tag was read or encoded, location is the tray when encoded from a tray, otherwise the spool is in hand (Shelf)
+    pub fn spool_seen(&mut self, tag_id: &str, filament: &FilamentInfo, location: SpoolLocation) {
+        let now = clock::unix_time();
+        if let SpoolLocation::Tray(tray_id) = location {
+            self.clear_tray(tray_id, tag_id);
+        }
+        let spool = self.spools.entry(String::from(tag_id)).or_insert_with(|| {
+            info!("New spool {} in inventory", tag_id);
+            SpoolRecord {
+                filament: filament.clone(),
+                brand: None,
+                initial_weight: None,
+                remaining_weight: None,
+                location,
+                first_seen: now,
+                last_seen: now,
+                tray_remain: None,
+            }
+        });
+        // the tag may have been encoded again with other filament settings
+        spool.filament = filament.clone();
+        if let Some(brand) = &filament.spool_info.brand {
+            spool.brand = Some(brand.clone());
+        } else if filament.tray_uuid.is_some() {
+            spool.brand = Some(String::from(BAMBU_BRAND));
+        }
+        if spool.initial_weight.is_none() {
+            spool.initial_weight = filament.tray_weight;
+        }
+        if spool.remaining_weight.is_none() {
+            spool.remaining_weight = spool.initial_weight;
+        }
+        if spool.location != location {
+            spool.tray_remain = None;
+        }
+        spool.location = location;
+        spool.last_seen = now.or(spool.last_seen);
+        self.spoolman.borrow().spool_seen(tag_id, spool);
+        self.save();
+    }
+
+    // The staging of a read spool was set to a tray
+    pub fn spool_to_tray(&mut self, tag_id: &str, tray_id: usize) {
+        if !self.spools.contains_key(tag_id) {
+            return;
+        }
+        self.clear_tray(tray_id, tag_id);
+        let spool = self.spools.get_mut(tag_id).unwrap();
+        spool.location = SpoolLocation::Tray(tray_id);
+        spool.tray_remain = None;
+        spool.last_seen = clock::unix_time().or(spool.last_seen);
+        self.spoolman.borrow().spool_location(tag_id, spool.location);
+        self.save();
+    }
+
+    // Spools of trays that became empty were taken out of the AMS, back to the shelf.
+    // For spools still in trays, the filament used since the last update is deducted from their remaining weight.
+    // Returns the trays whose spool remaining weight just fell below low_filament_threshold
+    pub fn trays_update(&mut self, bambu_printer: &BambuPrinter, low_filament_threshold: u32) -> Vec<usize> {
+        let mut changed = false;
+        let mut low_filament_trays = Vec::new();
+        for (tag_id, spool) in self.spools.iter_mut() {
+            let SpoolLocation::Tray(tray_id) = spool.location else {
+                continue;
+            };
+            let Some(tray) = bambu_printer.get_tray(tray_id) else {
+                continue;
+            };
+            if tray.state == TrayState::Empty {
+                spool.location = SpoolLocation::Shelf;
+                spool.tray_remain = None;
+                self.spoolman.borrow().spool_location(tag_id, spool.location);
+                changed = true;
+                continue;
+            }
+            let Some(remain) = tray.remain else {
+                continue;
+            };
+            let prev_remain = spool.tray_remain.replace(remain);
+            if prev_remain != Some(remain) {
+                changed = true;
+            }
+            // the first estimate after the spool was set to the tray is only the baseline, and estimate raises aren't usage
+            let Some(used_percent) = prev_remain.and_then(|prev_remain| prev_remain.checked_sub(remain)).filter(|v| *v > 0) else {
+                continue;
+            };
+            let spool_weight = match &tray.filament {
+                Filament::Known(filament_info) => filament_info.tray_weight,
+                Filament::Unknown => None,
+            };
+            let Some(spool_weight) = spool_weight.or(spool.initial_weight) else {
+                continue;
+            };
+            // in hundredths of grams, rounded to grams
+            let used = used_percent * spool_weight;
+            let prev_remaining_weight = spool.remaining_weight.unwrap_or(spool_weight);
+            let remaining_weight = (prev_remaining_weight * 100 + 50).saturating_sub(used) / 100;
+            spool.remaining_weight = Some(remaining_weight);
+            if prev_remaining_weight >= low_filament_threshold && remaining_weight < low_filament_threshold {
+                low_filament_trays.push(tray_id);
+            }
+            debug!("Spool {} used {}g, {}g left", tag_id, used / 100, remaining_weight);
+            self.spoolman.borrow().spool_use(tag_id, used as f32 / 100.0);
+        }
+        if changed {
+            self.save();
+        }
+        low_filament_trays
+    }
+
+    pub fn spool(&self, tag_id: &str) -> Option<&SpoolRecord> {
+        self.spools.get(tag_id)
+    }
+
+    pub fn replacement_spools(&self, filament: &FilamentInfo, min_weight: u32) -> Vec<(&str, &SpoolRecord)> {
+        replacement_spools(&self.spools, filament, min_weight)
+    }
+
+    // The spool set to a tray (by its staging), if known
+    pub fn spool_in_tray(&self, tray_id: usize) -> Option<&SpoolRecord> {
+        self.spools.values().find(|spool| spool.location == SpoolLocation::Tray(tray_id))
+    }
+
+    // Reconciles the remaining weight kept in the tag descriptor with the inventory. Filament is only used, so the lower weight
+    // is the recent one (the tag is behind when usage was tracked since its last session, the inventory is behind when the spool
+    // was used elsewhere). Returns the weight to write back to the tag when the tag is behind
+    pub fn tag_remaining_weight(&mut self, tag_id: &str, tag_remaining_weight: Option<u32>) -> Option<u32> {
+        let spool = self.spools.get_mut(tag_id)?;
+        match (spool.remaining_weight, tag_remaining_weight) {
+            (Some(remaining_weight), Some(tag_remaining_weight)) if tag_remaining_weight < remaining_weight => {
+                spool.remaining_weight = Some(tag_remaining_weight);
+                self.save();
+                None
+            }
+            (Some(remaining_weight), tag_remaining_weight) if tag_remaining_weight != Some(remaining_weight) => Some(remaining_weight),
+            _ => None,
+        }
+    }
+
+    // Only one spool can be in a tray, a previous spool there was replaced without the tray reported empty in between
+    fn clear_tray(&mut self, tray_id: usize, except_tag_id: &str) {
+        for (tag_id, spool) in self.spools.iter_mut() {
+            if spool.location == SpoolLocation::Tray(tray_id) && tag_id != except_tag_id {
+                spool.location = SpoolLocation::Shelf;
+                spool.tray_remain = None;
+                self.spoolman.borrow().spool_location(tag_id, spool.location);
+            }
+        }
+    }
+
+    fn save(&mut self) {
+        let Ok(data) = serde_json::to_string(&self.spools) else {
+            return;
+        };
+        match self.sdcard.borrow_mut().write_file_str(INVENTORY_FILENAME, &data) {
+            Ok(_) => self.write_failed = false,
+            Err(e) => {
+                // reported once, not on every change
+                if !self.write_failed {
+                    term_error!("Failed to write spool inventory '{}' to SDCard : {}", INVENTORY_FILENAME, e);
+                }
+                self.write_failed = true;
+            }
+        }
+    }
+}
+
+// Spools on the shelf with the same filament and color, to replace a spool running out. Opened spools are suggested
+// first (least filament left first) to use them up, spools of unknown weight last
+fn replacement_spools<'a>(spools: &'a HashMap<String, SpoolRecord>, filament: &FilamentInfo, min_weight: u32) -> Vec<(&'a str, &'a SpoolRecord)> {
+    let mut spools = spools
+        .iter()
+        .filter(|(_, spool)| {
+            spool.location == SpoolLocation::Shelf
+                && spool.filament.tray_info_idx == filament.tray_info_idx
+                && spool.filament.tray_color.eq_ignore_ascii_case(&filament.tray_color)
+                && spool
+                    .remaining_weight
+                    .is_none_or(|remaining_weight| remaining_weight >= min_weight.max(1))
+        })
+        .map(|(tag_id, spool)| (tag_id.as_str(), spool))
+        .collect::<Vec<_>>();
+    spools.sort_by_key(|(_, spool)| spool.remaining_weight.unwrap_or(u32::MAX));
+    spools
+}
+
+// The tag id (ID parameter) of a descriptor, None if it's missing or still the placeholder (descriptor not written to a tag)
+pub fn descriptor_tag_id(descriptor: &str) -> Option<String> {
+    let (_, params) = descriptor.split_once('?')?;
+    params
+        .split('&')
+        .find_map(|param| param.strip_prefix("ID="))
+        .filter(|tag_id| !tag_id.is_empty() && *tag_id != crate::spool_tag::TAG_PLACEHOLDER)
+        .map(String::from)
+}
+
+// The remaining weight (RW parameter, grams) kept in a descriptor, None if the tag never had the spool usage written back
+pub fn descriptor_remaining_weight(descriptor: &str) -> Option<u32> {
+    let (_, params) = descriptor.split_once('?')?;
+    params
+        .split('&')
+        .find_map(|param| param.strip_prefix("RW="))
+        .and_then(|remaining_weight| remaining_weight.parse::<u32>().ok())
+}
+
+// The descriptor with its remaining weight (RW parameter) set, other parameters are kept as is
+pub fn descriptor_with_remaining_weight(descriptor: &str, remaining_weight: u32) -> String {
+    let (prefix, params) = descriptor.split_once('?').unwrap_or((descriptor, ""));
+    let remaining_weight_param = format!("RW={remaining_weight}");
+    let params = params
+        .split('&')
+        .filter(|param| !param.is_empty() && !param.starts_with("RW="))
+        .chain(core::iter::once(remaining_weight_param.as_str()))
+        .collect::<Vec<_>>();
+    format!("{prefix}?{}", params.join("&"))
+}
+
+pub fn create_model(sdcard: Rc<RefCell<AppSDCard>>, spoolman: Rc<RefCell<Spoolman>>) -> Rc<RefCell<SpoolInventory>> {
+    Rc::new(RefCell::new(SpoolInventory::new(sdcard, spoolman)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filament(tray_info_idx: &str, tray_color: &str) -> FilamentInfo {
+        serde_json::from_value(serde_json::json!({
+            "tray_info_idx": tray_info_idx,
+            "tray_type": "PLA",
+            "tray_color": tray_color,
+            "nozzle_temp_max": 230,
+            "nozzle_temp_min": 190,
+            "calibrations": {},
+            "tray_uuid": null,
+            "tray_weight": 1000,
+        }))
+        .unwrap()
+    }
+
+    fn spool(filament: FilamentInfo, remaining_weight: Option<u32>, location: SpoolLocation) -> SpoolRecord {
+        SpoolRecord {
+            filament,
+            brand: None,
+            initial_weight: Some(1000),
+            remaining_weight,
+            location,
+            first_seen: None,
+            last_seen: None,
+            tray_remain: None,
+        }
+    }
+
+    fn spools() -> HashMap<String, SpoolRecord> {
+        [
+            ("full", "GFA00", "FF0000FF", Some(1000), SpoolLocation::Shelf),
+            ("opened", "GFA00", "ff0000ff", Some(300), SpoolLocation::Shelf),
+            ("unknown", "GFA00", "FF0000FF", None, SpoolLocation::Shelf),
+            ("almost-empty", "GFA00", "FF0000FF", Some(40), SpoolLocation::Shelf),
+            ("empty", "GFA00", "FF0000FF", Some(0), SpoolLocation::Shelf),
+            ("in-tray", "GFA00", "FF0000FF", Some(500), SpoolLocation::Tray(1)),
+            ("other-color", "GFA00", "00FF00FF", Some(500), SpoolLocation::Shelf),
+            ("other-filament", "GFL99", "FF0000FF", Some(500), SpoolLocation::Shelf),
+        ]
+        .into_iter()
+        .map(|(tag_id, tray_info_idx, tray_color, remaining_weight, location)| {
+            (
+                String::from(tag_id),
+                spool(filament(tray_info_idx, tray_color), remaining_weight, location),
+            )
+        })
+        .collect()
+    }
+
+    fn tag_ids(replacements: Vec<(&str, &SpoolRecord)>) -> Vec<String> {
+        replacements.into_iter().map(|(tag_id, _)| String::from(tag_id)).collect()
+    }
+
+    #[test]
+    fn suggests_shelf_spools_of_same_filament_and_color() {
+        let spools = spools();
+        let replacements = replacement_spools(&spools, &filament("GFA00", "FF0000FF"), 0);
+        assert_eq!(tag_ids(replacements), ["almost-empty", "opened", "full", "unknown"]);
+    }
+
+    #[test]
+    fn leaves_out_spools_below_min_weight() {
+        let spools = spools();
+        let replacements = replacement_spools(&spools, &filament("GFA00", "FF0000FF"), 100);
+        assert_eq!(tag_ids(replacements), ["opened", "full", "unknown"]);
+        let replacements = replacement_spools(&spools, &filament("GFA00", "FF0000FF"), 1000);
+        assert_eq!(tag_ids(replacements), ["full", "unknown"]);
+    }
+
+    #[test]
+    fn no_suggestions_without_matching_spools() {
+        let spools = spools();
+        assert!(replacement_spools(&spools, &filament("GFA00", "0000FFFF"), 0).is_empty());
+        assert!(replacement_spools(&spools, &filament("GFB00", "FF0000FF"), 0).is_empty());
+        assert!(replacement_spools(&HashMap::new(), &filament("GFA00", "FF0000FF"), 0).is_empty());
+    }
+}
@@ -14,6 +14,7 @@ pub const TAG_PLACEHOLDER: &str = "$tag-id$";
 pub struct SpoolTag {
     tag_operation: &'static embassy_sync::signal::Signal<embassy_sync::blocking_mutex::raw::NoopRawMutex, TagOperation>,
     observers: Vec<alloc::rc::Weak<RefCell<dyn SpoolTagObserver>>>,
+    tag_update: RefCell<Option<String>>, // descriptor to write to the tag just read, set by observers of the read
 }
 
 pub trait SpoolTagObserver {
@@ -28,6 +29,11 @@ impl SpoolTag {
         }));
     }
 
+    // Called by an observer on ReadSuccess, the text is written to the tag while it's still on the reader
+    pub fn update_read_tag(&self, text: &str) {
+        self.tag_update.replace(Some(String::from(text)));
+    }
+
     pub fn cancel_operation(&self) {
         self.tag_operation.reset();
     }
@@ -72,7 +78,7 @@ pub enum Failure {
 pub enum Status {
     FoundTagNowReading,
     FoundTagNowWriting,
-    WriteSuccess(/*tray_id*/ usize),
+    WriteSuccess(/*tray_id*/ usize, /*descriptor written*/ String),
     ReadSuccess(String),
     Failure(Failure),
 }
@@ -94,6 +100,7 @@ pub async fn init(
     let spool_tag_rc = Rc::new(RefCell::new(SpoolTag {
         tag_operation,
         observers: Vec::new(),
+        tag_update: RefCell::new(None),
     }));
 
     spawner.spawn(nfc_task(spool_tag_rc.clone(), spi_device, irq, tag_operation, app_config)).ok();
@@ -214,7 +221,7 @@ pub async fn nfc_task(
                         match crate::nfc::write_ndef_url_record(&mut pn532, &final_tag_text, Duration::from_secs(2)).await {
                             Ok(_num_bytes_written) => {
                                 debug!("Wrote {} to tag", final_tag_text);
-                                spool_tag_rc.borrow().notify_status(Status::WriteSuccess(write_tag_reuest.tray_id));
+                                spool_tag_rc.borrow().notify_status(Status::WriteSuccess(write_tag_reuest.tray_id, final_tag_text));
                             }
                             Err(e) => {
                                 term_error!("Error writing to tag {:?}", e);
@@ -229,6 +236,14 @@ pub async fn nfc_task(
                             Ok(read_record) => {
                                 debug!("{}", read_record.url_payload());
                                 spool_tag_rc.borrow().notify_status(Status::ReadSuccess(read_record.url_payload()));
+                                let tag_update = spool_tag_rc.borrow().tag_update.take();
+                                if let Some(tag_update) = tag_update {
+                                    match crate::nfc::write_ndef_url_record(&mut pn532, &tag_update, Duration::from_secs(2)).await {
+                                        Ok(_num_bytes_written) => debug!("Updated tag to {}", tag_update),
+                                        // kept in the inventory, written on a later read
+                                        Err(e) => warn!("Error updating tag {:?}", e),
+                                    }
+                                }
                             }
                             Err(e) => {
                                 error!("Error reading tag {:?}", e);
@@ -0,0 +1,722 @@
+use core::{cell::RefCell, str::FromStr};
+
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
+use embassy_net::{tcp::TcpSocket, IpEndpoint, Ipv4Address, Stack};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::Write;
+use hashbrown::HashMap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use framework::prelude::*;
+
+use crate::{
+    app_config::AppConfig,
+    bambu::{BambuPrinter, AMS_HT_FIRST_ID},
+    spool_inventory::{SpoolLocation, SpoolRecord},
+};
+
+// Client of a Spoolman server (https://github.com/Donkie/Spoolman), keeping it in sync with the spools of the inventory.
+// Spools are matched by their tag id, kept in a spool extra field that SpoolEase registers on the server.
+// Lists are requested filtered and in pages, so a response fits the heap, and what was found is cached. Requests are queued and sent one at a time by a task,
+// a request that failed because the server is unreachable is retried (the requests after it wait in the queue), other failures drop it (the spool is synced
+// again the next time its tag is read). The network stack has no DNS, so the server is configured by ip, and over http (as Spoolman serves by default)
+
+const TAG_EXTRA_FIELD: &str = "spoolease_tag";
+const TAG_EXTRA_FIELD_NAME: &str = "SpoolEase Tag";
+const SHELF_LOCATION: &str = "Shelf";
+const REQUESTS_QUEUE_SIZE: usize = 8;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_RESPONSE_SIZE: usize = 32 * 1024;
+const LIST_PAGE_SIZE: usize = 10; // a spool is listed with its filament and vendor, about 1.5KB
+const FILAMENT_DIAMETER: f32 = 1.75; // mm, when the spool diameter isn't known
+const DEFAULT_DENSITY: f32 = 1.24; // g/cm³, of PLA
+
+#[derive(Debug)]
+pub enum Error {
+    ConnectFailed,
+    Io,
+    ResponseTooLarge,
+    ParseError,
+    HttpStatus(u16),
+}
+
+impl Error {
+    // The server is unreachable or failed on its side, the same request may succeed later
+    fn is_transient(&self) -> bool {
+        matches!(self, Error::ConnectFailed | Error::Io | Error::HttpStatus(500..))
+    }
+}
+
+enum Request {
+    SpoolSeen { tag_id: String, spool: SpoolRecord, location: String },
+    SpoolLocation { tag_id: String, location: String },
+    SpoolUse { tag_id: String, grams: f32 },
+}
+
+pub struct Spoolman {
+    requests: &'static Channel<NoopRawMutex, Request, REQUESTS_QUEUE_SIZE>,
+    app_config: Rc<RefCell<AppConfig>>,
+}
+
+impl Spoolman {
+    // Looks up the spool (or creates it) and syncs its filament and location
+    pub fn spool_seen(&self, tag_id: &str, spool: &SpoolRecord) {
+        self.request(Request::SpoolSeen {
+            tag_id: String::from(tag_id),
+            spool: spool.clone(),
+            location: self.location_name(spool.location),
+        });
+    }
+
+    pub fn spool_location(&self, tag_id: &str, location: SpoolLocation) {
+        self.request(Request::SpoolLocation {
+            tag_id: String::from(tag_id),
+            location: self.location_name(location),
+        });
+    }
+
+    // Filament used from the spool, reported only if configured to
+    pub fn spool_use(&self, tag_id: &str, grams: f32) {
+        if !self.app_config.borrow().spoolman_report_consumption {
+            return;
+        }
+        self.request(Request::SpoolUse {
+            tag_id: String::from(tag_id),
+            grams,
+        });
+    }
+
+    fn request(&self, request: Request) {
+        if self.app_config.borrow().spoolman_url.is_none() {
+            return;
+        }
+        if self.requests.try_send(request).is_err() {
+            warn!("Spoolman requests queue is full, request dropped");
+        }
+    }
+
+    // Spoolman location is free text, trays are named by the printer (if named) and the AMS slot
+    fn location_name(&self, location: SpoolLocation) -> String {
+        let SpoolLocation::Tray(tray_id) = location else {
+            return String::from(SHELF_LOCATION);
+        };
+        let tray_name = match BambuPrinter::get_ams_and_tray_id(tray_id) {
+            (254, 254) => String::from("External"),
+            (254, _) => String::from("External 2"),
+            (ams_id, _) if ams_id >= AMS_HT_FIRST_ID => format!("AMS HT {}", ams_id - AMS_HT_FIRST_ID + 1),
+            (ams_id, slot) => format!("AMS {} Slot {}", ams_id + 1, slot + 1),
+        };
+        match &self.app_config.borrow().printer_name {
+            Some(printer_name) => format!("{printer_name} {tray_name}"),
+            None => tray_name,
+        }
+    }
+}
+
+// Server address from the configured url, e.g. http://192.168.1.20:7912
+pub struct ServerAddress {
+    ip: Ipv4Address,
+    port: u16,
+    path: String, // prefix of the api paths, when Spoolman is served under a path
+}
+
+impl ServerAddress {
+    pub fn parse(url: &str) -> Option<Self> {
+        let url = url.trim().strip_prefix("http://")?;
+        let (host, path) = url.split_once('/').unwrap_or((url, ""));
+        let (ip, port) = match host.split_once(':') {
+            Some((ip, port)) => (ip, port.parse::<u16>().ok()?),
+            None => (host, 80),
+        };
+        let path = path.trim_end_matches('/');
+        Some(Self {
+            ip: Ipv4Address::from_str(ip).ok()?,
+            port,
+            path: if path.is_empty() { String::new() } else { format!("/{path}") },
+        })
+    }
+}
+
+// Spoolman API objects, only the fields used
+
+#[derive(Deserialize)]
+struct IdDTO {
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct ExtraFieldKeyDTO {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct SpoolDTO {
+    id: u32,
+    filament: IdDTO,
+    #[serde(default)]
+    extra: HashMap<String, String>, // values are json encoded
+}
+
+#[derive(Deserialize)]
+struct VendorDTO {
+    id: u32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct FilamentDTO {
+    id: u32,
+    material: Option<String>,
+    color_hex: Option<String>,
+    vendor: Option<VendorDTO>,
+}
+
+#[derive(Serialize)]
+struct ExtraFieldDTO<'a> {
+    name: &'a str,
+    field_type: &'a str,
+}
+
+#[derive(Serialize)]
+struct NewVendorDTO<'a> {
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct NewFilamentDTO<'a> {
+    name: &'a str,
+    material: &'a str,
+    color_hex: &'a str,
+    density: f32,
+    diameter: f32,
+    settings_extruder_temp: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    settings_bed_temp: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spool_weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vendor_id: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct NewSpoolDTO<'a> {
+    filament_id: u32,
+    location: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lot_nr: Option<&'a str>,
+    extra: HashMap<&'a str, String>,
+}
+
+#[derive(Serialize)]
+struct SpoolPatchDTO<'a> {
+    location: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filament_id: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct SpoolUseDTO {
+    use_weight: f32,
+}
+
+// Spoolman spool of a tag
+#[derive(Clone, Copy)]
+struct SpoolmanSpool {
+    id: u32,
+    filament_id: u32,
+}
+
+type FilamentKey = (String, String, Option<String>); // material, color, brand
+
+struct SpoolmanClient {
+    stack: Stack<'static>,
+    server: ServerAddress,
+    field_registered: bool,
+    spools: HashMap<String, SpoolmanSpool>, // by tag id, of the spools listed so far
+    filaments: HashMap<FilamentKey, u32>,   // filament ids
+}
+
+impl SpoolmanClient {
+    async fn process(&mut self, request: &Request) -> Result<(), Error> {
+        if !self.field_registered {
+            self.register_tag_field().await?;
+            self.field_registered = true;
+        }
+        match request {
+            Request::SpoolSeen { tag_id, spool, location } => {
+                let filament_id = self.find_or_create_filament(spool).await?;
+                match self.find_spool(tag_id, Some(filament_id)).await? {
+                    Some(spoolman_spool) => {
+                        let patch = SpoolPatchDTO {
+                            location,
+                            // the tag was encoded with another filament
+                            filament_id: Some(filament_id).filter(|v| *v != spoolman_spool.filament_id),
+                        };
+                        self.http_request("PATCH", &format!("/api/v1/spool/{}", spoolman_spool.id), Some(&patch))
+                            .await?;
+                        self.spools.insert(
+                            tag_id.clone(),
+                            SpoolmanSpool {
+                                filament_id,
+                                ..spoolman_spool
+                            },
+                        );
+                    }
+                    None => {
+                        let mut extra = HashMap::new();
+                        extra.insert(TAG_EXTRA_FIELD, serde_json::to_string(tag_id).unwrap());
+                        let new_spool = NewSpoolDTO {
+                            filament_id,
+                            location,
+                            initial_weight: spool.initial_weight,
+                            remaining_weight: spool.remaining_weight,
+                            price: spool.filament.spool_info.price.as_ref().and_then(|v| v.parse::<f32>().ok()),
+                            lot_nr: spool.filament.spool_info.lot.as_deref(),
+                            extra,
+                        };
+                        let response = self.http_request("POST", "/api/v1/spool", Some(&new_spool)).await?;
+                        let created = serde_json::from_str::<IdDTO>(&response).map_err(|_| Error::ParseError)?;
+                        term_info!("Spool added to Spoolman (id {})", created.id);
+                        self.spools.insert(tag_id.clone(), SpoolmanSpool { id: created.id, filament_id });
+                    }
+                }
+            }
+            Request::SpoolLocation { tag_id, location } => {
+                // a spool not in Spoolman yet is added with its location the next time its tag is read
+                if let Some(spoolman_spool) = self.find_spool(tag_id, None).await? {
+                    let patch = SpoolPatchDTO { location, filament_id: None };
+                    self.http_request("PATCH", &format!("/api/v1/spool/{}", spoolman_spool.id), Some(&patch))
+                        .await?;
+                }
+            }
+            Request::SpoolUse { tag_id, grams } => {
+                if let Some(spoolman_spool) = self.find_spool(tag_id, None).await? {
+                    let spool_use = SpoolUseDTO { use_weight: *grams };
+                    self.http_request("PUT", &format!("/api/v1/spool/{}/use", spoolman_spool.id), Some(&spool_use))
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The field is registered only when missing, registering it again would overwrite changes made to it on the server
+    async fn register_tag_field(&self) -> Result<(), Error> {
+        let response = self.http_request::<()>("GET", "/api/v1/field/spool", None).await?;
+        let fields = serde_json::from_str::<Vec<ExtraFieldKeyDTO>>(&response).map_err(|_| Error::ParseError)?;
+        if fields.iter().any(|v| v.key == TAG_EXTRA_FIELD) {
+            return Ok(());
+        }
+        let field = ExtraFieldDTO {
+            name: TAG_EXTRA_FIELD_NAME,
+            field_type: "text",
+        };
+        self.http_request("POST", &format!("/api/v1/field/spool/{TAG_EXTRA_FIELD}"), Some(&field))
+            .await?;
+        term_info!("Spoolman spool field '{}' registered", TAG_EXTRA_FIELD_NAME);
+        Ok(())
+    }
+
+    // Spools are listed when a tag isn't known yet, the tag field can't be filtered on by the server, so the listing is
+    // narrowed to the spool's filament when known. The tagged spools listed on the way are cached
+    async fn find_spool(&mut self, tag_id: &str, filament_id: Option<u32>) -> Result<Option<SpoolmanSpool>, Error> {
+        if let Some(spoolman_spool) = self.spools.get(tag_id) {
+            return Ok(Some(*spoolman_spool));
+        }
+        let path = match filament_id {
+            Some(filament_id) => format!("/api/v1/spool?allow_archived=true&filament.id={filament_id}"),
+            None => String::from("/api/v1/spool?allow_archived=true"),
+        };
+        let mut offset = 0;
+        loop {
+            let spools = self.list_page::<SpoolDTO>(&path, offset).await?;
+            let listed = spools.len();
+            for spool in spools {
+                let Some(spool_tag_id) = spool.extra.get(TAG_EXTRA_FIELD).and_then(|v| serde_json::from_str::<String>(v).ok()) else {
+                    continue;
+                };
+                self.spools.insert(
+                    spool_tag_id,
+                    SpoolmanSpool {
+                        id: spool.id,
+                        filament_id: spool.filament.id,
+                    },
+                );
+            }
+            if let Some(spoolman_spool) = self.spools.get(tag_id) {
+                return Ok(Some(*spoolman_spool));
+            }
+            if listed < LIST_PAGE_SIZE {
+                return Ok(None);
+            }
+            offset += listed;
+        }
+    }
+
+    // Filament of the same material, color and brand (if known), filaments already on the server are used as they are (their settings
+    // may have been tuned there), the spool's temperatures are set only on filaments created here
+    async fn find_or_create_filament(&mut self, spool: &SpoolRecord) -> Result<u32, Error> {
+        let filament = &spool.filament;
+        let color_hex = filament.tray_color.get(..6).unwrap_or(&filament.tray_color);
+        let key = (
+            filament.tray_type.to_ascii_uppercase(),
+            color_hex.to_ascii_uppercase(),
+            spool.brand.clone(),
+        );
+
+        if let Some(filament_id) = self.filaments.get(&key) {
+            return Ok(*filament_id);
+        }
+        if let Some(filament_id) = self.find_filament(&filament.tray_type, color_hex, spool.brand.as_deref()).await? {
+            self.filaments.insert(key, filament_id);
+            return Ok(filament_id);
+        }
+
+        let vendor_id = match &spool.brand {
+            Some(brand) => Some(self.find_or_create_vendor(brand).await?),
+            None => None,
+        };
+        let spool_info = &filament.spool_info;
+        let new_filament = NewFilamentDTO {
+            name: spool_info.name.as_ref().unwrap_or(&filament.tray_type),
+            material: &filament.tray_type,
+            color_hex,
+            density: material_density(&filament.tray_type),
+            diameter: spool_info
+                .diameter
+                .as_ref()
+                .and_then(|v| v.parse::<f32>().ok())
+                .unwrap_or(FILAMENT_DIAMETER),
+            settings_extruder_temp: (filament.nozzle_temp_min + filament.nozzle_temp_max) / 2,
+            settings_bed_temp: spool_info.bed_temp,
+            weight: spool.initial_weight,
+            spool_weight: spool_info.spool_weight,
+            vendor_id,
+        };
+        let response = self.http_request("POST", "/api/v1/filament", Some(&new_filament)).await?;
+        let created = serde_json::from_str::<IdDTO>(&response).map_err(|_| Error::ParseError)?;
+        self.filaments.insert(key, created.id);
+        Ok(created.id)
+    }
+
+    // The server's filters match partially (and colors by similarity), so the filtered list is matched exactly
+    async fn find_filament(&self, material: &str, color_hex: &str, brand: Option<&str>) -> Result<Option<u32>, Error> {
+        let mut path = format!(
+            "/api/v1/filament?material={}&color_hex={}",
+            exact_query_term(material),
+            url_encode(color_hex)
+        );
+        if let Some(brand) = brand {
+            path.push_str(&format!("&vendor.name={}", exact_query_term(brand)));
+        }
+        let mut offset = 0;
+        loop {
+            let filaments = self.list_page::<FilamentDTO>(&path, offset).await?;
+            let found = filaments.iter().find(|v| {
+                v.material.as_deref().is_some_and(|value| value.eq_ignore_ascii_case(material))
+                    && v.color_hex.as_deref().is_some_and(|value| value.eq_ignore_ascii_case(color_hex))
+                    && brand.is_none_or(|brand| v.vendor.as_ref().is_some_and(|vendor| vendor.name == brand))
+            });
+            if let Some(found) = found {
+                return Ok(Some(found.id));
+            }
+            if filaments.len() < LIST_PAGE_SIZE {
+                return Ok(None);
+            }
+            offset += filaments.len();
+        }
+    }
+
+    async fn find_or_create_vendor(&mut self, name: &str) -> Result<u32, Error> {
+        let path = format!("/api/v1/vendor?name={}", exact_query_term(name));
+        let mut offset = 0;
+        loop {
+            let vendors = self.list_page::<VendorDTO>(&path, offset).await?;
+            if let Some(vendor) = vendors.iter().find(|v| v.name == name) {
+                return Ok(vendor.id);
+            }
+            if vendors.len() < LIST_PAGE_SIZE {
+                break;
+            }
+            offset += vendors.len();
+        }
+        let response = self.http_request("POST", "/api/v1/vendor", Some(&NewVendorDTO { name })).await?;
+        let created = serde_json::from_str::<IdDTO>(&response).map_err(|_| Error::ParseError)?;
+        Ok(created.id)
+    }
+
+    // A page of a list, path already has a query
+    async fn list_page<T: DeserializeOwned>(&self, path: &str, offset: usize) -> Result<Vec<T>, Error> {
+        let path = format!("{path}&sort=id:asc&limit={LIST_PAGE_SIZE}&offset={offset}");
+        let response = self.http_request::<()>("GET", &path, None).await?;
+        serde_json::from_str::<Vec<T>>(&response).map_err(|_| Error::ParseError)
+    }
+
+    // A request on a connection of its own (Connection: close), returns the body of a successful response
+    async fn http_request<T: Serialize>(&self, method: &str, path: &str, body: Option<&T>) -> Result<String, Error> {
+        let body = match body {
+            Some(body) => serde_json::to_string(body).map_err(|_| Error::ParseError)?,
+            None => String::new(),
+        };
+        debug!("Spoolman {} {} {}", method, path, body);
+
+        let mut rx_buffer = vec![0; 4096];
+        let mut tx_buffer = vec![0; 2048];
+        let mut socket = TcpSocket::new(self.stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(RESPONSE_TIMEOUT));
+        let remote_endpoint = IpEndpoint::new(self.server.ip.into(), self.server.port);
+        match with_timeout(CONNECT_TIMEOUT, socket.connect(remote_endpoint)).await {
+            Ok(Ok(())) => (),
+            _ => return Err(Error::ConnectFailed),
+        }
+
+        let request = format!(
+            "{method} {}{path} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nAccept: application/json\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            self.server.path,
+            self.server.ip,
+            self.server.port,
+            body.len()
+        );
+        if socket.write_all(request.as_bytes()).await.is_err() {
+            socket.abort();
+            return Err(Error::Io);
+        }
+
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            match socket.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(len) => {
+                    response.extend_from_slice(&buf[..len]);
+                    if response.len() > MAX_RESPONSE_SIZE {
+                        socket.abort();
+                        return Err(Error::ResponseTooLarge);
+                    }
+                }
+                Err(_) => {
+                    socket.abort();
+                    return Err(Error::Io);
+                }
+            }
+        }
+        socket.close();
+
+        let (status, body) = parse_http_response(&response)?;
+        if !(200..300).contains(&status) {
+            return Err(Error::HttpStatus(status));
+        }
+        Ok(body)
+    }
+}
+
+fn parse_http_response(response: &[u8]) -> Result<(u16, String), Error> {
+    let header_end = response.windows(4).position(|v| v == b"\r\n\r\n").ok_or(Error::ParseError)?;
+    let head = core::str::from_utf8(&response[..header_end]).map_err(|_| Error::ParseError)?;
+    let body = &response[header_end + 4..];
+
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(Error::ParseError)?;
+    let chunked = lines.any(|line| {
+        line.split_once(':')
+            .is_some_and(|(header, value)| header.trim().eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked"))
+    });
+    let body = if chunked { dechunk(body)? } else { body.to_vec() };
+    let body = String::from_utf8(body).map_err(|_| Error::ParseError)?;
+    Ok((status, body))
+}
+
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|v| v == b"\r\n").ok_or(Error::ParseError)?;
+        let size = core::str::from_utf8(&data[..line_end]).map_err(|_| Error::ParseError)?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| Error::ParseError)?;
+        if size == 0 {
+            return Ok(body);
+        }
+        data = &data[line_end + 2..];
+        body.extend_from_slice(data.get(..size).ok_or(Error::ParseError)?);
+        data = data.get(size..).and_then(|v| v.strip_prefix(b"\r\n")).ok_or(Error::ParseError)?;
+    }
+}
+
+// Spoolman's list filters search for the term within the value, unless it is quoted
+fn exact_query_term(value: &str) -> String {
+    url_encode(&format!("\"{value}\""))
+}
+
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+// Density is required when creating a filament, these are typical values
+fn material_density(tray_type: &str) -> f32 {
+    let tray_type = tray_type.to_ascii_uppercase();
+    let densities: [(&str, f32); 10] = [
+        ("PLA", 1.24),
+        ("PETG", 1.27),
+        ("PET", 1.27),
+        ("ABS", 1.04),
+        ("ASA", 1.07),
+        ("TPU", 1.21),
+        ("PC", 1.20),
+        ("PA", 1.14),
+        ("PVA", 1.23),
+        ("HIPS", 1.04),
+    ];
+    densities
+        .iter()
+        .filter(|(material, _)| tray_type.starts_with(material))
+        .max_by_key(|(material, _)| material.len())
+        .map(|(_, density)| *density)
+        .unwrap_or(DEFAULT_DENSITY)
+}
+
+pub async fn init(stack: Stack<'static>, app_config: Rc<RefCell<AppConfig>>) -> Rc<RefCell<Spoolman>> {
+    let requests = mk_static!(Channel<NoopRawMutex, Request, REQUESTS_QUEUE_SIZE>, Channel::new());
+    let spawner = embassy_executor::Spawner::for_current_executor().await;
+    spawner.spawn(spoolman_task(stack, app_config.clone(), requests)).ok();
+    Rc::new(RefCell::new(Spoolman { requests, app_config }))
+}
+
+#[embassy_executor::task]
+async fn spoolman_task(
+    stack: Stack<'static>,
+    app_config: Rc<RefCell<AppConfig>>,
+    requests: &'static Channel<NoopRawMutex, Request, REQUESTS_QUEUE_SIZE>,
+) {
+    let mut client: Option<SpoolmanClient> = None;
+    let mut client_url = String::new();
+    let mut failing = false;
+    let mut retry: Option<Request> = None;
+    loop {
+        let request = match retry.take() {
+            Some(request) => {
+                Timer::after(RETRY_INTERVAL).await;
+                request
+            }
+            None => requests.receive().await,
+        };
+        let Some(url) = app_config.borrow().spoolman_url.clone() else {
+            continue;
+        };
+        // the url may have been changed in the web config, and what's known is of the previous server
+        if client.is_none() || client_url != url {
+            client = ServerAddress::parse(&url).map(|server| SpoolmanClient {
+                stack,
+                server,
+                field_registered: false,
+                spools: HashMap::new(),
+                filaments: HashMap::new(),
+            });
+            client_url = url.clone();
+        }
+        let Some(client) = client.as_mut() else {
+            if !failing {
+                term_error!("Invalid Spoolman URL '{}', expected http://<ip>:<port>", url);
+            }
+            failing = true;
+            continue;
+        };
+        match client.process(&request).await {
+            Ok(_) => failing = false,
+            Err(e) => {
+                // a spool or filament deleted on the server, listed again on next request
+                if let Error::HttpStatus(404) = e {
+                    client.spools.clear();
+                    client.filaments.clear();
+                }
+                // reported once, not on every request while the server is unavailable
+                if !failing {
+                    term_error!("Spoolman request failed : {:?}", e);
+                }
+                failing = true;
+                if e.is_transient() {
+                    retry = Some(request);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dechunks_body() {
+        let body = dechunk(b"5\r\n[{\"id\r\nB;ext=1\r\n\":1},{\"id\":\r\n2\r\n2}\r\n1\r\n]\r\n0\r\n\r\n").unwrap();
+        assert_eq!(body, b"[{\"id\":1},{\"id\":2}]");
+        assert_eq!(dechunk(b"0\r\n\r\n").unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_broken_chunks() {
+        // chunk shorter than its size
+        assert!(dechunk(b"5\r\nabc\r\n0\r\n\r\n").is_err());
+        // missing the last chunk
+        assert!(dechunk(b"3\r\nabc\r\n").is_err());
+        assert!(dechunk(b"x\r\nabc\r\n0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_http_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 8\r\n\r\n{\"id\":3}";
+        let (status, body) = parse_http_response(response).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, "{\"id\":3}");
+
+        let response = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        let (status, body) = parse_http_response(response).unwrap();
+        assert_eq!(status, 404);
+        assert_eq!(body, "");
+    }
+
+    #[test]
+    fn parses_chunked_http_response() {
+        let response = b"HTTP/1.1 200 OK\r\ntransfer-encoding:  Chunked\r\n\r\n4\r\n[1,2\r\n1\r\n]\r\n0\r\n\r\n";
+        let (status, body) = parse_http_response(response).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, "[1,2]");
+    }
+
+    #[test]
+    fn rejects_broken_http_response() {
+        // headers not completed
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n").is_err());
+        assert!(parse_http_response(b"HTTP/1.1 OK\r\n\r\n").is_err());
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\n\r\n\xff").is_err());
+    }
+
+    #[test]
+    fn quotes_exact_query_terms() {
+        assert_eq!(exact_query_term("PLA"), "%22PLA%22");
+        assert_eq!(exact_query_term("Bambu Lab"), "%22Bambu%20Lab%22");
+        assert_eq!(exact_query_term("PLA+ & \"Silk\""), "%22PLA%2B%20%26%20%22Silk%22%22");
+    }
+}
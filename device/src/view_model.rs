@@ -1,9 +1,14 @@
-use core::{cell::RefCell, str::FromStr};
+use core::{
+    cell::{Cell, RefCell},
+    str::FromStr,
+};
 
 use alloc::{
+    collections::VecDeque,
     format,
     rc::Rc,
     string::{String, ToString},
+    vec,
     vec::Vec,
 };
 use embassy_net::Stack;
@@ -13,10 +18,14 @@ use framework::prelude::*;
 use framework::{ framework::{FrameworkObserver, WebConfigMode}, terminal::{self, term_mut, TerminalObserver} };
 
 use crate::{
-    app_config::{self, AppConfig, AppControlObserver},
-    bambu::{self, BambuPrinter, BambuPrinterObserver, Filament, FilamentInfo, TrayState},
+    app_config::{self, AppConfig, AppControlObserver, PrinterConnectionStatus},
+    bambu::{self, AmsSettings, BambuPrinter, BambuPrinterObserver, CommandContext, CommandResult, Filament, FilamentInfo, TrayState, MAIN_EXTRUDER},
     filament_staging::FilamentStaging,
+    printer_discovery::{self, PrinterDiscovery, PrinterDiscoveryObserver},
+    settings::CALIBRATIONS_BACKUP_FILENAME,
+    spool_inventory::{self, SpoolInventory, SpoolLocation},
     spool_tag::{self, SpoolTagObserver, Status},
+    AppSDCard,
 };
 
 pub struct ViewModel {
@@ -30,11 +39,29 @@ pub struct ViewModel {
     #[allow(dead_code)]
     app_config: Rc<RefCell<AppConfig>>,
     bambu_printer_model: Rc<RefCell<bambu::BambuPrinter>>,
+    printer_discovery_model: Rc<RefCell<PrinterDiscovery>>,
     spool_tag_model: Rc<RefCell<spool_tag::SpoolTag>>,
+    spool_inventory_model: Rc<RefCell<SpoolInventory>>,
     filament_staging: Rc<RefCell<FilamentStaging>>,
+    sdcard: Rc<RefCell<AppSDCard>>,
+    pending_auto_assign_tray: Cell<Option<usize>>, // tray that started reading, staging is applied to it once reading completes
+    runout_tray: Cell<Option<usize>>, // tray the printer reported ran out of filament, alerted once
+    low_filament_trays: RefCell<VecDeque<usize>>, // trays that got low on filament, alerted one at a time
+    shown_calibrations: RefCell<Option<(u32, Option<String>)>>, // calibrations version and nozzle of the calibrations list shown
+    filament_change: Rc<Cell<Option<FilamentChange>>>, // load / unload started from the UI, followed through the tray states
+}
+
+// Filament load into the extruder, or unload, of a tray
+#[derive(Debug, Clone, Copy)]
+struct FilamentChange {
+    tray_id: usize,
+    load: bool,
+    unloading_tray: Option<usize>, // when loading, the tray loaded before, the printer unloads it first
+    progress_seen: bool,
 }
 
 impl ViewModel {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         // Framework
         stack: Stack<'static>,
@@ -43,7 +70,11 @@ impl ViewModel {
         // Application
         app_config: Rc<RefCell<AppConfig>>,
         bambu_printer_model: Rc<RefCell<bambu::BambuPrinter>>,
+        printer_discovery_model: Rc<RefCell<PrinterDiscovery>>,
         spool_tag_model: Rc<RefCell<spool_tag::SpoolTag>>,
+        spool_inventory_model: Rc<RefCell<SpoolInventory>>,
+        filament_staging: Rc<RefCell<FilamentStaging>>,
+        sdcard: Rc<RefCell<AppSDCard>>,
     ) -> Rc<RefCell<ViewModel>> {
         let terminal_view_model = Rc::new(RefCell::new(TerminalViewModel {
             ui_weak: ui_weak.clone()
@@ -62,9 +93,17 @@ impl ViewModel {
             _terminal_view_model: terminal_view_model, // used by Terminal with weak reference, hold it so it won't be released
             // Application
             bambu_printer_model: bambu_printer_model.clone(),
+            printer_discovery_model: printer_discovery_model.clone(),
             spool_tag_model: spool_tag_model.clone(),
+            spool_inventory_model,
             app_config: app_config.clone(),
-            filament_staging: Rc::new(RefCell::new(FilamentStaging::new())),
+            filament_staging,
+            sdcard,
+            pending_auto_assign_tray: Cell::new(None),
+            runout_tray: Cell::new(None),
+            low_filament_trays: RefCell::new(VecDeque::new()),
+            shown_calibrations: RefCell::new(None),
+            filament_change: Rc::new(Cell::new(None)),
         }));
 
         let trait_for_bambu_printer_rc: alloc::rc::Rc<core::cell::RefCell<dyn bambu::BambuPrinterObserver>> = view_model_rc.clone();
@@ -72,6 +111,11 @@ impl ViewModel {
             alloc::rc::Rc::downgrade(&trait_for_bambu_printer_rc);
         bambu_printer_model.borrow_mut().subscribe(trait_for_bambu_printer_weak);
 
+        let trait_for_printer_discovery_rc: alloc::rc::Rc<core::cell::RefCell<dyn printer_discovery::PrinterDiscoveryObserver>> = view_model_rc.clone();
+        let trait_for_printer_discovery_weak: alloc::rc::Weak<core::cell::RefCell<dyn printer_discovery::PrinterDiscoveryObserver>> =
+            alloc::rc::Rc::downgrade(&trait_for_printer_discovery_rc);
+        printer_discovery_model.borrow_mut().subscribe(trait_for_printer_discovery_weak);
+
         let trait_for_spool_tag_rc: alloc::rc::Rc<core::cell::RefCell<dyn spool_tag::SpoolTagObserver>> = view_model_rc.clone();
         let trait_for_spool_tag_weak: alloc::rc::Weak<core::cell::RefCell<dyn spool_tag::SpoolTagObserver>> =
             alloc::rc::Rc::downgrade(&trait_for_spool_tag_rc);
@@ -163,17 +207,19 @@ impl ViewModel {
 
         let moved_filament_staging = self.filament_staging.clone();
         let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_spool_inventory = self.spool_inventory_model.clone();
         let moved_ui = self.ui_weak.clone();
         self.ui_weak
             .unwrap()
             .global::<crate::app::AppBackend>()
             .on_set_staging_to_tray(move |tray_id: i32| {
-                Self::set_staging_to_tray(&moved_filament_staging, &moved_bambu_printer, &moved_ui, tray_id);
+                Self::set_staging_to_tray(&moved_filament_staging, &moved_bambu_printer, &moved_spool_inventory, &moved_ui, tray_id);
             });
 
         let moved_filament_staging = self.filament_staging.clone();
         let moved_bambu_printer = self.bambu_printer_model.clone();
         let moved_spool_tag = self.spool_tag_model.clone();
+        let moved_spool_inventory = self.spool_inventory_model.clone();
         let moved_ui = self.ui_weak.clone();
         let moved_app_config = self.app_config.clone();
         moved_ui
@@ -184,19 +230,31 @@ impl ViewModel {
                 let spool_tag = moved_spool_tag.borrow();
                 let bambu_printer = moved_bambu_printer.borrow();
                 let tray_id = usize::try_from(tray_id).unwrap();
+                let no_filament = Filament::Unknown;
                 let filament = if tray_id == 999 {
                     // Staging
                     &moved_filament_staging.borrow().filament_info
-                } else if tray_id == 254 {
-                    // External
-                    &bambu_printer.virt_tray.filament
+                } else if let Some(tray) = bambu_printer.get_tray(tray_id) {
+                    // AMS or External
+                    &tray.filament
                 } else {
-                    &bambu_printer.ams_trays[tray_id].filament
+                    &no_filament
                 };
+                let mut left_off = String::new();
                 if let Filament::Known(f) = filament {
-                    spool_tag.write_tag(&f.to_descriptor(&moved_app_config.borrow().printer_name), tray_id);
+                    let f = tray_filament_with_spool_info(&moved_spool_inventory.borrow(), tray_id, f);
+                    let (descriptor, left_off_fields) = f.to_tag_descriptor(&moved_app_config.borrow().printer_name);
+                    if !left_off_fields.is_empty() {
+                        left_off = left_off_fields.join(", ");
+                        term_error!("Spool info doesn't fit in the tag, encoding without: {}", left_off);
+                    }
+                    spool_tag.write_tag(&descriptor, tray_id);
                     info!("Sent the write request of tray {} over signal", tray_id);
                 }
+                moved_ui
+                    .unwrap()
+                    .global::<crate::app::AppState>()
+                    .set_encode_left_off(SharedString::from(left_off));
                 // TODO: Get proper timeout fron config and pass it in the write_tag to spool_tag
                 10
             });
@@ -206,72 +264,625 @@ impl ViewModel {
         moved_ui.unwrap().global::<crate::app::AppBackend>().on_cancel_encode(move || {
             moved_spool_tag.borrow().cancel_operation();
         });
+
+        self.init_calibrations();
+        self.init_printers_discovery();
+        self.init_filament_change();
+        self.init_ams_settings();
+        self.init_ams_drying();
+        self.init_reset_tray();
+        self.init_spool_suggestions();
+    }
+
+    fn init_spool_suggestions(&mut self) {
+        let moved_filament_staging = self.filament_staging.clone();
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_spool_inventory = self.spool_inventory_model.clone();
+        let moved_app_config = self.app_config.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak.unwrap().global::<crate::app::AppBackend>().on_stage_spool(move |tag_id| {
+            let Some(spool) = moved_spool_inventory.borrow().spool(&tag_id).cloned() else {
+                return;
+            };
+            let bambu_printer = moved_bambu_printer.borrow();
+            // the calibrations kept with the spool are matched to the printer calibrations as when its tag is read
+            let descriptor = spool.filament.to_descriptor(&moved_app_config.borrow().printer_name);
+            let Ok(filament_info) = FilamentInfo::from_descriptor(&descriptor, &bambu_printer) else {
+                term_error!("Failed to stage spool {}", tag_id);
+                return;
+            };
+            info!("Staging spool {} suggested from inventory", tag_id);
+            let ui_spool_info = filament_info_to_ui_spool_info(bambu_printer, &filament_info);
+            {
+                let mut filament_staging = moved_filament_staging.borrow_mut();
+                filament_staging.filament_info = Filament::Known(filament_info);
+                filament_staging.tag_id = Some(String::from(tag_id.as_str()));
+            }
+            moved_ui
+                .unwrap()
+                .global::<crate::app::AppState>()
+                .invoke_spool_suggestion_staged(ui_spool_info);
+        });
+    }
+
+    // Offers the spools of the inventory that can replace the spool of the tray, the chosen one is staged.
+    // Returns false if not alerted, when the UI is in the middle of another operation or alert
+    fn suggest_replacement_spools(&self, bambu_printer: &BambuPrinter, tray_id: usize, alert: &str) -> bool {
+        let spool_inventory = self.spool_inventory_model.borrow();
+        // the tray filament, or the filament of the spool set to it if the printer doesn't report it
+        let filament = match bambu_printer.get_tray(tray_id).map(|tray| &tray.filament) {
+            Some(Filament::Known(filament_info)) => Some(filament_info),
+            _ => spool_inventory.spool_in_tray(tray_id).map(|spool| &spool.filament),
+        };
+        let min_weight = self.app_config.borrow().low_filament_threshold;
+        let suggestions = filament
+            .map(|filament| spool_inventory.replacement_spools(filament, min_weight))
+            .unwrap_or_default()
+            .into_iter()
+            .take(MAX_SPOOL_SUGGESTIONS)
+            .map(|(tag_id, spool)| crate::app::UiSpoolSuggestion {
+                tag_id: SharedString::from(tag_id),
+                remaining_weight: spool.remaining_weight.unwrap_or(0) as i32,
+            })
+            .collect::<Vec<_>>();
+        info!("Tray {} {}, {} spools suggested", tray_id, alert, suggestions.len());
+        self.ui_weak.unwrap().global::<crate::app::AppState>().invoke_low_filament_alert(
+            tray_id as i32,
+            SharedString::from(alert),
+            slint::ModelRc::from(Rc::new(slint::VecModel::from(suggestions))),
+        )
+    }
+
+    // The next of the trays low on filament is alerted once the UI is free for it, retried on every trays update
+    fn alert_low_filament_trays(&self, bambu_printer: &BambuPrinter, low_filament_threshold: u32) {
+        loop {
+            let Some(tray_id) = self.low_filament_trays.borrow().front().copied() else {
+                return;
+            };
+            let remaining_weight = self
+                .spool_inventory_model
+                .borrow()
+                .spool_in_tray(tray_id)
+                .and_then(|spool| spool.remaining_weight);
+            // no longer low, the spool was replaced or taken out while waiting
+            let Some(remaining_weight) = remaining_weight.filter(|v| *v < low_filament_threshold) else {
+                self.low_filament_trays.borrow_mut().pop_front();
+                continue;
+            };
+            if self.suggest_replacement_spools(bambu_printer, tray_id, &format!("Low Filament, {remaining_weight}g Left")) {
+                self.low_filament_trays.borrow_mut().pop_front();
+            }
+            return;
+        }
+    }
+
+    fn init_ams_settings(&mut self) {
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_set_ams_setting(move |setting, enabled| {
+                let mut ams_settings = AmsSettings::default();
+                match setting {
+                    crate::app::UiAmsSetting::InsertionRead => ams_settings.insertion_read = Some(enabled),
+                    crate::app::UiAmsSetting::StartupRead => ams_settings.startup_read = Some(enabled),
+                    crate::app::UiAmsSetting::RemainEstimate => ams_settings.remain_estimate = Some(enabled),
+                    crate::app::UiAmsSetting::AutoRefill => ams_settings.auto_refill = Some(enabled),
+                }
+                let mut bambu_printer = moved_bambu_printer.borrow_mut();
+                if let Err(e) = bambu_printer.set_ams_settings(&ams_settings) {
+                    term_error!("Failed to set AMS settings, not reported by printer yet ({e:?})");
+                }
+                update_ui_ams_settings(&moved_ui.unwrap(), &bambu_printer);
+            });
+    }
+
+    fn init_ams_drying(&mut self) {
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_start_ams_drying(move |ams_id| {
+                let ui = moved_ui.unwrap();
+                let app_state = ui.global::<crate::app::AppState>();
+                // completion is reported by the printer's response to the command (see on_command_result)
+                match moved_bambu_printer.borrow().start_ams_drying(ams_id as usize) {
+                    Ok(_) => app_state.invoke_ams_drying_started(true, ams_id),
+                    Err(_) => app_state.invoke_ams_drying_failed(true, ams_id, SharedString::from("No Known Filament")),
+                }
+            });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_stop_ams_drying(move |ams_id| {
+                moved_bambu_printer.borrow().stop_ams_drying(ams_id as usize);
+                let ui = moved_ui.unwrap();
+                ui.global::<crate::app::AppState>().invoke_ams_drying_started(false, ams_id);
+            });
+    }
+
+    fn init_filament_change(&mut self) {
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_filament_change = self.filament_change.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak.unwrap().global::<crate::app::AppBackend>().on_load_tray(move |tray_id| {
+            let bambu_printer = moved_bambu_printer.borrow();
+            let unloading_tray = bambu_printer.loaded_tray();
+            bambu_printer.load_tray(tray_id);
+            let tray_id = usize::try_from(tray_id).unwrap();
+            moved_filament_change.set(Some(FilamentChange {
+                tray_id,
+                load: true,
+                unloading_tray,
+                progress_seen: false,
+            }));
+            let (ams_id, slot) = BambuPrinter::get_ams_and_tray_id(tray_id);
+            moved_ui
+                .unwrap()
+                .global::<crate::app::AppState>()
+                .invoke_filament_change_started(true, ams_id as i32, slot as i32);
+        });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_filament_change = self.filament_change.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak.unwrap().global::<crate::app::AppBackend>().on_unload_tray(move |tray_id| {
+            moved_bambu_printer.borrow().unload_tray();
+            let tray_id = usize::try_from(tray_id).unwrap();
+            moved_filament_change.set(Some(FilamentChange {
+                tray_id,
+                load: false,
+                unloading_tray: Some(tray_id),
+                progress_seen: false,
+            }));
+            let (ams_id, slot) = BambuPrinter::get_ams_and_tray_id(tray_id);
+            moved_ui
+                .unwrap()
+                .global::<crate::app::AppState>()
+                .invoke_filament_change_started(false, ams_id as i32, slot as i32);
+        });
+    }
+
+    fn on_reset_tray_result(&self, tray_id: i32, result: &CommandResult) {
+        let (ams_id, tray_id) = BambuPrinter::get_ams_and_tray_id(tray_id as usize);
+        let ams_id = ams_id as i32;
+        let tray_id = tray_id as i32;
+        let ui = self.ui_weak.unwrap();
+        let app_state = ui.global::<crate::app::AppState>();
+        match result {
+            CommandResult::Success => app_state.invoke_tray_reset_succeeded(ams_id, tray_id),
+            CommandResult::Failed(reason) => app_state.invoke_tray_reset_failed(ams_id, tray_id, SharedString::from(reason)),
+            CommandResult::Timeout => app_state.invoke_tray_reset_failed(ams_id, tray_id, SharedString::from("No Response from Printer")),
+            CommandResult::NotSent => app_state.invoke_tray_reset_failed(ams_id, tray_id, SharedString::from("Printer Busy")),
+        }
+    }
+
+    fn on_ams_drying_result(&self, ams_id: usize, start: bool, result: &CommandResult) {
+        let ams_id = ams_id as i32;
+        let ui = self.ui_weak.unwrap();
+        let app_state = ui.global::<crate::app::AppState>();
+        match result {
+            CommandResult::Success => app_state.invoke_ams_drying_succeeded(start, ams_id),
+            CommandResult::Failed(reason) => app_state.invoke_ams_drying_failed(start, ams_id, SharedString::from(reason)),
+            CommandResult::Timeout => app_state.invoke_ams_drying_failed(start, ams_id, SharedString::from("No Response from Printer")),
+            CommandResult::NotSent => app_state.invoke_ams_drying_failed(start, ams_id, SharedString::from("Printer Busy")),
+        }
+    }
+
+    fn init_reset_tray(&mut self) {
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak.unwrap().global::<crate::app::AppBackend>().on_reset_tray(move |tray_id| {
+            let forgotten = moved_bambu_printer.borrow_mut().reset_tray_filament(tray_id);
+            if forgotten {
+                moved_bambu_printer.borrow().notify_trays_update();
+            }
+            let (ams_id, slot) = BambuPrinter::get_ams_and_tray_id(usize::try_from(tray_id).unwrap());
+            // completion is reported by the printer's response to the command (see on_command_result)
+            moved_ui
+                .unwrap()
+                .global::<crate::app::AppState>()
+                .invoke_tray_reset_started(ams_id as i32, slot as i32);
+        });
+    }
+
+    // Completion of a load / unload comes from the tray states, the command result only reports failures
+    fn on_filament_change_result(&self, result: &CommandResult) {
+        let Some(change) = self.filament_change.get() else {
+            return;
+        };
+        let err_txt = match result {
+            CommandResult::Success => return,
+            CommandResult::Failed(reason) => reason.as_str(),
+            // the printer may be too busy changing the filament to respond, but the tray states tell it is progressing
+            CommandResult::Timeout if change.progress_seen => return,
+            CommandResult::Timeout => "No Response from Printer",
+            CommandResult::NotSent => "Printer Busy",
+        };
+        self.filament_change.set(None);
+        let (ams_id, slot) = BambuPrinter::get_ams_and_tray_id(change.tray_id);
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppState>()
+            .invoke_filament_change_failed(change.load, ams_id as i32, slot as i32, SharedString::from(err_txt));
+    }
+
+    // Follows the load / unload through the tray states, the printer first unloads the loaded tray, then loads the new one
+    fn update_filament_change(&self, bambu_printer: &BambuPrinter) {
+        let Some(mut change) = self.filament_change.get() else {
+            return;
+        };
+        let ui = self.ui_weak.unwrap();
+        let app_state = ui.global::<crate::app::AppState>();
+        let (ams_id, slot) = BambuPrinter::get_ams_and_tray_id(change.tray_id);
+        let (ams_id, slot) = (ams_id as i32, slot as i32);
+        let tray_state = |tray_id: usize| bambu_printer.get_tray(tray_id).map(|tray| tray.state);
+        let unloading_tray = change.unloading_tray.filter(|tray_id| tray_state(*tray_id) == Some(TrayState::Unloading));
+
+        match tray_state(change.tray_id) {
+            Some(TrayState::Loaded) if change.load => {
+                self.filament_change.set(None);
+                app_state.invoke_filament_change_succeeded(true, ams_id, slot);
+                return;
+            }
+            Some(TrayState::Loaded) => (), // unload not started yet
+            Some(TrayState::Ready) if !change.load => {
+                self.filament_change.set(None);
+                app_state.invoke_filament_change_succeeded(false, ams_id, slot);
+                return;
+            }
+            Some(TrayState::Ready) => {
+                if let Some(unloading_tray) = unloading_tray {
+                    let (ams_id, slot) = BambuPrinter::get_ams_and_tray_id(unloading_tray);
+                    app_state.invoke_filament_change_progress(true, ams_id as i32, slot as i32);
+                    change.progress_seen = true;
+                }
+            }
+            Some(TrayState::Loading) | Some(TrayState::Unloading) => {
+                app_state.invoke_filament_change_progress(!change.load, ams_id, slot);
+                change.progress_seen = true;
+            }
+            _ => {
+                self.filament_change.set(None);
+                app_state.invoke_filament_change_failed(change.load, ams_id, slot, SharedString::from("Spool Removed"));
+                return;
+            }
+        }
+        self.filament_change.set(Some(change));
+    }
+
+    fn init_printers_discovery(&mut self) {
+        let moved_printer_discovery = self.printer_discovery_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak.unwrap().global::<crate::app::AppBackend>().on_discover_printers(move || {
+            moved_printer_discovery.borrow().probe();
+            moved_ui.unwrap().global::<crate::app::AppState>().set_printers_message(SharedString::new());
+        });
+
+        let moved_printer_discovery = self.printer_discovery_model.clone();
+        let moved_app_config = self.app_config.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_select_discovered_printer(move |index| {
+                let printer_discovery = moved_printer_discovery.borrow();
+                let Some(printer) = usize::try_from(index).ok().and_then(|index| printer_discovery.printers().get(index)) else {
+                    return;
+                };
+                let mut app_config = moved_app_config.borrow_mut();
+                // The ip is left empty so the printer is found by its serial also if its ip changes
+                let access_code = app_config.printer_access_code.clone().unwrap_or_default();
+                let message = match app_config.set_printer_config(String::new(), printer.name.clone(), printer.serial.clone(), access_code) {
+                    Ok(_) if app_config.printer_access_code.is_none() => {
+                        term_info!("Printer '{}' ({}) selected", printer.name, printer.serial);
+                        format!("'{}' Selected\nSet Access Code on Web Config", printer.name)
+                    }
+                    Ok(_) => {
+                        term_info!("Printer '{}' ({}) selected", printer.name, printer.serial);
+                        format!("'{}' Selected\nRestart Device to Connect", printer.name)
+                    }
+                    Err(e) => {
+                        term_error!("Failed to store printer configuration : {:?}", e);
+                        String::from("Failed to Store Printer Configuration")
+                    }
+                };
+                drop(app_config);
+                let ui = moved_ui.unwrap();
+                ui.global::<crate::app::AppState>().set_printers_message(SharedString::from(message));
+                update_ui_discovered_printers(&ui, &printer_discovery, &moved_app_config.borrow());
+            });
+    }
+
+    fn init_calibrations(&mut self) {
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_refresh_calibrations(move || {
+                update_ui_calibrations(&moved_ui.unwrap(), &moved_bambu_printer.borrow());
+            });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_next_calibration_filter(move || {
+                let ui = moved_ui.unwrap();
+                let bambu_printer = moved_bambu_printer.borrow();
+                let nozzle_key = bambu_printer.nozzle_key(MAIN_EXTRUDER).unwrap_or_default();
+                let filament_ids = bambu_printer.get_calibrated_filament_ids(&nozzle_key);
+                let curr_filter = ui.global::<crate::app::AppState>().get_calibrations_filter();
+                // cycle through All (empty) and then all filaments
+                let next_filter = match filament_ids.iter().position(|v| v.as_str() == curr_filter.as_str()) {
+                    Some(index) => filament_ids.get(index + 1).cloned().unwrap_or_default(),
+                    None => filament_ids.first().cloned().unwrap_or_default(),
+                };
+                ui.global::<crate::app::AppState>().set_calibrations_filter(SharedString::from(next_filter));
+                ui.global::<crate::app::AppState>().set_calibrations_page(0);
+                update_ui_calibrations(&ui, &bambu_printer);
+            });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_update_calibration_k(move |cali_idx, k| {
+                let bambu_printer = moved_bambu_printer.borrow();
+                let nozzle_key = bambu_printer.nozzle_key(MAIN_EXTRUDER).unwrap_or_default();
+                let message = match bambu_printer.update_calibration(&nozzle_key, cali_idx, None, Some(&k)) {
+                    Ok(_) => String::from("Calibration K Update Sent"),
+                    Err(e) => format!("Calibration Update Failed ({e:?})"),
+                };
+                moved_ui
+                    .unwrap()
+                    .global::<crate::app::AppState>()
+                    .set_calibrations_message(SharedString::from(message));
+            });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_rename_calibration(move |cali_idx, name| {
+                let bambu_printer = moved_bambu_printer.borrow();
+                let nozzle_key = bambu_printer.nozzle_key(MAIN_EXTRUDER).unwrap_or_default();
+                let message = match bambu_printer.update_calibration(&nozzle_key, cali_idx, Some(&name), None) {
+                    Ok(_) => String::from("Calibration Rename Sent"),
+                    Err(e) => format!("Calibration Rename Failed ({e:?})"),
+                };
+                moved_ui
+                    .unwrap()
+                    .global::<crate::app::AppState>()
+                    .set_calibrations_message(SharedString::from(message));
+            });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_delete_calibration(move |cali_idx| {
+                let bambu_printer = moved_bambu_printer.borrow();
+                let nozzle_key = bambu_printer.nozzle_key(MAIN_EXTRUDER).unwrap_or_default();
+                let message = match bambu_printer.delete_calibration(&nozzle_key, cali_idx) {
+                    Ok(_) => String::from("Calibration Delete Sent"),
+                    Err(e) => format!("Calibration Delete Failed ({e:?})"),
+                };
+                moved_ui
+                    .unwrap()
+                    .global::<crate::app::AppState>()
+                    .set_calibrations_message(SharedString::from(message));
+            });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_sdcard = self.sdcard.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_backup_calibrations(move || {
+                let backup = moved_bambu_printer.borrow().calibrations_backup();
+                let message = match moved_sdcard.borrow_mut().write_file_str(CALIBRATIONS_BACKUP_FILENAME, &backup) {
+                    Ok(_) => {
+                        term_info!("Backed up PA calibrations to SDCard file '{}'", CALIBRATIONS_BACKUP_FILENAME);
+                        String::from("Calibrations Backed Up to SD Card")
+                    }
+                    Err(e) => {
+                        term_error!("Failed to write '{}' to SDCard : {}", CALIBRATIONS_BACKUP_FILENAME, e);
+                        String::from("Backup to SD Card Failed")
+                    }
+                };
+                moved_ui
+                    .unwrap()
+                    .global::<crate::app::AppState>()
+                    .set_calibrations_message(SharedString::from(message));
+            });
+
+        let moved_bambu_printer = self.bambu_printer_model.clone();
+        let moved_sdcard = self.sdcard.clone();
+        let moved_ui = self.ui_weak.clone();
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppBackend>()
+            .on_restore_calibrations(move || {
+                let message = match moved_sdcard.borrow_mut().read_file_str(CALIBRATIONS_BACKUP_FILENAME) {
+                    Ok(backup) => match moved_bambu_printer.borrow_mut().restore_calibrations(&backup) {
+                        Ok((0, 0)) => String::from("No Missing Calibrations to Restore"),
+                        Ok((count, 0)) => format!("Restoring {count} Calibrations"),
+                        Ok((count, invalid)) => format!("Restoring {count} Calibrations, {invalid} Invalid Skipped"),
+                        Err(e) => format!("Restore Failed ({e:?})"),
+                    },
+                    Err(e) => {
+                        term_error!("Failed to read '{}' from SDCard : {}", CALIBRATIONS_BACKUP_FILENAME, e);
+                        String::from("Reading SD Card Backup Failed")
+                    }
+                };
+                moved_ui
+                    .unwrap()
+                    .global::<crate::app::AppState>()
+                    .set_calibrations_message(SharedString::from(message));
+            });
+
+        self.ui_weak.unwrap().global::<crate::app::AppBackend>().on_add_to_k(|k, delta| {
+            let k = f32::from_str(&k).unwrap_or(0.0) + delta as f32 / 1000.0;
+            SharedString::from(format!("{:.3}", k.clamp(0.0, 2.0)))
+        });
+
+        self.ui_weak.unwrap().global::<crate::app::AppBackend>().on_edit_text(|text, key| {
+            let mut text = String::from(text.as_str());
+            if key == "<-" {
+                text.pop();
+            } else if text.chars().count() < MAX_CALIBRATION_NAME_LEN {
+                text.push_str(&key);
+            }
+            SharedString::from(text)
+        });
     }
 
     fn set_staging_to_tray(
         filament_staging: &Rc<RefCell<FilamentStaging>>,
         bambu_printer: &Rc<RefCell<BambuPrinter>>,
+        spool_inventory: &Rc<RefCell<SpoolInventory>>,
         ui: &slint::Weak<crate::app::AppWindow>,
         tray_id: i32,
     ) {
         let mut filament_staging = filament_staging.borrow_mut();
         if let Filament::Known(ref filament_info) = &filament_staging.filament_info {
-            bambu_printer.borrow().set_tray_filament(tray_id, filament_info);
+            bambu_printer.borrow_mut().set_tray_filament(tray_id, filament_info);
+            if let Some(tag_id) = &filament_staging.tag_id {
+                spool_inventory.borrow_mut().spool_to_tray(tag_id, tray_id as usize);
+            }
             filament_staging.clear();
             ui.unwrap().global::<crate::app::AppState>().invoke_empty_spool_staging();
             let (ams_id, tray_id) = BambuPrinter::get_ams_and_tray_id(tray_id as usize);
             let ams_id = ams_id as i32;
             let tray_id = tray_id as i32;
-            ui.unwrap().global::<crate::app::AppState>().invoke_tray_update_succeeded(ams_id, tray_id);
-        }
-    }
-}
-
-impl From<&TrayState> for crate::app::UiTrayState {
-    fn from(v: &TrayState) -> crate::app::UiTrayState {
-        match v {
-            TrayState::Unknown => crate::app::UiTrayState::Unknown,
-            TrayState::Empty => crate::app::UiTrayState::Empty,
-            TrayState::Spool => crate::app::UiTrayState::Spool,
-            TrayState::Reading => crate::app::UiTrayState::Reading,
-            TrayState::Ready => crate::app::UiTrayState::Ready,
-            TrayState::Loading => crate::app::UiTrayState::Loading,
-            TrayState::Unloading => crate::app::UiTrayState::Unloading,
-            TrayState::Loaded => crate::app::UiTrayState::Loaded,
+            // completion is reported by the printer's response to the command (see on_command_result)
+            ui.unwrap().global::<crate::app::AppState>().invoke_tray_update_started(ams_id, tray_id);
         }
     }
-}
 
-impl BambuPrinterObserver for ViewModel {
-    fn on_trays_update(&self, bambu_printer: &BambuPrinter, prev_trays_reading_bits: Option<u32>, new_trays_reading_bits: Option<u32>) {
+    // Trays, AMS units and calibrations shown in the UI, and the staging applied to a newly inserted spool
+    fn on_trays_update_ui(&self, bambu_printer: &BambuPrinter, prev_trays_reading_bits: Option<u32>, new_trays_reading_bits: Option<u32>) {
         let ui = self.ui_weak.unwrap();
 
-        if let Some(mut ams_exist_bits) = bambu_printer.ams_exist_bits {
-            let mut ams_exist_vec = Vec::<i32>::new();
-            let mut ams_id = 0;
-            while ams_exist_bits != 0 {
-                if ams_exist_bits & 1 != 0 {
-                    ams_exist_vec.push(ams_id);
-                    ams_exist_bits >>= 1;
-                    ams_id += 1;
+        // The trays-state rows are the external trays followed by the slots of every AMS in the printer, built dynamically since
+        // the number of AMS units, their slots (AMS HT has a single slot) and external trays (one per extruder) differ between printers
+        let multi_extruder = bambu_printer.is_multi_extruder();
+        let extruder_name = |tray_id: usize| if bambu_printer.tray_extruder(tray_id) == MAIN_EXTRUDER { "R" } else { "L" };
+        let mut tray_ids = Vec::new();
+        let mut ui_external_list = Vec::new();
+        for tray_id in bambu_printer.virt_trays.keys() {
+            let name = if multi_extruder {
+                format!("Ext. {}", extruder_name(*tray_id))
+            } else {
+                String::from("External")
+            };
+            ui_external_list.push(crate::app::UiAms {
+                ams_id: 254,
+                name: SharedString::from(name),
+                tray_indexes: slint::ModelRc::from(Rc::new(slint::VecModel::from(vec![tray_ids.len() as i32]))),
+                ..Default::default()
+            });
+            tray_ids.push(*tray_id as i32);
+        }
+        let mut ui_ams_list = Vec::new();
+        for ams_id in bambu_printer.get_ams_ids() {
+            let mut tray_indexes = Vec::new();
+            for slot in 0..BambuPrinter::ams_slots(ams_id) {
+                tray_indexes.push(tray_ids.len() as i32);
+                tray_ids.push(BambuPrinter::get_global_tray_id(ams_id, slot) as i32);
+            }
+            let mut name = if ams_id >= 128 {
+                format!("AMS HT {}", ams_id - 127)
+            } else {
+                format!("AMS {}", ams_id + 1)
+            };
+            if multi_extruder {
+                name += &format!(" ({})", extruder_name(BambuPrinter::get_global_tray_id(ams_id, 0)));
+            }
+            let drying_preset = bambu_printer
+                .ams_drying_preset(ams_id)
+                .map(|preset| format!("{} {}°C {}h", preset.material, preset.temp, preset.duration))
+                .unwrap_or_default();
+            ui_ams_list.push(crate::app::UiAms {
+                ams_id: ams_id as i32,
+                name: SharedString::from(name),
+                tray_indexes: slint::ModelRc::from(Rc::new(slint::VecModel::from(tray_indexes))),
+                drying_supported: bambu_printer.ams_drying_supported(ams_id),
+                drying_remaining: bambu_printer.ams_drying_remaining(ams_id) as i32,
+                drying_preset: SharedString::from(drying_preset),
+            });
+        }
+
+        let app_state = ui.global::<crate::app::AppState>();
+        let mut trays_state = app_state.get_trays_state();
+        let curr_tray_ids = trays_state.iter().map(|ui_tray| ui_tray.id).collect::<Vec<_>>();
+        // names change when the extruders of the AMS units become known
+        let curr_names = app_state.get_ams_list().iter().chain(app_state.get_external_list().iter()).map(|ams| ams.name).collect::<Vec<_>>();
+        let names = ui_ams_list.iter().chain(ui_external_list.iter()).map(|ams| ams.name.clone()).collect::<Vec<_>>();
+        let topology_changed = curr_tray_ids != tray_ids || curr_names != names;
+        if topology_changed {
+            info!("AMS topology changed, trays {:?}", tray_ids);
+            let template = trays_state.row_data(0).unwrap();
+            let new_trays_state = tray_ids
+                .iter()
+                .map(|tray_id| crate::app::UiTray {
+                    id: *tray_id,
+                    external: *tray_id >= 254,
+                    ..template.clone()
+                })
+                .collect::<Vec<_>>();
+            trays_state = slint::ModelRc::from(Rc::new(slint::VecModel::from(new_trays_state)));
+            app_state.set_trays_state(trays_state.clone());
+            let ams_count = ui_ams_list.len() as i32;
+            app_state.set_ams_list(slint::ModelRc::from(Rc::new(slint::VecModel::from(ui_ams_list))));
+            if app_state.get_curr_ams_index() >= ams_count {
+                app_state.set_curr_ams_index(0);
+            }
+            let external_count = ui_external_list.len() as i32;
+            app_state.set_external_list(slint::ModelRc::from(Rc::new(slint::VecModel::from(ui_external_list))));
+            if app_state.get_curr_external_index() >= external_count {
+                app_state.set_curr_external_index(0);
+            }
+        } else {
+            // the drying state changes without the topology changing
+            let ams_list = app_state.get_ams_list();
+            for (row, ui_ams) in ui_ams_list.into_iter().enumerate() {
+                let Some(curr_ams) = ams_list.row_data(row) else {
+                    continue;
+                };
+                if curr_ams.drying_supported != ui_ams.drying_supported
+                    || curr_ams.drying_remaining != ui_ams.drying_remaining
+                    || curr_ams.drying_preset != ui_ams.drying_preset
+                {
+                    ams_list.set_row_data(row, ui_ams);
                 }
             }
-            let ams_exists: Rc<slint::VecModel<i32>> = Rc::new(slint::VecModel::from(ams_exist_vec));
-            let ams_exists = slint::ModelRc::from(ams_exists);
-            ui.global::<crate::app::AppState>().set_ams_exists(ams_exists);
         }
 
-        let trays_state_rc = ui.global::<crate::app::AppState>().get_trays_state();
-        // let trays_state_rc = ui.get_trays_state();
-        let trays_state = trays_state_rc;
+        let spool_inventory = self.spool_inventory_model.borrow();
+        let mut trays_inserted = Vec::new();
         for tray_row in 0..trays_state.row_count() {
             let tray_id = trays_state.row_data(tray_row).unwrap().id;
-            let curr_tray = if tray_id == 254 {
-                &bambu_printer.virt_tray
-            } else {
-                &bambu_printer.ams_trays[usize::try_from(tray_id).unwrap()]
+            let Some(curr_tray) = bambu_printer.get_tray(usize::try_from(tray_id).unwrap()) else {
+                continue;
             };
             let mut ui_tray = trays_state.row_data(tray_row).unwrap().clone();
+            let inserted = ui_tray.spool_state == crate::app::UiTrayState::Empty && !matches!(curr_tray.state, TrayState::Empty | TrayState::Unknown);
+            if inserted && !topology_changed && !ui_tray.external {
+                trays_inserted.push(usize::try_from(tray_id).unwrap());
+            }
             ui_tray.spool_state = crate::app::UiTrayState::from(&curr_tray.state);
             if let bambu::Filament::Known(filament_info) = &curr_tray.filament {
                 // FIX: when color string is less than 6 chars
@@ -285,29 +896,175 @@ impl BambuPrinterObserver for ViewModel {
             let k_value_unformatted = curr_tray.k.as_ref().unwrap_or(&"(0.020)".to_string()).clone();
             let k_value_for_ui = k_value_for_ui(&k_value_unformatted);
             ui_tray.k = SharedString::from(k_value_for_ui);
+            ui_tray.bambu_rfid = curr_tray.is_bbl;
+            ui_tray.remaining_weight = spool_inventory
+                .spool_in_tray(usize::try_from(tray_id).unwrap())
+                .and_then(|spool| spool.remaining_weight)
+                .unwrap_or(0) as i32;
             trays_state.set_row_data(tray_row, ui_tray);
         }
 
+        drop(spool_inventory);
+
+        // the calibrations list is rebuilt here only when the calibrations or the nozzle changed, the calibrations screen
+        // rebuilds it on page and filter changes
+        let shown_calibrations = Some((bambu_printer.calibrations_version(), bambu_printer.nozzle_key(MAIN_EXTRUDER)));
+        if *self.shown_calibrations.borrow() != shown_calibrations {
+            update_ui_calibrations(&ui, bambu_printer);
+            *self.shown_calibrations.borrow_mut() = shown_calibrations;
+        }
+        update_ui_ams_settings(&ui, bambu_printer);
+
+        // Without reading on insertion the AMS doesn't report reading the spool, so the staging goes to the tray that
+        // switched from empty
+        if bambu_printer.ams_settings.insertion_read == Some(false) && trays_inserted.len() == 1 {
+            info!("Single tray {} inserted without reading", trays_inserted[0]);
+            self.pending_auto_assign_tray.set(Some(trays_inserted[0]));
+        }
+
         // If the staging is loaded and only a SINGLE slot SWITCHED to reading update it to the stating filament info
         // TODO: Think if UI wise, we want to ask on the panel if to load or not, and not do automatically (maybe with timeout)
-        if let Some(new_trays_reading_bits) = new_trays_reading_bits {
-            let prev_trays_reading_bits = prev_trays_reading_bits.unwrap_or(0);
+        if new_trays_reading_bits.is_some() {
+            let prev_trays_reading_bits = Some(prev_trays_reading_bits.unwrap_or(0));
             let mut trays_reading_changed = Vec::new();
-            for tray_id in 0..bambu_printer.ams_trays.len() {
-                let prev_tray_reading_bit = ((prev_trays_reading_bits >> tray_id) & 0x01) != 0;
-                let new_tray_reading_bit = ((new_trays_reading_bits >> tray_id) & 0x01) != 0;
-                if prev_tray_reading_bit == false && new_tray_reading_bit == true {
-                    trays_reading_changed.push(tray_id);
+            for (ams_id, slot) in bambu_printer.ams_trays.keys() {
+                let prev_tray_reading_bit = BambuPrinter::is_tray_reading(prev_trays_reading_bits, *ams_id, *slot);
+                let new_tray_reading_bit = BambuPrinter::is_tray_reading(new_trays_reading_bits, *ams_id, *slot);
+                if !prev_tray_reading_bit && new_tray_reading_bit {
+                    trays_reading_changed.push(BambuPrinter::get_global_tray_id(*ams_id, *slot));
                 }
             }
             if trays_reading_changed.len() == 1 {
                 let only_reading_tray = trays_reading_changed[0];
                 info!("Single tray {only_reading_tray} is loading now");
-                ui.global::<crate::app::AppState>()
-                    .invoke_new_single_tray_loading(only_reading_tray as i32);
+                self.pending_auto_assign_tray.set(Some(only_reading_tray));
+            }
+        }
+
+        // Staging is applied only after the AMS completed reading the tray, so that a Bambu spool identified by its RFID tag
+        // keeps the settings the AMS assigned to it instead of being overwritten by the staging
+        if let Some(pending_tray_id) = self.pending_auto_assign_tray.get() {
+            let Some(pending_tray) = bambu_printer.get_tray(pending_tray_id) else {
+                // AMS removed before reading completed
+                self.pending_auto_assign_tray.set(None);
+                return;
+            };
+            let insertion_read = bambu_printer.ams_settings.insertion_read != Some(false);
+            match pending_tray.state {
+                TrayState::Reading => (), // still reading, wait for next update
+                TrayState::Spool if insertion_read => (),
+                TrayState::Empty | TrayState::Unknown => {
+                    // spool removed before reading completed
+                    self.pending_auto_assign_tray.set(None);
+                }
+                _ => {
+                    self.pending_auto_assign_tray.set(None);
+                    if pending_tray.is_bbl {
+                        info!("Tray {pending_tray_id} identified by AMS as Bambu spool, not applying staging");
+                        let (ams_id, tray_id) = BambuPrinter::get_ams_and_tray_id(pending_tray_id);
+                        ui.global::<crate::app::AppState>()
+                            .invoke_tray_rfid_identified(ams_id as i32, tray_id as i32);
+                    } else {
+                        ui.global::<crate::app::AppState>()
+                            .invoke_new_single_tray_loading(pending_tray_id as i32);
+                    }
+                }
             }
         }
-        // }
+    }
+}
+
+impl From<&TrayState> for crate::app::UiTrayState {
+    fn from(v: &TrayState) -> crate::app::UiTrayState {
+        match v {
+            TrayState::Unknown => crate::app::UiTrayState::Unknown,
+            TrayState::Empty => crate::app::UiTrayState::Empty,
+            TrayState::Spool => crate::app::UiTrayState::Spool,
+            TrayState::Reading => crate::app::UiTrayState::Reading,
+            TrayState::Ready => crate::app::UiTrayState::Ready,
+            TrayState::Loading => crate::app::UiTrayState::Loading,
+            TrayState::Unloading => crate::app::UiTrayState::Unloading,
+            TrayState::Loaded => crate::app::UiTrayState::Loaded,
+        }
+    }
+}
+
+impl BambuPrinterObserver for ViewModel {
+    fn on_nozzle_change(&self, bambu_printer: &BambuPrinter, extruder_id: usize) {
+        let nozzle = bambu_printer.nozzle_diameter(extruder_id).cloned().unwrap_or_default();
+        let nozzle = if !bambu_printer.is_multi_extruder() {
+            nozzle
+        } else if extruder_id == MAIN_EXTRUDER {
+            format!("{nozzle} (R)")
+        } else {
+            format!("{nozzle} (L)")
+        };
+        self.ui_weak
+            .unwrap()
+            .global::<crate::app::AppState>()
+            .invoke_nozzle_changed(SharedString::from(nozzle));
+    }
+
+    fn on_command_result(&self, _bambu_printer: &BambuPrinter, context: &CommandContext, result: &CommandResult) {
+        let tray_id = match context {
+            CommandContext::SetTrayFilament { tray_id } => tray_id,
+            CommandContext::ResetTrayFilament { tray_id } => {
+                self.on_reset_tray_result(*tray_id, result);
+                return;
+            }
+            CommandContext::LoadTray { .. } | CommandContext::UnloadTray => {
+                self.on_filament_change_result(result);
+                return;
+            }
+            CommandContext::AmsDrying { ams_id, start } => {
+                self.on_ams_drying_result(*ams_id, *start, result);
+                return;
+            }
+            CommandContext::Internal => return,
+        };
+        let (ams_id, tray_id) = BambuPrinter::get_ams_and_tray_id(*tray_id as usize);
+        let ams_id = ams_id as i32;
+        let tray_id = tray_id as i32;
+        let app_state = self.ui_weak.unwrap().global::<crate::app::AppState>();
+        match result {
+            CommandResult::Success => app_state.invoke_tray_update_succeeded(ams_id, tray_id),
+            CommandResult::Failed(reason) => app_state.invoke_tray_update_failed(ams_id, tray_id, SharedString::from(reason)),
+            CommandResult::Timeout => app_state.invoke_tray_update_failed(ams_id, tray_id, SharedString::from("No Response from Printer")),
+            CommandResult::NotSent => app_state.invoke_tray_update_failed(ams_id, tray_id, SharedString::from("Printer Busy")),
+        }
+    }
+
+    fn on_trays_update(&self, bambu_printer: &BambuPrinter, prev_trays_reading_bits: Option<u32>, new_trays_reading_bits: Option<u32>) {
+        // inventory first, the trays show the remaining weight of their spools
+        let low_filament_threshold = self.app_config.borrow().low_filament_threshold;
+        let low_filament_trays = self
+            .spool_inventory_model
+            .borrow_mut()
+            .trays_update(bambu_printer, low_filament_threshold);
+        self.on_trays_update_ui(bambu_printer, prev_trays_reading_bits, new_trays_reading_bits);
+        self.update_filament_change(bambu_printer);
+
+        {
+            let mut queued_trays = self.low_filament_trays.borrow_mut();
+            if low_filament_threshold == 0 {
+                queued_trays.clear();
+            }
+            for tray_id in low_filament_trays {
+                if !queued_trays.contains(&tray_id) {
+                    queued_trays.push_back(tray_id);
+                }
+            }
+        }
+
+        let runout_tray = bambu_printer.runout_tray();
+        if runout_tray != self.runout_tray.get() {
+            self.runout_tray.set(runout_tray);
+            if let Some(tray_id) = runout_tray {
+                self.suggest_replacement_spools(bambu_printer, tray_id, "Filament Ran Out");
+                return;
+            }
+        }
+        self.alert_low_filament_trays(bambu_printer, low_filament_threshold);
     }
 }
 
@@ -325,7 +1082,7 @@ impl SpoolTagObserver for ViewModel {
             Status::FoundTagNowWriting => {
                 ui.unwrap().global::<crate::app::AppState>().invoke_encode_tag_found();
             }
-            Status::WriteSuccess(pure_tray_id) => {
+            Status::WriteSuccess(pure_tray_id, descriptor) => {
                 let (ams_id, tray_id) = BambuPrinter::get_ams_and_tray_id(*pure_tray_id);
                 let ams_id = ams_id as i32;
                 let tray_id = tray_id as i32;
@@ -333,18 +1090,30 @@ impl SpoolTagObserver for ViewModel {
 
                 let filament = if *pure_tray_id == 999 {
                     self.filament_staging.borrow().filament_info.clone()
-                } else if *pure_tray_id == 254 {
-                    let bambu_printer_model_clone = self.bambu_printer_model.clone();
-                    let bambu_printer_model = bambu_printer_model_clone.borrow();
-                    let tray = &bambu_printer_model.virt_tray;
-                    tray.filament.clone()
                 } else {
                     let bambu_printer_model_clone = self.bambu_printer_model.clone();
                     let bambu_printer_model = bambu_printer_model_clone.borrow();
-                    let tray = &bambu_printer_model.ams_trays[*pure_tray_id];
-                    tray.filament.clone()
+                    match bambu_printer_model.get_tray(*pure_tray_id).map(|tray| &tray.filament) {
+                        Some(Filament::Known(filament_info)) => Filament::Known(tray_filament_with_spool_info(
+                            &self.spool_inventory_model.borrow(),
+                            *pure_tray_id,
+                            filament_info,
+                        )),
+                        _ => Filament::Unknown,
+                    }
                 };
                 if let Filament::Known(filament_info) = filament {
+                    if let Some(tag_id) = spool_inventory::descriptor_tag_id(descriptor) {
+                        let location = if *pure_tray_id == 999 {
+                            SpoolLocation::Shelf
+                        } else {
+                            SpoolLocation::Tray(*pure_tray_id)
+                        };
+                        self.spool_inventory_model.borrow_mut().spool_seen(&tag_id, &filament_info, location);
+                        if *pure_tray_id == 999 {
+                            self.filament_staging.borrow_mut().tag_id = Some(tag_id);
+                        }
+                    }
                     let ui_spool_info = filament_info_to_ui_spool_info(self.bambu_printer_model.borrow(), &filament_info);
                     ui.unwrap().global::<crate::app::AppState>().invoke_update_spool_staging(ui_spool_info);
                 }
@@ -352,8 +1121,23 @@ impl SpoolTagObserver for ViewModel {
             Status::ReadSuccess(read_text) => {
                 let bambu_printer_model = self.bambu_printer_model.borrow();
                 if let Ok(filament_info) = FilamentInfo::from_descriptor(read_text, &bambu_printer_model) {
+                    let tag_id = spool_inventory::descriptor_tag_id(read_text);
+                    if let Some(tag_id) = &tag_id {
+                        let mut inventory = self.spool_inventory_model.borrow_mut();
+                        inventory.spool_seen(tag_id, &filament_info, SpoolLocation::Shelf);
+                        // usage tracked since the tag was last read is written back to it
+                        let tag_remaining_weight = spool_inventory::descriptor_remaining_weight(read_text);
+                        if let Some(remaining_weight) = inventory.tag_remaining_weight(tag_id, tag_remaining_weight) {
+                            let tag_update = spool_inventory::descriptor_with_remaining_weight(read_text, remaining_weight);
+                            self.spool_tag_model.borrow().update_read_tag(&tag_update);
+                        }
+                    }
                     let ui_spool_info = filament_info_to_ui_spool_info(bambu_printer_model, &filament_info);
-                    self.filament_staging.borrow_mut().filament_info = Filament::Known(filament_info);
+                    {
+                        let mut filament_staging = self.filament_staging.borrow_mut();
+                        filament_staging.filament_info = Filament::Known(filament_info);
+                        filament_staging.tag_id = tag_id;
+                    }
 
                     ui.unwrap().global::<crate::app::AppState>().invoke_read_tag_succeeded(ui_spool_info);
                 } else {
@@ -374,17 +1158,93 @@ impl SpoolTagObserver for ViewModel {
     }
 }
 
+const CALIBRATIONS_PAGE_SIZE: usize = 5;
+const MAX_CALIBRATION_NAME_LEN: usize = 40; // as on the web config page
+const MAX_SPOOL_SUGGESTIONS: usize = 5;
+
+fn update_ui_calibrations(ui: &crate::app::AppWindow, bambu_printer: &BambuPrinter) {
+    let app_state = ui.global::<crate::app::AppState>();
+    let nozzle_key = bambu_printer.nozzle_key(MAIN_EXTRUDER).unwrap_or_default();
+
+    let filter = app_state.get_calibrations_filter();
+    let filter = if filter.is_empty() { None } else { Some(filter.as_str()) };
+    let calibrations = bambu_printer.get_nozzle_calibrations(&nozzle_key, filter);
+
+    let pages = calibrations.len().div_ceil(CALIBRATIONS_PAGE_SIZE).max(1);
+    let page = usize::try_from(app_state.get_calibrations_page()).unwrap_or(0).min(pages - 1);
+
+    let ui_calibrations: Vec<crate::app::UiCalibration> = calibrations
+        .iter()
+        .skip(page * CALIBRATIONS_PAGE_SIZE)
+        .take(CALIBRATIONS_PAGE_SIZE)
+        .map(|calibration| crate::app::UiCalibration {
+            cali_idx: calibration.cali_idx(),
+            filament_id: SharedString::from(calibration.filament_id()),
+            // on multi extruder printers the list has the calibrations of both extruders, marked (R)ight / (L)eft
+            name: SharedString::from(match calibration.extruder_id() {
+                Some(0) => format!("R {}", calibration.name()),
+                Some(_) => format!("L {}", calibration.name()),
+                None => String::from(calibration.name()),
+            }),
+            plain_name: SharedString::from(calibration.name()),
+            k: SharedString::from(format!("{:.3}", f32::from_str(calibration.k_value()).unwrap_or(0.0))),
+        })
+        .collect();
+
+    app_state.set_calibrations_nozzle(SharedString::from(nozzle_key));
+    app_state.set_calibrations_pages(pages as i32);
+    app_state.set_calibrations_page(page as i32);
+    app_state.set_calibrations(slint::ModelRc::from(Rc::new(slint::VecModel::from(ui_calibrations))));
+}
+
+fn update_ui_ams_settings(ui: &crate::app::AppWindow, bambu_printer: &BambuPrinter) {
+    let ams_settings = &bambu_printer.ams_settings;
+    ui.global::<crate::app::AppState>().set_ams_settings(crate::app::UiAmsSettings {
+        insertion_read_known: ams_settings.insertion_read.is_some(),
+        insertion_read: ams_settings.insertion_read.unwrap_or(false),
+        startup_read_known: ams_settings.startup_read.is_some(),
+        startup_read: ams_settings.startup_read.unwrap_or(false),
+        remain_estimate_known: ams_settings.remain_estimate.is_some(),
+        remain_estimate: ams_settings.remain_estimate.unwrap_or(false),
+        auto_refill_known: ams_settings.auto_refill.is_some(),
+        auto_refill: ams_settings.auto_refill.unwrap_or(false),
+    });
+}
+
 fn filament_info_to_ui_spool_info(bambu_printer_model: core::cell::Ref<'_, BambuPrinter>, filament_info: &FilamentInfo) -> crate::app::UiSpoolInfo {
     let color = u32::from_str_radix(&filament_info.tray_color[..6], 16).unwrap() + 0xFF000000;
     // the plus at the end is fo add alpha
+    let spool_info = &filament_info.spool_info;
+    let spool_name = [&spool_info.brand, &spool_info.name]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
     let ui_spool_info = crate::app::UiSpoolInfo {
         color: slint::Color::from_argb_encoded(color),
         k: SharedString::from(k_value_for_ui(&bambu_printer_model.get_filament_k_for_current_nozzle(filament_info))),
         material: SharedString::from(&filament_info.tray_type),
+        spool_name: SharedString::from(spool_name),
     };
     ui_spool_info
 }
 
+// The printer reports spool metadata only for Bambu spools, for other spools the metadata of the spool set to the tray from
+// its tag is encoded, as long as the tray filament wasn't changed since
+fn tray_filament_with_spool_info(spool_inventory: &SpoolInventory, tray_id: usize, filament_info: &FilamentInfo) -> FilamentInfo {
+    let mut filament_info = filament_info.clone();
+    if filament_info.spool_info.is_empty() {
+        if let Some(spool) = spool_inventory
+            .spool_in_tray(tray_id)
+            .filter(|spool| spool.filament.tray_info_idx == filament_info.tray_info_idx)
+        {
+            filament_info.spool_info = spool.filament.spool_info.clone();
+        }
+    }
+    filament_info
+}
+
 fn k_value_for_ui(k: &str) -> String {
     if k.is_empty() {
         return "".to_string();
@@ -400,6 +1260,28 @@ fn k_value_for_ui(k: &str) -> String {
     k_value_for_ui
 }
 
+impl PrinterDiscoveryObserver for ViewModel {
+    fn on_printers_update(&self, printer_discovery: &PrinterDiscovery) {
+        let ui = self.ui_weak.unwrap();
+        update_ui_discovered_printers(&ui, printer_discovery, &self.app_config.borrow());
+    }
+}
+
+fn update_ui_discovered_printers(ui: &crate::app::AppWindow, printer_discovery: &PrinterDiscovery, app_config: &AppConfig) {
+    let ui_printers: Vec<crate::app::UiDiscoveredPrinter> = printer_discovery
+        .printers()
+        .iter()
+        .map(|printer| crate::app::UiDiscoveredPrinter {
+            name: SharedString::from(&printer.name),
+            model: SharedString::from(printer.model_name()),
+            ip: printer.ip.to_shared_string(),
+            configured: app_config.printer_serial.as_deref() == Some(printer.serial.as_str()),
+        })
+        .collect();
+    ui.global::<crate::app::AppState>()
+        .set_discovered_printers(slint::ModelRc::from(Rc::new(slint::VecModel::from(ui_printers))));
+}
+
 impl FrameworkObserver for ViewModel {
     fn on_web_config_started(&self, key: &str, mode: WebConfigMode) {
         let mode = match mode {
@@ -480,8 +1362,11 @@ impl FrameworkObserver for ViewModel {
 }
 
 impl AppControlObserver for ViewModel {
-    fn on_printer_connect_status(&self, status: bool) {
-        if status {
+    fn on_printer_connect_status(&self, status: &PrinterConnectionStatus) {
+        let ui = self.ui_weak.unwrap();
+        let app_state = ui.global::<crate::app::AppState>();
+        if *status == PrinterConnectionStatus::Connected {
+            app_state.set_printer_status(SharedString::new());
             // TODO: I can't borrow at this stage because my_mqtt reports this and need to borrow_mut so now can't borrow.
             //       Need to switch to the notifications coming from a notifier object and not directly from the objects.
             //       Or switch to a message loop notifications (which is a major change to the code, but more correct for these types of apps)
@@ -490,8 +1375,13 @@ impl AppControlObserver for ViewModel {
             term_info!(&"-".repeat(66));
             term_info!("Startup completed successfully");
             term_info!(&"-".repeat(66));
-            self.ui_weak.unwrap().global::<crate::app::AppState>().invoke_boot_succeeded();
+            app_state.invoke_boot_succeeded();
             // }
+        } else if status.configuration_needed() {
+            app_state.set_printer_status(status.to_shared_string());
+            app_state.invoke_boot_failed(format!("{status}\nFix in Web Config").to_shared_string());
+        } else {
+            app_state.set_printer_status(status.to_shared_string());
         }
     }
 }
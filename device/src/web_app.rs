@@ -4,6 +4,7 @@ use core::future::ready;
 use alloc::format;
 use alloc::rc::Rc;
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use picoserve::response::Redirect;
 use picoserve::routing::get;
 use picoserve::{
@@ -22,11 +23,18 @@ use framework::{
     prelude::*,
 };
 
-use crate::app_config::AppConfig;
+use crate::app_config::{AppConfig, PrinterConnectionStatus};
+use crate::bambu::{AmsSettings, BambuPrinter, Filament, MAIN_EXTRUDER};
+use crate::filament_staging::FilamentStaging;
+use crate::printer_discovery::PrinterDiscovery;
+use crate::spoolman::ServerAddress;
 
 pub struct NestedAppBuilder {
     pub framework: Rc<RefCell<Framework>>,
     pub app_config: Rc<RefCell<AppConfig>>,
+    pub bambu_printer: Rc<RefCell<BambuPrinter>>,
+    pub printer_discovery: Rc<RefCell<PrinterDiscovery>>,
+    pub filament_staging: Rc<RefCell<FilamentStaging>>,
 }
 
 impl NestedAppWithWebAppStateBuilder for NestedAppBuilder {
@@ -41,6 +49,9 @@ impl AppWithStateBuilder for NestedAppBuilder {
 
     fn build_app(self) -> picoserve::Router<Self::PathRouter, Self::State> {
         let app_config = self.app_config.clone();
+        let bambu_printer = self.bambu_printer.clone();
+        let printer_discovery = self.printer_discovery.clone();
+        let filament_staging = self.filament_staging.clone();
         let _framework = self.framework.clone();
 
         let router = picoserve::Router::from_service(CustomNotFound {
@@ -99,10 +110,319 @@ impl AppWithStateBuilder for NestedAppBuilder {
             }),
         );
 
+        let app_config_clone_post = app_config.clone();
+        let app_config_clone_get = app_config.clone();
+        let router = router.route(
+            "/api/spoolman-config",
+            post(
+                move |State(Encryption(key)): State<Encryption>, SpoolmanConfigDTO { url, report_consumption }| {
+                    let url = String::from(url.trim());
+                    let result = if !url.is_empty() && ServerAddress::parse(&url).is_none() {
+                        Err(String::from("Invalid URL, expected http://<ip>:<port>"))
+                    } else {
+                        app_config_clone_post
+                            .borrow_mut()
+                            .set_spoolman_config(url, report_consumption)
+                            .map_err(|e| format!("{e:?}"))
+                    };
+                    ready(SetConfigResponseDTO { error_text: result.err() }.encrypt(&key.borrow()))
+                },
+            )
+            .get(move |State(Encryption(key)): State<Encryption>| {
+                let app_config = app_config_clone_get.borrow();
+                ready(
+                    SpoolmanConfigDTO {
+                        url: app_config.spoolman_url.clone().unwrap_or_default(),
+                        report_consumption: app_config.spoolman_report_consumption,
+                    }
+                    .encrypt(&key.borrow()),
+                )
+            }),
+        );
+
+        let app_config_clone_get = app_config.clone();
+        let router = router.route(
+            "/api/printer-status",
+            get(move |State(Encryption(key)): State<Encryption>| {
+                let app_config = app_config_clone_get.borrow();
+                let status = app_config.printer_connection_status();
+                ready(
+                    PrinterStatusDTO {
+                        status: status.to_string(),
+                        connected: *status == PrinterConnectionStatus::Connected,
+                        configuration_needed: status.configuration_needed(),
+                    }
+                    .encrypt(&key.borrow()),
+                )
+            }),
+        );
+
+        let printer_discovery_clone_get = printer_discovery.clone();
+        let router = router.route(
+            "/api/discovered-printers",
+            get(move |State(Encryption(key)): State<Encryption>| {
+                let printer_discovery = printer_discovery_clone_get.borrow();
+                // also search again, so printers that are not yet listed show up on the next refresh
+                printer_discovery.probe();
+                let printers = printer_discovery
+                    .printers()
+                    .iter()
+                    .map(|printer| DiscoveredPrinterDTO {
+                        ip: printer.ip.to_string(),
+                        name: printer.name.clone(),
+                        serial: printer.serial.clone(),
+                        model: printer.model_name().to_string(),
+                    })
+                    .collect();
+                ready(DiscoveredPrintersDTO { printers }.encrypt(&key.borrow()))
+            }),
+        );
+
+        let bambu_printer_clone_get = bambu_printer.clone();
+        let router = router.route(
+            "/api/calibrations",
+            get(move |State(Encryption(key)): State<Encryption>| {
+                let bambu_printer = bambu_printer_clone_get.borrow();
+                let mut calibrations = Vec::new();
+                for nozzle_key in bambu_printer.calibrations.keys() {
+                    for calibration in bambu_printer.get_nozzle_calibrations(nozzle_key, None) {
+                        calibrations.push(CalibrationDTO {
+                            nozzle_diameter: nozzle_key.clone(),
+                            cali_idx: calibration.cali_idx(),
+                            filament_id: calibration.filament_id().to_string(),
+                            setting_id: calibration.setting_id().to_string(),
+                            name: calibration.name().to_string(),
+                            k_value: calibration.k_value().to_string(),
+                            extruder_id: calibration.extruder_id(),
+                        });
+                    }
+                }
+                ready(
+                    CalibrationsDTO {
+                        nozzle_diameter: bambu_printer.nozzle_key(MAIN_EXTRUDER).unwrap_or(String::from("")),
+                        calibrations,
+                    }
+                    .encrypt(&key.borrow()),
+                )
+            }),
+        );
+
+        let bambu_printer_clone_post = bambu_printer.clone();
+        let router = router.route(
+            "/api/calibration-update",
+            post(
+                move |State(Encryption(key)): State<Encryption>,
+                      CalibrationUpdateDTO {
+                    nozzle_diameter,
+                    cali_idx,
+                    name,
+                    k_value,
+                }| {
+                    ready(
+                        match bambu_printer_clone_post
+                            .borrow()
+                            .update_calibration(&nozzle_diameter, cali_idx, Some(&name), Some(&k_value))
+                        {
+                            Ok(_) => SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow()),
+                            Err(e) => SetConfigResponseDTO {
+                                error_text: Some(format!("{e:?}")),
+                            }
+                            .encrypt(&key.borrow()),
+                        },
+                    )
+                },
+            ),
+        );
+
+        let bambu_printer_clone_post = bambu_printer.clone();
+        let router = router.route(
+            "/api/calibration-delete",
+            post(
+                move |State(Encryption(key)): State<Encryption>, CalibrationDeleteDTO { nozzle_diameter, cali_idx }| {
+                    ready(match bambu_printer_clone_post.borrow().delete_calibration(&nozzle_diameter, cali_idx) {
+                        Ok(_) => SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow()),
+                        Err(e) => SetConfigResponseDTO {
+                            error_text: Some(format!("{e:?}")),
+                        }
+                        .encrypt(&key.borrow()),
+                    })
+                },
+            ),
+        );
+
+        let bambu_printer_clone_post = bambu_printer.clone();
+        let bambu_printer_clone_get = bambu_printer.clone();
+        let router = router.route(
+            "/api/calibrations-backup",
+            post(move |State(Encryption(key)): State<Encryption>, CalibrationsBackupDTO { backup }| {
+                ready(match bambu_printer_clone_post.borrow_mut().restore_calibrations(&backup) {
+                    Ok((_, 0)) => SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow()),
+                    Ok((count, invalid)) => SetConfigResponseDTO {
+                        error_text: Some(format!(
+                            "{invalid} calibrations with an invalid K value skipped, restoring the other {count}"
+                        )),
+                    }
+                    .encrypt(&key.borrow()),
+                    Err(e) => SetConfigResponseDTO {
+                        error_text: Some(format!("{e:?}")),
+                    }
+                    .encrypt(&key.borrow()),
+                })
+            })
+            .get(move |State(Encryption(key)): State<Encryption>| {
+                ready(
+                    CalibrationsBackupDTO {
+                        backup: bambu_printer_clone_get.borrow().calibrations_backup(),
+                    }
+                    .encrypt(&key.borrow()),
+                )
+            }),
+        );
+
+        let bambu_printer_clone_post = bambu_printer.clone();
+        let bambu_printer_clone_get = bambu_printer.clone();
+        let router = router.route(
+            "/api/ams-config",
+            post(
+                move |State(Encryption(key)): State<Encryption>,
+                      AmsConfigDTO {
+                    insertion_read,
+                    startup_read,
+                    remain_estimate,
+                    auto_refill,
+                }| {
+                    let ams_settings = AmsSettings {
+                        insertion_read,
+                        startup_read,
+                        remain_estimate,
+                        auto_refill,
+                    };
+                    ready(match bambu_printer_clone_post.borrow_mut().set_ams_settings(&ams_settings) {
+                        Ok(_) => SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow()),
+                        Err(e) => SetConfigResponseDTO {
+                            error_text: Some(format!("{e:?}")),
+                        }
+                        .encrypt(&key.borrow()),
+                    })
+                },
+            )
+            .get(move |State(Encryption(key)): State<Encryption>| {
+                let ams_settings = bambu_printer_clone_get.borrow().ams_settings;
+                ready(
+                    AmsConfigDTO {
+                        insertion_read: ams_settings.insertion_read,
+                        startup_read: ams_settings.startup_read,
+                        remain_estimate: ams_settings.remain_estimate,
+                        auto_refill: ams_settings.auto_refill,
+                    }
+                    .encrypt(&key.borrow()),
+                )
+            }),
+        );
+
+        // Spool info of the staged spool that can't be read from tags or the printer, encoded with it to the next tag
+        let filament_staging_clone_post = filament_staging.clone();
+        let filament_staging_clone_get = filament_staging.clone();
+        let router = router.route(
+            "/api/staging-config",
+            post(
+                move |State(Encryption(key)): State<Encryption>,
+                      StagingConfigDTO {
+                    lot,
+                    purchase_date,
+                    open_date,
+                    price,
+                    spool_weight,
+                    ..
+                }| {
+                    let result = set_staging_spool_info(
+                        &mut filament_staging_clone_post.borrow_mut(),
+                        &lot,
+                        &purchase_date,
+                        &open_date,
+                        &price,
+                        &spool_weight,
+                    );
+                    ready(SetConfigResponseDTO { error_text: result.err() }.encrypt(&key.borrow()))
+                },
+            )
+            .get(move |State(Encryption(key)): State<Encryption>| {
+                let filament_staging = filament_staging_clone_get.borrow();
+                let dto = match &filament_staging.filament_info {
+                    Filament::Known(filament_info) => {
+                        let spool_info = &filament_info.spool_info;
+                        let product = spool_info.name.as_ref().unwrap_or(&filament_info.tray_type);
+                        StagingConfigDTO {
+                            staged: match &spool_info.brand {
+                                Some(brand) => format!("{brand} {product} #{}", filament_info.tray_color),
+                                None => format!("{product} #{}", filament_info.tray_color),
+                            },
+                            lot: spool_info.lot.clone().unwrap_or_default(),
+                            purchase_date: spool_info.purchase_date.clone().unwrap_or_default(),
+                            open_date: spool_info.open_date.clone().unwrap_or_default(),
+                            price: spool_info.price.clone().unwrap_or_default(),
+                            spool_weight: spool_info.spool_weight.map(|v| v.to_string()).unwrap_or_default(),
+                        }
+                    }
+                    _ => StagingConfigDTO::default(),
+                };
+                ready(dto.encrypt(&key.borrow()))
+            }),
+        );
+
         router
     }
 }
 
+// Empty values clear the fields
+fn set_staging_spool_info(
+    filament_staging: &mut FilamentStaging,
+    lot: &str,
+    purchase_date: &str,
+    open_date: &str,
+    price: &str,
+    spool_weight: &str,
+) -> Result<(), String> {
+    let Filament::Known(filament_info) = &mut filament_staging.filament_info else {
+        return Err(String::from("No spool is staged, scan its tag or encode a slot first"));
+    };
+    let text = |value: &str| Some(String::from(value.trim())).filter(|v| !v.is_empty());
+    let (lot, purchase_date, open_date, price, spool_weight) = (text(lot), text(purchase_date), text(open_date), text(price), text(spool_weight));
+    for date in [&purchase_date, &open_date].into_iter().flatten() {
+        if !is_date(date) {
+            return Err(format!("Invalid date '{date}', expected YYYY-MM-DD"));
+        }
+    }
+    if price.as_ref().is_some_and(|v| v.parse::<f32>().is_err()) {
+        return Err(String::from("Invalid price, expected a number, e.g. 19.99"));
+    }
+    let spool_weight = match spool_weight {
+        Some(spool_weight) => Some(
+            spool_weight
+                .parse::<u32>()
+                .map_err(|_| String::from("Invalid spool weight, expected grams, e.g. 250"))?,
+        ),
+        None => None,
+    };
+
+    let spool_info = &mut filament_info.spool_info;
+    spool_info.lot = lot;
+    spool_info.purchase_date = purchase_date;
+    spool_info.open_date = open_date;
+    spool_info.price = price;
+    spool_info.spool_weight = spool_weight;
+    term_info!("Staged spool info updated, encode a tag to write it");
+    Ok(())
+}
+
+// YYYY-MM-DD
+fn is_date(value: &str) -> bool {
+    value.len() == 10
+        && value
+            .char_indices()
+            .all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() })
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 struct PrinterConfigDTO {
     ip: String,
@@ -112,8 +432,100 @@ struct PrinterConfigDTO {
 }
 encrypted_input!(PrinterConfigDTO);
 
+#[derive(serde::Deserialize, serde::Serialize)]
+struct PrinterStatusDTO {
+    status: String,
+    connected: bool,
+    configuration_needed: bool, // the printer rejected the configuration (e.g. wrong access code)
+}
+encrypted_input!(PrinterStatusDTO);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct DiscoveredPrinterDTO {
+    ip: String,
+    name: String,
+    serial: String,
+    model: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct DiscoveredPrintersDTO {
+    printers: Vec<DiscoveredPrinterDTO>,
+}
+encrypted_input!(DiscoveredPrintersDTO);
+
 #[derive(serde::Deserialize, serde::Serialize)]
 struct TagConfigDTO {
     tag_scan_timeout: u64,
 }
 encrypted_input!(TagConfigDTO);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct SpoolmanConfigDTO {
+    url: String, // empty when not syncing with Spoolman
+    report_consumption: bool,
+}
+encrypted_input!(SpoolmanConfigDTO);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct CalibrationDTO {
+    nozzle_diameter: String, // nozzle key, the diameter and nozzle type if known, e.g. "0.4-HS"
+    cali_idx: i32,
+    filament_id: String,
+    setting_id: String,
+    name: String,
+    k_value: String,
+    extruder_id: Option<u32>, // only on multi extruder printers, 0 - right, 1 - left
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct CalibrationsDTO {
+    nozzle_diameter: String, // current printer nozzle key
+    calibrations: Vec<CalibrationDTO>,
+}
+encrypted_input!(CalibrationsDTO);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct CalibrationUpdateDTO {
+    nozzle_diameter: String,
+    cali_idx: i32,
+    name: String,
+    k_value: String,
+}
+encrypted_input!(CalibrationUpdateDTO);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct CalibrationDeleteDTO {
+    nozzle_diameter: String,
+    cali_idx: i32,
+}
+encrypted_input!(CalibrationDeleteDTO);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct CalibrationsBackupDTO {
+    backup: String, // backup file content (json)
+}
+encrypted_input!(CalibrationsBackupDTO);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct AmsConfigDTO {
+    // None when not reported by the printer yet, or (when setting) to leave unchanged
+    insertion_read: Option<bool>,
+    startup_read: Option<bool>,
+    remain_estimate: Option<bool>,
+    auto_refill: Option<bool>,
+}
+encrypted_input!(AmsConfigDTO);
+
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+struct StagingConfigDTO {
+    #[serde(default)]
+    staged: String, // the staged filament, empty when nothing is staged (not set by the web app)
+    // empty when not set
+    lot: String,
+    purchase_date: String, // YYYY-MM-DD
+    open_date: String,     // YYYY-MM-DD
+    price: String,
+    spool_weight: String, // grams, of the empty spool
+}
+encrypted_input!(StagingConfigDTO);
@@ -0,0 +1,50 @@
+#!/bin/bash
+# Generates the certificates of the simulated printer.
+# A CA (trusted by the device firmware when built with the 'simulator' feature) and a printer certificate signed with it.
+# The printer certificate CN must be the printer serial (the device verifies it), to simulate a printer with another
+# serial run: ./gen-certs.sh <SERIAL>
+# The CA is generated only if missing, since the device firmware embeds it
+
+SERIAL=${1:-SIMULATOR0001}
+CERTS_DIR=./certs
+
+mkdir -p $CERTS_DIR
+
+if [ -f $CERTS_DIR/ca.pem ] && [ ! -f $CERTS_DIR/ca-key.pem ]; then
+  echo "$CERTS_DIR/ca.pem exists without its key $CERTS_DIR/ca-key.pem, remove it to generate a new CA (and rebuild the device firmware)"
+  exit 1
+fi
+
+# Generate CA certificate
+if [ ! -f $CERTS_DIR/ca.pem ]; then
+  openssl req \
+    -x509 \
+    -newkey rsa:2048 \
+    -keyout $CERTS_DIR/ca-key.pem \
+    -out $CERTS_DIR/ca.pem \
+    -nodes \
+    -days 3650 \
+    -subj "/CN=SpoolEase Printer Simulator CA/O=SpoolEase"
+fi
+
+# Generate certificate signing request (CSR)
+openssl req \
+    -newkey rsa:2048 \
+    -keyout $CERTS_DIR/printer-key.pem \
+    -out $CERTS_DIR/csr.pem \
+    -nodes \
+    -subj "/CN=$SERIAL"
+
+# Sign key with CA certificates from CSR
+openssl x509 \
+    -req \
+    -in $CERTS_DIR/csr.pem \
+    -CA $CERTS_DIR/ca.pem \
+    -CAkey $CERTS_DIR/ca-key.pem \
+    -out $CERTS_DIR/printer-cert.pem \
+    -CAcreateserial \
+    -days 3650
+
+# Remove csr
+rm $CERTS_DIR/csr.pem
+rm -f $CERTS_DIR/ca.srl
@@ -0,0 +1,336 @@
+// Mock of the Spoolman REST API (https://github.com/Donkie/Spoolman), for testing the Spoolman sync of SpoolEase without
+// a Spoolman server. Keeps vendors, filaments, spools and spool extra fields in memory, and logs the requests and changes.
+// Only the endpoints, fields and list filters SpoolEase uses are implemented.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use clap::Parser;
+use log::{error, info, warn};
+use serde_json::{json, Map, Value};
+
+#[derive(Parser)]
+#[command(about = "Mock of the Spoolman REST API")]
+struct Args {
+    #[arg(long, default_value_t = 7912)]
+    port: u16,
+    /// Send responses with chunked transfer encoding
+    #[arg(long)]
+    chunked: bool,
+}
+
+#[derive(Default)]
+struct Spoolman {
+    vendors: Vec<Value>,
+    filaments: Vec<Value>,
+    spools: Vec<Value>,
+    spool_fields: Vec<Value>,
+}
+
+fn next_id(items: &[Value]) -> u64 {
+    items.iter().filter_map(|v| v["id"].as_u64()).max().unwrap_or(0) + 1
+}
+
+fn find_mut(items: &mut [Value], id: u64) -> Option<&mut Value> {
+    items.iter_mut().find(|v| v["id"].as_u64() == Some(id))
+}
+
+// Query parameters of a request path, percent decoded
+fn query_params(path: &str) -> Vec<(String, String)> {
+    let Some((_, query)) = path.split_once('?') else {
+        return Vec::new();
+    };
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|v| std::str::from_utf8(v).ok());
+        match (bytes[i], hex.and_then(|v| u8::from_str_radix(v, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// A list filtered as Spoolman does, by the given filters (query parameters named by the field, e.g. vendor.name),
+// and paged by limit and offset. A quoted term matches the value exactly, otherwise the value contains it, both case
+// insensitive. Spoolman matches colors by similarity, here they're matched as the other fields
+fn filtered_list(items: Vec<Value>, query: &[(String, String)], filters: &[&str]) -> Value {
+    let param = |name: &str| query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    let items = items.into_iter().filter(|item| {
+        filters.iter().all(|filter| {
+            let Some(term) = param(filter) else {
+                return true;
+            };
+            let value = match item.pointer(&format!("/{}", filter.replace('.', "/"))) {
+                Some(Value::String(value)) => value.to_lowercase(),
+                Some(Value::Null) | None => return false,
+                Some(value) => value.to_string(),
+            };
+            let term = term.to_lowercase();
+            match term.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(exact) => value == exact,
+                None => value.contains(&term),
+            }
+        })
+    });
+    let offset = param("offset").and_then(|v| v.parse().ok()).unwrap_or(0);
+    let limit = param("limit").and_then(|v| v.parse().ok()).unwrap_or(usize::MAX);
+    items.skip(offset).take(limit).collect()
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    if let (Some(target), Some(patch)) = (target.as_object_mut(), patch.as_object()) {
+        for (key, value) in patch {
+            target.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+impl Spoolman {
+    fn vendor(&self, id: &Value) -> Value {
+        id.as_u64()
+            .and_then(|id| self.vendors.iter().find(|v| v["id"].as_u64() == Some(id)))
+            .cloned()
+            .unwrap_or(Value::Null)
+    }
+
+    // Filaments are returned with their vendor, and spools with their filament, as Spoolman does
+    fn filament(&self, filament: &Value) -> Value {
+        let mut filament = filament.clone();
+        let vendor = self.vendor(&filament["vendor_id"]);
+        if let Some(filament) = filament.as_object_mut() {
+            filament.remove("vendor_id");
+            if !vendor.is_null() {
+                filament.insert(String::from("vendor"), vendor);
+            }
+        }
+        filament
+    }
+
+    fn spool(&self, spool: &Value) -> Value {
+        let mut spool = spool.clone();
+        let filament = spool["filament_id"]
+            .as_u64()
+            .and_then(|id| self.filaments.iter().find(|v| v["id"].as_u64() == Some(id)))
+            .map(|v| self.filament(v))
+            .unwrap_or(Value::Null);
+        if let Some(spool) = spool.as_object_mut() {
+            spool.remove("filament_id");
+            spool.insert(String::from("filament"), filament);
+        }
+        spool
+    }
+
+    fn handle(&mut self, method: &str, path: &str, body: &Value) -> (u16, Value) {
+        let query = query_params(path);
+        let path = path.split('?').next().unwrap_or_default();
+        let parts = path.trim_start_matches("/api/v1/").split('/').collect::<Vec<_>>();
+        let id = parts.get(1).and_then(|v| v.parse::<u64>().ok());
+        match (method, parts.as_slice()) {
+            ("GET", ["field", "spool"]) => (200, Value::from(self.spool_fields.clone())),
+            ("POST", ["field", "spool", key]) => {
+                self.spool_fields.retain(|v| v["key"] != *key);
+                let mut field = body.clone();
+                merge(&mut field, &json!({ "key": key, "entity_type": "spool" }));
+                info!("Spool extra field '{key}' registered");
+                self.spool_fields.push(field);
+                (200, Value::from(self.spool_fields.clone()))
+            }
+            ("GET", ["vendor"]) => (200, filtered_list(self.vendors.clone(), &query, &["name"])),
+            ("POST", ["vendor"]) => {
+                let mut vendor = body.clone();
+                merge(&mut vendor, &json!({ "id": next_id(&self.vendors) }));
+                info!("Vendor created: {vendor}");
+                self.vendors.push(vendor.clone());
+                (200, vendor)
+            }
+            ("GET", ["filament"]) => {
+                let filaments = self.filaments.iter().map(|v| self.filament(v)).collect();
+                (200, filtered_list(filaments, &query, &["material", "color_hex", "vendor.name"]))
+            }
+            ("POST", ["filament"]) => {
+                if body["density"].is_null() || body["diameter"].is_null() {
+                    return (422, json!({ "message": "density and diameter are required" }));
+                }
+                let mut filament = body.clone();
+                merge(&mut filament, &json!({ "id": next_id(&self.filaments) }));
+                info!("Filament created: {filament}");
+                self.filaments.push(filament.clone());
+                (200, self.filament(&filament))
+            }
+            ("PATCH", ["filament", _]) => match id.and_then(|id| find_mut(&mut self.filaments, id)) {
+                Some(filament) => {
+                    merge(filament, body);
+                    info!("Filament updated: {filament}");
+                    let filament = filament.clone();
+                    (200, self.filament(&filament))
+                }
+                None => (404, json!({ "message": "No filament with that id" })),
+            },
+            ("GET", ["spool"]) => {
+                let spools = self.spools.iter().map(|v| self.spool(v)).collect();
+                (200, filtered_list(spools, &query, &["filament.id"]))
+            }
+            ("POST", ["spool"]) => {
+                if !self.filaments.iter().any(|v| v["id"] == body["filament_id"]) {
+                    return (404, json!({ "message": "No filament with that id" }));
+                }
+                let mut spool = body.clone();
+                merge(&mut spool, &json!({ "id": next_id(&self.spools), "used_weight": 0.0 }));
+                info!("Spool created: {spool}");
+                self.spools.push(spool.clone());
+                (200, self.spool(&spool))
+            }
+            ("PATCH", ["spool", _]) => match id.and_then(|id| find_mut(&mut self.spools, id)) {
+                Some(spool) => {
+                    // extra fields are merged, not replaced
+                    let mut patch = body.clone();
+                    if let (Some(extra), Some(patch_extra)) = (spool["extra"].as_object().cloned(), body["extra"].as_object()) {
+                        let mut merged: Map<String, Value> = extra;
+                        merged.extend(patch_extra.clone());
+                        patch["extra"] = Value::Object(merged);
+                    }
+                    merge(spool, &patch);
+                    info!("Spool updated: {spool}");
+                    let spool = spool.clone();
+                    (200, self.spool(&spool))
+                }
+                None => (404, json!({ "message": "No spool with that id" })),
+            },
+            ("PUT", ["spool", _, "use"]) => match id.and_then(|id| find_mut(&mut self.spools, id)) {
+                Some(spool) => {
+                    let use_weight = body["use_weight"].as_f64().unwrap_or(0.0);
+                    let used_weight = spool["used_weight"].as_f64().unwrap_or(0.0) + use_weight;
+                    spool["used_weight"] = Value::from(used_weight);
+                    if let Some(remaining_weight) = spool["remaining_weight"].as_f64() {
+                        spool["remaining_weight"] = Value::from((remaining_weight - use_weight).max(0.0));
+                    }
+                    info!("Spool {} used {use_weight}g: {spool}", spool["id"]);
+                    let spool = spool.clone();
+                    (200, self.spool(&spool))
+                }
+                None => (404, json!({ "message": "No spool with that id" })),
+            },
+            _ => {
+                warn!("Unsupported request {method} {path}");
+                (404, json!({ "message": "Not found" }))
+            }
+        }
+    }
+}
+
+// Reads a request, returns the method, path and json body (Null when there's none)
+fn read_request(stream: &TcpStream) -> std::io::Result<(String, String, Value)> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((header, value)) = line.split_once(':') {
+            if header.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body).unwrap_or(Value::Null)
+    };
+    Ok((method, path, body))
+}
+
+fn write_response(mut stream: &TcpStream, status: u16, body: &Value, chunked: bool) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Unprocessable Entity",
+    };
+    let body = body.to_string();
+    if chunked {
+        // in two chunks, to exercise reassembly
+        let (first, second) = body.as_bytes().split_at(body.len() / 2);
+        write!(
+            stream,
+            "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+        )?;
+        for chunk in [first, second] {
+            write!(stream, "{:x}\r\n", chunk.len())?;
+            stream.write_all(chunk)?;
+            write!(stream, "\r\n")?;
+        }
+        write!(stream, "0\r\n\r\n")?;
+    } else {
+        write!(
+            stream,
+            "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+    }
+    stream.flush()
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+
+    let listener = match TcpListener::bind(("0.0.0.0", args.port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on port {}: {e}", args.port);
+            std::process::exit(1);
+        }
+    };
+    info!("Spoolman mock listening on port {}", args.port);
+
+    let mut spoolman = Spoolman::default();
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let (method, path, body) = match read_request(&stream) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Failed to read request: {e}");
+                continue;
+            }
+        };
+        info!("{method} {path} {body}");
+        let (status, response) = spoolman.handle(&method, &path, &body);
+        if let Err(e) = write_response(&stream, status, &response, args.chunked) {
+            warn!("Failed to write response: {e}");
+        }
+    }
+}
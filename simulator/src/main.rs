@@ -0,0 +1,275 @@
+// Simulates a Bambu Lab printer on the LAN, so SpoolEase can be tested end to end without a printer:
+// MQTT over TLS on port 8883 (user 'bblp' with the access code), SSDP announcements, and reports that follow the
+// requests SpoolEase sends and scripted events (e.g. spool inserted).
+// SpoolEase verifies the printer certificate, so the device firmware needs to be built with the 'simulator' feature
+
+mod mqtt;
+mod printer;
+mod script;
+mod ssdp;
+
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use log::{debug, error, info, warn};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde_json::Value;
+
+use printer::Printer;
+
+const MQTT_PORT: u16 = 8883;
+const MQTT_USERNAME: &str = "bblp";
+const IDLE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const FILAMENT_CHANGE_STEP_TIME: Duration = Duration::from_secs(5);
+
+#[derive(Parser)]
+#[command(about = "Simulates a Bambu Lab printer on the LAN")]
+struct Args {
+    /// Printer serial, must match the CN of certs/printer-cert.pem (see gen-certs.sh)
+    #[arg(long, default_value = "SIMULATOR0001")]
+    serial: String,
+    #[arg(long, default_value = "12345678")]
+    access_code: String,
+    #[arg(long, default_value = "Simulator")]
+    name: String,
+    /// Model code announced, e.g. C11 (P1P), C12 (P1S), N2S (A1), BL-P001 (X1C)
+    #[arg(long, default_value = "C12")]
+    model: String,
+    /// Address to announce, by default the address of the interface with the default route
+    #[arg(long)]
+    ip: Option<Ipv4Addr>,
+    /// Number of AMS units
+    #[arg(long, default_value_t = 1)]
+    ams: usize,
+    /// Simulate AMS 2 Pro units, which can dry filament
+    #[arg(long)]
+    ams_2_pro: bool,
+    #[arg(long, default_value = "0.4")]
+    nozzle: String,
+    /// Directory with printer-cert.pem and printer-key.pem
+    #[arg(long, default_value = "certs")]
+    certs: PathBuf,
+    /// Events to run once SpoolEase connects, before reading events from stdin
+    #[arg(long)]
+    script: Option<PathBuf>,
+}
+
+pub struct Simulator {
+    pub ip: Ipv4Addr,
+    pub serial: String,
+    pub name: String,
+    pub model: String,
+    access_code: String,
+    pub printer: Mutex<Printer>,
+    clients: Mutex<Vec<mpsc::Sender<Vec<u8>>>>, // subscribed clients
+}
+
+impl Simulator {
+    fn report_topic(&self) -> String {
+        format!("device/{}/report", self.serial)
+    }
+
+    fn request_topic(&self) -> String {
+        format!("device/{}/request", self.serial)
+    }
+
+    // Sends the report to all subscribed clients
+    pub fn publish(&self, report: &Value) {
+        let payload = serde_json::to_vec(report).unwrap();
+        debug!("Report: {report}");
+        let packet = mqtt::encode_publish(&self.report_topic(), &payload);
+        self.clients.lock().unwrap().retain(|client| client.send(packet.clone()).is_ok());
+    }
+
+    fn wait_for_client(&self) {
+        while self.clients.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+}
+
+fn load_tls_config(certs_dir: &Path) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(certs_dir.join("printer-cert.pem"))?)).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(certs_dir.join("printer-key.pem"))?))?
+        .ok_or("no private key in printer-key.pem")?;
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS12])?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+// The address used to reach the LAN, without sending anything
+fn local_ip() -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((Ipv4Addr::new(8, 8, 8, 8), 80))?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) => Ok(ip),
+        std::net::IpAddr::V6(_) => Err(io::Error::other("no IPv4 address")),
+    }
+}
+
+fn handle_client(sim: &Simulator, tls_stream: &mut StreamOwned<ServerConnection, TcpStream>) -> Result<(), Box<dyn std::error::Error>> {
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    let mut tx = Some(tx); // moved to the clients once subscribed
+    let mut connected = false;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        match tls_stream.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()), // closed without TLS close_notify
+            Err(e) => return Err(e.into()),
+        }
+
+        while let Some((packet, len)) = mqtt::decode(&buf)? {
+            buf.drain(..len);
+            match packet {
+                mqtt::Packet::Connect { username, password } => {
+                    let authorized = username.as_deref() == Some(MQTT_USERNAME) && password.as_deref() == Some(sim.access_code.as_bytes());
+                    if !authorized {
+                        warn!("Rejected login of user {username:?}");
+                        tls_stream.write_all(&mqtt::encode_connack(mqtt::CONNACK_BAD_USERNAME_OR_PASSWORD))?;
+                        tls_stream.flush()?;
+                        return Ok(());
+                    }
+                    info!("Client logged in");
+                    connected = true;
+                    tls_stream.write_all(&mqtt::encode_connack(mqtt::CONNACK_ACCEPTED))?;
+                }
+                _ if !connected => return Err("packet before CONNECT".into()),
+                mqtt::Packet::Subscribe { pid, topics } => {
+                    let accepted: Vec<bool> = topics.iter().map(|topic| *topic == sim.report_topic()).collect();
+                    info!("Client subscribed to {topics:?}");
+                    tls_stream.write_all(&mqtt::encode_suback(pid, &accepted))?;
+                    if accepted.contains(&true) {
+                        if let Some(tx) = tx.take() {
+                            sim.clients.lock().unwrap().push(tx);
+                        }
+                    }
+                }
+                mqtt::Packet::Publish { topic, pid, payload } => {
+                    if let Some(pid) = pid {
+                        tls_stream.write_all(&mqtt::encode_puback(pid))?;
+                    }
+                    if topic != sim.request_topic() {
+                        warn!("Publish to unexpected topic '{topic}'");
+                        continue;
+                    }
+                    match serde_json::from_slice::<Value>(&payload) {
+                        Ok(request) => {
+                            info!("Request: {request}");
+                            let reports = sim.printer.lock().unwrap().handle_request(&request);
+                            for report in reports {
+                                sim.publish(&report);
+                            }
+                        }
+                        Err(e) => warn!("Request isn't json ({e}): {}", String::from_utf8_lossy(&payload)),
+                    }
+                }
+                mqtt::Packet::PingReq => tls_stream.write_all(&mqtt::encode_pingresp())?,
+                mqtt::Packet::Disconnect => return Ok(()),
+                mqtt::Packet::Other(packet_type) => debug!("Ignored packet of type {packet_type}"),
+            }
+        }
+
+        while let Ok(packet) = rx.try_recv() {
+            tls_stream.write_all(&packet)?;
+        }
+        tls_stream.flush()?;
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+
+    let tls_config = load_tls_config(&args.certs).map_err(|e| {
+        format!(
+            "loading certificates from {:?} failed, run ./gen-certs.sh to generate them : {e}",
+            args.certs
+        )
+    })?;
+    let script = args.script.map(std::fs::read_to_string).transpose()?;
+    let ip = match args.ip {
+        Some(ip) => ip,
+        None => local_ip()?,
+    };
+
+    let sim = Arc::new(Simulator {
+        ip,
+        serial: args.serial,
+        name: args.name,
+        model: args.model,
+        access_code: args.access_code,
+        printer: Mutex::new(Printer::new(args.ams, &args.nozzle, args.ams_2_pro)),
+        clients: Mutex::new(Vec::new()),
+    });
+
+    ssdp::start(sim.clone())?;
+
+    let script_sim = sim.clone();
+    thread::spawn(move || {
+        script_sim.wait_for_client();
+        script::start(script_sim, script);
+    });
+
+    let idle_sim = sim.clone();
+    thread::spawn(move || loop {
+        thread::sleep(IDLE_REPORT_INTERVAL);
+        let report = idle_sim.printer.lock().unwrap().idle_report();
+        idle_sim.publish(&report);
+    });
+
+    let change_sim = sim.clone();
+    thread::spawn(move || loop {
+        thread::sleep(FILAMENT_CHANGE_STEP_TIME);
+        let report = change_sim.printer.lock().unwrap().change_filament_step();
+        if let Some(report) = report {
+            change_sim.publish(&report);
+        }
+    });
+
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, MQTT_PORT))?;
+    info!(
+        "Printer '{}' ({}) listening on {}:{} with access code {}",
+        sim.name, sim.serial, sim.ip, MQTT_PORT, sim.access_code
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Accept failed: {e}");
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().map(|v| v.to_string()).unwrap_or_default();
+        info!("Connection from {peer}");
+        let sim = sim.clone();
+        let tls_config = tls_config.clone();
+        thread::spawn(move || {
+            let res = stream
+                .set_read_timeout(Some(READ_POLL_INTERVAL))
+                .map_err(|e| e.into())
+                .and_then(|_| ServerConnection::new(tls_config).map_err(|e| e.into()))
+                .and_then(|connection| handle_client(&sim, &mut StreamOwned::new(connection, stream)));
+            match res {
+                Ok(_) => info!("Connection from {peer} closed"),
+                Err(e) => warn!("Connection from {peer} failed: {e}"),
+            }
+        });
+    }
+    Ok(())
+}
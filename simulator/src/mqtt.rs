@@ -0,0 +1,175 @@
+// Minimal MQTT 3.1.1 packets handling, only what a printer's broker does with a single client type (no retained
+// messages, no wills, QoS 1 publishes are acknowledged but never redelivered)
+
+use std::fmt;
+
+pub const CONNACK_ACCEPTED: u8 = 0;
+pub const CONNACK_BAD_USERNAME_OR_PASSWORD: u8 = 4;
+
+#[derive(Debug)]
+pub enum Packet {
+    Connect { username: Option<String>, password: Option<Vec<u8>> },
+    Publish { topic: String, pid: Option<u16>, payload: Vec<u8> },
+    Subscribe { pid: u16, topics: Vec<String> },
+    PingReq,
+    Disconnect,
+    Other(u8), // packet type of packets that are ignored
+}
+
+#[derive(Debug)]
+pub struct DecodeError(&'static str);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed MQTT packet: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        let v = *self.data.get(self.offset).ok_or(DecodeError("truncated"))?;
+        self.offset += 1;
+        Ok(v)
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.u16()? as usize;
+        let v = self.data.get(self.offset..self.offset + len).ok_or(DecodeError("truncated"))?;
+        self.offset += len;
+        Ok(v)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| DecodeError("invalid utf8 string"))
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.offset..]
+    }
+}
+
+// Decodes the packet at the start of buf, returns it with its length, or None if it didn't fully arrive yet
+pub fn decode(buf: &[u8]) -> Result<Option<(Packet, usize)>, DecodeError> {
+    let Some(&first) = buf.first() else {
+        return Ok(None);
+    };
+    let mut remaining_len = 0usize;
+    let mut offset = 1;
+    loop {
+        let Some(&byte) = buf.get(offset) else {
+            return Ok(None);
+        };
+        remaining_len |= ((byte & 0x7F) as usize) << (7 * (offset - 1));
+        offset += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if offset > 4 {
+            return Err(DecodeError("remaining length"));
+        }
+    }
+    let packet_len = offset + remaining_len;
+    if buf.len() < packet_len {
+        return Ok(None);
+    }
+
+    let mut reader = Reader {
+        data: &buf[offset..packet_len],
+        offset: 0,
+    };
+    let packet = match first >> 4 {
+        1 => {
+            let _protocol_name = reader.string()?;
+            let _protocol_level = reader.u8()?;
+            let flags = reader.u8()?;
+            let _keep_alive = reader.u16()?;
+            let _client_id = reader.string()?;
+            if flags & 0x04 != 0 {
+                // will topic and message
+                reader.bytes()?;
+                reader.bytes()?;
+            }
+            let username = if flags & 0x80 != 0 { Some(reader.string()?) } else { None };
+            let password = if flags & 0x40 != 0 { Some(reader.bytes()?.to_vec()) } else { None };
+            Packet::Connect { username, password }
+        }
+        3 => {
+            let qos = (first >> 1) & 0x03;
+            let topic = reader.string()?;
+            let pid = if qos > 0 { Some(reader.u16()?) } else { None };
+            Packet::Publish {
+                topic,
+                pid,
+                payload: reader.rest().to_vec(),
+            }
+        }
+        8 => {
+            let pid = reader.u16()?;
+            let mut topics = Vec::new();
+            while !reader.rest().is_empty() {
+                topics.push(reader.string()?);
+                let _qos = reader.u8()?;
+            }
+            Packet::Subscribe { pid, topics }
+        }
+        12 => Packet::PingReq,
+        14 => Packet::Disconnect,
+        packet_type => Packet::Other(packet_type),
+    };
+    Ok(Some((packet, packet_len)))
+}
+
+fn encode_packet(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+pub fn encode_connack(return_code: u8) -> Vec<u8> {
+    encode_packet(0x20, &[0, return_code])
+}
+
+pub fn encode_suback(pid: u16, topics_accepted: &[bool]) -> Vec<u8> {
+    let mut body = pid.to_be_bytes().to_vec();
+    body.extend(topics_accepted.iter().map(|accepted| if *accepted { 0x00 } else { 0x80 }));
+    encode_packet(0x90, &body)
+}
+
+pub fn encode_puback(pid: u16) -> Vec<u8> {
+    encode_packet(0x40, &pid.to_be_bytes())
+}
+
+pub fn encode_pingresp() -> Vec<u8> {
+    encode_packet(0xD0, &[])
+}
+
+// QoS 0, like the printer's reports
+pub fn encode_publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+    encode_packet(0x30, &body)
+}
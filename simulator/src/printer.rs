@@ -0,0 +1,644 @@
+// State of the simulated printer, the reports it produces and how it reacts to requests
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+
+pub const VT_TRAY_ID: u32 = 254;
+const NO_TRAY: u32 = 255; // tray_now / tray_tar when no tray is loaded
+const RUNOUT_ERROR: u32 = 0x0700_8011; // print_error of a filament runout, the AMS id in bits 16-23, 0xFF for the external spool
+const HOME_FLAG_REMAIN_ESTIMATE_BIT: u32 = 7;
+const HOME_FLAG_AUTO_REFILL_BIT: u32 = 10;
+const TRAYS_PER_AMS: usize = 4;
+const AMS_TYPE_AMS: u32 = 1; // bits 0-3 of the ams info
+const AMS_TYPE_AMS_2_PRO: u32 = 3;
+const NO_TAG_UID: &str = "0000000000000000";
+const NO_TRAY_UUID: &str = "00000000000000000000000000000000";
+
+#[derive(Debug, Clone, Default)]
+pub struct TrayFilament {
+    pub tray_info_idx: String, // e.g. "GFA00"
+    pub tray_type: String,     // e.g. "PLA"
+    pub tray_color: String,    // e.g. "FF0000FF"
+    pub nozzle_temp_min: u32,
+    pub nozzle_temp_max: u32,
+}
+
+impl TrayFilament {
+    pub fn new(tray_type: &str, tray_color: &str, tray_info_idx: &str) -> Self {
+        let (nozzle_temp_min, nozzle_temp_max) = match tray_type {
+            "PETG" => (220, 260),
+            "ABS" | "ASA" => (240, 270),
+            "TPU" => (200, 250),
+            _ => (190, 240),
+        };
+        Self {
+            tray_info_idx: tray_info_idx.to_string(),
+            tray_type: tray_type.to_string(),
+            tray_color: tray_color.to_string(),
+            nozzle_temp_min,
+            nozzle_temp_max,
+        }
+    }
+}
+
+// A spool in a tray
+#[derive(Debug, Clone, Default)]
+pub struct Tray {
+    pub filament: Option<TrayFilament>, // None when the printer doesn't know what's loaded
+    pub tray_uuid: Option<String>,      // Bambu spools RFID
+    pub remain: i32,
+    pub cali_idx: i32,
+    pub k: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Calibration {
+    nozzle_diameter: String,
+    cali_idx: i32,
+    filament_id: String,
+    setting_id: String,
+    name: String,
+    k_value: String,
+    n_coef: String,
+}
+
+pub struct Printer {
+    ams: Vec<[Option<Tray>; TRAYS_PER_AMS]>, // None when no spool in the slot
+    ams_type: u32,
+    drying_ends: Vec<Option<Instant>>, // per AMS, when drying ends
+    vt_tray: Tray,
+    nozzle_diameter: String,
+    nozzle_type: String,
+    calibrations: Vec<Calibration>,
+    next_cali_idx: i32,
+    tray_reading_bits: u32,
+    tray_now: u32,    // tray loaded into the extruder
+    tray_tar: u32,    // tray being switched to, differs from tray_now while changing filament
+    print_error: u32, // 0 when none
+    insertion_read: bool,
+    startup_read: bool,
+    remain_estimate: bool,
+    auto_refill: bool,
+    sequence_id: u64,
+    ams_version: u64,
+}
+
+impl Printer {
+    pub fn new(ams_count: usize, nozzle_diameter: &str, ams_2_pro: bool) -> Self {
+        Self {
+            ams: vec![Default::default(); ams_count],
+            ams_type: if ams_2_pro { AMS_TYPE_AMS_2_PRO } else { AMS_TYPE_AMS },
+            drying_ends: vec![None; ams_count],
+            vt_tray: Tray {
+                cali_idx: -1,
+                k: 0.02,
+                ..Default::default()
+            },
+            nozzle_diameter: nozzle_diameter.to_string(),
+            nozzle_type: "stainless_steel".to_string(),
+            calibrations: Vec::new(),
+            next_cali_idx: 1,
+            tray_reading_bits: 0,
+            tray_now: NO_TRAY,
+            tray_tar: NO_TRAY,
+            print_error: 0,
+            insertion_read: true,
+            startup_read: false,
+            remain_estimate: true,
+            auto_refill: false,
+            sequence_id: 0,
+            ams_version: 0,
+        }
+    }
+
+    fn next_sequence_id(&mut self) -> String {
+        self.sequence_id += 1;
+        self.sequence_id.to_string()
+    }
+
+    fn slot_mut(&mut self, tray_id: u32) -> Result<&mut Option<Tray>, String> {
+        let (ams_id, slot_id) = (tray_id as usize / TRAYS_PER_AMS, tray_id as usize % TRAYS_PER_AMS);
+        let ams_count = self.ams.len();
+        self.ams
+            .get_mut(ams_id)
+            .map(|ams| &mut ams[slot_id])
+            .ok_or_else(|| format!("no tray {tray_id}, there are {ams_count} AMS units"))
+    }
+
+    fn tray_mut(&mut self, tray_id: u32) -> Result<&mut Tray, String> {
+        if tray_id == VT_TRAY_ID {
+            return Ok(&mut self.vt_tray);
+        }
+        self.slot_mut(tray_id)?.as_mut().ok_or_else(|| format!("no spool in tray {tray_id}"))
+    }
+
+    // Reports
+
+    fn tray_json(id: u32, tray: &Tray) -> Value {
+        let filament = tray.filament.clone().unwrap_or_default();
+        json!({
+            "id": id.to_string(),
+            "remain": tray.remain,
+            "k": tray.k,
+            "n": 1.0,
+            "cali_idx": tray.cali_idx,
+            "tag_uid": if tray.tray_uuid.is_some() { "A1B2C3D4E5F60708" } else { NO_TAG_UID },
+            "tray_id_name": if tray.tray_uuid.is_some() { "A00-R0" } else { "" },
+            "tray_info_idx": filament.tray_info_idx,
+            "tray_type": filament.tray_type,
+            "tray_sub_brands": "",
+            "tray_color": filament.tray_color,
+            "tray_weight": if tray.tray_uuid.is_some() { "1000" } else { "0" },
+            "tray_diameter": "1.75",
+            "tray_temp": "0",
+            "tray_time": "0",
+            "bed_temp_type": "0",
+            "bed_temp": "0",
+            "nozzle_temp_max": filament.nozzle_temp_max.to_string(),
+            "nozzle_temp_min": filament.nozzle_temp_min.to_string(),
+            "xcam_info": "000000000000000000000000",
+            "tray_uuid": tray.tray_uuid.as_deref().unwrap_or(NO_TRAY_UUID),
+            "ctype": 0,
+            "cols": tray.filament.iter().map(|filament| filament.tray_color.clone()).collect::<Vec<_>>(),
+        })
+    }
+
+    fn ams_json(&mut self) -> Value {
+        self.ams_version += 1;
+        let now = Instant::now();
+        let dry_times: Vec<u64> = self
+            .drying_ends
+            .iter()
+            .map(|end| end.map(|end| end.saturating_duration_since(now).as_secs().div_ceil(60)).unwrap_or(0))
+            .collect();
+        let mut tray_exist_bits = 0u32;
+        let mut tray_is_bbl_bits = 0u32;
+        let ams: Vec<Value> = self
+            .ams
+            .iter()
+            .enumerate()
+            .map(|(ams_id, slots)| {
+                let trays: Vec<Value> = slots
+                    .iter()
+                    .enumerate()
+                    .map(|(slot_id, slot)| {
+                        let tray_id = (ams_id * TRAYS_PER_AMS + slot_id) as u32;
+                        match slot {
+                            Some(tray) => {
+                                tray_exist_bits |= 1 << tray_id;
+                                if tray.tray_uuid.is_some() {
+                                    tray_is_bbl_bits |= 1 << tray_id;
+                                }
+                                let mut tray = Self::tray_json(tray_id, tray);
+                                tray["id"] = json!(slot_id.to_string());
+                                tray
+                            }
+                            None => json!({ "id": slot_id.to_string() }),
+                        }
+                    })
+                    .collect();
+                json!({
+                    "id": ams_id.to_string(),
+                    "humidity": "4",
+                    "temp": "24.6",
+                    "info": format!("100{}", self.ams_type),
+                    "dry_time": dry_times[ams_id],
+                    "tray": trays,
+                })
+            })
+            .collect();
+        let all_trays_bits = (1u32 << (self.ams.len() * TRAYS_PER_AMS)).wrapping_sub(1);
+        json!({
+            "ams": ams,
+            "ams_exist_bits": format!("{:x}", (1u32 << self.ams.len()).wrapping_sub(1)),
+            "tray_exist_bits": format!("{tray_exist_bits:x}"),
+            "tray_is_bbl_bits": format!("{tray_is_bbl_bits:x}"),
+            "tray_tar": self.tray_tar.to_string(),
+            "tray_now": self.tray_now.to_string(),
+            "tray_pre": "255",
+            "tray_read_done_bits": format!("{:x}", all_trays_bits & !self.tray_reading_bits),
+            "tray_reading_bits": format!("{:x}", self.tray_reading_bits),
+            "version": self.ams_version,
+            "insert_flag": self.insertion_read,
+            "power_on_flag": self.startup_read,
+        })
+    }
+
+    // Status bits, including the AMS settings not reported in the ams section
+    fn home_flag(&self) -> u32 {
+        let mut home_flag = 0x0000_0147; // homed axes etc. as reported by an idle printer
+        if self.remain_estimate {
+            home_flag |= 1 << HOME_FLAG_REMAIN_ESTIMATE_BIT;
+        }
+        if self.auto_refill {
+            home_flag |= 1 << HOME_FLAG_AUTO_REFILL_BIT;
+        }
+        home_flag
+    }
+
+    fn push_status(&mut self, msg: u32, fields: Map<String, Value>) -> Value {
+        let mut print = json!({
+            "command": "push_status",
+            "msg": msg,
+            "sequence_id": self.next_sequence_id(),
+        });
+        print.as_object_mut().unwrap().extend(fields);
+        json!({ "print": print })
+    }
+
+    // Response to pushall, the whole state
+    pub fn full_report(&mut self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("ams".to_string(), self.ams_json());
+        fields.insert("vt_tray".to_string(), Self::tray_json(VT_TRAY_ID, &self.vt_tray));
+        fields.insert("nozzle_diameter".to_string(), json!(self.nozzle_diameter));
+        fields.insert("nozzle_type".to_string(), json!(self.nozzle_type));
+        fields.insert("home_flag".to_string(), json!(self.home_flag()));
+        fields.insert("print_error".to_string(), json!(self.print_error));
+        // fields not used by SpoolEase, here so reports are of realistic size
+        fields.insert("nozzle_temper".to_string(), json!(24.5));
+        fields.insert("nozzle_target_temper".to_string(), json!(0));
+        fields.insert("bed_temper".to_string(), json!(23.8));
+        fields.insert("bed_target_temper".to_string(), json!(0));
+        fields.insert("gcode_state".to_string(), json!("IDLE"));
+        fields.insert("wifi_signal".to_string(), json!("-44dBm"));
+        fields.insert("lights_report".to_string(), json!([{ "node": "chamber_light", "mode": "on" }]));
+        fields.insert("hms".to_string(), json!([]));
+        fields.insert(
+            "upgrade_state".to_string(),
+            json!({ "status": "IDLE", "progress": "", "message": "", "new_version_state": 2 }),
+        );
+        self.push_status(0, fields)
+    }
+
+    pub fn ams_report(&mut self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("ams".to_string(), self.ams_json());
+        self.push_status(1, fields)
+    }
+
+    pub fn vt_tray_report(&mut self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("vt_tray".to_string(), Self::tray_json(VT_TRAY_ID, &self.vt_tray));
+        self.push_status(1, fields)
+    }
+
+    pub fn tray_report(&mut self, tray_id: u32) -> Value {
+        if tray_id == VT_TRAY_ID {
+            self.vt_tray_report()
+        } else {
+            self.ams_report()
+        }
+    }
+
+    // Periodic report with what changes while idle
+    pub fn idle_report(&mut self) -> Value {
+        let mut fields = Map::new();
+        if self.drying_ends.iter().any(|end| end.is_some()) {
+            fields.insert("ams".to_string(), self.ams_json());
+            let now = Instant::now();
+            for end in self.drying_ends.iter_mut() {
+                if end.is_some_and(|end| end <= now) {
+                    *end = None;
+                }
+            }
+        }
+        fields.insert("nozzle_temper".to_string(), json!(24.5));
+        fields.insert("bed_temper".to_string(), json!(23.8));
+        fields.insert("wifi_signal".to_string(), json!("-45dBm"));
+        self.push_status(1, fields)
+    }
+
+    // Events (scripted or typed)
+
+    pub fn insert_spool(&mut self, tray_id: u32, filament: Option<TrayFilament>) -> Result<(), String> {
+        let tray = Tray {
+            tray_uuid: filament.as_ref().map(|_| format!("{:032X}", 0x5A5A_0000_0000u64 + tray_id as u64)),
+            filament,
+            remain: 100,
+            cali_idx: -1,
+            k: 0.02,
+        };
+        if tray_id == VT_TRAY_ID {
+            self.vt_tray = tray;
+        } else {
+            *self.slot_mut(tray_id)? = Some(tray);
+        }
+        Ok(())
+    }
+
+    pub fn remove_spool(&mut self, tray_id: u32) -> Result<(), String> {
+        if tray_id == VT_TRAY_ID {
+            self.vt_tray.filament = None;
+            self.vt_tray.tray_uuid = None;
+        } else {
+            *self.slot_mut(tray_id)? = None;
+        }
+        Ok(())
+    }
+
+    // The AMS reads the RFID of a spool for a few seconds after it's inserted
+    pub fn set_tray_reading(&mut self, tray_id: u32, reading: bool) {
+        if tray_id == VT_TRAY_ID {
+            return;
+        }
+        if reading {
+            self.tray_reading_bits |= 1 << tray_id;
+        } else {
+            self.tray_reading_bits &= !(1 << tray_id);
+        }
+    }
+
+    // Advances a filament change, first unloading the loaded tray and then loading the target, returns the report if changed
+    pub fn change_filament_step(&mut self) -> Option<Value> {
+        if self.tray_now == self.tray_tar {
+            return None;
+        }
+        self.tray_now = if self.tray_now != NO_TRAY { NO_TRAY } else { self.tray_tar };
+        info!("Filament change: tray_now {}, tray_tar {}", self.tray_now, self.tray_tar);
+        Some(self.ams_report())
+    }
+
+    pub fn set_remain(&mut self, tray_id: u32, remain: i32) -> Result<(), String> {
+        self.tray_mut(tray_id)?.remain = remain;
+        Ok(())
+    }
+
+    // The filament of the tray ran out while printing from it, the printer pauses with the runout error until resumed
+    pub fn runout(&mut self, tray_id: u32) -> Result<(), String> {
+        self.tray_mut(tray_id)?.remain = 0;
+        self.tray_now = tray_id;
+        self.tray_tar = tray_id;
+        let unit = if tray_id == VT_TRAY_ID { 0xFF } else { tray_id / TRAYS_PER_AMS as u32 };
+        self.print_error = RUNOUT_ERROR | (unit << 16);
+        Ok(())
+    }
+
+    pub fn resume(&mut self) {
+        self.print_error = 0;
+    }
+
+    pub fn error_report(&mut self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("ams".to_string(), self.ams_json());
+        fields.insert("vt_tray".to_string(), Self::tray_json(VT_TRAY_ID, &self.vt_tray));
+        fields.insert("print_error".to_string(), json!(self.print_error));
+        self.push_status(1, fields)
+    }
+
+    pub fn set_nozzle(&mut self, nozzle_diameter: &str) {
+        self.nozzle_diameter = nozzle_diameter.to_string();
+    }
+
+    // A tray update with zeroed filament fields, as sometimes reported by printers
+    pub fn junk_tray_report(&mut self, tray_id: u32) -> Result<Value, String> {
+        let tray = self.tray_mut(tray_id)?.clone();
+        let mut tray_json = Self::tray_json(tray_id, &tray);
+        tray_json["tray_type"] = json!("00000000");
+        tray_json["tray_info_idx"] = json!("00000000");
+        tray_json["tray_color"] = json!("00000000");
+        let mut fields = Map::new();
+        if tray_id == VT_TRAY_ID {
+            fields.insert("vt_tray".to_string(), tray_json);
+        } else {
+            let ams_id = tray_id as usize / TRAYS_PER_AMS;
+            tray_json["id"] = json!((tray_id as usize % TRAYS_PER_AMS).to_string());
+            fields.insert(
+                "ams".to_string(),
+                json!({ "ams": [{ "id": ams_id.to_string(), "humidity": "4", "temp": "24.6", "tray": [tray_json] }] }),
+            );
+        }
+        Ok(self.push_status(1, fields))
+    }
+
+    // Requests
+
+    // Returns the reports the printer sends in response
+    pub fn handle_request(&mut self, request: &Value) -> Vec<Value> {
+        if let Some(pushing) = request.get("pushing") {
+            if pushing.get("command").and_then(|v| v.as_str()) == Some("pushall") {
+                return vec![self.full_report()];
+            }
+        }
+        let Some(print) = request.get("print") else {
+            warn!("Unhandled request {request}");
+            return Vec::new();
+        };
+        let command = print.get("command").and_then(|v| v.as_str()).unwrap_or_default();
+        let res = match command {
+            "ams_filament_setting" => self.ams_filament_setting(print),
+            "extrusion_cali_get" => return vec![self.extrusion_cali_get(print)],
+            "extrusion_cali_sel" => self.extrusion_cali_sel(print),
+            "extrusion_cali_set" => self.extrusion_cali_set(print),
+            "extrusion_cali_del" => self.extrusion_cali_del(print),
+            "ams_change_filament" => self.ams_change_filament(print),
+            "ams_user_setting" => self.ams_user_setting(print),
+            "print_option" => self.print_option(print),
+            "ams_filament_drying" => self.ams_filament_drying(print),
+            _ => {
+                warn!("Unhandled command '{command}'");
+                return Vec::new();
+            }
+        };
+        let mut response = print.clone();
+        let mut reports = Vec::new();
+        match res {
+            Ok(report) => {
+                response["result"] = json!("success");
+                response["reason"] = json!("success");
+                reports.push(json!({ "print": response }));
+                reports.extend(report);
+            }
+            Err(e) => {
+                warn!("Command '{command}' failed: {e}");
+                response["result"] = json!("fail");
+                response["reason"] = json!(e);
+                reports.push(json!({ "print": response }));
+            }
+        }
+        reports
+    }
+
+    fn ams_filament_setting(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        let ams_id = print.get("ams_id").and_then(|v| v.as_u64()).ok_or("missing ams_id")? as u32;
+        let tray_id = print.get("tray_id").and_then(|v| v.as_i64()).ok_or("missing tray_id")?;
+        let tray_id = if ams_id == 255 || tray_id == VT_TRAY_ID as i64 {
+            VT_TRAY_ID
+        } else {
+            ams_id * TRAYS_PER_AMS as u32 + tray_id as u32
+        };
+        let str_field = |name: &str| print.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let u32_field = |name: &str| print.get(name).and_then(|v| v.as_u64()).unwrap_or_default() as u32;
+        let filament = TrayFilament {
+            tray_info_idx: str_field("tray_info_idx"),
+            tray_type: str_field("tray_type"),
+            tray_color: str_field("tray_color"),
+            nozzle_temp_min: u32_field("nozzle_temp_min"),
+            nozzle_temp_max: u32_field("nozzle_temp_max"),
+        };
+        let tray = self.tray_mut(tray_id)?;
+        if filament.tray_info_idx.is_empty() {
+            // reset, the printer no longer knows what's loaded
+            info!("Tray {tray_id} filament reset");
+            tray.filament = None;
+        } else {
+            info!(
+                "Tray {tray_id} set to {} {} ({})",
+                filament.tray_type, filament.tray_color, filament.tray_info_idx
+            );
+            tray.filament = Some(filament);
+        }
+        tray.cali_idx = -1;
+        Ok(Some(self.tray_report(tray_id)))
+    }
+
+    // Starts the change, it progresses with change_filament_step
+    fn ams_change_filament(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        let target = print.get("target").and_then(|v| v.as_u64()).ok_or("missing target")? as u32;
+        if target != NO_TRAY {
+            self.tray_mut(target)?;
+        }
+        if self.tray_now != self.tray_tar {
+            return Err("filament change in progress".to_string());
+        }
+        info!("Filament change from tray {} to tray {target}", self.tray_now);
+        self.tray_tar = target;
+        Ok(Some(self.ams_report()))
+    }
+
+    fn ams_user_setting(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        let bool_field = |name: &str| print.get(name).and_then(|v| v.as_bool()).ok_or(format!("missing {name}"));
+        self.startup_read = bool_field("startup_read_option")?;
+        self.insertion_read = bool_field("tray_read_option")?;
+        self.remain_estimate = bool_field("calibrate_remain_flag")?;
+        info!(
+            "AMS settings: read on insertion {}, read on startup {}, remain estimate {}",
+            self.insertion_read, self.startup_read, self.remain_estimate
+        );
+        Ok(Some(self.settings_report()))
+    }
+
+    fn print_option(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        if let Some(auto_refill) = print.get("auto_switch_filament").and_then(|v| v.as_bool()) {
+            self.auto_refill = auto_refill;
+            info!("AMS settings: auto refill {auto_refill}");
+        }
+        Ok(Some(self.settings_report()))
+    }
+
+    fn ams_filament_drying(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        let ams_id = print.get("ams_id").and_then(|v| v.as_u64()).ok_or("missing ams_id")? as usize;
+        if ams_id >= self.ams.len() {
+            return Err(format!("no AMS {ams_id}"));
+        }
+        if self.ams_type != AMS_TYPE_AMS_2_PRO {
+            return Err("AMS can't dry filament".to_string());
+        }
+        let u64_field = |name: &str| print.get(name).and_then(|v| v.as_u64()).unwrap_or_default();
+        if u64_field("mode") == 1 {
+            let duration = u64_field("duration");
+            info!("AMS {ams_id} drying at {}°C for {duration}h", u64_field("temp"));
+            self.drying_ends[ams_id] = Some(Instant::now() + Duration::from_secs(duration * 3600));
+        } else {
+            info!("AMS {ams_id} drying stopped");
+            self.drying_ends[ams_id] = None;
+        }
+        Ok(Some(self.ams_report()))
+    }
+
+    fn settings_report(&mut self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("ams".to_string(), self.ams_json());
+        fields.insert("home_flag".to_string(), json!(self.home_flag()));
+        self.push_status(1, fields)
+    }
+
+    fn extrusion_cali_get(&mut self, print: &Value) -> Value {
+        let nozzle_diameter = print.get("nozzle_diameter").and_then(|v| v.as_str()).unwrap_or_default();
+        let filaments: Vec<Value> = self
+            .calibrations
+            .iter()
+            .filter(|calibration| calibration.nozzle_diameter == nozzle_diameter)
+            .map(|calibration| {
+                json!({
+                    "cali_idx": calibration.cali_idx,
+                    "filament_id": calibration.filament_id,
+                    "k_value": calibration.k_value,
+                    "n_coef": calibration.n_coef,
+                    "name": calibration.name,
+                    "setting_id": calibration.setting_id,
+                })
+            })
+            .collect();
+        let mut response = print.clone();
+        response["filaments"] = json!(filaments);
+        response["result"] = json!("success");
+        response["reason"] = json!("success");
+        json!({ "print": response })
+    }
+
+    fn extrusion_cali_sel(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        let tray_id = print.get("tray_id").and_then(|v| v.as_u64()).ok_or("missing tray_id")? as u32;
+        let cali_idx = print.get("cali_idx").and_then(|v| v.as_i64()).ok_or("missing cali_idx")? as i32;
+        let k = if cali_idx == -1 {
+            0.02
+        } else {
+            let calibration = self
+                .calibrations
+                .iter()
+                .find(|calibration| calibration.cali_idx == cali_idx)
+                .ok_or_else(|| format!("no calibration {cali_idx}"))?;
+            calibration.k_value.parse::<f32>().unwrap_or(0.02)
+        };
+        let tray = self.tray_mut(tray_id)?;
+        info!("Tray {tray_id} calibration set to {cali_idx}");
+        tray.cali_idx = cali_idx;
+        tray.k = k;
+        Ok(Some(self.tray_report(tray_id)))
+    }
+
+    fn extrusion_cali_set(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        let nozzle_diameter = print.get("nozzle_diameter").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let filaments = print.get("filaments").and_then(|v| v.as_array()).ok_or("missing filaments")?;
+        for filament in filaments {
+            let str_field = |name: &str| filament.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+            let cali_idx = filament.get("cali_idx").and_then(|v| v.as_i64()).map(|v| v as i32);
+            let existing = cali_idx.and_then(|cali_idx| self.calibrations.iter_mut().find(|calibration| calibration.cali_idx == cali_idx));
+            match existing {
+                Some(calibration) => {
+                    info!("Calibration {} '{}' updated", calibration.cali_idx, str_field("name"));
+                    calibration.name = str_field("name");
+                    calibration.k_value = str_field("k_value");
+                }
+                None => {
+                    let calibration = Calibration {
+                        nozzle_diameter: nozzle_diameter.clone(),
+                        cali_idx: self.next_cali_idx,
+                        filament_id: str_field("filament_id"),
+                        setting_id: str_field("setting_id"),
+                        name: str_field("name"),
+                        k_value: str_field("k_value"),
+                        n_coef: str_field("n_coef"),
+                    };
+                    info!("Calibration {} '{}' added", calibration.cali_idx, calibration.name);
+                    self.next_cali_idx += 1;
+                    self.calibrations.push(calibration);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn extrusion_cali_del(&mut self, print: &Value) -> Result<Option<Value>, String> {
+        let cali_idx = print.get("cali_idx").and_then(|v| v.as_i64()).ok_or("missing cali_idx")? as i32;
+        let count = self.calibrations.len();
+        self.calibrations.retain(|calibration| calibration.cali_idx != cali_idx);
+        if self.calibrations.len() == count {
+            return Err(format!("no calibration {cali_idx}"));
+        }
+        info!("Calibration {cali_idx} deleted");
+        Ok(None)
+    }
+}
@@ -0,0 +1,153 @@
+// Scripted events, read from a script file and then typed on stdin, one per line:
+//   insert <tray> [<type> <color> <tray_info_idx>]  spool inserted, with filament details it's a Bambu spool with RFID
+//   remove <tray>                                   spool removed
+//   remain <tray> <percent>                         filament left on the spool
+//   runout <tray>                                   filament of the tray ran out while printing from it
+//   resume                                          runout error cleared
+//   junk <tray>                                     tray report with zeroed filament fields
+//   nozzle <diameter>                               nozzle swapped
+//   pushall                                         send the full state, as if requested
+//   wait <seconds>
+// Trays are numbered ams_id * 4 + slot, the external spool is 254. Lines starting with '#' are comments
+
+use std::{
+    io::{self, BufRead},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use log::{error, info};
+
+use crate::{
+    printer::{TrayFilament, VT_TRAY_ID},
+    Simulator,
+};
+
+const RFID_READING_TIME: Duration = Duration::from_millis(1500);
+
+fn parse_tray(arg: Option<&str>) -> Result<u32, String> {
+    let arg = arg.ok_or("missing tray")?;
+    match arg {
+        "ext" => Ok(VT_TRAY_ID),
+        _ => arg.parse::<u32>().map_err(|_| format!("bad tray '{arg}'")),
+    }
+}
+
+pub fn run_line(sim: &Simulator, line: &str) -> Result<(), String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+    let mut args = line.split_whitespace();
+    let command = args.next().unwrap_or_default();
+    match command {
+        "insert" => {
+            let tray_id = parse_tray(args.next())?;
+            let filament = match (args.next(), args.next(), args.next()) {
+                (Some(tray_type), Some(tray_color), Some(tray_info_idx)) => Some(TrayFilament::new(tray_type, tray_color, tray_info_idx)),
+                (None, _, _) => None,
+                _ => return Err("insert needs all of <type> <color> <tray_info_idx>, or none".to_string()),
+            };
+            let report = {
+                let mut printer = sim.printer.lock().unwrap();
+                printer.insert_spool(tray_id, filament)?;
+                printer.set_tray_reading(tray_id, true);
+                printer.tray_report(tray_id)
+            };
+            sim.publish(&report);
+            if tray_id != VT_TRAY_ID {
+                thread::sleep(RFID_READING_TIME);
+                let report = {
+                    let mut printer = sim.printer.lock().unwrap();
+                    printer.set_tray_reading(tray_id, false);
+                    printer.ams_report()
+                };
+                sim.publish(&report);
+            }
+        }
+        "remove" => {
+            let tray_id = parse_tray(args.next())?;
+            let report = {
+                let mut printer = sim.printer.lock().unwrap();
+                printer.remove_spool(tray_id)?;
+                printer.tray_report(tray_id)
+            };
+            sim.publish(&report);
+        }
+        "remain" => {
+            let tray_id = parse_tray(args.next())?;
+            let remain = args.next().and_then(|v| v.parse::<i32>().ok()).ok_or("missing percent")?;
+            let report = {
+                let mut printer = sim.printer.lock().unwrap();
+                printer.set_remain(tray_id, remain)?;
+                printer.tray_report(tray_id)
+            };
+            sim.publish(&report);
+        }
+        "runout" => {
+            let tray_id = parse_tray(args.next())?;
+            let report = {
+                let mut printer = sim.printer.lock().unwrap();
+                printer.runout(tray_id)?;
+                printer.error_report()
+            };
+            sim.publish(&report);
+        }
+        "resume" => {
+            let report = {
+                let mut printer = sim.printer.lock().unwrap();
+                printer.resume();
+                printer.error_report()
+            };
+            sim.publish(&report);
+        }
+        "junk" => {
+            let tray_id = parse_tray(args.next())?;
+            let report = sim.printer.lock().unwrap().junk_tray_report(tray_id)?;
+            sim.publish(&report);
+        }
+        "nozzle" => {
+            let nozzle_diameter = args.next().ok_or("missing diameter")?;
+            let report = {
+                let mut printer = sim.printer.lock().unwrap();
+                printer.set_nozzle(nozzle_diameter);
+                printer.full_report()
+            };
+            sim.publish(&report);
+        }
+        "pushall" => {
+            let report = sim.printer.lock().unwrap().full_report();
+            sim.publish(&report);
+        }
+        "wait" => {
+            let seconds = args.next().and_then(|v| v.parse::<f32>().ok()).ok_or("missing seconds")?;
+            thread::sleep(Duration::from_secs_f32(seconds));
+        }
+        _ => return Err(format!("unknown command '{command}'")),
+    }
+    info!("Done: {line}");
+    Ok(())
+}
+
+// Runs the script, then the commands typed on stdin
+pub fn start(sim: Arc<Simulator>, script: Option<String>) {
+    thread::spawn(move || {
+        if let Some(script) = script {
+            for (line_num, line) in script.lines().enumerate() {
+                if let Err(e) = run_line(&sim, line) {
+                    error!("Script line {}: {e}", line_num + 1);
+                }
+            }
+            info!("Script completed");
+        }
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if let Err(e) = run_line(&sim, &line) {
+                error!("{e}");
+            }
+        }
+    });
+}
@@ -0,0 +1,93 @@
+// SSDP announcements of the simulated printer, the way Bambu printers announce themselves (NOTIFY to ports 1990 and
+// 2021), plus responses to M-SEARCH requests
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::Simulator;
+
+const SSDP_MULTICAST_ADDRESS: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORTS: [u16; 2] = [1990, 2021];
+const BAMBU_PRINTER_URN: &str = "urn:bambulab-com:device:3dprinter:1";
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+fn device_headers(sim: &Simulator) -> String {
+    format!(
+        "Location: {}\r\n\
+         USN: {}\r\n\
+         Cache-Control: max-age=1800\r\n\
+         DevModel.bambu.com: {}\r\n\
+         DevName.bambu.com: {}\r\n\
+         DevSignal.bambu.com: -44\r\n\
+         DevConnect.bambu.com: lan\r\n\
+         DevBind.bambu.com: free\r\n\
+         Devseclink.bambu.com: secure\r\n\
+         DevVersion.bambu.com: 01.08.00.00\r\n\
+         DevCap.bambu.com: 1\r\n",
+        sim.ip, sim.serial, sim.model, sim.name
+    )
+}
+
+fn notify_message(sim: &Simulator, port: u16) -> String {
+    format!(
+        "NOTIFY * HTTP/1.1\r\nHOST: {SSDP_MULTICAST_ADDRESS}:{port}\r\nServer: UPnP/1.0\r\nNT: {BAMBU_PRINTER_URN}\r\nNTS: ssdp:alive\r\n{}\r\n",
+        device_headers(sim)
+    )
+}
+
+fn search_response(sim: &Simulator) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nServer: UPnP/1.0\r\nST: {BAMBU_PRINTER_URN}\r\n{}\r\n",
+        device_headers(sim)
+    )
+}
+
+pub fn start(sim: Arc<Simulator>) -> std::io::Result<()> {
+    let send_socket = UdpSocket::bind(SocketAddrV4::new(sim.ip, 0))?;
+    send_socket.set_multicast_ttl_v4(1)?;
+    let notify_sim = sim.clone();
+    thread::spawn(move || loop {
+        for port in SSDP_PORTS {
+            let message = notify_message(&notify_sim, port);
+            if let Err(e) = send_socket.send_to(message.as_bytes(), SocketAddrV4::new(SSDP_MULTICAST_ADDRESS, port)) {
+                warn!("Failed to send SSDP NOTIFY: {e}");
+            }
+        }
+        thread::sleep(NOTIFY_INTERVAL);
+    });
+
+    // M-SEARCH is sent to the multicast address, other listeners on the host may share the port
+    let search_socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    search_socket.set_reuse_address(true)?;
+    search_socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, SSDP_PORTS[0]).into())?;
+    search_socket.join_multicast_v4(&SSDP_MULTICAST_ADDRESS, &sim.ip)?;
+    let search_socket: UdpSocket = search_socket.into();
+    info!("Announcing printer '{}' ({}) at {} with SSDP", sim.name, sim.serial, sim.ip);
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (len, from) = match search_socket.recv_from(&mut buf) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("SSDP receive error: {e}");
+                    continue;
+                }
+            };
+            let message = String::from_utf8_lossy(&buf[..len]);
+            if message.starts_with("M-SEARCH") && message.contains("urn:bambulab-com:device:3dprinter") {
+                debug!("M-SEARCH from {from}");
+                if let Err(e) = search_socket.send_to(search_response(&sim).as_bytes(), from) {
+                    warn!("Failed to respond to M-SEARCH: {e}");
+                }
+            }
+        }
+    });
+    Ok(())
+}